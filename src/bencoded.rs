@@ -1,4 +1,3 @@
-use serde_json;
 use serde_json::json;
 use std::collections::HashMap;
 use std::hash::Hash;
@@ -15,7 +14,7 @@ pub enum Value {
 
 impl Value {
     pub fn get_optional_by_key(&self, key: &str) -> Option<&Value> {
-        let wrapped_key = Value::String(key.chars().into_iter().map(|x| x as u8).collect());
+        let wrapped_key = Value::String(key.chars().map(|x| x as u8).collect());
         match self {
             Value::Object(values) => {
                 values.iter().find(|(stored_key, _)| {
@@ -31,16 +30,16 @@ impl Value {
             Some(value) => {
                 Ok(value)
             },
-            None => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Did not find key {:?} in {:?}", key, self)))
+            None => Err(std::io::Error::other(format!("Did not find key {:?} in {:?}", key, self)))
         }
     }
 
     pub fn as_string(&self) -> Result<String, std::io::Error> {
         match self {
             Value::String(bytes) => {
-                String::from_utf8(bytes.clone()).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+                String::from_utf8(bytes.clone()).map_err(std::io::Error::other)
             },
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?} cannot be cast to String", self)))
+            _ => Err(std::io::Error::other(format!("{:?} cannot be cast to String", self)))
         }
     }
 
@@ -49,7 +48,7 @@ impl Value {
             Value::String(bytes) => {
                 Ok(bytes.clone())
             },
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?} cannot be cast to Vec<u8>", self)))
+            _ => Err(std::io::Error::other(format!("{:?} cannot be cast to Vec<u8>", self)))
         }
     }
 
@@ -58,28 +57,58 @@ impl Value {
             Value::Number(value) => {
                 Ok(*value)
             },
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?} cannot be cast to i64", self)))
+            _ => Err(std::io::Error::other(format!("{:?} cannot be cast to i64", self)))
         }
     }
 
     pub fn as_values(&self) -> Result<&Vec<Value>, std::io::Error> {
         match self {
             Value::List(values) => Ok(values),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?} cannot be cast to a Vec<Value>", self)))
+            _ => Err(std::io::Error::other(format!("{:?} cannot be cast to a Vec<Value>", self)))
         }
     }
 }
 
 impl Value {
+    // Canonical encoding with the keys of the dictionaries sorted, the same value always encodes to the same bytes
+    pub(crate) fn bencode(&self) -> Vec<u8> {
+        let mut bencoded: Vec<u8> = Vec::new();
+        self.bencode_into(&mut bencoded);
+        bencoded
+    }
+
+    fn bencode_into(&self, bencoded: &mut Vec<u8>) {
+        match self {
+            Value::Number(number) => bencoded.encode_i64(number),
+            Value::String(bytes) => bencoded.encode_bytes(bytes),
+            Value::List(values) => {
+                bencoded.push(b'l');
+                values.iter().for_each(|value| value.bencode_into(bencoded));
+                bencoded.push(b'e');
+            },
+            Value::Object(pairs) => {
+                let mut sorted_pairs: Vec<&(Value, Value)> = pairs.iter().collect();
+                // Keys are byte strings compared as raw bytes, not by their encodings which start with the length
+                sorted_pairs.sort_by_key(|(key, _)| key.as_bytes().unwrap_or_default());
+                bencoded.push(b'd');
+                for (key, value) in sorted_pairs {
+                    key.bencode_into(bencoded);
+                    value.bencode_into(bencoded);
+                }
+                bencoded.push(b'e');
+            }
+        }
+    }
+
     pub(crate) fn as_json(&self) -> serde_json::Value {
         match self {
             Value::Number(number) => json!(number),
             Value::String(string_bytes) => {
-                let string: String = string_bytes.into_iter().map(|x| *x as char).collect();
+                let string: String = string_bytes.iter().map(|x| *x as char).collect();
                 json!(string)
             },
             Value::List(values) => {
-                let encoded_values: Vec<serde_json::Value> = values.into_iter().map(|value| value.as_json()).collect();
+                let encoded_values: Vec<serde_json::Value> = values.iter().map(|value| value.as_json()).collect();
                 json!(serde_json::Value::Array(encoded_values))
             },
             Value::Object(pairs) => {
@@ -127,21 +156,39 @@ pub(crate) fn decode_bencoded_from_str(input: &str) -> Result<Value, std::io::Er
     decode_bencoded(&input.chars().collect())
 }
 
-pub(crate) fn decode_bencoded_from_bytes(input: &Vec<u8>) -> Result<Value, std::io::Error> {
+pub(crate) fn decode_bencoded_from_bytes(input: &[u8]) -> Result<Value, std::io::Error> {
     let chars = input.iter().map(|b| *b as char).collect::<Vec<char>>();
     decode_bencoded(&chars)
 }
 
+// Decodes the value the input starts with, returns it with the number of bytes it takes, the rest of the input is left for the caller
+pub(crate) fn decode_bencoded_prefix(input: &[u8]) -> Result<(Value, usize), std::io::Error> {
+    let chars = input.iter().map(|b| *b as char).collect::<Vec<char>>();
+    match decode_bencoded_at_position(&chars, 0)? {
+        (Some(value), length) => Ok((value, length)),
+        (None, _) => Err(std::io::Error::other("Could not decode input"))
+    }
+}
+
 pub(crate) fn decode_bencoded(input: &Vec<char>) -> Result<Value, std::io::Error> {
-    //TODO: Return a Result, rather than use unwrap
     //TODO: Handle the case when not all of the encoded_value input has been read
-    decode_bencoded_at_position(input, 0).0.ok_or(std::io::Error::new(std::io::ErrorKind::Other, "Could not decode input"))
+    decode_bencoded_at_position(input, 0)?.0.ok_or(std::io::Error::other("Could not decode input"))
+}
+
+// Peers and trackers can send anything, so malformed input is an error rather than a panic
+fn malformed_input(input: &[char], position: usize) -> std::io::Error {
+    let unparsed_input: String = input[position.min(input.len())..].iter().take(64).collect();
+    std::io::Error::other(format!("Malformed input around position {:?}: {:?}", position, unparsed_input))
+}
+
+fn symbol_at(input: &[char], position: usize) -> Result<char, std::io::Error> {
+    input.get(position).copied().ok_or_else(|| malformed_input(input, position))
 }
 
-fn decode_bencoded_at_position(input: &Vec<char>, position: usize) -> (Option<Value>, usize) {
+fn decode_bencoded_at_position(input: &Vec<char>, position: usize) -> Result<(Option<Value>, usize), std::io::Error> {
     let current_position: usize = position;
-    let next_symbol = input[current_position];
-    if next_symbol.is_digit(10) {
+    let next_symbol = symbol_at(input, current_position)?;
+    if next_symbol.is_ascii_digit() {
         decode_string(input, position)
     } else if next_symbol == 'i' {
         decode_number(input, position)
@@ -150,37 +197,33 @@ fn decode_bencoded_at_position(input: &Vec<char>, position: usize) -> (Option<Va
     } else if next_symbol == 'l' {
         decode_list(input, position)
     } else {
-        let unparsed_input: String = input[position..].into_iter().collect();
-        panic!("Unhandled encoded value: {}", unparsed_input)
+        Err(malformed_input(input, position))
     }
 }
 
-fn decode_list(input: &Vec<char>, position: usize) -> (Option<Value>, usize) {
+fn decode_list(input: &Vec<char>, position: usize) -> Result<(Option<Value>, usize), std::io::Error> {
     let mut current_position: usize = position + 1; // skip 'l'
     let mut array_values: Vec<Value> = Vec::new();
-    while input[current_position] != 'e' && current_position < input.len() {
-        let (current_decoded_value, updated_position) = decode_bencoded_at_position(input, current_position);
+    while symbol_at(input, current_position)? != 'e' {
+        let (current_decoded_value, updated_position) = decode_bencoded_at_position(input, current_position)?;
         current_position = updated_position;
         if let Some(decoded_value) = current_decoded_value {
             array_values.push(decoded_value);
         }
     }
-    if input[current_position] != 'e' {
-        panic!("Malformed input '{:?}' around position {:?}", input, current_position);
-    }
-    (Some(Value::List(array_values)), current_position + 1) // skip 'e'
+    Ok((Some(Value::List(array_values)), current_position + 1)) // skip 'e'
 }
 
-fn decode_dictionary(input: &Vec<char>, position: usize) -> (Option<Value>, usize) {
+fn decode_dictionary(input: &Vec<char>, position: usize) -> Result<(Option<Value>, usize), std::io::Error> {
     let mut current_position: usize = position + 1; // skip 'd'
     let mut object: HashMap<Value, Value> = HashMap::new();
 
-    while current_position < input.len() && input[current_position] != 'e' {
-        let (maybe_key, new_position) = decode_bencoded_at_position(input, current_position);
+    while symbol_at(input, current_position)? != 'e' {
+        let (maybe_key, new_position) = decode_bencoded_at_position(input, current_position)?;
         current_position = new_position;
-        if input[current_position] != 'e' {
+        if symbol_at(input, current_position)? != 'e' {
             if let Some(key) = maybe_key {
-                let (maybe_value, new_position) = decode_bencoded_at_position(input, current_position);
+                let (maybe_value, new_position) = decode_bencoded_at_position(input, current_position)?;
                 current_position = new_position;
                 if let Some(value) = maybe_value {
                     object.insert(key, value);
@@ -188,43 +231,42 @@ fn decode_dictionary(input: &Vec<char>, position: usize) -> (Option<Value>, usiz
             }
         }
     }
-    if input[current_position] != 'e' {
-        panic!("Malformed input '{:?}' around position {:?}", input, current_position);
-    }
-    (Some(Value::Object(object.into_iter().collect())), current_position + 1) // skip 'e'
+    Ok((Some(Value::Object(object.into_iter().collect())), current_position + 1)) // skip 'e'
 }
 
-fn decode_string(input: &Vec<char>, position: usize) -> (Option<Value>, usize) {
+fn decode_string(input: &[char], position: usize) -> Result<(Option<Value>, usize), std::io::Error> {
     let mut current_position: usize = position;
-    let mut next_symbol = input[current_position];
+    let mut next_symbol = symbol_at(input, current_position)?;
 
     let mut number_chars: Vec<char> = Vec::new();
     while next_symbol != ':' {
         number_chars.push(next_symbol);
         current_position += 1;
-        next_symbol = input[current_position];
+        next_symbol = symbol_at(input, current_position)?;
     }
     current_position += 1;
     let string_value_length_input: String = number_chars.into_iter().collect();
-    let string_value_length = string_value_length_input.parse::<usize>().unwrap();
-    let string_value: Vec<u8> = input[current_position..current_position + string_value_length].into_iter().map(|ch| *ch as u8).collect();
+    let string_value_length = string_value_length_input.parse::<usize>().map_err(|_| malformed_input(input, position))?;
+    let string_end = current_position.checked_add(string_value_length).filter(|end| *end <= input.len())
+        .ok_or_else(|| malformed_input(input, position))?;
+    let string_value: Vec<u8> = input[current_position..string_end].iter().map(|ch| *ch as u8).collect();
 
-    (Some(Value::String(string_value)), current_position + string_value_length)
+    Ok((Some(Value::String(string_value)), string_end))
 }
 
-fn decode_number(input: &Vec<char>, position: usize) -> (Option<Value>, usize) {
+fn decode_number(input: &[char], position: usize) -> Result<(Option<Value>, usize), std::io::Error> {
     let mut current_position: usize = position + 1;
-    let mut next_symbol = input[current_position];
+    let mut next_symbol = symbol_at(input, current_position)?;
 
     let mut number_chars: Vec<char> = Vec::new();
     while next_symbol != 'e' {
         number_chars.push(next_symbol);
         current_position += 1;
-        next_symbol = input[current_position];
+        next_symbol = symbol_at(input, current_position)?;
     }
     let number_input: String = number_chars.into_iter().collect();
-    let number: i64 = number_input.parse::<i64>().unwrap();
-    (Some(Value::Number(number)), current_position + 1) // skip the 'e' symbol
+    let number: i64 = number_input.parse::<i64>().map_err(|_| malformed_input(input, position))?;
+    Ok((Some(Value::Number(number)), current_position + 1)) // skip the 'e' symbol
 }
 
 #[cfg(test)]
@@ -291,4 +333,27 @@ mod tests {
             }
         ]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn should_fail_to_decode_malformed_input() {
+        for input in ["garbage", "d3:foo", "l5:hello", "i52", "ixe", "10:short", "d3:fooi1e"] {
+            assert!(decode_bencoded_from_str(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn should_bencode_values_canonically() {
+        let input = "d10:longer key1:a3:bari52e3:fool1:xi-1edeee";
+        let decoded = decode_bencoded_from_str(input).unwrap();
+        assert_eq!(decoded.bencode(), b"d3:bari52e3:fool1:xi-1edee10:longer key1:ae".to_vec());
+        assert_eq!(decode_bencoded_from_bytes(&decoded.bencode()).unwrap().bencode(), decoded.bencode());
+    }
+
+    #[test]
+    fn should_decode_value_at_start_of_input() {
+        let (value, length) = decode_bencoded_prefix(b"d5:piecei0eeraw bytes").unwrap();
+        assert_eq!(value.get_by_key("piece").unwrap().as_number().unwrap(), 0);
+        assert_eq!(length, 12);
+        assert!(decode_bencoded_prefix(b"d5:piece").is_err());
+    }
+}
@@ -0,0 +1,125 @@
+// Pieces which a peer has, the high bit of the first byte is piece 0 https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+use anyhow::ensure;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Bitfield {
+    bytes: Vec<u8>,
+    piece_count: usize
+}
+
+impl Bitfield {
+    pub(crate) fn new(piece_count: usize) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; piece_count.div_ceil(8)],
+            piece_count
+        }
+    }
+
+    // Sent as 'have all' by the seeds which support the fast extension https://www.bittorrent.org/beps/bep_0006.html
+    pub(crate) fn full(piece_count: usize) -> Bitfield {
+        let mut bitfield = Bitfield::new(piece_count);
+        for index in 0..piece_count {
+            bitfield.bytes[index / 8] |= 1 << (7 - (index % 8));
+        }
+        bitfield
+    }
+
+    // Validates the bitfield received from a peer: it has one bit per piece and the spare bits at the end are cleared
+    pub(crate) fn from_bytes(bytes: &[u8], piece_count: usize) -> Result<Bitfield, anyhow::Error> {
+        ensure!(bytes.len() == piece_count.div_ceil(8), "Bitfield of {} bytes does not match {} pieces", bytes.len(), piece_count);
+        let bitfield = Bitfield {
+            bytes: bytes.to_vec(),
+            piece_count
+        };
+        ensure!((piece_count..bytes.len() * 8).all(|index| !bitfield.bit(index)), "Spare bits of the bitfield are set");
+        Ok(bitfield)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub(crate) fn has(&self, index: usize) -> bool {
+        index < self.piece_count && self.bit(index)
+    }
+
+    pub(crate) fn set(&mut self, index: usize) -> Result<(), anyhow::Error> {
+        ensure!(index < self.piece_count, "Piece index {} is out of range, there are {} pieces", index, self.piece_count);
+        self.bytes[index / 8] |= 1 << (7 - (index % 8));
+        Ok(())
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        (0..self.piece_count).filter(|index| self.bit(*index)).count()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.iter().all(|byte| *byte == 0)
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.count() == self.piece_count
+    }
+
+    // Only the given pieces out of the ones in this bitfield
+    pub(crate) fn only(&self, pieces: impl IntoIterator<Item = usize>) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.piece_count);
+        for index in pieces.into_iter().filter(|index| self.has(*index)) {
+            bitfield.bytes[index / 8] |= 1 << (7 - (index % 8));
+        }
+        bitfield
+    }
+
+    pub(crate) fn piece_indices(&self) -> Vec<usize> {
+        (0..self.piece_count).filter(|index| self.bit(*index)).collect()
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (1 << (7 - (index % 8))) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn as_byte(binary_str: &str) -> u8 {
+        u8::from_str_radix(binary_str, 2).unwrap()
+    }
+
+    #[test]
+    fn should_compute_piece_indices_from_bitfield() {
+        assert_eq!(Bitfield::from_bytes(&[], 0).unwrap().piece_indices(), Vec::<usize>::new());
+        assert_eq!(Bitfield::from_bytes(&[as_byte("01010000")], 8).unwrap().piece_indices(), vec![1, 3]);
+        assert_eq!(Bitfield::from_bytes(&[
+            as_byte("00100010"), as_byte("11110000"), as_byte("10101010")
+        ], 24).unwrap().piece_indices(), vec![2, 6, 8, 9, 10, 11, 16, 18, 20, 22]);
+    }
+
+    #[test]
+    fn should_validate_length_and_spare_bits() {
+        assert!(Bitfield::from_bytes(&[as_byte("11100000")], 3).is_ok());
+        assert!(Bitfield::from_bytes(&[as_byte("11110000")], 3).is_err());
+        assert!(Bitfield::from_bytes(&[0, 0], 3).is_err());
+        assert!(Bitfield::from_bytes(&[], 3).is_err());
+    }
+
+    #[test]
+    fn should_set_pieces() {
+        let mut bitfield = Bitfield::new(10);
+        assert!(bitfield.is_empty());
+        bitfield.set(0).unwrap();
+        bitfield.set(9).unwrap();
+        assert!(bitfield.set(10).is_err());
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.as_bytes(), &[as_byte("10000000"), as_byte("01000000")]);
+        assert_eq!(bitfield.count(), 2);
+        assert!(!bitfield.is_complete());
+
+        let full_bitfield = Bitfield::full(10);
+        assert!(full_bitfield.is_complete());
+        assert_eq!(full_bitfield.as_bytes(), &[as_byte("11111111"), as_byte("11000000")]);
+        assert_eq!(bitfield.only([0, 3, 9, 12]).piece_indices(), vec![0, 9]);
+    }
+}
@@ -0,0 +1,194 @@
+// Decides which peers we upload to, reciprocating to the peers we download from the most https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// Gives the peers we know nothing about a chance to show that they upload faster than the unchoked ones
+const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Bytes transferred in the current and the previous rechoke interval, a rolling average over twice the interval
+#[derive(Default)]
+struct TransferRate {
+    current: u64,
+    previous: u64
+}
+
+impl TransferRate {
+    fn rate(&self) -> u64 {
+        self.current + self.previous
+    }
+
+    fn roll(&mut self) {
+        self.previous = self.current;
+        self.current = 0;
+    }
+}
+
+#[derive(Default)]
+struct PeerRates {
+    downloaded: TransferRate,
+    uploaded: TransferRate
+}
+
+pub(crate) struct ChokeCandidate<K> {
+    pub(crate) key: K,
+    // Whether the peer is interested in our pieces
+    pub(crate) interested: bool,
+    // A peer which sends us nothing while having our requests only gets the optimistic unchoke
+    pub(crate) snubbed: bool
+}
+
+pub(crate) struct Choker<K> {
+    upload_slots: usize,
+    rates: HashMap<K, PeerRates>,
+    // Peers unchoked for their rates, the optimistic unchoke is not among them
+    unchoked: HashSet<K>,
+    optimistic: Option<K>,
+    last_rechoke: Instant,
+    last_optimistic_unchoke: Instant
+}
+
+impl<K: Eq + Hash + Clone> Choker<K> {
+    pub(crate) fn new(upload_slots: usize, now: Instant) -> Choker<K> {
+        Choker {
+            upload_slots: upload_slots.max(1),
+            rates: HashMap::new(),
+            unchoked: HashSet::new(),
+            optimistic: None,
+            last_rechoke: now,
+            last_optimistic_unchoke: now
+        }
+    }
+
+    pub(crate) fn downloaded(&mut self, key: &K, bytes: usize) {
+        self.rates.entry(key.clone()).or_default().downloaded.current += bytes as u64;
+    }
+
+    pub(crate) fn uploaded(&mut self, key: &K, bytes: usize) {
+        self.rates.entry(key.clone()).or_default().uploaded.current += bytes as u64;
+    }
+
+    pub(crate) fn remove_peer(&mut self, key: &K) {
+        self.rates.remove(key);
+        self.unchoked.remove(key);
+        if self.optimistic.as_ref() == Some(key) {
+            self.optimistic = None;
+        }
+    }
+
+    // One of the upload slots is kept for the optimistic unchoke
+    fn regular_slots(&self) -> usize {
+        if self.upload_slots > 1 { self.upload_slots - 1 } else { 1 }
+    }
+
+    fn has_optimistic_slot(&self) -> bool {
+        self.upload_slots > 1
+    }
+
+    fn rate(&self, key: &K, seeding: bool) -> u64 {
+        self.rates.get(key).map_or(0, |rates| if seeding { rates.uploaded.rate() } else { rates.downloaded.rate() })
+    }
+
+    // Returns the peers which should be unchoked: every rechoke interval the interested peers we download from the fastest,
+    // or upload to the fastest while seeding, in between the slots freed by the peers which lost interest are filled right away
+    pub(crate) fn update(&mut self, candidates: &[ChokeCandidate<K>], seeding: bool, now: Instant, rng: &mut impl Rng) -> HashSet<K> {
+        if now.saturating_duration_since(self.last_rechoke) >= RECHOKE_INTERVAL {
+            self.unchoked.clear();
+            for rates in self.rates.values_mut() {
+                rates.downloaded.roll();
+                rates.uploaded.roll();
+            }
+            self.last_rechoke = now;
+        }
+        if now.saturating_duration_since(self.last_optimistic_unchoke) >= OPTIMISTIC_UNCHOKE_INTERVAL {
+            self.optimistic = None;
+            self.last_optimistic_unchoke = now;
+        }
+        let interested: HashMap<&K, &ChokeCandidate<K>> = candidates.iter()
+            .filter(|candidate| candidate.interested)
+            .map(|candidate| (&candidate.key, candidate))
+            .collect();
+        self.unchoked.retain(|key| interested.get(key).is_some_and(|candidate| !candidate.snubbed));
+        if self.optimistic.as_ref().is_some_and(|key| !interested.contains_key(key)) {
+            self.optimistic = None;
+        }
+
+        let mut by_rate: Vec<&K> = interested.values()
+            .filter(|candidate| !candidate.snubbed && !self.unchoked.contains(&candidate.key) && self.optimistic.as_ref() != Some(&candidate.key))
+            .map(|candidate| &candidate.key)
+            .collect();
+        by_rate.sort_by_key(|key| std::cmp::Reverse(self.rate(key, seeding)));
+        let free_slots = self.regular_slots().saturating_sub(self.unchoked.len());
+        let newly_unchoked: Vec<K> = by_rate.into_iter().take(free_slots).cloned().collect();
+        self.unchoked.extend(newly_unchoked);
+
+        if self.optimistic.is_none() && self.has_optimistic_slot() {
+            let choked: Vec<&K> = interested.keys().copied().filter(|key| !self.unchoked.contains(*key)).collect();
+            self.optimistic = choked.choose(rng).map(|key| (*key).clone());
+        }
+        self.unchoked.iter().chain(self.optimistic.iter()).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn interested(key: u32) -> ChokeCandidate<u32> {
+        ChokeCandidate { key, interested: true, snubbed: false }
+    }
+
+    #[test]
+    fn should_unchoke_fastest_peers_and_rotate_optimistic_unchoke() {
+        let start = Instant::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut choker = Choker::new(3, start);
+        let candidates: Vec<ChokeCandidate<u32>> = (0..6).map(interested).collect();
+        for key in 0..6 {
+            choker.downloaded(&key, 1000 * key as usize);
+        }
+        let first_round = choker.update(&candidates, false, start + RECHOKE_INTERVAL, &mut rng);
+        assert_eq!(first_round.len(), 3);
+        assert!(first_round.contains(&5) && first_round.contains(&4));
+        let optimistic = choker.optimistic.unwrap();
+        assert!(optimistic < 4);
+
+        // Peer 1 now uploads the fastest to us
+        choker.downloaded(&1, 100_000);
+        let second_round = choker.update(&candidates, false, start + 2 * RECHOKE_INTERVAL, &mut rng);
+        assert!(second_round.contains(&1));
+        assert_eq!(second_round.len(), 3);
+        assert_eq!(choker.optimistic, Some(optimistic));
+
+        // While seeding the rates of uploading to the peers count
+        for key in 0..6 {
+            choker.uploaded(&key, 1000 * (6 - key as usize));
+        }
+        let seeding_round = choker.update(&candidates, true, start + 3 * RECHOKE_INTERVAL, &mut rng);
+        assert!(seeding_round.contains(&0) && seeding_round.contains(&1));
+        assert_eq!(seeding_round.len(), 3);
+    }
+
+    #[test]
+    fn should_fill_freed_slots_and_not_reward_snubbing_peers() {
+        let start = Instant::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut choker = Choker::new(1, start);
+        assert_eq!(choker.update(&[interested(1), interested(2)], false, start, &mut rng).len(), 1);
+        let unchoked = *choker.unchoked.iter().next().unwrap();
+        let other = 3 - unchoked;
+        let unchoked_after_losing_interest = choker.update(&[ChokeCandidate { key: unchoked, interested: false, snubbed: false }, interested(other)], false, start, &mut rng);
+        assert_eq!(unchoked_after_losing_interest, HashSet::from([other]));
+
+        choker.downloaded(&1, 100_000);
+        let candidates = [ChokeCandidate { key: 1, interested: true, snubbed: true }, interested(2)];
+        assert_eq!(choker.update(&candidates, false, start + RECHOKE_INTERVAL, &mut rng), HashSet::from([2]));
+        choker.remove_peer(&2);
+        assert!(choker.update(&candidates[0..1], false, start + RECHOKE_INTERVAL, &mut rng).is_empty());
+    }
+}
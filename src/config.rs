@@ -0,0 +1,130 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use crate::dht;
+use crate::engine::ConnectionLimit;
+use crate::peer;
+
+const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+// Settings of the client which can be overridden with the command line options
+pub(crate) struct ClientConfig {
+    // Port on which we accept peer connections, it is announced to the trackers
+    pub(crate) port: usize,
+    // Local address of the socket shared by all the UDP trackers, port 0 picks an ephemeral port
+    pub(crate) udp_tracker_address: SocketAddr,
+    // Identifies us to the trackers across IP address changes, the same key is sent during the whole session
+    pub(crate) tracker_key: u32,
+    // Number of peers to ask the trackers for, None leaves it to the tracker
+    pub(crate) num_want: Option<u32>,
+    // Address advertised to the trackers instead of the one the announce comes from
+    pub(crate) announce_ip: Option<IpAddr>,
+    // Asks the trackers which send the peers as dictionaries to leave out the peer ids, --peer-id keeps them
+    pub(crate) no_peer_id: bool,
+    // Peers sending larger messages are disconnected
+    pub(crate) max_peer_message_length: usize,
+    // Number of peers we upload to at the same time
+    pub(crate) upload_slots: usize,
+    // Number of peers of a torrent which are connected or being connected to at the same time
+    pub(crate) max_peer_connections: usize,
+    // Peer connections shared by all the torrents which use this config
+    pub(crate) connection_limit: Arc<ConnectionLimit>,
+    // A peer which fails to connect this many times is not retried
+    pub(crate) peer_connect_attempts: u32,
+    // Local IPv4 and IPv6 addresses of the DHT node, without either the DHT is off
+    pub(crate) dht_address: Option<SocketAddr>,
+    pub(crate) dht_address6: Option<SocketAddr>,
+    // "host:port" of the nodes the DHT node joins through when its saved routing table does not get it in
+    pub(crate) dht_bootstrap_nodes: Vec<String>,
+    // File which keeps the DHT routing table across restarts
+    pub(crate) dht_state_path: String
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            port: 6881,
+            udp_tracker_address: SocketAddr::from(([0, 0, 0, 0], 0)),
+            tracker_key: rand::random::<u32>(),
+            num_want: None,
+            announce_ip: None,
+            no_peer_id: true,
+            max_peer_message_length: peer::DEFAULT_MAX_MESSAGE_LENGTH,
+            upload_slots: 4,
+            max_peer_connections: 50,
+            connection_limit: Arc::new(ConnectionLimit::new(DEFAULT_MAX_CONNECTIONS)),
+            peer_connect_attempts: 3,
+            dht_address: None,
+            dht_address6: None,
+            dht_bootstrap_nodes: dht::DEFAULT_BOOTSTRAP_NODES.iter().map(|node| node.to_string()).collect(),
+            dht_state_path: "dht_state".to_string()
+        }
+    }
+}
+
+impl ClientConfig {
+    pub(crate) fn from_args(args: &[String]) -> Result<ClientConfig, anyhow::Error> {
+        let default = ClientConfig::default();
+        Ok(ClientConfig {
+            port: find_option(args, "--port").map(|port| port.parse::<usize>()).transpose()?.unwrap_or(default.port),
+            udp_tracker_address: find_option(args, "--tracker-udp-address").map(|address| address.parse::<SocketAddr>()).transpose()?.unwrap_or(default.udp_tracker_address),
+            tracker_key: default.tracker_key,
+            num_want: find_option(args, "--numwant").map(|num_want| num_want.parse::<u32>()).transpose()?,
+            announce_ip: find_option(args, "--ip").map(|ip| ip.parse::<IpAddr>()).transpose()?,
+            no_peer_id: !has_flag(args, "--peer-id"),
+            max_peer_message_length: find_option(args, "--max-message-length").map(|length| length.parse::<usize>()).transpose()?.unwrap_or(default.max_peer_message_length),
+            upload_slots: find_option(args, "--upload-slots").map(|slots| slots.parse::<usize>()).transpose()?.unwrap_or(default.upload_slots),
+            max_peer_connections: find_option(args, "--max-peers").map(|peers| peers.parse::<usize>()).transpose()?.unwrap_or(default.max_peer_connections),
+            connection_limit: Arc::new(ConnectionLimit::new(find_option(args, "--max-connections").map(|connections| connections.parse::<usize>()).transpose()?.unwrap_or(DEFAULT_MAX_CONNECTIONS))),
+            peer_connect_attempts: find_option(args, "--peer-connect-attempts").map(|attempts| attempts.parse::<u32>()).transpose()?.unwrap_or(default.peer_connect_attempts),
+            dht_address: find_option(args, "--dht-address").map(|address| address.parse::<SocketAddr>()).transpose()?,
+            dht_address6: find_option(args, "--dht-address6").map(|address| address.parse::<SocketAddr>()).transpose()?,
+            dht_bootstrap_nodes: find_option(args, "--dht-bootstrap").map(|nodes| nodes.split(',').map(|node| node.to_string()).collect()).unwrap_or(default.dht_bootstrap_nodes),
+            dht_state_path: find_option(args, "--dht-state").map(|path| path.to_string()).unwrap_or(default.dht_state_path)
+        })
+    }
+}
+
+pub(crate) fn find_option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|position| args.get(position + 1))
+        .map(|value| value.as_str())
+}
+
+pub(crate) fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|arg| arg == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_config_from_args() {
+        let args: Vec<String> = ["", "peers", "sample.torrent", "--port", "6882", "--tracker-udp-address", "127.0.0.1:7000", "--numwant", "100", "--ip", "10.0.0.1", "--max-peers", "10", "--peer-connect-attempts", "5", "--dht-address", "0.0.0.0:6881", "--dht-address6", "[::]:6882", "--dht-bootstrap", "10.0.0.2:6881,node.example:6881", "--peer-id"]
+            .iter().map(|arg| arg.to_string()).collect();
+        let config = ClientConfig::from_args(&args).unwrap();
+        assert_eq!(config.port, 6882);
+        assert_eq!(config.udp_tracker_address, "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.num_want, Some(100));
+        assert_eq!(config.announce_ip, Some("10.0.0.1".parse().unwrap()));
+        assert!(!config.no_peer_id);
+        assert_eq!(config.max_peer_connections, 10);
+        assert_eq!(config.peer_connect_attempts, 5);
+        assert_eq!(config.dht_address, Some("0.0.0.0:6881".parse().unwrap()));
+        assert_eq!(config.dht_address6, Some("[::]:6882".parse().unwrap()));
+        assert_eq!(config.dht_bootstrap_nodes, vec!["10.0.0.2:6881".to_string(), "node.example:6881".to_string()]);
+
+        let default_config = ClientConfig::from_args(&args[0..3]).unwrap();
+        assert_eq!(default_config.port, 6881);
+        assert_eq!(default_config.udp_tracker_address.port(), 0);
+        assert_eq!(default_config.num_want, None);
+        assert_eq!(default_config.announce_ip, None);
+        assert!(default_config.no_peer_id);
+        assert_eq!(default_config.max_peer_connections, 50);
+        assert_eq!(default_config.peer_connect_attempts, 3);
+        assert_eq!(default_config.dht_address, None);
+        assert_eq!(default_config.dht_address6, None);
+        assert_eq!(default_config.dht_bootstrap_nodes.len(), dht::DEFAULT_BOOTSTRAP_NODES.len());
+    }
+}
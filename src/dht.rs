@@ -0,0 +1,741 @@
+// Mainline DHT node which finds the peers of a torrent without the trackers https://www.bittorrent.org/beps/bep_0005.html, over IPv4
+// and IPv6 https://www.bittorrent.org/beps/bep_0032.html
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use anyhow::ensure;
+use ed25519_dalek::SigningKey;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use crate::error::new_error;
+use crate::format;
+use crate::peer::PeerAddress;
+use krpc::{KrpcBody, KrpcMessage, NodeInfo, Query, Response, Want};
+use peer_store::PeerStore;
+use routing::{RoutingTable, SavedTable, BUCKET_SIZE};
+use security::ExternalIpVotes;
+use storage::{ItemStore, Signed};
+use tokens::Tokens;
+
+pub(crate) use keys::{generate_key, read_key};
+pub(crate) use krpc::NodeId;
+pub(crate) use mutable_torrent::MutableTorrentLink;
+pub(crate) use storage::Item;
+
+mod keys;
+mod krpc;
+mod mutable_torrent;
+mod peer_store;
+mod routing;
+mod security;
+mod storage;
+mod tokens;
+
+pub(crate) const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = ["router.bittorrent.com:6881", "dht.transmissionbt.com:6881", "router.utorrent.com:6881"];
+// The nodes forget the announced peers after 30 minutes
+pub(crate) const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Number of nodes queried at the same time in each step of a lookup
+const LOOKUP_PARALLELISM: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+// Keeps a get_peers response within a single datagram
+const MAX_RETURNED_PEERS: usize = 50;
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+type PendingQueries = Arc<Mutex<HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<KrpcBody>)>>>;
+
+#[derive(Default)]
+pub(crate) struct DhtConfig {
+    // At most one address of each family https://www.bittorrent.org/beps/bep_0032.html
+    pub(crate) addresses: Vec<SocketAddr>,
+    // With a state path the node keeps its ids and routing tables across restarts
+    pub(crate) state_path: Option<PathBuf>,
+    // Our address as the other nodes see it, when it is known the node id of its family is derived from it right away
+    pub(crate) external_ip: Option<IpAddr>,
+    // Also checks the ids of the nodes on local networks, which lets the nodes of a test on the loopback interface act as nodes
+    // on the internet https://www.bittorrent.org/beps/bep_0042.html
+    pub(crate) verify_local_ids: bool
+}
+
+// Each address family has its own node id and routing table https://www.bittorrent.org/beps/bep_0032.html
+struct FamilyState {
+    routing_table: RoutingTable,
+    external_ip_votes: ExternalIpVotes
+}
+
+struct DhtState {
+    ipv4: FamilyState,
+    ipv6: FamilyState,
+    tokens: Tokens,
+    peer_store: PeerStore,
+    item_store: ItemStore,
+    rng: StdRng,
+    verify_local_ids: bool
+}
+
+impl DhtState {
+    fn routing_table(&mut self, ipv6: bool) -> &mut RoutingTable {
+        if ipv6 { &mut self.ipv6.routing_table } else { &mut self.ipv4.routing_table }
+    }
+
+    // Nodes go to the routing table of the family of their address
+    fn insert(&mut self, node: NodeInfo, now: Instant) {
+        let secure = security::is_secure_node_id(&node.id, &node.address.ip(), self.verify_local_ids);
+        self.routing_table(node.address.is_ipv6()).insert(node, secure, now);
+    }
+
+    fn vote_external_ip(&mut self, voter: IpAddr, external_ip: IpAddr, now: Instant) {
+        if voter.is_ipv6() != external_ip.is_ipv6() {
+            return;
+        }
+        let family = if external_ip.is_ipv6() { &mut self.ipv6 } else { &mut self.ipv4 };
+        if let Some(external_ip) = family.external_ip_votes.vote(voter, external_ip) {
+            self.use_external_ip(external_ip, now);
+        }
+    }
+
+    // Our id is derived from our address once we know it https://www.bittorrent.org/beps/bep_0042.html
+    fn use_external_ip(&mut self, external_ip: IpAddr, now: Instant) {
+        let DhtState { ipv4, ipv6, rng, verify_local_ids, .. } = self;
+        let routing_table = if external_ip.is_ipv6() { &mut ipv6.routing_table } else { &mut ipv4.routing_table };
+        if !security::is_secure_node_id(&routing_table.id(), &external_ip, *verify_local_ids) {
+            let id = security::secure_node_id(&external_ip, rng);
+            routing_table.change_id(id, now);
+            println!("Changed the DHT node id to {} for our address {}", format::format_as_hex_string(&id.0), external_ip);
+        }
+    }
+}
+
+// A sent query waiting for its response
+struct PendingQuery {
+    address: SocketAddr,
+    transaction_id: Vec<u8>,
+    receiver: oneshot::Receiver<KrpcBody>
+}
+
+// The peers and items a lookup found and the closest nodes which responded to it, with the tokens for announcing and putting to them
+#[derive(Default)]
+struct LookupResult {
+    peers: Vec<PeerAddress>,
+    items: Vec<(Vec<u8>, Option<Signed>)>,
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>
+}
+
+pub(crate) struct DhtNode {
+    sockets: Vec<Arc<UdpSocket>>,
+    local_addrs: Vec<SocketAddr>,
+    state: Arc<Mutex<DhtState>>,
+    pending_queries: PendingQueries,
+    next_transaction_id: AtomicU16,
+    // Nodes of the saved routing tables, they are asked for the nodes close to us when bootstrapping
+    saved_nodes: Vec<NodeInfo>,
+    state_path: Option<PathBuf>,
+    dispatchers: Vec<JoinHandle<()>>
+}
+
+impl DhtNode {
+    // The node is maintained in the background while it is in use
+    pub(crate) async fn bind(config: DhtConfig) -> Result<Arc<DhtNode>, anyhow::Error> {
+        ensure!(!config.addresses.is_empty(), "The DHT node needs an address");
+        ensure!(config.addresses.iter().filter(|address| address.is_ipv6()).count() <= 1 && config.addresses.iter().filter(|address| address.is_ipv4()).count() <= 1,
+            "The DHT node takes at most one address of each family, not {:?}", config.addresses);
+        let mut sockets = Vec::new();
+        let mut local_addrs = Vec::new();
+        for address in &config.addresses {
+            let socket = UdpSocket::bind(address).await?;
+            local_addrs.push(socket.local_addr()?);
+            sockets.push(Arc::new(socket));
+        }
+        let mut rng = StdRng::from_entropy();
+        let (saved, saved6) = match config.state_path.as_deref().filter(|path| path.exists()).map(routing::load_tables) {
+            Some(Ok((saved, saved6))) => (Some(saved), saved6),
+            Some(Err(error)) => {
+                println!("Could not load the DHT routing tables, starting with empty ones: {}", error);
+                (None, None)
+            },
+            None => (None, None)
+        };
+        let now = Instant::now();
+        let mut saved_nodes = Vec::new();
+        let mut routing_table = |saved: Option<SavedTable>, rng: &mut StdRng| {
+            let id = saved.as_ref().map_or_else(|| NodeId::random(rng), |saved| saved.id);
+            saved_nodes.extend(saved.into_iter().flat_map(|saved| saved.nodes));
+            RoutingTable::new(id, now)
+        };
+        let ipv4 = FamilyState { routing_table: routing_table(saved, &mut rng), external_ip_votes: ExternalIpVotes::default() };
+        let ipv6 = FamilyState { routing_table: routing_table(saved6, &mut rng), external_ip_votes: ExternalIpVotes::default() };
+        let mut state = DhtState { ipv4, ipv6, tokens: Tokens::new(now, &mut rng), peer_store: PeerStore::default(), item_store: ItemStore::default(), rng, verify_local_ids: config.verify_local_ids };
+        if let Some(external_ip) = config.external_ip {
+            state.use_external_ip(external_ip, now);
+        }
+        let state = Arc::new(Mutex::new(state));
+        let pending_queries: PendingQueries = Arc::new(Mutex::new(HashMap::new()));
+        let dispatchers = sockets.iter()
+            .map(|socket| tokio::spawn(dispatch(Arc::clone(socket), Arc::clone(&state), Arc::clone(&pending_queries))))
+            .collect();
+        let node = Arc::new(DhtNode {
+            sockets,
+            local_addrs,
+            state,
+            pending_queries,
+            next_transaction_id: AtomicU16::new(rand::random()),
+            saved_nodes,
+            state_path: config.state_path,
+            dispatchers
+        });
+        tokio::spawn(run_maintenance(Arc::downgrade(&node)));
+        Ok(node)
+    }
+
+    // Our id in the DHT of the address family
+    pub(crate) fn id(&self, ipv6: bool) -> NodeId {
+        self.state.lock().unwrap().routing_table(ipv6).id()
+    }
+
+    pub(crate) fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub(crate) fn node_count(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.ipv4.routing_table.len() + state.ipv6.routing_table.len()
+    }
+
+    fn socket(&self, address: &SocketAddr) -> Option<&Arc<UdpSocket>> {
+        self.local_addrs.iter().position(|local_addr| local_addr.is_ipv6() == address.is_ipv6()).map(|index| &self.sockets[index])
+    }
+
+    fn has_family(&self, ipv6: bool) -> bool {
+        self.local_addrs.iter().any(|local_addr| local_addr.is_ipv6() == ipv6)
+    }
+
+    // Joins the DHT through the saved nodes and the given "host:port" nodes, returns the number of nodes in the routing tables
+    pub(crate) async fn bootstrap(&self, nodes: &[String]) -> usize {
+        let mut addresses: Vec<SocketAddr> = self.saved_nodes.iter().map(|node| node.address).collect();
+        for node in nodes {
+            match tokio::net::lookup_host(node).await {
+                Ok(resolved) => addresses.extend(resolved.filter(|address| self.has_family(address.is_ipv6()))),
+                Err(error) => println!("Could not resolve DHT node {}: {}", node, error)
+            }
+        }
+        addresses.sort();
+        addresses.dedup();
+        // Their ids are not known yet, the nodes which respond are added to the routing tables. They are asked for the nodes of all
+        // our address families, so that the DHT of one family can be joined through the nodes of the other
+        let want = Want { ipv4: self.has_family(false), ipv6: self.has_family(true) };
+        let queries = addresses.into_iter().map(|address| (address, Query::FindNode { target: self.id(address.is_ipv6()), want })).collect();
+        let (found6, found): (Vec<_>, Vec<_>) = self.query_all(queries).await.into_iter()
+            .filter_map(|(_, result)| result.ok())
+            .flat_map(|(_, response)| response.nodes)
+            .partition(|node| node.address.is_ipv6());
+        let (id, id6) = (self.id(false), self.id(true));
+        tokio::join!(
+            self.lookup(id, false, Query::FindNode { target: id, want: Want::default() }, found),
+            self.lookup(id6, true, Query::FindNode { target: id6, want: Want::default() }, found6)
+        );
+        self.node_count()
+    }
+
+    pub(crate) async fn ping(&self, address: SocketAddr) -> Result<NodeId, anyhow::Error> {
+        let query = self.send_query(address, Query::Ping).await?;
+        let (id, _) = self.response(query, tokio::time::Instant::now() + QUERY_TIMEOUT).await?;
+        Ok(id)
+    }
+
+    pub(crate) async fn get_peers(&self, info_hash: &NodeId) -> Vec<PeerAddress> {
+        self.lookup_both(*info_hash, Query::GetPeers { info_hash: *info_hash, want: Want::default() }).await.peers
+    }
+
+    // Finds the peers of the torrent and tells the nodes closest to it that we accept connections on the port
+    pub(crate) async fn announce(&self, info_hash: &NodeId, port: u16) -> Vec<PeerAddress> {
+        let lookup = self.lookup_both(*info_hash, Query::GetPeers { info_hash: *info_hash, want: Want::default() }).await;
+        let queries = lookup.closest.into_iter()
+            .filter_map(|(node, token)| token.map(|token| (node.address, Query::AnnouncePeer { info_hash: *info_hash, port, implied_port: false, token })))
+            .collect();
+        let announced_count = self.query_all(queries).await.into_iter().filter(|(_, result)| result.is_ok()).count();
+        println!("Announced torrent {} to {} DHT nodes", format::format_as_hex_string(&info_hash.0), announced_count);
+        lookup.peers
+    }
+
+    // Immutable items are found by the hash of their value https://www.bittorrent.org/beps/bep_0044.html
+    pub(crate) async fn get_immutable(&self, target: &NodeId) -> Option<Vec<u8>> {
+        let lookup = self.lookup_both(*target, Query::Get { target: *target, seq: None, want: Want::default() }).await;
+        lookup.items.into_iter().map(|(value, _)| Item::immutable(value)).find(|item| item.target() == *target).map(|item| item.value)
+    }
+
+    pub(crate) async fn put_immutable(&self, value: Vec<u8>) -> Result<NodeId, anyhow::Error> {
+        let item = Item::immutable(value);
+        let target = item.target();
+        let lookup = self.lookup_both(target, Query::Get { target, seq: None, want: Want::default() }).await;
+        self.put(lookup, item, None).await?;
+        Ok(target)
+    }
+
+    // The mutable item with the highest sequence number among the ones the nodes return, the salt tells apart the items of a key
+    pub(crate) async fn get_mutable(&self, public_key: &[u8; 32], salt: &[u8]) -> Option<Item> {
+        let target = storage::mutable_target(public_key, salt);
+        let lookup = self.lookup_both(target, Query::Get { target, seq: None, want: Want::default() }).await;
+        latest_item(lookup.items, public_key, salt)
+    }
+
+    // Replaces the mutable item with one of the next sequence number, the nodes which meanwhile got a newer one reject it
+    pub(crate) async fn put_mutable(&self, key: &SigningKey, salt: &[u8], value: Vec<u8>) -> Result<Item, anyhow::Error> {
+        let public_key = key.verifying_key().to_bytes();
+        let target = storage::mutable_target(&public_key, salt);
+        let mut lookup = self.lookup_both(target, Query::Get { target, seq: None, want: Want::default() }).await;
+        let cas = latest_item(std::mem::take(&mut lookup.items), &public_key, salt).and_then(|item| item.seq());
+        let item = Item::mutable(value, salt.to_vec(), cas.map_or(1, |seq| seq + 1), key);
+        self.put(lookup, item.clone(), cas).await?;
+        Ok(item)
+    }
+
+    // The info hash of the current version of the torrent the link points to
+    pub(crate) async fn resolve_mutable_torrent(&self, link: &MutableTorrentLink) -> Result<NodeId, anyhow::Error> {
+        let item = self.get_mutable(&link.public_key, &link.salt).await
+            .ok_or(new_error(format!("No DHT node has the torrent of {}", link.to_magnet_link())))?;
+        mutable_torrent::parse_info_hash(&item.value)
+    }
+
+    // Points the link of the key and the salt to a new version of the torrent
+    pub(crate) async fn publish_mutable_torrent(&self, key: &SigningKey, salt: &[u8], info_hash: &NodeId) -> Result<MutableTorrentLink, anyhow::Error> {
+        self.put_mutable(key, salt, mutable_torrent::encode_info_hash(info_hash)).await?;
+        Ok(MutableTorrentLink { public_key: key.verifying_key().to_bytes(), salt: salt.to_vec() })
+    }
+
+    // Puts the item to the closest nodes of the lookup of its target
+    async fn put(&self, lookup: LookupResult, item: Item, cas: Option<i64>) -> Result<(), anyhow::Error> {
+        item.verify().map_err(|error| new_error(error.message.to_string()))?;
+        let queries = lookup.closest.into_iter()
+            .filter_map(|(node, token)| token.map(|token| (node.address, Query::Put { item: item.clone(), cas, token })))
+            .collect();
+        let stored_count = self.query_all(queries).await.into_iter().filter(|(_, result)| result.is_ok()).count();
+        ensure!(stored_count > 0, "No DHT node stored the item {}", format::format_as_hex_string(&item.target().0));
+        println!("Stored the item {} on {} DHT nodes", format::format_as_hex_string(&item.target().0), stored_count);
+        Ok(())
+    }
+
+    // Looks the target up in the DHTs of both address families at the same time
+    async fn lookup_both(&self, target: NodeId, query: Query) -> LookupResult {
+        let (mut lookup, lookup6) = tokio::join!(self.lookup(target, false, query.clone(), Vec::new()), self.lookup(target, true, query, Vec::new()));
+        lookup.peers.extend(lookup6.peers);
+        lookup.items.extend(lookup6.items);
+        lookup.closest.extend(lookup6.closest);
+        lookup
+    }
+
+    // Iteratively asks the closest nodes we know of for the nodes even closer to the target, until the closest ones all answered
+    // https://www.bittorrent.org/beps/bep_0005.html#routing-table
+    async fn lookup(&self, target: NodeId, ipv6: bool, query: Query, found: Vec<NodeInfo>) -> LookupResult {
+        if !self.has_family(ipv6) {
+            return LookupResult::default();
+        }
+        let id = self.id(ipv6);
+        let known = self.state.lock().unwrap().routing_table(ipv6).closest(&target, BUCKET_SIZE);
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = known.into_iter().chain(found)
+            .filter(|node| node.id != id && node.address.is_ipv6() == ipv6)
+            .map(|node| (node.id.distance(&target), node))
+            .collect();
+        let mut queried: HashSet<SocketAddr> = HashSet::new();
+        let mut responded: BTreeMap<NodeId, (NodeInfo, Option<Vec<u8>>)> = BTreeMap::new();
+        let mut peers: Vec<PeerAddress> = Vec::new();
+        let mut items: Vec<(Vec<u8>, Option<Signed>)> = Vec::new();
+        loop {
+            let next: Vec<NodeInfo> = candidates.values()
+                .take(BUCKET_SIZE)
+                .filter(|node| !queried.contains(&node.address))
+                .take(LOOKUP_PARALLELISM)
+                .cloned()
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+            queried.extend(next.iter().map(|node| node.address));
+            let responses = self.query_all(next.iter().map(|node| (node.address, query.clone())).collect()).await;
+            for (node, (address, result)) in next.iter().zip(responses) {
+                match result {
+                    Ok((sender, response)) => {
+                        for found in response.nodes {
+                            if found.id != id && found.address.is_ipv6() == ipv6 && !queried.contains(&found.address) {
+                                candidates.entry(found.id.distance(&target)).or_insert(found);
+                            }
+                        }
+                        for peer in response.values {
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                        if let Some(value) = response.value {
+                            items.push((value, response.signed));
+                        }
+                        responded.insert(sender.distance(&target), (NodeInfo { id: sender, address }, response.token));
+                    },
+                    Err(_) => {
+                        candidates.remove(&node.id.distance(&target));
+                    }
+                }
+            }
+        }
+        LookupResult { peers, items, closest: responded.into_values().take(BUCKET_SIZE).collect() }
+    }
+
+    // Sends all the queries at once, then waits for their responses
+    async fn query_all(&self, queries: Vec<(SocketAddr, Query)>) -> Vec<(SocketAddr, Result<(NodeId, Response), anyhow::Error>)> {
+        let deadline = tokio::time::Instant::now() + QUERY_TIMEOUT;
+        let mut sent_queries = Vec::new();
+        for (address, query) in queries {
+            sent_queries.push((address, self.send_query(address, query).await));
+        }
+        let mut responses = Vec::new();
+        for (address, sent_query) in sent_queries {
+            let response = match sent_query {
+                Ok(sent_query) => self.response(sent_query, deadline).await,
+                Err(error) => Err(error)
+            };
+            responses.push((address, response));
+        }
+        responses
+    }
+
+    // Goes out of the socket of the address family of the node, with our id in the DHT of that family
+    async fn send_query(&self, address: SocketAddr, query: Query) -> Result<PendingQuery, anyhow::Error> {
+        let socket = self.socket(&address).ok_or_else(|| new_error(format!("The DHT node has no address of the family of {}", address)))?;
+        let transaction_id = self.next_transaction_id.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let message = KrpcMessage { transaction_id: transaction_id.clone(), body: KrpcBody::Query { sender: self.id(address.is_ipv6()), query }, requester: None };
+        let (sender, receiver) = oneshot::channel();
+        self.pending_queries.lock().unwrap().insert(transaction_id.clone(), (address, sender));
+        if let Err(error) = socket.send_to(&message.get_bytes(), address).await {
+            self.pending_queries.lock().unwrap().remove(&transaction_id);
+            return Err(error.into());
+        }
+        Ok(PendingQuery { address, transaction_id, receiver })
+    }
+
+    // A node which responds is added to the routing table, one which does not is marked as failed there
+    async fn response(&self, query: PendingQuery, deadline: tokio::time::Instant) -> Result<(NodeId, Response), anyhow::Error> {
+        let result = tokio::time::timeout_at(deadline, query.receiver).await;
+        self.pending_queries.lock().unwrap().remove(&query.transaction_id);
+        match result {
+            Ok(Ok(KrpcBody::Response { sender, response })) => {
+                self.state.lock().unwrap().insert(NodeInfo { id: sender, address: query.address }, Instant::now());
+                Ok((sender, response))
+            },
+            Ok(Ok(KrpcBody::Error { code, message })) => Err(new_error(format!("DHT node {} returned error {}: {}", query.address, code, message))),
+            _ => {
+                self.state.lock().unwrap().routing_table(query.address.is_ipv6()).failed(&query.address);
+                Err(new_error(format!("DHT node {} did not respond", query.address)))
+            }
+        }
+    }
+
+    // Pings the nodes we did not hear from for a while, refreshes the buckets which did not change and saves the routing tables
+    async fn maintain(&self) {
+        let now = Instant::now();
+        let mut questionable = Vec::new();
+        let mut refresh_targets = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let DhtState { ipv4, ipv6, tokens, peer_store, item_store, rng, .. } = &mut *state;
+            tokens.rotate_if_due(now, rng);
+            peer_store.expire(now);
+            item_store.expire(now);
+            for (family, is_ipv6) in [(ipv4, false), (ipv6, true)] {
+                questionable.extend(family.routing_table.questionable(now));
+                refresh_targets.extend(family.routing_table.refresh_targets(now, rng).into_iter().map(|target| (target, is_ipv6)));
+            }
+        }
+        self.query_all(questionable.into_iter().map(|node| (node.address, Query::Ping)).collect()).await;
+        for (target, ipv6) in refresh_targets {
+            self.lookup(target, ipv6, Query::FindNode { target, want: Want::default() }, Vec::new()).await;
+        }
+        self.save();
+    }
+
+    pub(crate) fn save(&self) {
+        if let Some(state_path) = &self.state_path {
+            let state = self.state.lock().unwrap();
+            if let Err(error) = routing::save_tables(state_path, &state.ipv4.routing_table.saved(), &state.ipv6.routing_table.saved()) {
+                println!("Could not save the DHT routing tables to {:?}: {}", state_path, error);
+            }
+        }
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.save();
+        for dispatcher in &self.dispatchers {
+            dispatcher.abort();
+        }
+    }
+}
+
+async fn run_maintenance(node: Weak<DhtNode>) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + MAINTENANCE_INTERVAL, MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(node) = node.upgrade() else {
+            return;
+        };
+        node.maintain().await;
+    }
+}
+
+// Answers the queries of the other nodes and hands the responses to the queries waiting for them
+async fn dispatch(socket: Arc<UdpSocket>, state: Arc<Mutex<DhtState>>, pending_queries: PendingQueries) {
+    let mut buffer: [u8; MAX_MESSAGE_LENGTH] = [0; MAX_MESSAGE_LENGTH];
+    loop {
+        let Ok((length, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        // A dual stack IPv6 socket also receives the IPv4 messages, which belong to the IPv4 socket
+        if let SocketAddr::V6(from) = from {
+            if from.ip().to_ipv4_mapped().is_some() {
+                continue;
+            }
+        }
+        // Malformed messages are ignored
+        let Ok(message) = KrpcMessage::parse(&buffer[0..length]) else {
+            continue;
+        };
+        match message.body {
+            KrpcBody::Query { sender, query } => {
+                let body = answer(&mut state.lock().unwrap(), from, sender, query);
+                let response = KrpcMessage { transaction_id: message.transaction_id, body, requester: Some(from) };
+                let _ = socket.send_to(&response.get_bytes(), from).await;
+            },
+            body => {
+                // Only the queried node may respond
+                let waiting = {
+                    let mut pending_queries = pending_queries.lock().unwrap();
+                    match pending_queries.get(&message.transaction_id) {
+                        Some((address, _)) if *address == from => pending_queries.remove(&message.transaction_id).map(|(_, sender)| sender),
+                        _ => None
+                    }
+                };
+                if let Some(waiting) = waiting {
+                    if let Some(requester) = message.requester {
+                        state.lock().unwrap().vote_external_ip(from.ip(), requester.ip(), Instant::now());
+                    }
+                    let _ = waiting.send(body);
+                }
+            }
+        }
+    }
+}
+
+fn answer(state: &mut DhtState, from: SocketAddr, sender: NodeId, query: Query) -> KrpcBody {
+    let now = Instant::now();
+    state.insert(NodeInfo { id: sender, address: from }, now);
+    let response = match query {
+        Query::Ping => Response::default(),
+        Query::FindNode { target, want } => Response { nodes: closest_nodes(state, &target, want, &from), ..Response::default() },
+        Query::GetPeers { info_hash, want } => {
+            let values = state.peer_store.peers(&info_hash, MAX_RETURNED_PEERS, from.is_ipv6());
+            Response {
+                nodes: if values.is_empty() { closest_nodes(state, &info_hash, want, &from) } else { Vec::new() },
+                values,
+                token: Some(state.tokens.token_for(&from.ip())),
+                ..Response::default()
+            }
+        },
+        Query::Get { target, seq, want } => {
+            // The querying node already has the items up to the sequence number
+            let item = state.item_store.get(&target).filter(|item| seq.is_none_or(|seq| item.seq().is_none_or(|stored_seq| stored_seq > seq))).cloned();
+            Response {
+                nodes: closest_nodes(state, &target, want, &from),
+                token: Some(state.tokens.token_for(&from.ip())),
+                value: item.as_ref().map(|item| item.value.clone()),
+                signed: item.and_then(|item| item.signed),
+                ..Response::default()
+            }
+        },
+        Query::Put { item, cas, token } => {
+            if !state.tokens.is_valid(&token, &from.ip()) {
+                return KrpcBody::Error { code: krpc::PROTOCOL_ERROR, message: "Bad token".to_string() };
+            }
+            if let Err(error) = state.item_store.put(item, cas, now) {
+                return KrpcBody::Error { code: error.code, message: error.message.to_string() };
+            }
+            Response::default()
+        },
+        Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+            if !state.tokens.is_valid(&token, &from.ip()) {
+                return KrpcBody::Error { code: krpc::PROTOCOL_ERROR, message: "Bad token".to_string() };
+            }
+            let port = if implied_port { from.port() } else { port };
+            state.peer_store.announce(info_hash, PeerAddress { address: from.ip(), port }, now);
+            Response::default()
+        },
+        Query::Unknown { method } => return KrpcBody::Error { code: krpc::METHOD_UNKNOWN_ERROR, message: format!("Method Unknown {}", method) }
+    };
+    KrpcBody::Response { sender: state.routing_table(from.is_ipv6()).id(), response }
+}
+
+// Only the items signed with the key verify
+fn latest_item(items: Vec<(Vec<u8>, Option<Signed>)>, public_key: &[u8; 32], salt: &[u8]) -> Option<Item> {
+    items.into_iter()
+        .map(|(value, signed)| Item { value, salt: salt.to_vec(), signed })
+        .filter(|item| item.signed.as_ref().is_some_and(|signed| signed.public_key == *public_key) && item.verify().is_ok())
+        .max_by_key(|item| item.seq())
+}
+
+// Without a want the querying node gets the nodes of the family it queried over https://www.bittorrent.org/beps/bep_0032.html
+fn closest_nodes(state: &DhtState, target: &NodeId, want: Want, from: &SocketAddr) -> Vec<NodeInfo> {
+    let want = if want.is_empty() { Want { ipv4: from.is_ipv4(), ipv6: from.is_ipv6() } } else { want };
+    let mut nodes = Vec::new();
+    if want.ipv4 {
+        nodes.extend(state.ipv4.routing_table.closest(target, BUCKET_SIZE));
+    }
+    if want.ipv6 {
+        nodes.extend(state.ipv6.routing_table.closest(target, BUCKET_SIZE));
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn node_on(addresses: &[&str], state_path: Option<PathBuf>) -> Arc<DhtNode> {
+        let addresses = addresses.iter().map(|address| address.parse().unwrap()).collect();
+        DhtNode::bind(DhtConfig { addresses, state_path, ..DhtConfig::default() }).await.unwrap()
+    }
+
+    async fn loopback_node(state_path: Option<PathBuf>) -> Arc<DhtNode> {
+        node_on(&["127.0.0.1:0"], state_path).await
+    }
+
+    // All of 127.0.0.0/8 is on the loopback interface, so each node can have its own address
+    async fn virtual_ip_node(ip: [u8; 4], external_ip: Option<IpAddr>) -> Arc<DhtNode> {
+        let config = DhtConfig { addresses: vec![SocketAddr::from((ip, 0))], external_ip, verify_local_ids: true, ..DhtConfig::default() };
+        DhtNode::bind(config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_find_announced_peers_over_loopback() {
+        let bootstrap_node = loopback_node(None).await;
+        let bootstrap_nodes = vec![bootstrap_node.local_addrs()[0].to_string()];
+        let mut nodes = Vec::new();
+        for _ in 0..30 {
+            let node = loopback_node(None).await;
+            assert!(node.bootstrap(&bootstrap_nodes).await > 0);
+            nodes.push(node);
+        }
+
+        let info_hash = NodeId([7; 20]);
+        assert!(nodes[0].announce(&info_hash, 6881).await.is_empty());
+        let peers = nodes[29].get_peers(&info_hash).await;
+        assert_eq!(peers, vec![PeerAddress { address: "127.0.0.1".parse().unwrap(), port: 6881 }]);
+        assert_eq!(nodes[15].announce(&info_hash, 6882).await, peers);
+        assert_eq!(nodes[29].get_peers(&info_hash).await.len(), 2);
+        assert!(nodes[29].get_peers(&NodeId([8; 20])).await.is_empty());
+        assert_eq!(nodes[29].ping(nodes[0].local_addrs()[0]).await.unwrap(), nodes[0].id(false));
+    }
+
+    #[tokio::test]
+    async fn should_derive_node_id_from_address_told_by_other_nodes() {
+        let bootstrap_ip = [127, 0, 1, 1];
+        let bootstrap_node = virtual_ip_node(bootstrap_ip, Some(IpAddr::from(bootstrap_ip))).await;
+        assert!(security::is_secure_node_id(&bootstrap_node.id(false), &IpAddr::from(bootstrap_ip), true));
+        let bootstrap_nodes = vec![bootstrap_node.local_addrs()[0].to_string()];
+        let mut nodes = Vec::new();
+        for host in 2..12 {
+            let ip = [127, 0, 1, host];
+            let node = virtual_ip_node(ip, Some(IpAddr::from(ip))).await;
+            node.bootstrap(&bootstrap_nodes).await;
+            nodes.push(node);
+        }
+
+        // Starts with a random id, the nodes which respond to it tell it its address
+        let ip = [127, 0, 2, 1];
+        let node = virtual_ip_node(ip, None).await;
+        let random_id = node.id(false);
+        assert!(!security::is_secure_node_id(&random_id, &IpAddr::from(ip), true));
+        assert!(node.bootstrap(&bootstrap_nodes).await > 0);
+        assert_ne!(node.id(false), random_id);
+        assert!(security::is_secure_node_id(&node.id(false), &IpAddr::from(ip), true));
+        assert_eq!(nodes[0].ping(node.local_addrs()[0]).await.unwrap(), node.id(false));
+    }
+
+    #[tokio::test]
+    async fn should_find_announced_peers_over_both_address_families() {
+        let bootstrap_node = node_on(&["127.0.0.1:0", "[::1]:0"], None).await;
+        let bootstrap_nodes: Vec<String> = bootstrap_node.local_addrs().iter().map(|address| address.to_string()).collect();
+        let mut nodes = Vec::new();
+        for _ in 0..10 {
+            let node = node_on(&["127.0.0.1:0", "[::1]:0"], None).await;
+            node.bootstrap(&bootstrap_nodes).await;
+            nodes.push(node);
+        }
+        let ipv6_only_node = node_on(&["[::1]:0"], None).await;
+        assert!(ipv6_only_node.bootstrap(&bootstrap_nodes).await > 0);
+        assert_eq!(ipv6_only_node.state.lock().unwrap().ipv4.routing_table.len(), 0);
+
+        // Only asked over IPv4, the bootstrap node also returns the IPv6 nodes it knows
+        let late_node = node_on(&["127.0.0.1:0", "[::1]:0"], None).await;
+        late_node.bootstrap(&bootstrap_nodes[0..1]).await;
+        assert!(late_node.state.lock().unwrap().ipv6.routing_table.len() > 0);
+
+        let info_hash = NodeId([7; 20]);
+        nodes[0].announce(&info_hash, 6881).await;
+        let mut peers = late_node.get_peers(&info_hash).await;
+        peers.sort_by_key(|peer| peer.address);
+        assert_eq!(peers, vec![
+            PeerAddress { address: "127.0.0.1".parse().unwrap(), port: 6881 },
+            PeerAddress { address: "::1".parse().unwrap(), port: 6881 }
+        ]);
+        assert_eq!(ipv6_only_node.get_peers(&info_hash).await, vec![PeerAddress { address: "::1".parse().unwrap(), port: 6881 }]);
+    }
+
+    #[tokio::test]
+    async fn should_put_and_get_items_over_loopback() {
+        let bootstrap_node = loopback_node(None).await;
+        let bootstrap_nodes = vec![bootstrap_node.local_addrs()[0].to_string()];
+        let mut nodes = Vec::new();
+        for _ in 0..20 {
+            let node = loopback_node(None).await;
+            node.bootstrap(&bootstrap_nodes).await;
+            nodes.push(node);
+        }
+
+        let target = nodes[0].put_immutable(b"12:Hello World!".to_vec()).await.unwrap();
+        assert_eq!(nodes[19].get_immutable(&target).await, Some(b"12:Hello World!".to_vec()));
+        assert_eq!(nodes[19].get_immutable(&NodeId([8; 20])).await, None);
+
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let public_key = key.verifying_key().to_bytes();
+        assert_eq!(nodes[0].put_mutable(&key, b"salt", b"5:first".to_vec()).await.unwrap().seq(), Some(1));
+        // Another node which has the key continues from the sequence number it finds
+        assert_eq!(nodes[10].put_mutable(&key, b"salt", b"6:second".to_vec()).await.unwrap().seq(), Some(2));
+        let item = nodes[19].get_mutable(&public_key, b"salt").await.unwrap();
+        assert_eq!((item.seq(), item.value), (Some(2), b"6:second".to_vec()));
+        assert_eq!(nodes[19].get_mutable(&public_key, b"other salt").await, None);
+
+        let link = nodes[0].publish_mutable_torrent(&key, b"dataset", &NodeId([7; 20])).await.unwrap();
+        assert_eq!(nodes[19].resolve_mutable_torrent(&MutableTorrentLink::parse(&link.to_magnet_link()).unwrap()).await.unwrap(), NodeId([7; 20]));
+        nodes[5].publish_mutable_torrent(&key, b"dataset", &NodeId([9; 20])).await.unwrap();
+        assert_eq!(nodes[19].resolve_mutable_torrent(&link).await.unwrap(), NodeId([9; 20]));
+    }
+
+    #[tokio::test]
+    async fn should_rejoin_from_saved_routing_table() {
+        let directory = tempfile::tempdir().unwrap();
+        let state_path = directory.path().join("dht_state");
+        let other_node = loopback_node(None).await;
+        let node = loopback_node(Some(state_path.clone())).await;
+        assert_eq!(node.bootstrap(&[other_node.local_addrs()[0].to_string()]).await, 1);
+        let id = node.id(false);
+        drop(node);
+
+        let restarted_node = loopback_node(Some(state_path)).await;
+        assert_eq!(restarted_node.id(false), id);
+        assert_eq!(restarted_node.bootstrap(&[]).await, 1);
+        assert_eq!(other_node.node_count(), 1);
+    }
+}
@@ -0,0 +1,46 @@
+// Ed25519 keys which sign the mutable items we put, a key file holds the hex encoded 32 byte seed of the key
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use crate::error::new_error;
+use crate::format;
+
+// An existing key is never overwritten, the items signed with it could not be updated anymore
+pub(crate) fn generate_key(path: &Path) -> Result<SigningKey, anyhow::Error> {
+    let key = SigningKey::generate(&mut OsRng);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // Only the owner may read the key
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    file.write_all(format::format_as_hex_string(key.as_bytes()).as_bytes())?;
+    Ok(key)
+}
+
+pub(crate) fn read_key(path: &Path) -> Result<SigningKey, anyhow::Error> {
+    let seed = hex::decode(std::fs::read_to_string(path)?.trim())?;
+    let seed: [u8; 32] = seed.try_into().map_err(|_| new_error(format!("Key file {} does not hold a 32 byte seed", path.display())))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_generated_key() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("dht.key");
+        let key = generate_key(&path).unwrap();
+        assert_eq!(read_key(&path).unwrap().verifying_key(), key.verifying_key());
+        assert!(generate_key(&path).is_err());
+
+        std::fs::write(&path, "8543d3").unwrap();
+        assert!(read_key(&path).is_err());
+    }
+}
@@ -0,0 +1,573 @@
+// KRPC messages which the DHT nodes exchange over UDP https://www.bittorrent.org/beps/bep_0005.html#krpc-protocol
+use std::net::{IpAddr, SocketAddr};
+use anyhow::ensure;
+use rand::Rng;
+use crate::bencoded::{self, BencodeEncoding, Value};
+use crate::error::new_error;
+use crate::peer::PeerAddress;
+use crate::tracker;
+use super::storage::{Item, Signed};
+
+// 20 bytes of the node id followed by the IPv4 address and the port
+const COMPACT_NODE_LENGTH: usize = 26;
+// The same with an IPv6 address, in the nodes6 field https://www.bittorrent.org/beps/bep_0032.html
+const COMPACT_NODE6_LENGTH: usize = 38;
+
+// Error codes https://www.bittorrent.org/beps/bep_0005.html#errors
+pub(crate) const PROTOCOL_ERROR: i64 = 203;
+pub(crate) const METHOD_UNKNOWN_ERROR: i64 = 204;
+
+// Node ids and info hashes share the same 160 bit space, the distance between them is their XOR
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct NodeId(pub(crate) [u8; 20]);
+
+impl NodeId {
+    pub(crate) fn random(rng: &mut impl Rng) -> NodeId {
+        NodeId(rng.gen())
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<NodeId, anyhow::Error> {
+        Ok(NodeId(bytes.try_into().map_err(|_| new_error(format!("Node id {:?} is not 20 bytes long", bytes)))?))
+    }
+
+    // Compared as a big endian number, the smaller the closer
+    pub(crate) fn distance(&self, other: &NodeId) -> NodeId {
+        NodeId(std::array::from_fn(|position| self.0[position] ^ other.0[position]))
+    }
+
+    pub(crate) fn common_prefix_length(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        distance.0.iter().position(|byte| *byte != 0)
+            .map_or(160, |position| position * 8 + distance.0[position].leading_zeros() as usize)
+    }
+
+    pub(crate) fn bit(&self, position: usize) -> bool {
+        self.0[position / 8] & (0x80 >> (position % 8)) != 0
+    }
+
+    pub(crate) fn set_bit(&mut self, position: usize, value: bool) {
+        if value {
+            self.0[position / 8] |= 0x80 >> (position % 8);
+        } else {
+            self.0[position / 8] &= !(0x80 >> (position % 8));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NodeInfo {
+    pub(crate) id: NodeId,
+    pub(crate) address: SocketAddr
+}
+
+// Only the nodes of the given address family are encoded
+pub(crate) fn encode_compact_nodes(nodes: &[NodeInfo], ipv6: bool) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for node in nodes.iter().filter(|node| node.address.is_ipv6() == ipv6) {
+        bytes.extend(node.id.0);
+        bytes.extend(encode_compact_address(&node.address));
+    }
+    bytes
+}
+
+pub(crate) fn parse_compact_nodes(bytes: &[u8], ipv6: bool) -> Result<Vec<NodeInfo>, anyhow::Error> {
+    let node_length = if ipv6 { COMPACT_NODE6_LENGTH } else { COMPACT_NODE_LENGTH };
+    ensure!(bytes.len().is_multiple_of(node_length), "Nodes field size is not a multiple of {}, {:?}", node_length, bytes);
+    let mut nodes = Vec::new();
+    for node in bytes.chunks(node_length) {
+        nodes.push(NodeInfo { id: NodeId::from_bytes(&node[0..20])?, address: parse_compact_address(&node[20..])? });
+    }
+    Ok(nodes)
+}
+
+fn encode_compact_address(address: &SocketAddr) -> Vec<u8> {
+    let mut bytes: Vec<u8> = match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec()
+    };
+    bytes.extend(address.port().to_be_bytes());
+    bytes
+}
+
+// The address family is told by the length
+fn parse_compact_address(bytes: &[u8]) -> Result<SocketAddr, anyhow::Error> {
+    let mut addresses = if bytes.len() == 18 { tracker::parse_compact_peers_v6(bytes)? } else { tracker::parse_compact_peers_v4(bytes)? };
+    ensure!(addresses.len() == 1, "{:?} is not a single compact address", bytes);
+    let address = addresses.remove(0);
+    Ok(SocketAddr::new(address.address, address.port))
+}
+
+// Address families of the nodes a find_node or get_peers query asks for, without any the nodes of the family the query came over
+// are returned https://www.bittorrent.org/beps/bep_0032.html
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Want {
+    pub(crate) ipv4: bool,
+    pub(crate) ipv6: bool
+}
+
+impl Want {
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.ipv4 && !self.ipv6
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Query {
+    Ping,
+    FindNode { target: NodeId, want: Want },
+    GetPeers { info_hash: NodeId, want: Want },
+    // With implied_port the port the query comes from is announced instead of the given one
+    AnnouncePeer { info_hash: NodeId, port: u16, implied_port: bool, token: Vec<u8> },
+    // With a seq only a mutable item with a higher sequence number is returned https://www.bittorrent.org/beps/bep_0044.html
+    Get { target: NodeId, seq: Option<i64>, want: Want },
+    // With a cas a mutable item only replaces the one with that sequence number
+    Put { item: Item, cas: Option<i64>, token: Vec<u8> },
+    // Answered with an error
+    Unknown { method: String }
+}
+
+impl Query {
+    fn method(&self) -> &str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+            Query::Unknown { method } => method
+        }
+    }
+}
+
+// The fields of the responses to all the queries, the ones which a response does not have are empty. The nodes and values of both
+// address families are kept together
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Response {
+    pub(crate) nodes: Vec<NodeInfo>,
+    pub(crate) values: Vec<PeerAddress>,
+    pub(crate) token: Option<Vec<u8>>,
+    // The stored item a get returns, its salt is known to the querying node
+    pub(crate) value: Option<Vec<u8>>,
+    pub(crate) signed: Option<Signed>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum KrpcBody {
+    Query { sender: NodeId, query: Query },
+    Response { sender: NodeId, response: Response },
+    Error { code: i64, message: String }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KrpcMessage {
+    // Chosen by the querying node and echoed back in the response
+    pub(crate) transaction_id: Vec<u8>,
+    pub(crate) body: KrpcBody,
+    // The address the query came from, in the responses https://www.bittorrent.org/beps/bep_0042.html
+    pub(crate) requester: Option<SocketAddr>
+}
+
+impl KrpcMessage {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        // The keys of a bencoded dictionary are sorted
+        let mut bencoded: Vec<u8> = Vec::new();
+        bencoded.push(b'd');
+        match &self.body {
+            KrpcBody::Query { sender, query } => {
+                bencoded.encode_str("a");
+                encode_arguments(&mut bencoded, sender, query);
+            },
+            KrpcBody::Error { code, message } => {
+                bencoded.encode_str("e");
+                bencoded.push(b'l');
+                bencoded.encode_i64(code);
+                bencoded.encode_str(message);
+                bencoded.push(b'e');
+            },
+            KrpcBody::Response { .. } => {}
+        }
+        if let Some(requester) = &self.requester {
+            bencoded.encode_str("ip");
+            bencoded.encode_bytes(&encode_compact_address(requester));
+        }
+        match &self.body {
+            KrpcBody::Query { query, .. } => {
+                bencoded.encode_str("q");
+                bencoded.encode_str(query.method());
+            },
+            KrpcBody::Response { sender, response } => {
+                bencoded.encode_str("r");
+                encode_response(&mut bencoded, sender, response);
+            },
+            KrpcBody::Error { .. } => {}
+        }
+        bencoded.encode_str("t");
+        bencoded.encode_bytes(&self.transaction_id);
+        bencoded.encode_str("y");
+        bencoded.encode_str(match self.body {
+            KrpcBody::Query { .. } => "q",
+            KrpcBody::Response { .. } => "r",
+            KrpcBody::Error { .. } => "e"
+        });
+        bencoded.push(b'e');
+        bencoded
+    }
+
+    pub(crate) fn parse(bytes: &[u8]) -> Result<KrpcMessage, anyhow::Error> {
+        let message = bencoded::decode_bencoded_from_bytes(bytes)?;
+        let transaction_id = message.get_by_key("t")?.as_bytes()?;
+        let body = match message.get_by_key("y")?.as_string()?.as_str() {
+            "q" => parse_query(&message)?,
+            "r" => parse_response(message.get_by_key("r")?)?,
+            "e" => {
+                let error = message.get_by_key("e")?.as_values()?;
+                ensure!(error.len() == 2, "KRPC error {:?} is not a code and a message", error);
+                KrpcBody::Error { code: error[0].as_number()?, message: String::from_utf8_lossy(&error[1].as_bytes()?).to_string() }
+            },
+            message_type => return Err(new_error(format!("Unknown KRPC message type {:?}", message_type)))
+        };
+        let requester = message.get_optional_by_key("ip").map(|requester| parse_compact_address(&requester.as_bytes()?)).transpose()?;
+        Ok(KrpcMessage { transaction_id, body, requester })
+    }
+}
+
+fn encode_arguments(bencoded: &mut Vec<u8>, sender: &NodeId, query: &Query) {
+    bencoded.push(b'd');
+    // The only key sorted before the id
+    if let Query::Put { cas: Some(cas), .. } = query {
+        bencoded.encode_str("cas");
+        bencoded.encode_i64(cas);
+    }
+    bencoded.encode_str("id");
+    bencoded.encode_bytes(&sender.0);
+    match query {
+        Query::FindNode { target, want } => {
+            bencoded.encode_str("target");
+            bencoded.encode_bytes(&target.0);
+            encode_want(bencoded, want);
+        },
+        Query::GetPeers { info_hash, want } => {
+            bencoded.encode_str("info_hash");
+            bencoded.encode_bytes(&info_hash.0);
+            encode_want(bencoded, want);
+        },
+        Query::Get { target, seq, want } => {
+            if let Some(seq) = seq {
+                bencoded.encode_str("seq");
+                bencoded.encode_i64(seq);
+            }
+            bencoded.encode_str("target");
+            bencoded.encode_bytes(&target.0);
+            encode_want(bencoded, want);
+        },
+        Query::Put { item, token, .. } => {
+            if let Some(signed) = &item.signed {
+                bencoded.encode_str("k");
+                bencoded.encode_bytes(&signed.public_key);
+                if !item.salt.is_empty() {
+                    bencoded.encode_str("salt");
+                    bencoded.encode_bytes(&item.salt);
+                }
+                bencoded.encode_str("seq");
+                bencoded.encode_i64(&signed.seq);
+                bencoded.encode_str("sig");
+                bencoded.encode_bytes(&signed.signature);
+            }
+            bencoded.encode_str("token");
+            bencoded.encode_bytes(token);
+            bencoded.encode_str("v");
+            bencoded.extend_from_slice(&item.value);
+        },
+        Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+            bencoded.encode_str("implied_port");
+            bencoded.encode_i64(&(*implied_port as i64));
+            bencoded.encode_str("info_hash");
+            bencoded.encode_bytes(&info_hash.0);
+            bencoded.encode_str("port");
+            bencoded.encode_i64(&(*port as i64));
+            bencoded.encode_str("token");
+            bencoded.encode_bytes(token);
+        },
+        Query::Ping | Query::Unknown { .. } => {}
+    }
+    bencoded.push(b'e');
+}
+
+fn encode_want(bencoded: &mut Vec<u8>, want: &Want) {
+    if want.is_empty() {
+        return;
+    }
+    bencoded.encode_str("want");
+    bencoded.push(b'l');
+    if want.ipv4 {
+        bencoded.encode_str("n4");
+    }
+    if want.ipv6 {
+        bencoded.encode_str("n6");
+    }
+    bencoded.push(b'e');
+}
+
+fn encode_response(bencoded: &mut Vec<u8>, sender: &NodeId, response: &Response) {
+    bencoded.push(b'd');
+    bencoded.encode_str("id");
+    bencoded.encode_bytes(&sender.0);
+    if let Some(signed) = &response.signed {
+        bencoded.encode_str("k");
+        bencoded.encode_bytes(&signed.public_key);
+    }
+    let (nodes, nodes6) = (encode_compact_nodes(&response.nodes, false), encode_compact_nodes(&response.nodes, true));
+    if !nodes.is_empty() {
+        bencoded.encode_str("nodes");
+        bencoded.encode_bytes(&nodes);
+    }
+    if !nodes6.is_empty() {
+        bencoded.encode_str("nodes6");
+        bencoded.encode_bytes(&nodes6);
+    }
+    if let Some(signed) = &response.signed {
+        bencoded.encode_str("seq");
+        bencoded.encode_i64(&signed.seq);
+        bencoded.encode_str("sig");
+        bencoded.encode_bytes(&signed.signature);
+    }
+    if let Some(token) = &response.token {
+        bencoded.encode_str("token");
+        bencoded.encode_bytes(token);
+    }
+    if let Some(value) = &response.value {
+        bencoded.encode_str("v");
+        bencoded.extend_from_slice(value);
+    }
+    if !response.values.is_empty() {
+        bencoded.encode_str("values");
+        bencoded.push(b'l');
+        for value in &response.values {
+            bencoded.encode_bytes(&encode_compact_address(&SocketAddr::new(value.address, value.port)));
+        }
+        bencoded.push(b'e');
+    }
+    bencoded.push(b'e');
+}
+
+fn parse_query(message: &Value) -> Result<KrpcBody, anyhow::Error> {
+    let arguments = message.get_by_key("a")?;
+    let node_id = |key: &str| -> Result<NodeId, anyhow::Error> { NodeId::from_bytes(&arguments.get_by_key(key)?.as_bytes()?) };
+    let mut want = Want::default();
+    if let Some(families) = arguments.get_optional_by_key("want") {
+        for family in families.as_values()? {
+            match family.as_bytes()?.as_slice() {
+                b"n4" => want.ipv4 = true,
+                b"n6" => want.ipv6 = true,
+                _ => {}
+            }
+        }
+    }
+    let query = match message.get_by_key("q")?.as_string()?.as_str() {
+        "ping" => Query::Ping,
+        "find_node" => Query::FindNode { target: node_id("target")?, want },
+        "get_peers" => Query::GetPeers { info_hash: node_id("info_hash")?, want },
+        "announce_peer" => Query::AnnouncePeer {
+            info_hash: node_id("info_hash")?,
+            port: u16::try_from(arguments.get_by_key("port")?.as_number()?)?,
+            implied_port: arguments.get_optional_by_key("implied_port").and_then(|implied_port| implied_port.as_number().ok()).is_some_and(|implied_port| implied_port != 0),
+            token: arguments.get_by_key("token")?.as_bytes()?
+        },
+        "get" => Query::Get {
+            target: node_id("target")?,
+            seq: arguments.get_optional_by_key("seq").map(|seq| seq.as_number()).transpose()?,
+            want
+        },
+        "put" => Query::Put {
+            item: Item {
+                value: arguments.get_by_key("v")?.bencode(),
+                salt: arguments.get_optional_by_key("salt").map(|salt| salt.as_bytes()).transpose()?.unwrap_or_default(),
+                signed: parse_signed(arguments)?
+            },
+            cas: arguments.get_optional_by_key("cas").map(|cas| cas.as_number()).transpose()?,
+            token: arguments.get_by_key("token")?.as_bytes()?
+        },
+        method => Query::Unknown { method: method.to_string() }
+    };
+    Ok(KrpcBody::Query { sender: node_id("id")?, query })
+}
+
+fn parse_response(response: &Value) -> Result<KrpcBody, anyhow::Error> {
+    let mut nodes = Vec::new();
+    for (key, ipv6) in [("nodes", false), ("nodes6", true)] {
+        if let Some(compact_nodes) = response.get_optional_by_key(key) {
+            nodes.extend(parse_compact_nodes(&compact_nodes.as_bytes()?, ipv6)?);
+        }
+    }
+    let mut values = Vec::new();
+    if let Some(peers) = response.get_optional_by_key("values") {
+        for peer in peers.as_values()? {
+            let address = parse_compact_address(&peer.as_bytes()?)?;
+            values.push(PeerAddress { address: address.ip(), port: address.port() });
+        }
+    }
+    Ok(KrpcBody::Response {
+        sender: NodeId::from_bytes(&response.get_by_key("id")?.as_bytes()?)?,
+        response: Response {
+            nodes,
+            values,
+            token: response.get_optional_by_key("token").map(|token| token.as_bytes()).transpose()?,
+            value: response.get_optional_by_key("v").map(|value| value.bencode()),
+            signed: parse_signed(response)?
+        }
+    })
+}
+
+// The key, sequence number and signature of a mutable item, which are all there or all missing
+fn parse_signed(dictionary: &Value) -> Result<Option<Signed>, anyhow::Error> {
+    let Some(public_key) = dictionary.get_optional_by_key("k") else {
+        return Ok(None);
+    };
+    let public_key = public_key.as_bytes()?;
+    let signature = dictionary.get_by_key("sig")?.as_bytes()?;
+    Ok(Some(Signed {
+        public_key: public_key.as_slice().try_into().map_err(|_| new_error(format!("Public key {:?} is not 32 bytes long", public_key)))?,
+        seq: dictionary.get_by_key("seq")?.as_number()?,
+        signature: signature.as_slice().try_into().map_err(|_| new_error(format!("Signature {:?} is not 64 bytes long", signature)))?
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id(id: &[u8; 20]) -> NodeId {
+        NodeId(*id)
+    }
+
+    #[test]
+    fn should_encode_and_parse_krpc_messages() {
+        // Examples from https://www.bittorrent.org/beps/bep_0005.html#ping
+        let ping = KrpcMessage { transaction_id: b"aa".to_vec(), body: KrpcBody::Query { sender: node_id(b"abcdefghij0123456789"), query: Query::Ping }, requester: None };
+        assert_eq!(ping.get_bytes(), b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec());
+        assert_eq!(KrpcMessage::parse(&ping.get_bytes()).unwrap(), ping);
+        let pong = KrpcMessage { transaction_id: b"aa".to_vec(), body: KrpcBody::Response { sender: node_id(b"mnopqrstuvwxyz123456"), response: Response::default() }, requester: None };
+        assert_eq!(pong.get_bytes(), b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re".to_vec());
+        assert_eq!(KrpcMessage::parse(&pong.get_bytes()).unwrap(), pong);
+
+        let announce = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            body: KrpcBody::Query {
+                sender: node_id(b"abcdefghij0123456789"),
+                query: Query::AnnouncePeer { info_hash: node_id(b"mnopqrstuvwxyz123456"), port: 6881, implied_port: true, token: b"aoeusnth".to_vec() }
+            },
+            requester: None
+        };
+        assert_eq!(announce.get_bytes(), b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe".to_vec());
+        assert_eq!(KrpcMessage::parse(&announce.get_bytes()).unwrap(), announce);
+
+        let peers = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            body: KrpcBody::Response {
+                sender: node_id(b"abcdefghij0123456789"),
+                response: Response {
+                    nodes: vec![NodeInfo { id: node_id(b"mnopqrstuvwxyz123456"), address: "10.0.0.1:6881".parse().unwrap() }],
+                    values: vec![PeerAddress { address: "10.0.0.2".parse().unwrap(), port: 6882 }],
+                    token: Some(b"aoeusnth".to_vec()),
+                    ..Response::default()
+                }
+            },
+            requester: None
+        };
+        assert_eq!(peers.get_bytes(), b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456\x0a\x00\x00\x01\x1a\xe15:token8:aoeusnth6:valuesl6:\x0a\x00\x00\x02\x1a\xe2ee1:t2:aa1:y1:re".to_vec());
+        assert_eq!(KrpcMessage::parse(&peers.get_bytes()).unwrap(), peers);
+
+        let find_node = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            body: KrpcBody::Query { sender: node_id(b"abcdefghij0123456789"), query: Query::FindNode { target: node_id(b"mnopqrstuvwxyz123456"), want: Want { ipv4: true, ipv6: true } } },
+            requester: None
+        };
+        assert_eq!(find_node.get_bytes(), b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe".to_vec());
+        assert_eq!(KrpcMessage::parse(&find_node.get_bytes()).unwrap(), find_node);
+
+        let dual_stack_peers = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            body: KrpcBody::Response {
+                sender: node_id(b"abcdefghij0123456789"),
+                response: Response {
+                    nodes: vec![
+                        NodeInfo { id: node_id(b"mnopqrstuvwxyz123456"), address: "10.0.0.1:6881".parse().unwrap() },
+                        NodeInfo { id: node_id(b"mnopqrstuvwxyz654321"), address: "[::1]:6881".parse().unwrap() }
+                    ],
+                    values: vec![PeerAddress { address: "::2".parse().unwrap(), port: 6882 }],
+                    ..Response::default()
+                }
+            },
+            requester: Some("[::3]:6883".parse().unwrap())
+        };
+        assert_eq!(dual_stack_peers.get_bytes(), [
+            b"d2:ip18:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x03\x1a\xe31:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456\x0a\x00\x00\x01\x1a\xe1".as_slice(),
+            b"6:nodes638:mnopqrstuvwxyz654321\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1",
+            b"6:valuesl18:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x02\x1a\xe2ee1:t2:aa1:y1:re"
+        ].concat());
+        assert_eq!(KrpcMessage::parse(&dual_stack_peers.get_bytes()).unwrap(), dual_stack_peers);
+
+        // The test vector of https://www.bittorrent.org/beps/bep_0044.html#test-vectors
+        let signed = Signed {
+            public_key: hex::decode("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548").unwrap().try_into().unwrap(),
+            seq: 1,
+            signature: hex::decode("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01").unwrap().try_into().unwrap()
+        };
+        let put = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            body: KrpcBody::Query {
+                sender: node_id(b"abcdefghij0123456789"),
+                query: Query::Put { item: Item { value: b"12:Hello World!".to_vec(), salt: b"foobar".to_vec(), signed: Some(signed.clone()) }, cas: Some(0), token: b"aoeusnth".to_vec() }
+            },
+            requester: None
+        };
+        let put_bytes = [
+            b"d1:ad3:casi0e2:id20:abcdefghij01234567891:k32:".as_slice(), &signed.public_key,
+            b"4:salt6:foobar3:seqi1e3:sig64:", &signed.signature, b"5:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:y1:qe"
+        ].concat();
+        assert_eq!(put.get_bytes(), put_bytes);
+        assert_eq!(KrpcMessage::parse(&put_bytes).unwrap(), put);
+        let get = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            body: KrpcBody::Query { sender: node_id(b"abcdefghij0123456789"), query: Query::Get { target: node_id(b"mnopqrstuvwxyz123456"), seq: Some(1), want: Want::default() } },
+            requester: None
+        };
+        assert_eq!(get.get_bytes(), b"d1:ad2:id20:abcdefghij01234567893:seqi1e6:target20:mnopqrstuvwxyz123456e1:q3:get1:t2:aa1:y1:qe".to_vec());
+        assert_eq!(KrpcMessage::parse(&get.get_bytes()).unwrap(), get);
+        let item = KrpcMessage {
+            transaction_id: b"aa".to_vec(),
+            body: KrpcBody::Response {
+                sender: node_id(b"abcdefghij0123456789"),
+                response: Response { token: Some(b"aoeusnth".to_vec()), value: Some(b"li1ei2ee".to_vec()), signed: Some(signed.clone()), ..Response::default() }
+            },
+            requester: None
+        };
+        assert_eq!(item.get_bytes(), [
+            b"d1:rd2:id20:abcdefghij01234567891:k32:".as_slice(), &signed.public_key,
+            b"3:seqi1e3:sig64:", &signed.signature, b"5:token8:aoeusnth1:vli1ei2eee1:t2:aa1:y1:re"
+        ].concat());
+        assert_eq!(KrpcMessage::parse(&item.get_bytes()).unwrap(), item);
+
+        let error = KrpcMessage::parse(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(error.body, KrpcBody::Error { code: 201, message: "A Generic Error Ocurred".to_string() });
+        assert_eq!(error.get_bytes(), b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee".to_vec());
+        let unknown = KrpcMessage::parse(b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(unknown.body, KrpcBody::Query { sender: node_id(b"abcdefghij0123456789"), query: Query::Unknown { method: "vote".to_string() } });
+        assert!(KrpcMessage::parse(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").is_err());
+    }
+
+    #[test]
+    fn should_measure_distance_between_node_ids() {
+        let mut id = NodeId([0; 20]);
+        let mut other = id;
+        assert_eq!(id.common_prefix_length(&other), 160);
+        other.set_bit(9, true);
+        assert!(other.bit(9) && !other.bit(8));
+        assert_eq!(id.common_prefix_length(&other), 9);
+        assert_eq!(id.distance(&other).0[1], 0x40);
+        id.set_bit(0, true);
+        assert_eq!(id.common_prefix_length(&other), 0);
+        assert!(other.distance(&NodeId([0; 20])) < id.distance(&NodeId([0; 20])));
+    }
+}
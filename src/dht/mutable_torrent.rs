@@ -0,0 +1,92 @@
+// Links to the latest version of a torrent, which its publisher puts as a mutable item signed with its key
+// https://www.bittorrent.org/beps/bep_0046.html
+use url::Url;
+use crate::bencoded::{self, BencodeEncoding};
+use crate::error::new_error;
+use crate::format;
+use super::krpc::NodeId;
+
+const PUBLIC_KEY_URN: &str = "urn:btpk:";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MutableTorrentLink {
+    pub(crate) public_key: [u8; 32],
+    pub(crate) salt: Vec<u8>
+}
+
+impl MutableTorrentLink {
+    // magnet:?xs=urn:btpk:<hex encoded public key>&s=<hex encoded salt>
+    pub(crate) fn parse(link: &str) -> Result<MutableTorrentLink, anyhow::Error> {
+        let url = Url::parse(link)?;
+        if url.scheme() != "magnet" {
+            return Err(new_error(format!("{} is not a magnet link", link)));
+        }
+        let mut public_key = None;
+        let mut salt = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xs" if value.starts_with(PUBLIC_KEY_URN) => {
+                    let bytes = hex::decode(&value[PUBLIC_KEY_URN.len()..])?;
+                    public_key = Some(<[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| new_error(format!("Public key {} is not 32 bytes long", value)))?);
+                },
+                "s" => salt = hex::decode(value.as_ref())?,
+                _ => {}
+            }
+        }
+        let public_key = public_key.ok_or(new_error(format!("Magnet link {} has no public key", link)))?;
+        Ok(MutableTorrentLink { public_key, salt })
+    }
+
+    pub(crate) fn to_magnet_link(&self) -> String {
+        let mut link = format!("magnet:?xs={}{}", PUBLIC_KEY_URN, format::format_as_hex_string(&self.public_key));
+        if !self.salt.is_empty() {
+            link.push_str(&format!("&s={}", format::format_as_hex_string(&self.salt)));
+        }
+        link
+    }
+}
+
+// The value of the item is a dictionary with the info hash of the current version
+pub(crate) fn encode_info_hash(info_hash: &NodeId) -> Vec<u8> {
+    let mut bencoded: Vec<u8> = Vec::new();
+    bencoded.push(b'd');
+    bencoded.encode_str("ih");
+    bencoded.encode_bytes(&info_hash.0);
+    bencoded.push(b'e');
+    bencoded
+}
+
+pub(crate) fn parse_info_hash(value: &[u8]) -> Result<NodeId, anyhow::Error> {
+    NodeId::from_bytes(&bencoded::decode_bencoded_from_bytes(value)?.get_by_key("ih")?.as_bytes()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_and_format_mutable_torrent_links() {
+        let public_key = "8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e";
+        let link = MutableTorrentLink::parse(&format!("magnet:?xs=urn:btpk:{}&s=6e", public_key)).unwrap();
+        assert_eq!(format::format_as_hex_string(&link.public_key), public_key);
+        assert_eq!(link.salt, b"n".to_vec());
+        assert_eq!(link.to_magnet_link(), format!("magnet:?xs=urn:btpk:{}&s=6e", public_key));
+
+        let unsalted_link = MutableTorrentLink::parse(&format!("magnet:?xs=urn:btpk:{}", public_key)).unwrap();
+        assert!(unsalted_link.salt.is_empty());
+        assert_eq!(unsalted_link.to_magnet_link(), format!("magnet:?xs=urn:btpk:{}", public_key));
+
+        assert!(MutableTorrentLink::parse("magnet:?xt=urn:btih:0000000000000000000000000000000000000000").is_err());
+        assert!(MutableTorrentLink::parse("magnet:?xs=urn:btpk:8543").is_err());
+        assert!(MutableTorrentLink::parse(&format!("http://example.com/?xs=urn:btpk:{}", public_key)).is_err());
+    }
+
+    #[test]
+    fn should_encode_and_parse_info_hash_value() {
+        let info_hash = NodeId([7; 20]);
+        let value = encode_info_hash(&info_hash);
+        assert_eq!(value, b"d2:ih20:\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07\x07e".to_vec());
+        assert_eq!(parse_info_hash(&value).unwrap(), info_hash);
+        assert!(parse_info_hash(b"d2:ih3:abce").is_err());
+    }
+}
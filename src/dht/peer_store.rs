@@ -0,0 +1,69 @@
+// Peers which announced themselves to us with announce_peer, they are returned to the get_peers queries for the same torrent
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::peer::PeerAddress;
+use super::krpc::NodeId;
+
+// Peers re-announce themselves before they expire https://www.bittorrent.org/beps/bep_0005.html#announce-peer
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+// Bounds the memory which the other nodes can make us use
+const MAX_TORRENTS: usize = 1000;
+const MAX_PEERS_PER_TORRENT: usize = 1000;
+
+#[derive(Default)]
+pub(crate) struct PeerStore {
+    torrents: HashMap<NodeId, HashMap<PeerAddress, Instant>>
+}
+
+impl PeerStore {
+    pub(crate) fn announce(&mut self, info_hash: NodeId, peer: PeerAddress, now: Instant) {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_TORRENTS {
+            return;
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        if peers.contains_key(&peer) || peers.len() < MAX_PEERS_PER_TORRENT {
+            peers.insert(peer, now);
+        }
+    }
+
+    // Only the peers of the address family the query came over are returned https://www.bittorrent.org/beps/bep_0032.html
+    pub(crate) fn peers(&self, info_hash: &NodeId, count: usize, ipv6: bool) -> Vec<PeerAddress> {
+        self.torrents.get(info_hash)
+            .map(|peers| peers.keys().filter(|peer| peer.address.is_ipv6() == ipv6).take(count).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn expire(&mut self, now: Instant) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, announced| now.saturating_duration_since(*announced) < PEER_EXPIRY);
+        }
+        self.torrents.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_return_announced_peers_until_they_expire() {
+        let now = Instant::now();
+        let info_hash = NodeId([1; 20]);
+        let first = PeerAddress { address: "10.0.0.1".parse().unwrap(), port: 6881 };
+        let second = PeerAddress { address: "10.0.0.2".parse().unwrap(), port: 6881 };
+        let mut store = PeerStore::default();
+        store.announce(info_hash, first.clone(), now);
+        store.announce(info_hash, second.clone(), now + PEER_EXPIRY / 2);
+        let ipv6_peer = PeerAddress { address: "::1".parse().unwrap(), port: 6881 };
+        store.announce(info_hash, ipv6_peer.clone(), now + PEER_EXPIRY / 2);
+        assert_eq!(store.peers(&info_hash, 10, false).len(), 2);
+        assert_eq!(store.peers(&info_hash, 1, false).len(), 1);
+        assert_eq!(store.peers(&info_hash, 10, true), vec![ipv6_peer]);
+        assert!(store.peers(&NodeId([2; 20]), 10, false).is_empty());
+
+        store.expire(now + PEER_EXPIRY);
+        assert_eq!(store.peers(&info_hash, 10, false), vec![second]);
+        store.expire(now + 2 * PEER_EXPIRY);
+        assert!(store.torrents.is_empty());
+    }
+}
@@ -0,0 +1,383 @@
+// Kademlia routing table of the DHT https://www.bittorrent.org/beps/bep_0005.html#routing-table
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use crate::bencoded::{self, BencodeEncoding};
+use super::krpc::{self, NodeId, NodeInfo};
+
+pub(crate) const BUCKET_SIZE: usize = 8;
+// A node which did not respond this many times in a row is bad and gets replaced
+const MAX_FAILURES: u32 = 2;
+// A node we heard from this recently is good, a bucket which did not change for this long is refreshed
+pub(crate) const NODE_ACTIVITY_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone)]
+struct RoutingNode {
+    info: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+    // Whether the id is derived from the address https://www.bittorrent.org/beps/bep_0042.html
+    secure: bool
+}
+
+impl RoutingNode {
+    fn is_good(&self, now: Instant) -> bool {
+        self.failures == 0 && now.saturating_duration_since(self.last_seen) < NODE_ACTIVITY_TIMEOUT
+    }
+
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+struct Bucket {
+    nodes: Vec<RoutingNode>,
+    // Nodes which did not fit into the full bucket, they take the places of the bad nodes
+    replacements: Vec<RoutingNode>,
+    last_changed: Instant
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Bucket {
+        Bucket { nodes: Vec::new(), replacements: Vec::new(), last_changed: now }
+    }
+}
+
+// Bucket i holds the nodes which share exactly i leading bits with our id, the last bucket holds all the nodes closer than that
+// and is split in two when it is full
+pub(crate) struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Bucket>
+}
+
+impl RoutingTable {
+    pub(crate) fn new(id: NodeId, now: Instant) -> RoutingTable {
+        RoutingTable { id, buckets: vec![Bucket::new(now)] }
+    }
+
+    pub(crate) fn id(&self) -> NodeId {
+        self.id
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        self.id.common_prefix_length(id).min(self.buckets.len() - 1)
+    }
+
+    // Records a node which responded to us or queried us
+    pub(crate) fn insert(&mut self, node: NodeInfo, secure: bool, now: Instant) {
+        self.insert_node(RoutingNode { info: node, last_seen: now, failures: 0, secure }, now);
+    }
+
+    fn insert_node(&mut self, routing_node: RoutingNode, now: Instant) {
+        let node = routing_node.info.clone();
+        if node.id == self.id {
+            return;
+        }
+        loop {
+            let can_split = self.buckets.len() < 160;
+            let index = self.bucket_index(&node.id);
+            let is_last = index == self.buckets.len() - 1;
+            let bucket = &mut self.buckets[index];
+            if let Some(known) = bucket.nodes.iter_mut().find(|known| known.info.id == node.id) {
+                // A node does not take over the id of another one
+                if known.info.address == node.address {
+                    known.last_seen = now;
+                    known.failures = 0;
+                    bucket.last_changed = now;
+                }
+                return;
+            }
+            if bucket.nodes.len() < BUCKET_SIZE {
+                bucket.nodes.push(routing_node);
+                bucket.last_changed = now;
+                return;
+            }
+            if is_last && can_split {
+                self.split(now);
+                continue;
+            }
+            if let Some(bad) = bucket.nodes.iter_mut().find(|known| known.is_bad()) {
+                *bad = routing_node;
+                bucket.last_changed = now;
+                return;
+            }
+            // The nodes whose ids are not derived from their addresses give their places to the ones whose ids are
+            if routing_node.secure {
+                if let Some(insecure) = bucket.nodes.iter_mut().filter(|known| !known.secure).min_by_key(|known| known.last_seen) {
+                    *insecure = routing_node;
+                    bucket.last_changed = now;
+                    return;
+                }
+            }
+            bucket.replacements.retain(|replacement| replacement.info.id != node.id);
+            bucket.replacements.push(routing_node);
+            if bucket.replacements.len() > BUCKET_SIZE {
+                bucket.replacements.remove(0);
+            }
+            return;
+        }
+    }
+
+    fn split(&mut self, now: Instant) {
+        let last = self.buckets.len() - 1;
+        let nodes = std::mem::take(&mut self.buckets[last].nodes);
+        let replacements = std::mem::take(&mut self.buckets[last].replacements);
+        self.buckets.push(Bucket::new(now));
+        for node in nodes {
+            let index = self.bucket_index(&node.info.id);
+            self.buckets[index].nodes.push(node);
+        }
+        for replacement in replacements {
+            let index = self.bucket_index(&replacement.info.id);
+            self.buckets[index].replacements.push(replacement);
+        }
+    }
+
+    // A node which keeps failing to respond is replaced by the most recently seen replacement, preferably one with a secure id
+    pub(crate) fn failed(&mut self, address: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(position) = bucket.nodes.iter().position(|known| known.info.address == *address) {
+                bucket.nodes[position].failures += 1;
+                if bucket.nodes[position].is_bad() {
+                    let replacement_position = bucket.replacements.iter().rposition(|replacement| replacement.secure)
+                        .or(bucket.replacements.len().checked_sub(1));
+                    if let Some(replacement_position) = replacement_position {
+                        bucket.nodes[position] = bucket.replacements.remove(replacement_position);
+                    }
+                }
+                return;
+            }
+        }
+    }
+
+    // The buckets depend on our id, so the known nodes are sorted into new ones
+    pub(crate) fn change_id(&mut self, id: NodeId, now: Instant) {
+        let buckets = std::mem::replace(&mut self.buckets, vec![Bucket::new(now)]);
+        self.id = id;
+        for bucket in buckets {
+            for node in bucket.nodes.into_iter().chain(bucket.replacements) {
+                self.insert_node(node, now);
+            }
+        }
+    }
+
+    pub(crate) fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<&RoutingNode> = self.buckets.iter().flat_map(|bucket| bucket.nodes.iter()).filter(|node| !node.is_bad()).collect();
+        nodes.sort_by_key(|node| node.info.id.distance(target));
+        nodes.into_iter().take(count).map(|node| node.info.clone()).collect()
+    }
+
+    // Nodes which we did not hear from for a while, they are pinged to find out whether they are still there
+    pub(crate) fn questionable(&self, now: Instant) -> Vec<NodeInfo> {
+        self.buckets.iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| !node.is_good(now) && !node.is_bad())
+            .map(|node| node.info.clone())
+            .collect()
+    }
+
+    // Random ids in the ranges of the buckets which did not change for a while, looking them up refreshes the buckets
+    pub(crate) fn refresh_targets(&mut self, now: Instant, rng: &mut impl Rng) -> Vec<NodeId> {
+        let mut targets = Vec::new();
+        for index in 0..self.buckets.len() {
+            if now.saturating_duration_since(self.buckets[index].last_changed) >= NODE_ACTIVITY_TIMEOUT {
+                self.buckets[index].last_changed = now;
+                targets.push(self.random_id_in_bucket(index, rng));
+            }
+        }
+        targets
+    }
+
+    fn random_id_in_bucket(&self, index: usize, rng: &mut impl Rng) -> NodeId {
+        let mut id = NodeId::random(rng);
+        for position in 0..index {
+            id.set_bit(position, self.id.bit(position));
+        }
+        if index < self.buckets.len() - 1 {
+            id.set_bit(index, !self.id.bit(index));
+        }
+        id
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    pub(crate) fn saved(&self) -> SavedTable {
+        let nodes = self.buckets.iter().flat_map(|bucket| bucket.nodes.iter()).filter(|node| !node.is_bad()).map(|node| node.info.clone()).collect();
+        SavedTable { id: self.id, nodes }
+    }
+}
+
+// Our id and the nodes of a routing table, kept across restarts so that the next start does not depend on the bootstrap nodes.
+// The nodes are only added once they respond again
+#[derive(Debug, PartialEq)]
+pub(crate) struct SavedTable {
+    pub(crate) id: NodeId,
+    pub(crate) nodes: Vec<NodeInfo>
+}
+
+// The IPv6 table is saved under the keys of the IPv4 one followed by a 6, like in the KRPC messages
+pub(crate) fn save_tables(path: &Path, table: &SavedTable, table6: &SavedTable) -> Result<(), anyhow::Error> {
+    let mut bencoded: Vec<u8> = Vec::new();
+    bencoded.push(b'd');
+    bencoded.encode_str("id");
+    bencoded.encode_bytes(&table.id.0);
+    bencoded.encode_str("id6");
+    bencoded.encode_bytes(&table6.id.0);
+    bencoded.encode_str("nodes");
+    bencoded.encode_bytes(&krpc::encode_compact_nodes(&table.nodes, false));
+    bencoded.encode_str("nodes6");
+    bencoded.encode_bytes(&krpc::encode_compact_nodes(&table6.nodes, true));
+    bencoded.push(b'e');
+    std::fs::write(path, bencoded)?;
+    Ok(())
+}
+
+// The IPv6 table is missing from the files saved before it existed
+pub(crate) fn load_tables(path: &Path) -> Result<(SavedTable, Option<SavedTable>), anyhow::Error> {
+    let saved = bencoded::decode_bencoded_from_bytes(&std::fs::read(path)?)?;
+    let table = SavedTable {
+        id: NodeId::from_bytes(&saved.get_by_key("id")?.as_bytes()?)?,
+        nodes: krpc::parse_compact_nodes(&saved.get_by_key("nodes")?.as_bytes()?, false)?
+    };
+    let table6 = match (saved.get_optional_by_key("id6"), saved.get_optional_by_key("nodes6")) {
+        (Some(id), Some(nodes)) => Some(SavedTable { id: NodeId::from_bytes(&id.as_bytes()?)?, nodes: krpc::parse_compact_nodes(&nodes.as_bytes()?, true)? }),
+        _ => None
+    };
+    Ok((table, table6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use tempfile::NamedTempFile;
+
+    fn node(rng: &mut StdRng, port: u16) -> NodeInfo {
+        NodeInfo { id: NodeId::random(rng), address: SocketAddr::from(([127, 0, 0, 1], port)) }
+    }
+
+    #[test]
+    fn should_split_buckets_and_replace_bad_nodes() {
+        let now = Instant::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut table = RoutingTable::new(NodeId::random(&mut rng), now);
+        let nodes: Vec<NodeInfo> = (0..200).map(|port| node(&mut rng, port)).collect();
+        for node in &nodes {
+            table.insert(node.clone(), true, now);
+        }
+        assert!(table.buckets.len() > 1);
+        assert!(table.buckets.iter().all(|bucket| bucket.nodes.len() <= BUCKET_SIZE));
+        assert!(table.len() < nodes.len());
+
+        // The closest node always fits, as the bucket near our id is split when it is full
+        let closest = nodes.iter().min_by_key(|node| node.id.distance(&table.id())).unwrap();
+        let closest_in_table = table.closest(&table.id(), BUCKET_SIZE);
+        assert_eq!(&closest_in_table[0], closest);
+        assert!(closest_in_table.windows(2).all(|pair| pair[0].id.distance(&table.id()) < pair[1].id.distance(&table.id())));
+
+        // The farthest bucket is full, so a failing node there gives its place to a replacement
+        let full_bucket = &table.buckets[0];
+        let failing = full_bucket.nodes[0].info.clone();
+        let replacement = full_bucket.replacements.last().unwrap().info.clone();
+        for _ in 0..MAX_FAILURES {
+            table.failed(&failing.address);
+        }
+        assert!(table.buckets[0].nodes.iter().any(|known| known.info == replacement));
+        assert!(!table.buckets[0].nodes.iter().any(|known| known.info == failing));
+
+        assert!(table.questionable(now).is_empty());
+        assert_eq!(table.questionable(now + NODE_ACTIVITY_TIMEOUT).len(), table.len());
+        let targets = table.refresh_targets(now + NODE_ACTIVITY_TIMEOUT, &mut rng);
+        assert_eq!(targets.len(), table.buckets.len());
+        for (index, target) in targets.iter().enumerate() {
+            assert_eq!(table.bucket_index(target), index);
+        }
+        assert!(table.refresh_targets(now + NODE_ACTIVITY_TIMEOUT, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn should_prefer_nodes_with_secure_ids() {
+        let now = Instant::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut table = RoutingTable::new(NodeId([0; 20]), now);
+        // Nodes far from our id, which all go to the first bucket once it is split
+        let mut far_node = |port: u16| {
+            let mut node = node(&mut rng, port);
+            node.id.set_bit(0, true);
+            node
+        };
+        table.buckets.push(Bucket::new(now));
+        let insecure: Vec<NodeInfo> = (0..BUCKET_SIZE as u16).map(&mut far_node).collect();
+        for node in &insecure {
+            table.insert(node.clone(), false, now);
+        }
+        let late_insecure = far_node(100);
+        table.insert(late_insecure.clone(), false, now);
+        assert!(!table.buckets[0].nodes.iter().any(|known| known.info == late_insecure));
+
+        let secure = far_node(200);
+        table.insert(secure.clone(), true, now + Duration::from_secs(1));
+        assert!(table.buckets[0].nodes.iter().any(|known| known.info == secure));
+        assert_eq!(table.buckets[0].nodes.len(), BUCKET_SIZE);
+
+        // Once all the nodes are secure, a secure replacement is preferred to a more recent insecure one
+        for port in 201..200 + BUCKET_SIZE as u16 {
+            table.insert(far_node(port), true, now);
+        }
+        assert!(table.buckets[0].nodes.iter().all(|known| known.secure));
+        let secure_replacement = far_node(300);
+        table.insert(secure_replacement.clone(), true, now);
+        table.insert(far_node(400), false, now);
+        let failing = table.buckets[0].nodes[0].info.clone();
+        for _ in 0..MAX_FAILURES {
+            table.failed(&failing.address);
+        }
+        assert!(table.buckets[0].nodes.iter().any(|known| known.info == secure_replacement));
+    }
+
+    #[test]
+    fn should_keep_nodes_when_id_changes() {
+        let now = Instant::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut table = RoutingTable::new(NodeId::random(&mut rng), now);
+        let nodes: Vec<NodeInfo> = (0..50).map(|port| node(&mut rng, port)).collect();
+        for node in &nodes {
+            table.insert(node.clone(), true, now);
+        }
+        let new_id = NodeId::random(&mut rng);
+        table.change_id(new_id, now);
+        assert_eq!(table.id(), new_id);
+        let closest = nodes.iter().min_by_key(|node| node.id.distance(&new_id)).unwrap();
+        assert_eq!(&table.closest(&new_id, 1)[0], closest);
+    }
+
+    #[test]
+    fn should_save_and_load_routing_tables() {
+        let now = Instant::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut table = RoutingTable::new(NodeId::random(&mut rng), now);
+        let mut table6 = RoutingTable::new(NodeId::random(&mut rng), now);
+        let nodes: Vec<NodeInfo> = (0..5).map(|port| node(&mut rng, port)).collect();
+        for node in &nodes {
+            table.insert(node.clone(), true, now);
+        }
+        let node6 = NodeInfo { id: NodeId::random(&mut rng), address: "[::1]:6881".parse().unwrap() };
+        table6.insert(node6.clone(), true, now);
+        let file = NamedTempFile::new().unwrap();
+        save_tables(file.path(), &table.saved(), &table6.saved()).unwrap();
+        let (mut loaded, loaded6) = load_tables(file.path()).unwrap();
+        assert_eq!(loaded.id, table.id());
+        loaded.nodes.sort_by_key(|node| node.address.port());
+        assert_eq!(loaded.nodes, nodes);
+        assert_eq!(loaded6, Some(SavedTable { id: table6.id(), nodes: vec![node6] }));
+
+        // Saved before there was an IPv6 table
+        std::fs::write(file.path(), b"d2:id20:abcdefghij01234567895:nodes0:e").unwrap();
+        let (loaded, loaded6) = load_tables(file.path()).unwrap();
+        assert!(loaded.nodes.is_empty() && loaded6.is_none());
+    }
+}
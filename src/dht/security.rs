@@ -0,0 +1,129 @@
+// Node ids derived from the IP address of the node, so that an attacker cannot choose where it sits in the DHT
+// https://www.bittorrent.org/beps/bep_0042.html
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use rand::Rng;
+use super::krpc::NodeId;
+
+// Number of distinct nodes which have to agree on our external address before we change our id for it
+const MIN_EXTERNAL_IP_VOTES: usize = 3;
+// Bounds the memory which the other nodes can make us use
+const MAX_EXTERNAL_IP_CANDIDATES: usize = 20;
+
+// CRC32-C (Castagnoli), reflected polynomial
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Only the high bits of the address count, so that the nodes of a network cannot get arbitrary ids by using many addresses of it
+fn id_prefix(ip: &IpAddr, random: u8) -> u32 {
+    let mut masked: Vec<u8> = match ip {
+        IpAddr::V4(ip) => (u32::from(*ip) & 0x030f_3fff).to_be_bytes().to_vec(),
+        IpAddr::V6(ip) => (u64::from_be_bytes(ip.octets()[0..8].try_into().unwrap()) & 0x0103_070f_1f3f_7fff).to_be_bytes().to_vec()
+    };
+    masked[0] |= (random & 0x07) << 5;
+    crc32c(&masked)
+}
+
+// The first 21 bits come from the address, the last byte holds the random number which went into them
+pub(crate) fn secure_node_id(ip: &IpAddr, rng: &mut impl Rng) -> NodeId {
+    let mut id = NodeId::random(rng);
+    let prefix = id_prefix(ip, id.0[19]).to_be_bytes();
+    id.0[0] = prefix[0];
+    id.0[1] = prefix[1];
+    id.0[2] = (prefix[2] & 0xf8) | (id.0[2] & 0x07);
+    id
+}
+
+// Nodes on local networks are exempt, unless they are verified too, which lets the nodes of a test on the loopback interface act as
+// nodes on the internet
+pub(crate) fn is_secure_node_id(id: &NodeId, ip: &IpAddr, verify_local: bool) -> bool {
+    if is_local(ip) && !verify_local {
+        return true;
+    }
+    let prefix = id_prefix(ip, id.0[19]).to_be_bytes();
+    id.0[0] == prefix[0] && id.0[1] == prefix[1] && id.0[2] & 0xf8 == prefix[2] & 0xf8
+}
+
+fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        // Unique local and link local addresses
+        IpAddr::V6(ip) => ip.is_loopback() || ip.segments()[0] & 0xfe00 == 0xfc00 || ip.segments()[0] & 0xffc0 == 0xfe80
+    }
+}
+
+// The nodes tell us which address our queries come from, we trust the address enough nodes agree on
+#[derive(Default)]
+pub(crate) struct ExternalIpVotes {
+    votes: HashMap<IpAddr, HashSet<IpAddr>>
+}
+
+impl ExternalIpVotes {
+    // Returns the address once it has enough votes, each node votes once
+    pub(crate) fn vote(&mut self, voter: IpAddr, external_ip: IpAddr) -> Option<IpAddr> {
+        if !self.votes.contains_key(&external_ip) && self.votes.len() >= MAX_EXTERNAL_IP_CANDIDATES {
+            return None;
+        }
+        let voters = self.votes.entry(external_ip).or_default();
+        voters.insert(voter);
+        if voters.len() >= MIN_EXTERNAL_IP_VOTES {
+            // Counting starts again, so that a later change of the address is noticed
+            self.votes.clear();
+            return Some(external_ip);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn should_verify_node_ids_derived_from_addresses() {
+        // Examples from https://www.bittorrent.org/beps/bep_0042.html#test-vectors
+        let examples = [
+            ("124.31.75.21", "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eefe01"),
+            ("21.75.31.124", "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256"),
+            ("65.23.51.170", "a5d43220bc8f112a3d426c84764f8c2a1150e616"),
+            ("84.124.73.14", "1b0321dd1bb1fe518101ceef99462b947a01ff41"),
+            ("43.213.53.83", "e56f6cbf5b7c4be0237986d5243b87aa6d51305a")
+        ];
+        for (ip, id) in examples {
+            let id = NodeId::from_bytes(&hex::decode(id).unwrap()).unwrap();
+            assert!(is_secure_node_id(&id, &ip.parse().unwrap(), false));
+            assert!(!is_secure_node_id(&id, &"1.2.3.4".parse().unwrap(), false));
+        }
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for ip in ["124.31.75.21", "2001:db8::1", "127.0.0.5"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(is_secure_node_id(&secure_node_id(&ip, &mut rng), &ip, true));
+        }
+        let random_id = NodeId([0; 20]);
+        assert!(is_secure_node_id(&random_id, &"192.168.1.1".parse().unwrap(), false));
+        assert!(!is_secure_node_id(&random_id, &"192.168.1.1".parse().unwrap(), true));
+    }
+
+    #[test]
+    fn should_agree_on_external_address_after_enough_votes() {
+        let mut votes = ExternalIpVotes::default();
+        let external_ip: IpAddr = "1.2.3.4".parse().unwrap();
+        assert_eq!(votes.vote("10.0.0.1".parse().unwrap(), external_ip), None);
+        assert_eq!(votes.vote("10.0.0.1".parse().unwrap(), external_ip), None);
+        assert_eq!(votes.vote("10.0.0.2".parse().unwrap(), "5.6.7.8".parse().unwrap()), None);
+        assert_eq!(votes.vote("10.0.0.2".parse().unwrap(), external_ip), None);
+        assert_eq!(votes.vote("10.0.0.3".parse().unwrap(), external_ip), Some(external_ip));
+        assert!(votes.votes.is_empty());
+    }
+}
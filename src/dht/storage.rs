@@ -0,0 +1,208 @@
+// Arbitrary data stored in the DHT, put and got by the nodes closest to its target https://www.bittorrent.org/beps/bep_0044.html
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use crate::bencoded::BencodeEncoding;
+use crate::hash;
+use super::krpc::NodeId;
+
+// Limits of the encoded value and of the salt
+const MAX_VALUE_LENGTH: usize = 1000;
+const MAX_SALT_LENGTH: usize = 64;
+// The items are put again before they expire
+const ITEM_EXPIRY: Duration = Duration::from_secs(2 * 60 * 60);
+// Bounds the memory which the other nodes can make us use
+const MAX_ITEMS: usize = 1000;
+
+// Error codes https://www.bittorrent.org/beps/bep_0044.html#errors
+pub(crate) const VALUE_TOO_BIG_ERROR: i64 = 205;
+pub(crate) const INVALID_SIGNATURE_ERROR: i64 = 206;
+pub(crate) const SALT_TOO_BIG_ERROR: i64 = 207;
+pub(crate) const CAS_MISMATCH_ERROR: i64 = 301;
+pub(crate) const SEQUENCE_NUMBER_ERROR: i64 = 302;
+
+// Why an item is not stored, sent back in a KRPC error
+#[derive(Debug, PartialEq)]
+pub(crate) struct ItemError {
+    pub(crate) code: i64,
+    pub(crate) message: &'static str
+}
+
+// A mutable item is signed by the owner of the key, who replaces it with items of higher sequence numbers
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Signed {
+    pub(crate) public_key: [u8; 32],
+    pub(crate) seq: i64,
+    pub(crate) signature: [u8; 64]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Item {
+    // Bencoded
+    pub(crate) value: Vec<u8>,
+    // Lets the owner of a key keep several mutable items, the nodes do not return it
+    pub(crate) salt: Vec<u8>,
+    // None for the immutable items, which are stored under the hash of their value
+    pub(crate) signed: Option<Signed>
+}
+
+impl Item {
+    pub(crate) fn immutable(value: Vec<u8>) -> Item {
+        Item { value, salt: Vec::new(), signed: None }
+    }
+
+    pub(crate) fn mutable(value: Vec<u8>, salt: Vec<u8>, seq: i64, key: &SigningKey) -> Item {
+        let signature = key.sign(&signature_payload(&salt, seq, &value)).to_bytes();
+        let signed = Signed { public_key: key.verifying_key().to_bytes(), seq, signature };
+        Item { value, salt, signed: Some(signed) }
+    }
+
+    pub(crate) fn target(&self) -> NodeId {
+        match &self.signed {
+            Some(signed) => mutable_target(&signed.public_key, &self.salt),
+            None => NodeId(hash::compute_hash(&self.value).try_into().unwrap())
+        }
+    }
+
+    pub(crate) fn seq(&self) -> Option<i64> {
+        self.signed.as_ref().map(|signed| signed.seq)
+    }
+
+    pub(crate) fn verify(&self) -> Result<(), ItemError> {
+        if self.value.len() > MAX_VALUE_LENGTH {
+            return Err(ItemError { code: VALUE_TOO_BIG_ERROR, message: "Message (v field) too big" });
+        }
+        if self.salt.len() > MAX_SALT_LENGTH {
+            return Err(ItemError { code: SALT_TOO_BIG_ERROR, message: "Salt (salt field) too big" });
+        }
+        if let Some(signed) = &self.signed {
+            let payload = signature_payload(&self.salt, signed.seq, &self.value);
+            VerifyingKey::from_bytes(&signed.public_key)
+                .and_then(|key| key.verify(&payload, &Signature::from_bytes(&signed.signature)))
+                .map_err(|_| ItemError { code: INVALID_SIGNATURE_ERROR, message: "Invalid signature" })?;
+        }
+        Ok(())
+    }
+}
+
+// Mutable items are stored under the hash of the public key and the salt
+pub(crate) fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut input = public_key.to_vec();
+    input.extend_from_slice(salt);
+    NodeId(hash::compute_hash(&input).try_into().unwrap())
+}
+
+// The salt, the sequence number and the value as they appear in a bencoded dictionary, without its delimiters
+fn signature_payload(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    if !salt.is_empty() {
+        payload.encode_str("salt");
+        payload.encode_bytes(salt);
+    }
+    payload.encode_str("seq");
+    payload.encode_i64(&seq);
+    payload.encode_str("v");
+    payload.extend_from_slice(value);
+    payload
+}
+
+#[derive(Default)]
+pub(crate) struct ItemStore {
+    items: HashMap<NodeId, (Item, Instant)>
+}
+
+impl ItemStore {
+    // A mutable item only replaces one with a lower sequence number, with a cas only the one with exactly that number.
+    // Putting the same value with the same sequence number again only refreshes its timeout
+    pub(crate) fn put(&mut self, item: Item, cas: Option<i64>, now: Instant) -> Result<(), ItemError> {
+        item.verify()?;
+        let target = item.target();
+        let stored = self.items.get(&target).and_then(|(stored, _)| stored.seq().map(|stored_seq| (stored_seq, &stored.value)));
+        match stored.zip(item.seq()) {
+            Some(((stored_seq, _), _)) if cas.is_some_and(|cas| cas != stored_seq) => {
+                return Err(ItemError { code: CAS_MISMATCH_ERROR, message: "CAS mismatch, re-read value and try again" });
+            },
+            Some(((stored_seq, _), seq)) if seq < stored_seq => {
+                return Err(ItemError { code: SEQUENCE_NUMBER_ERROR, message: "Sequence number less than current" });
+            },
+            Some(((stored_seq, stored_value), seq)) if seq == stored_seq && *stored_value != item.value => {
+                return Err(ItemError { code: SEQUENCE_NUMBER_ERROR, message: "Sequence number not newer than current" });
+            },
+            _ => {}
+        }
+        if self.items.contains_key(&target) || self.items.len() < MAX_ITEMS {
+            self.items.insert(target, (item, now));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, target: &NodeId) -> Option<&Item> {
+        self.items.get(target).map(|(item, _)| item)
+    }
+
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.items.retain(|_, (_, stored)| now.saturating_duration_since(*stored) < ITEM_EXPIRY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use crate::format;
+
+    fn signed_item(value: &[u8], salt: &[u8], signature: &str) -> Item {
+        let public_key = hex::decode("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548").unwrap();
+        let signed = Signed { public_key: public_key.try_into().unwrap(), seq: 1, signature: hex::decode(signature).unwrap().try_into().unwrap() };
+        Item { value: value.to_vec(), salt: salt.to_vec(), signed: Some(signed) }
+    }
+
+    #[test]
+    fn should_verify_items() {
+        // Test vectors from https://www.bittorrent.org/beps/bep_0044.html#test-vectors
+        let item = signed_item(b"12:Hello World!", b"", "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01");
+        assert_eq!(item.verify(), Ok(()));
+        assert_eq!(format::format_as_hex_string(&item.target().0), "4a533d47ec9c7d95b1ad75f576cffc641853b750");
+        let salted_item = signed_item(b"12:Hello World!", b"foobar", "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08");
+        assert_eq!(salted_item.verify(), Ok(()));
+        assert_eq!(format::format_as_hex_string(&salted_item.target().0), "411eba73b6f087ca51a3795d9c8c938d365e32c1");
+        let immutable_item = Item::immutable(b"12:Hello World!".to_vec());
+        assert_eq!(format::format_as_hex_string(&immutable_item.target().0), "e5f96f6f38320f0f33959cb4d3d656452117aadb");
+
+        let tampered_item = Item { value: b"12:Hello World?".to_vec(), ..item };
+        assert_eq!(tampered_item.verify().unwrap_err().code, INVALID_SIGNATURE_ERROR);
+        assert_eq!(Item::immutable(vec![b'a'; MAX_VALUE_LENGTH + 1]).verify().unwrap_err().code, VALUE_TOO_BIG_ERROR);
+        let key = SigningKey::generate(&mut OsRng);
+        assert_eq!(Item::mutable(b"i1e".to_vec(), vec![0; MAX_SALT_LENGTH + 1], 1, &key).verify().unwrap_err().code, SALT_TOO_BIG_ERROR);
+    }
+
+    #[test]
+    fn should_only_replace_mutable_items_with_newer_ones() {
+        let now = Instant::now();
+        let key = SigningKey::generate(&mut OsRng);
+        let mut store = ItemStore::default();
+        let first = Item::mutable(b"5:first".to_vec(), b"salt".to_vec(), 1, &key);
+        store.put(first.clone(), None, now).unwrap();
+        assert_eq!(store.get(&mutable_target(&key.verifying_key().to_bytes(), b"salt")), Some(&first));
+
+        let second = Item::mutable(b"6:second".to_vec(), b"salt".to_vec(), 2, &key);
+        assert_eq!(store.put(second.clone(), Some(0), now).unwrap_err().code, CAS_MISMATCH_ERROR);
+        store.put(second.clone(), Some(1), now).unwrap();
+        assert_eq!(store.put(first.clone(), None, now).unwrap_err().code, SEQUENCE_NUMBER_ERROR);
+        let same_seq = Item::mutable(b"5:third".to_vec(), b"salt".to_vec(), 2, &key);
+        assert_eq!(store.put(same_seq, None, now).unwrap_err().code, SEQUENCE_NUMBER_ERROR);
+        assert_eq!(store.get(&second.target()), Some(&second));
+        // The same value again keeps the item stored for longer
+        store.put(second.clone(), None, now + ITEM_EXPIRY / 2).unwrap();
+        store.expire(now + ITEM_EXPIRY);
+        assert_eq!(store.get(&second.target()), Some(&second));
+        // Another salt is another item
+        store.put(Item::mutable(b"5:other".to_vec(), Vec::new(), 1, &key), None, now).unwrap();
+
+        let immutable = Item::immutable(b"9:immutable".to_vec());
+        store.put(immutable.clone(), None, now + ITEM_EXPIRY).unwrap();
+        assert_eq!(store.get(&immutable.target()), Some(&immutable));
+        store.expire(now + 2 * ITEM_EXPIRY);
+        assert!(store.get(&immutable.target()).is_none());
+    }
+}
@@ -0,0 +1,74 @@
+// Tokens handed out in the get_peers responses, a node may only announce itself with a recent token it got for its IP address
+// https://www.bittorrent.org/beps/bep_0005.html#announce-peer
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use crate::hash;
+
+const SECRET_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const TOKEN_LENGTH: usize = 8;
+
+// Tokens of the current and of the previous secret are accepted, so a token stays valid for at least one rotation interval
+pub(crate) struct Tokens {
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    last_rotation: Instant
+}
+
+impl Tokens {
+    pub(crate) fn new(now: Instant, rng: &mut impl Rng) -> Tokens {
+        let secret = rng.gen();
+        Tokens { secret, previous_secret: secret, last_rotation: now }
+    }
+
+    pub(crate) fn rotate_if_due(&mut self, now: Instant, rng: &mut impl Rng) {
+        if now.saturating_duration_since(self.last_rotation) >= SECRET_ROTATION_INTERVAL {
+            self.previous_secret = self.secret;
+            self.secret = rng.gen();
+            self.last_rotation = now;
+        }
+    }
+
+    pub(crate) fn token_for(&self, address: &IpAddr) -> Vec<u8> {
+        compute_token(&self.secret, address)
+    }
+
+    pub(crate) fn is_valid(&self, token: &[u8], address: &IpAddr) -> bool {
+        token == self.token_for(address) || token == compute_token(&self.previous_secret, address)
+    }
+}
+
+fn compute_token(secret: &[u8; 20], address: &IpAddr) -> Vec<u8> {
+    let mut input: Vec<u8> = match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec()
+    };
+    input.extend_from_slice(secret);
+    hash::compute_hash(&input)[0..TOKEN_LENGTH].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn should_accept_tokens_until_second_rotation() {
+        let now = Instant::now();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut tokens = Tokens::new(now, &mut rng);
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = tokens.token_for(&address);
+        assert!(tokens.is_valid(&token, &address));
+        assert!(!tokens.is_valid(&token, &"10.0.0.2".parse().unwrap()));
+
+        tokens.rotate_if_due(now + SECRET_ROTATION_INTERVAL / 2, &mut rng);
+        assert_eq!(tokens.token_for(&address), token);
+        tokens.rotate_if_due(now + SECRET_ROTATION_INTERVAL, &mut rng);
+        assert_ne!(tokens.token_for(&address), token);
+        assert!(tokens.is_valid(&token, &address));
+        tokens.rotate_if_due(now + 2 * SECRET_ROTATION_INTERVAL, &mut rng);
+        assert!(!tokens.is_valid(&token, &address));
+    }
+}
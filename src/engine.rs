@@ -0,0 +1,1237 @@
+// Downloads and seeds a torrent: a torrent actor owns the piece state and drives the peer tasks over channels
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::ensure;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use tokio::sync::mpsc;
+use crate::bitfield::Bitfield;
+use crate::choker::{ChokeCandidate, Choker};
+use crate::config::ClientConfig;
+use crate::dht::{DhtNode, NodeId};
+use crate::error::new_error;
+use crate::format;
+use crate::file;
+use crate::hash;
+use crate::peer::{Capabilities, ExtensionHandshake, ExtensionRegistry, MetadataMessage, Peer, PeerAddress, PeerChokedState, PeerConnectionState, PeerInterestedState, PeerExtensions, PeerMessage, PexMessage, Piece, RequestPipeline};
+use crate::peer;
+use crate::piece_picker::{BlockRequest, PiecePicker};
+use crate::torrent::{Torrent, TorrentInfo};
+use crate::tracker::{AnnounceEvent, SwarmTrackers, Tracker, TrackerRequest};
+use crate::tracker::udp::UdpTrackerSocket;
+use connections::ConnectionManager;
+use peer_task::PeerTaskConfig;
+
+pub(crate) use acceptor::PeerAcceptor;
+pub(crate) use connections::ConnectionLimit;
+
+mod acceptor;
+mod connections;
+mod peer_task;
+
+// Peer tasks wait when the torrent actor falls behind
+const EVENT_QUEUE_LENGTH: usize = 1024;
+const PEER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_REANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+// A peer which keeps snubbing us is dropped in favour of another one
+const MAX_SNUB_COUNT: u32 = 3;
+const CHOKED_PEER_TIMEOUT: Duration = Duration::from_secs(120);
+// Requests of a peer which we did not answer yet, further ones are rejected or the peer is dropped without the fast extension
+const ADVERTISED_REQUEST_QUEUE_LENGTH: usize = 250;
+// Peers are exchanged with each peer at most once a minute https://www.bittorrent.org/beps/bep_0011.html
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// Peer exchange messages which a peer sends more often are ignored
+const MIN_PEX_RECEIVE_INTERVAL: Duration = Duration::from_secs(30);
+// Blocks are normally 16 KiB, larger requests are refused
+const MAX_REQUEST_LENGTH: u32 = 1 << 17;
+
+#[derive(PartialEq, Clone, Copy)]
+pub(crate) enum DownloadMode {
+    Piece,
+    File
+}
+
+pub(crate) enum TorrentEvent {
+    // Incoming when the peer connected to us
+    PeerConnected { address: PeerAddress, peer: Peer, capabilities: Capabilities, incoming: bool, commands: mpsc::UnboundedSender<PeerCommand> },
+    PeerMessage { address: PeerAddress, message: PeerMessage },
+    // Also sent when the connection or the handshake fails
+    PeerDisconnected { address: PeerAddress, reason: String },
+    // The announce task hands the tracker statuses back along with the peers it found
+    PeersAnnounced { peer_addresses: Vec<PeerAddress>, trackers: SwarmTrackers },
+    // The disk is accessed outside of the torrent actor, which keeps handling the peer events meanwhile
+    BlockRead { address: PeerAddress, request: BlockRequest, result: Result<Vec<u8>, anyhow::Error> },
+    // The peer task wrote a block to the peer, so the next one can be read
+    BlockSent { address: PeerAddress },
+    PieceWritten { index: usize, result: Result<(), anyhow::Error> }
+}
+
+pub(crate) enum PeerCommand {
+    Send(PeerMessage),
+    Disconnect(String)
+}
+
+// What the torrent actor knows about a connected peer
+struct PeerState {
+    peer: Peer,
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    // The address of a peer which connected to us is not the one it accepts connections on
+    incoming: bool,
+    bitfield: Bitfield,
+    connection_state: PeerConnectionState,
+    // Blocks requested from this peer which it did not send yet
+    pipeline: RequestPipeline,
+    choked_since: Option<Instant>,
+    snub_count: u32,
+    // Learned from the extension handshake https://www.bittorrent.org/beps/bep_0010.html
+    extensions: PeerExtensions,
+    client: Option<String>,
+    listen_port: Option<u16>,
+    // A peer which only uploads is never interested in our pieces
+    upload_only: bool,
+    // Both sides support the fast extension https://www.bittorrent.org/beps/bep_0006.html
+    fast: bool,
+    // Pieces the peer lets us download while it chokes us
+    allowed_fast: HashSet<usize>,
+    // Pieces the peer lets us download while we choke it
+    granted_fast: HashSet<u32>,
+    // Requests of the peer which we did not answer yet, their blocks are read one after another
+    upload_queue: VecDeque<BlockRequest>,
+    // A block is being read from the disk or written to the peer
+    uploading_block: bool,
+    // Pieces the peer suggests that we download first, only the latest ones are kept
+    suggested: VecDeque<usize>,
+    // Peers which we told this peer about with peer exchange
+    pex_sent: HashSet<PeerAddress>,
+    last_pex_sent: Option<Instant>,
+    last_pex_received: Option<Instant>
+}
+
+impl PeerState {
+    // A failed send means that the peer task is gone, it then reports the disconnection itself
+    fn send(&self, message: PeerMessage) {
+        let _ = self.commands.send(PeerCommand::Send(message));
+    }
+
+    fn id(&self) -> String {
+        format::format_as_hex_string(&self.peer.id)
+    }
+}
+
+pub(crate) struct TorrentActor {
+    torrent: Arc<Torrent>,
+    // The bencoded info dictionary served with ut_metadata https://www.bittorrent.org/beps/bep_0009.html
+    metadata: Vec<u8>,
+    config: Arc<ClientConfig>,
+    peer_task_config: PeerTaskConfig,
+    current_peer_id: String,
+    output_file_path: String,
+    download_mode: DownloadMode,
+    piece_picker: PiecePicker,
+    our_pieces: Bitfield,
+    // Keeps running after all the wanted pieces are verified
+    seed: bool,
+    peers: HashMap<PeerAddress, PeerState>,
+    choker: Choker<PeerAddress>,
+    extensions: ExtensionRegistry,
+    connections: ConnectionManager,
+    dht: Option<Arc<DhtNode>>,
+    udp_tracker_socket: Arc<UdpTrackerSocket>,
+    // Taken by the announce task while it runs
+    trackers: Option<SwarmTrackers>,
+    // Sent with the next announce https://www.bittorrent.org/beps/bep_0003.html#trackers
+    tracker_event: AnnounceEvent,
+    last_announce: Instant,
+    peers_dropped_since_announce: bool,
+    events: mpsc::Receiver<TorrentEvent>,
+    events_sender: mpsc::Sender<TorrentEvent>,
+    rng: StdRng
+}
+
+impl TorrentActor {
+    pub(crate) fn new(torrent: Arc<Torrent>, config: Arc<ClientConfig>, current_peer_id: &str, wanted_pieces: Vec<Piece>, output_file_path: &str, download_mode: DownloadMode, udp_tracker_socket: Arc<UdpTrackerSocket>) -> TorrentActor {
+        let piece_count = torrent.info.total_piece_number();
+        let (events_sender, events) = mpsc::channel(EVENT_QUEUE_LENGTH);
+        TorrentActor {
+            peer_task_config: PeerTaskConfig {
+                info_hash: torrent.info.compute_hash(),
+                peer_id: current_peer_id.as_bytes().to_vec(),
+                max_message_length: config.max_peer_message_length,
+                dht: false
+            },
+            current_peer_id: current_peer_id.to_string(),
+            output_file_path: output_file_path.to_string(),
+            download_mode,
+            piece_picker: PiecePicker::new(piece_count, wanted_pieces),
+            our_pieces: Bitfield::new(piece_count),
+            seed: false,
+            peers: HashMap::new(),
+            choker: Choker::new(config.upload_slots, Instant::now()),
+            extensions: {
+                let mut extensions = ExtensionRegistry::default();
+                extensions.register(peer::UPLOAD_ONLY_EXTENSION);
+                extensions.register(peer::METADATA_EXTENSION);
+                // The peers of a private torrent only come from its trackers https://www.bittorrent.org/beps/bep_0027.html
+                if !torrent.info.is_private() {
+                    extensions.register(peer::PEX_EXTENSION);
+                }
+                extensions
+            },
+            connections: ConnectionManager::new(config.max_peer_connections, config.peer_connect_attempts, Arc::clone(&config.connection_limit)),
+            dht: None,
+            udp_tracker_socket,
+            trackers: Some(SwarmTrackers::new(&torrent.tracker_tiers(), Instant::now())),
+            tracker_event: AnnounceEvent::Started,
+            last_announce: Instant::now(),
+            peers_dropped_since_announce: false,
+            events,
+            events_sender,
+            rng: StdRng::from_entropy(),
+            metadata: torrent.info.bencode(),
+            torrent,
+            config
+        }
+    }
+
+    // Peers are also looked up in the DHT and the peers we connect to learn about our DHT node,
+    // the peers of a private torrent only come from its trackers https://www.bittorrent.org/beps/bep_0027.html
+    pub(crate) fn with_dht(mut self, dht: Option<Arc<DhtNode>>) -> TorrentActor {
+        if !self.torrent.info.is_private() {
+            self.peer_task_config.dht = dht.is_some();
+            self.dht = dht;
+        }
+        self
+    }
+
+    // Uploads the verified pieces of the file until the client is stopped, the wanted pieces are downloaded first
+    pub(crate) fn seeding(mut self, verified_pieces: Bitfield) -> TorrentActor {
+        self.our_pieces = verified_pieces;
+        self.seed = true;
+        self
+    }
+
+    // Runs until all the wanted pieces are verified, fails when no peers are left to download from
+    pub(crate) async fn run(mut self, peer_addresses: Vec<PeerAddress>) -> Result<(), anyhow::Error> {
+        let result = self.exchange_pieces(peer_addresses).await;
+        // Dropping the peer states closes the command channels, which ends the peer tasks
+        self.peers.clear();
+        self.leave_swarm().await;
+        result
+    }
+
+    async fn exchange_pieces(&mut self, peer_addresses: Vec<PeerAddress>) -> Result<(), anyhow::Error> {
+        self.connections.add_candidates(peer_addresses, Instant::now());
+        self.connect();
+        self.announce(!self.piece_picker.is_complete());
+        let mut peer_check = tokio::time::interval(PEER_CHECK_INTERVAL);
+        while self.seed || !self.piece_picker.is_complete() {
+            tokio::select! {
+                Some(event) = self.events.recv() => self.handle_event(event)?,
+                _ = peer_check.tick() => {
+                    self.check_peers(Instant::now());
+                    self.update_choking(Instant::now());
+                    self.exchange_peers(Instant::now());
+                    self.connect();
+                },
+                // The trackers are still told that we leave the swarm
+                _ = tokio::signal::ctrl_c() => {
+                    ensure!(self.piece_picker.is_complete(), "Stopped before all the pieces were downloaded");
+                    return Ok(());
+                }
+            }
+            // Peers which failed to connect may still be waiting to be retried, but new peers are asked for right away only when there are none
+            let reannounce_due = (self.connections.connection_count() == 0 || self.peers_dropped_since_announce) && self.last_announce.elapsed() >= MIN_REANNOUNCE_INTERVAL;
+            let peers_needed = !self.piece_picker.is_complete() && (self.connections.is_exhausted() || reannounce_due);
+            let trackers_due = self.trackers.as_ref().and_then(|trackers| trackers.next_announce()).is_some_and(|next_announce| next_announce <= Instant::now());
+            if peers_needed || trackers_due {
+                self.announce(peers_needed);
+            }
+        }
+        println!("Finished downloading {}", self.output_file_path);
+        Ok(())
+    }
+
+    // Tells the trackers that we finished downloading, if we did not tell them yet, and that we are leaving
+    async fn leave_swarm(&mut self) {
+        while self.trackers.is_none() {
+            match self.events.recv().await {
+                Some(TorrentEvent::PeersAnnounced { trackers, .. }) => self.trackers = Some(trackers),
+                Some(_) => {},
+                None => return
+            }
+        }
+        let Some(mut trackers) = self.trackers.take() else {
+            return;
+        };
+        if self.tracker_event == AnnounceEvent::Completed {
+            let request = TrackerRequest { event: AnnounceEvent::Completed, ..self.tracker_request() };
+            Tracker::announce_to_swarm(&mut trackers, &request, &self.udp_tracker_socket).await;
+        }
+        let request = TrackerRequest { event: AnnounceEvent::Stopped, ..self.tracker_request() };
+        Tracker::announce_to_swarm(&mut trackers, &request, &self.udp_tracker_socket).await;
+    }
+
+    // Connects to the candidate peers while there are free connection slots, a seed waits for the peers to connect to it
+    fn connect(&mut self) {
+        if self.piece_picker.is_complete() {
+            return;
+        }
+        for address in self.connections.next_to_dial(Instant::now()) {
+            tokio::spawn(peer_task::run_peer(address, self.peer_task_config.clone(), self.events_sender.clone()));
+        }
+    }
+
+    // Announces to the trackers which are due without blocking the handling of the peer events,
+    // when we need more peers all the trackers and the DHT are asked for them
+    fn announce(&mut self, peers_needed: bool) {
+        let Some(mut trackers) = self.trackers.take() else {
+            return;
+        };
+        let now = Instant::now();
+        if peers_needed {
+            self.last_announce = now;
+            self.peers_dropped_since_announce = false;
+            trackers.make_due(now);
+        }
+        let request = TrackerRequest { event: self.tracker_event, ..self.tracker_request() };
+        self.tracker_event = AnnounceEvent::None;
+        let dht = if peers_needed { self.dht.clone() } else { None };
+        let (info_hash, port, events_sender, udp_tracker_socket) = (self.peer_task_config.info_hash.clone(), self.config.port as u16, self.events_sender.clone(), Arc::clone(&self.udp_tracker_socket));
+        tokio::spawn(async move {
+            let dht_announce = async {
+                match (dht, NodeId::from_bytes(&info_hash)) {
+                    (Some(dht), Ok(info_hash)) => dht.announce(&info_hash, port).await,
+                    _ => Vec::new()
+                }
+            };
+            let (mut peer_addresses, dht_peer_addresses) = tokio::join!(Tracker::announce_to_swarm(&mut trackers, &request, &udp_tracker_socket), dht_announce);
+            for peer_address in dht_peer_addresses {
+                if !peer_addresses.contains(&peer_address) {
+                    peer_addresses.push(peer_address);
+                }
+            }
+            let _ = events_sender.send(TorrentEvent::PeersAnnounced { peer_addresses, trackers }).await;
+        });
+    }
+
+    fn tracker_request(&self) -> TrackerRequest {
+        // Bytes of the pieces which we don't have yet
+        let left = (0..self.torrent.info.total_piece_number())
+            .filter(|index| !self.our_pieces.has(*index))
+            .map(|index| self.torrent.info.piece_length_at_index(index as u32).unwrap_or(0) as u64)
+            .sum();
+        TrackerRequest::new(&self.current_peer_id, self.peer_task_config.info_hash.clone(), left, &self.config)
+    }
+
+    // Of two peers which dialed each other, the connection opened by the one with the smaller peer id is kept
+    fn keep_connection(&mut self, peer: &Peer, incoming: bool) -> bool {
+        if peer.id == self.peer_task_config.peer_id {
+            return false;
+        }
+        let Some((existing_address, existing_incoming)) = self.peers.iter()
+            .find(|(_, peer_state)| peer_state.peer == *peer)
+            .map(|(address, peer_state)| (address.clone(), peer_state.incoming)) else {
+            return true;
+        };
+        let opened_by = |incoming: bool| if incoming { &peer.id } else { &self.peer_task_config.peer_id };
+        if opened_by(incoming) < opened_by(existing_incoming) {
+            self.drop_peer(&existing_address, "Connected to the peer again".to_string());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn handle_event(&mut self, event: TorrentEvent) -> Result<(), anyhow::Error> {
+        match event {
+            TorrentEvent::PeerConnected { address, peer, capabilities, incoming, commands } => {
+                if incoming && !self.connections.accepted(&address) {
+                    let _ = commands.send(PeerCommand::Disconnect("No free connection slot".to_string()));
+                    return Ok(());
+                }
+                self.connections.connected(&address);
+                if !self.keep_connection(&peer, incoming) {
+                    let _ = commands.send(PeerCommand::Disconnect("Already connected to the peer".to_string()));
+                    return Ok(());
+                }
+                println!("Established connection to peer {:?} peer address {:?}", format::format_as_hex_string(&peer.id), &address);
+                let mut peer_state = PeerState {
+                    peer,
+                    commands,
+                    incoming,
+                    bitfield: Bitfield::new(self.torrent.info.total_piece_number()),
+                    connection_state: PeerConnectionState::initial(),
+                    pipeline: RequestPipeline::new(Instant::now()),
+                    choked_since: Some(Instant::now()),
+                    snub_count: 0,
+                    extensions: PeerExtensions::default(),
+                    client: None,
+                    listen_port: None,
+                    upload_only: false,
+                    fast: capabilities.fast,
+                    allowed_fast: HashSet::new(),
+                    granted_fast: HashSet::new(),
+                    upload_queue: VecDeque::new(),
+                    uploading_block: false,
+                    suggested: VecDeque::new(),
+                    pex_sent: HashSet::new(),
+                    last_pex_sent: None,
+                    last_pex_received: None
+                };
+                // Only the first message after the handshake may tell which pieces we have https://www.bittorrent.org/beps/bep_0006.html#have-all-have-none
+                if peer_state.fast && self.our_pieces.is_complete() {
+                    peer_state.send(PeerMessage::HaveAll);
+                } else if peer_state.fast && self.our_pieces.is_empty() {
+                    peer_state.send(PeerMessage::HaveNone);
+                } else if !self.our_pieces.is_empty() {
+                    // Without the fast extension peers which don't have anything yet may skip the 'bitfield' message https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+                    peer_state.send(PeerMessage::Bitfield { bitfield: self.our_pieces.as_bytes().to_vec() });
+                }
+                if capabilities.extension_protocol {
+                    let handshake = ExtensionHandshake {
+                        listen_port: u16::try_from(self.config.port).ok(),
+                        request_queue_length: Some(ADVERTISED_REQUEST_QUEUE_LENGTH),
+                        your_ip: Some(address.address),
+                        metadata_size: Some(self.metadata.len()),
+                        ..self.extensions.handshake()
+                    };
+                    peer_state.send(PeerMessage::Extended { extended_id: peer::EXTENSION_HANDSHAKE_ID, payload: handshake.get_bytes() });
+                }
+                // The peer can add our DHT node to its routing table https://www.bittorrent.org/beps/bep_0005.html#bittorrent-protocol-extension
+                if let (true, Some(dht)) = (capabilities.dht, &self.dht) {
+                    peer_state.send(PeerMessage::Port { port: dht.local_addrs()[0].port() });
+                }
+                // Lets a new peer download a few pieces before we unchoke it, so that it has something to offer
+                if peer_state.fast {
+                    for index in peer::allowed_fast_set(&address.address, &self.peer_task_config.info_hash, self.torrent.info.total_piece_number(), peer::ALLOWED_FAST_SET_SIZE) {
+                        peer_state.send(PeerMessage::AllowedFast { index });
+                        peer_state.granted_fast.insert(index);
+                    }
+                }
+                self.peers.insert(address.clone(), peer_state);
+                self.update_peer(&address);
+            },
+            TorrentEvent::PeerMessage { address, message } => {
+                if let Err(error) = self.handle_message(&address, message) {
+                    self.drop_peer(&address, error.to_string());
+                } else {
+                    self.update_peer(&address);
+                }
+            },
+            TorrentEvent::PeerDisconnected { address, reason } => {
+                if self.connections.is_connecting(&address) {
+                    println!("Could not connect to peer {:?}: {}", &address, reason);
+                } else {
+                    self.remove_peer(&address, &reason);
+                }
+                // The freed connection slot goes to another peer
+                self.connections.disconnected(&address, Instant::now());
+                self.connect();
+            },
+            TorrentEvent::PeersAnnounced { peer_addresses, trackers } => {
+                let new_peer_count = self.connections.add_candidates(peer_addresses, Instant::now());
+                let tracker_errors: Vec<String> = trackers.statuses().iter()
+                    .filter_map(|status| status.last_error.as_ref().map(|error| format!("{}: {}", status.url, error)))
+                    .collect();
+                self.trackers = Some(trackers);
+                if !self.piece_picker.is_complete() && self.connections.is_exhausted() && new_peer_count == 0 {
+                    return Err(new_error(format!("All the peers were dropped and the trackers returned no new peers {:?}", tracker_errors)));
+                }
+                self.connect();
+            },
+            TorrentEvent::BlockRead { address, request, result } => {
+                let Some(peer_state) = self.peers.get_mut(&address) else {
+                    return Ok(());
+                };
+                match result {
+                    // The request may have been cancelled while the block was read
+                    Ok(block) if peer_state.upload_queue.front() == Some(&request) => {
+                        peer_state.upload_queue.pop_front();
+                        self.choker.uploaded(&address, block.len());
+                        peer_state.send(PeerMessage::Piece { index: request.index, begin: request.begin, block });
+                    },
+                    Ok(_) => {
+                        peer_state.uploading_block = false;
+                        self.read_next_block(&address);
+                    },
+                    Err(error) => self.drop_peer(&address, format!("Could not read the requested block {:?}: {}", request, error))
+                }
+            },
+            TorrentEvent::BlockSent { address } => {
+                if let Some(peer_state) = self.peers.get_mut(&address) {
+                    peer_state.uploading_block = false;
+                    self.read_next_block(&address);
+                }
+            },
+            TorrentEvent::PieceWritten { index, result } => {
+                result.map_err(|error| new_error(format!("Could not write piece {} to {}: {}", index, self.output_file_path, error)))?;
+                println!("Piece {} downloaded to {}.", index, self.output_file_path);
+                self.our_pieces.set(index)?;
+                self.piece_picker.verified(index);
+                for peer_state in self.peers.values() {
+                    peer_state.send(PeerMessage::Have { index: index as u32 });
+                }
+                if self.our_pieces.is_complete() {
+                    self.tracker_event = AnnounceEvent::Completed;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(&mut self, address: &PeerAddress, message: PeerMessage) -> Result<(), anyhow::Error> {
+        if let PeerMessage::Request { index, begin, length } = message {
+            return self.upload_block(address, BlockRequest { index, begin, length });
+        }
+        let Some(peer_state) = self.peers.get_mut(address) else {
+            return Ok(());
+        };
+        match message {
+            PeerMessage::Bitfield { bitfield } => {
+                println!("Received: 'bitfield' from peer {:?}", peer_state.id());
+                let received_bitfield = Bitfield::from_bytes(&bitfield, self.torrent.info.total_piece_number())?;
+                self.piece_picker.remove_peer(&peer_state.bitfield);
+                self.piece_picker.add_peer(&received_bitfield);
+                peer_state.bitfield = received_bitfield;
+            },
+            PeerMessage::Have { index } if !peer_state.bitfield.has(index as usize) => {
+                peer_state.bitfield.set(index as usize)?;
+                self.piece_picker.add_have(index as usize)?;
+            },
+            PeerMessage::HaveAll | PeerMessage::HaveNone => {
+                ensure!(peer_state.fast, "Peer sent {:?} without the fast extension", message);
+                let piece_count = self.torrent.info.total_piece_number();
+                let received_bitfield = if message == PeerMessage::HaveAll { Bitfield::full(piece_count) } else { Bitfield::new(piece_count) };
+                self.piece_picker.remove_peer(&peer_state.bitfield);
+                self.piece_picker.add_peer(&received_bitfield);
+                peer_state.bitfield = received_bitfield;
+            },
+            PeerMessage::Choke => {
+                println!("Received: 'choke' from peer {:?}", peer_state.id());
+                peer_state.connection_state = peer_state.connection_state.update_choked(PeerChokedState::Choked);
+                peer_state.choked_since = Some(Instant::now());
+                // A choking peer discards our requests, the other peers can download these blocks
+                // with the fast extension it rejects each of them instead https://www.bittorrent.org/beps/bep_0006.html#reject-request
+                if !peer_state.fast {
+                    self.piece_picker.release_blocks(&peer_state.peer);
+                    peer_state.pipeline.clear();
+                }
+            },
+            PeerMessage::RejectRequest { index, begin, length } => {
+                ensure!(peer_state.fast, "Peer sent {:?} without the fast extension", message);
+                let block = BlockRequest { index, begin, length };
+                peer_state.pipeline.remove(&block);
+                self.piece_picker.release_block(&peer_state.peer, &block);
+            },
+            PeerMessage::AllowedFast { index } if peer_state.fast && (index as usize) < self.torrent.info.total_piece_number() => {
+                peer_state.allowed_fast.insert(index as usize);
+            },
+            // Only a hint, the pieces we don't need are skipped by the piece picker
+            PeerMessage::SuggestPiece { index } if peer_state.fast && (index as usize) < self.torrent.info.total_piece_number() && !peer_state.suggested.contains(&(index as usize)) => {
+                if peer_state.suggested.len() == peer::ALLOWED_FAST_SET_SIZE {
+                    peer_state.suggested.pop_front();
+                }
+                peer_state.suggested.push_back(index as usize);
+            },
+            PeerMessage::Unchoke => {
+                println!("Received: 'unchoke' from peer {:?}", peer_state.id());
+                peer_state.connection_state = peer_state.connection_state.update_choked(PeerChokedState::Unchoked);
+                peer_state.choked_since = None;
+            },
+            PeerMessage::Interested => {
+                peer_state.connection_state = peer_state.connection_state.update_peer_interested(PeerInterestedState::Interested);
+                self.update_choking(Instant::now());
+            },
+            PeerMessage::NotInterested => {
+                peer_state.connection_state = peer_state.connection_state.update_peer_interested(PeerInterestedState::NotInterested);
+                self.update_choking(Instant::now());
+            },
+            PeerMessage::Cancel { index, begin, length } => {
+                let block = BlockRequest { index, begin, length };
+                peer_state.upload_queue.retain(|request| *request != block);
+            },
+            PeerMessage::Extended { extended_id: peer::EXTENSION_HANDSHAKE_ID, payload } => {
+                let handshake = ExtensionHandshake::parse(&payload)?;
+                println!("Peer {:?} runs {:?}, listens on port {:?} and sees us as {:?}", peer_state.id(), handshake.client, handshake.listen_port, handshake.your_ip);
+                if let Some(request_queue_length) = handshake.request_queue_length {
+                    peer_state.pipeline.set_peer_limit(request_queue_length);
+                }
+                peer_state.extensions = PeerExtensions::from_handshake(&handshake);
+                peer_state.client = handshake.client.or(peer_state.client.take());
+                peer_state.listen_port = handshake.listen_port.or(peer_state.listen_port);
+                // Tells the peer that we only seed, it gets nothing from our interest
+                if self.piece_picker.is_complete() {
+                    if let Some(upload_only) = peer_state.extensions.message(peer::UPLOAD_ONLY_EXTENSION, vec![1]) {
+                        peer_state.send(upload_only);
+                    }
+                }
+            },
+            PeerMessage::Extended { extended_id, payload } => match self.extensions.name_of(extended_id) {
+                Some(peer::UPLOAD_ONLY_EXTENSION) => {
+                    peer_state.upload_only = payload.first().is_some_and(|upload_only| *upload_only != 0);
+                    self.update_choking(Instant::now());
+                },
+                // Peers which only know the info hash download the info dictionary from us https://www.bittorrent.org/beps/bep_0009.html
+                Some(peer::METADATA_EXTENSION) => {
+                    if let MetadataMessage::Request { piece } = MetadataMessage::parse(&payload)? {
+                        if let Some(response) = peer_state.extensions.message(peer::METADATA_EXTENSION, peer::metadata_response(&self.metadata, piece).get_bytes()) {
+                            peer_state.send(response);
+                        }
+                    }
+                },
+                Some(peer::PEX_EXTENSION) => {
+                    if peer_state.last_pex_received.is_some_and(|last_pex_received| last_pex_received.elapsed() < MIN_PEX_RECEIVE_INTERVAL) {
+                        println!("Ignoring too frequent peer exchange from peer {:?}", peer_state.id());
+                        return Ok(());
+                    }
+                    peer_state.last_pex_received = Some(Instant::now());
+                    let pex = PexMessage::parse(&payload)?;
+                    let added: Vec<PeerAddress> = pex.added.into_iter().map(|(address, _)| address).take(peer::MAX_PEX_PEERS).collect();
+                    let new_peer_count = self.connections.add_candidates(added, Instant::now());
+                    println!("Peer {:?} told us about {} new peers", peer_state.id(), new_peer_count);
+                    self.connect();
+                },
+                // Extended messages which we did not negotiate are ignored
+                _ => println!("Ignoring extended message {} from peer {:?}", extended_id, peer_state.id())
+            },
+            PeerMessage::Port { port } => {
+                if let Some(dht) = &self.dht {
+                    let (dht, node_address) = (Arc::clone(dht), SocketAddr::new(address.address, port));
+                    // A node which responds is added to the routing table
+                    tokio::spawn(async move { dht.ping(node_address).await });
+                }
+            },
+            PeerMessage::Piece { index, begin, block } => {
+                // Blocks which we did not ask this peer for don't make it a better peer to unchoke
+                if peer_state.pipeline.received(index, begin, block.len(), Instant::now()) {
+                    self.choker.downloaded(address, block.len());
+                }
+                let request = BlockRequest { index, begin, length: block.len() as u32 };
+                let needed = self.piece_picker.is_block_needed(&request);
+                let piece_data = self.piece_picker.block_received(index as usize, begin, &block)?;
+                if needed {
+                    self.cancel_elsewhere(address, &request);
+                }
+                if let Some(piece_data) = piece_data {
+                    self.piece_completed(index as usize, piece_data);
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Requests of a choked peer are dropped https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+    // with the fast extension every request gets either the block or a rejection https://www.bittorrent.org/beps/bep_0006.html#reject-request
+    fn upload_block(&mut self, address: &PeerAddress, request: BlockRequest) -> Result<(), anyhow::Error> {
+        let Some(peer_state) = self.peers.get_mut(address) else {
+            return Ok(());
+        };
+        let BlockRequest { index, begin, length } = request;
+        let allowed = peer_state.connection_state.peer_choked == PeerChokedState::Unchoked || peer_state.granted_fast.contains(&index);
+        if peer_state.fast && (!allowed || !self.our_pieces.has(index as usize)) {
+            peer_state.send(PeerMessage::RejectRequest { index, begin, length });
+            return Ok(());
+        }
+        if !allowed {
+            return Ok(());
+        }
+        ensure!(self.our_pieces.has(index as usize), "Peer requested piece {} which we don't have", index);
+        ensure!(length <= MAX_REQUEST_LENGTH, "Requested block of {} bytes is too large", length);
+        let piece_length = self.torrent.info.piece_length_at_index(index)?;
+        ensure!(begin as u64 + length as u64 <= piece_length as u64, "Requested block {}..{} is outside of piece {}", begin, begin as u64 + length as u64, index);
+        if peer_state.fast && peer_state.upload_queue.len() >= ADVERTISED_REQUEST_QUEUE_LENGTH {
+            peer_state.send(PeerMessage::RejectRequest { index, begin, length });
+            return Ok(());
+        }
+        ensure!(peer_state.upload_queue.len() < ADVERTISED_REQUEST_QUEUE_LENGTH, "Peer sent more than {} requests which we did not answer yet", ADVERTISED_REQUEST_QUEUE_LENGTH);
+        peer_state.upload_queue.push_back(request);
+        self.read_next_block(address);
+        Ok(())
+    }
+
+    // Reads the block of the oldest request of the peer, the next one is read once the peer task wrote it
+    fn read_next_block(&mut self, address: &PeerAddress) {
+        let Some(peer_state) = self.peers.get_mut(address) else {
+            return;
+        };
+        let Some(request) = peer_state.upload_queue.front().filter(|_| !peer_state.uploading_block).cloned() else {
+            return;
+        };
+        peer_state.uploading_block = true;
+        // When downloading a single piece the file only contains that piece
+        let piece_begin_in_file = if self.download_mode == DownloadMode::File { self.torrent.info.piece_length * request.index as usize } else { 0 };
+        let (begin, length) = (piece_begin_in_file + request.begin as usize, request.length as usize);
+        let (output_file_path, address, events_sender) = (self.output_file_path.clone(), address.clone(), self.events_sender.clone());
+        tokio::spawn(async move {
+            let result = match tokio::task::spawn_blocking(move || file::read_piece_from(&output_file_path, begin, length)).await {
+                Ok(result) => result,
+                Err(error) => Err(error.into())
+            };
+            let _ = events_sender.send(TorrentEvent::BlockRead { address, request, result }).await;
+        });
+    }
+
+    // Chokes and unchokes the peers as the choker decides
+    fn update_choking(&mut self, now: Instant) {
+        let candidates: Vec<ChokeCandidate<PeerAddress>> = self.peers.iter().map(|(address, peer_state)| ChokeCandidate {
+            key: address.clone(),
+            interested: peer_state.connection_state.peer_interested == PeerInterestedState::Interested && !peer_state.upload_only,
+            snubbed: peer_state.pipeline.is_snubbed()
+        }).collect();
+        // Without anything left to download the upload rates decide
+        let unchoked = self.choker.update(&candidates, self.piece_picker.is_complete(), now, &mut self.rng);
+        for (address, peer_state) in self.peers.iter_mut() {
+            let peer_choked = if unchoked.contains(address) { PeerChokedState::Unchoked } else { PeerChokedState::Choked };
+            if peer_state.connection_state.peer_choked != peer_choked {
+                peer_state.send(if peer_choked == PeerChokedState::Choked { PeerMessage::Choke } else { PeerMessage::Unchoke });
+                peer_state.connection_state = peer_state.connection_state.update_peer_choked(peer_choked);
+            }
+            // Only the requests for the allowed fast pieces are still answered, with the fast extension the others are rejected
+            if peer_choked == PeerChokedState::Choked {
+                let (granted, dropped): (VecDeque<BlockRequest>, VecDeque<BlockRequest>) = peer_state.upload_queue.drain(..)
+                    .partition(|request| peer_state.granted_fast.contains(&request.index));
+                peer_state.upload_queue = granted;
+                for BlockRequest { index, begin, length } in dropped.into_iter().filter(|_| peer_state.fast) {
+                    peer_state.send(PeerMessage::RejectRequest { index, begin, length });
+                }
+            }
+        }
+    }
+
+    // Tells each peer which supports peer exchange about the peers we connected to and disconnected from since the last message
+    fn exchange_peers(&mut self, now: Instant) {
+        if self.torrent.info.is_private() {
+            return;
+        }
+        // The peers we dialed accept connections, the ones which connected to us are listed on the port they told us if any
+        let connected: HashMap<PeerAddress, (PeerAddress, u8)> = self.peers.iter().filter_map(|(address, peer_state)| {
+            let seed_flag = if peer_state.upload_only || peer_state.bitfield.is_complete() { peer::PEX_FLAG_SEED } else { 0 };
+            if peer_state.incoming {
+                peer_state.listen_port.map(|port| (address.clone(), (PeerAddress { address: address.address, port }, seed_flag)))
+            } else {
+                Some((address.clone(), (address.clone(), peer::PEX_FLAG_REACHABLE | seed_flag)))
+            }
+        }).collect();
+        let listed: HashSet<&PeerAddress> = connected.values().map(|(listed_address, _)| listed_address).collect();
+        for (address, peer_state) in self.peers.iter_mut() {
+            if peer_state.last_pex_sent.is_some_and(|last_pex_sent| now.saturating_duration_since(last_pex_sent) < PEX_INTERVAL) {
+                continue;
+            }
+            let pex = PexMessage {
+                added: connected.iter()
+                    .filter(|(other, (listed_address, _))| *other != address && !peer_state.pex_sent.contains(listed_address))
+                    .map(|(_, (listed_address, flags))| (listed_address.clone(), *flags))
+                    .take(peer::MAX_PEX_PEERS)
+                    .collect(),
+                dropped: peer_state.pex_sent.iter()
+                    .filter(|other| !listed.contains(other))
+                    .take(peer::MAX_PEX_PEERS)
+                    .cloned()
+                    .collect()
+            };
+            if pex.is_empty() {
+                continue;
+            }
+            let Some(message) = peer_state.extensions.message(peer::PEX_EXTENSION, pex.get_bytes()) else {
+                continue;
+            };
+            peer_state.send(message);
+            peer_state.last_pex_sent = Some(now);
+            peer_state.pex_sent.extend(pex.added.into_iter().map(|(other, _)| other));
+            for other in &pex.dropped {
+                peer_state.pex_sent.remove(other);
+            }
+        }
+    }
+
+    // The piece is only announced to the peers once it is written, see TorrentEvent::PieceWritten
+    fn piece_completed(&mut self, index: usize, piece_data: Vec<u8>) {
+        let expected_piece_hash = &self.torrent.info.pieces[index * 20..(index + 1) * 20];
+        let computed_piece_hash = hash::compute_hash(&piece_data);
+        if expected_piece_hash != computed_piece_hash.as_slice() {
+            println!("Piece {} does not match its hash {}, downloading it again", index, format::format_as_hex_string(expected_piece_hash));
+            self.piece_picker.failed(index);
+            return;
+        }
+        let (download_mode, output_file_path, events_sender) = (self.download_mode, self.output_file_path.clone(), self.events_sender.clone());
+        let begin_in_file = self.torrent.info.piece_length * index;
+        tokio::spawn(async move {
+            let write = move || if download_mode == DownloadMode::File {
+                file::write_piece_to(&output_file_path, begin_in_file, piece_data.as_slice())
+            } else {
+                File::create(&output_file_path).and_then(|mut file| file.write_all(piece_data.as_slice())).map_err(anyhow::Error::from)
+            };
+            let result = match tokio::task::spawn_blocking(write).await {
+                Ok(result) => result,
+                Err(error) => Err(error.into())
+            };
+            let _ = events_sender.send(TorrentEvent::PieceWritten { index, result }).await;
+        });
+    }
+
+    // In the endgame the same block is requested from several peers, the other requests are cancelled as soon as it arrives
+    fn cancel_elsewhere(&mut self, address: &PeerAddress, block: &BlockRequest) {
+        for (other_address, peer_state) in self.peers.iter_mut() {
+            if other_address != address && peer_state.pipeline.requests().any(|request| request == block) {
+                peer_state.send(PeerMessage::Cancel { index: block.index, begin: block.begin, length: block.length });
+                peer_state.pipeline.remove(block);
+            }
+        }
+    }
+
+    // Updates our interest and fills the request pipeline
+    fn update_peer(&mut self, address: &PeerAddress) {
+        let Some(peer_state) = self.peers.get_mut(address) else {
+            return;
+        };
+        let interesting = self.piece_picker.is_interesting(&peer_state.bitfield);
+        let interested = peer_state.connection_state.interested == PeerInterestedState::Interested;
+        if !interested && interesting {
+            peer_state.send(PeerMessage::Interested);
+            peer_state.connection_state = peer_state.connection_state.update_interested(PeerInterestedState::Interested);
+        } else if interested && !interesting && peer_state.pipeline.is_empty() {
+            peer_state.send(PeerMessage::NotInterested);
+            peer_state.connection_state = peer_state.connection_state.update_interested(PeerInterestedState::NotInterested);
+        } else if interested {
+            // A choking peer only lets us download its allowed fast pieces
+            let pieces = if peer_state.connection_state.choked == PeerChokedState::Unchoked {
+                peer_state.bitfield.clone()
+            } else {
+                peer_state.bitfield.only(peer_state.allowed_fast.iter().copied())
+            };
+            let free_slots = peer_state.pipeline.free_slots();
+            peer_state.suggested.retain(|index| !self.our_pieces.has(*index));
+            let mut next_blocks_to_ask = self.piece_picker.pick_blocks(&peer_state.peer, &pieces.only(peer_state.suggested.iter().copied()), free_slots, &mut self.rng);
+            next_blocks_to_ask.extend(self.piece_picker.pick_blocks(&peer_state.peer, &pieces, free_slots - next_blocks_to_ask.len(), &mut self.rng));
+            for block in next_blocks_to_ask {
+                peer_state.send(PeerMessage::Request { index: block.index, begin: block.begin, length: block.length });
+                peer_state.pipeline.sent(block, Instant::now());
+            }
+        }
+    }
+
+    // Returns the timed out requests to the pool and drops the peers which snub us or keep us choked
+    fn check_peers(&mut self, now: Instant) {
+        let addresses: Vec<PeerAddress> = self.peers.keys().cloned().collect();
+        for address in addresses {
+            let peer_state = self.peers.get_mut(&address).unwrap();
+            for block in peer_state.pipeline.take_timed_out(now) {
+                println!("Request {:?} to peer {:?} timed out", &block, peer_state.id());
+                peer_state.send(PeerMessage::Cancel { index: block.index, begin: block.begin, length: block.length });
+                self.piece_picker.release_block(&peer_state.peer, &block);
+            }
+            if peer_state.pipeline.check_snubbed(now) {
+                peer_state.snub_count += 1;
+                if peer_state.snub_count > MAX_SNUB_COUNT {
+                    self.drop_peer(&address, "Peer did not send the requested blocks for too long".to_string());
+                    continue;
+                }
+                println!("Peer {:?} is snubbing us, requesting its blocks from the other peers", peer_state.id());
+                for block in peer_state.pipeline.requests() {
+                    peer_state.send(PeerMessage::Cancel { index: block.index, begin: block.begin, length: block.length });
+                }
+                self.piece_picker.release_blocks(&peer_state.peer);
+                peer_state.pipeline.clear();
+            }
+            let interesting = self.piece_picker.is_interesting(&peer_state.bitfield);
+            if interesting && peer_state.choked_since.is_some_and(|choked_since| now.saturating_duration_since(choked_since) >= CHOKED_PEER_TIMEOUT) {
+                self.drop_peer(&address, format!("Peer kept us choked for {:?}", CHOKED_PEER_TIMEOUT));
+                continue;
+            }
+            self.update_peer(&address);
+        }
+    }
+
+    fn drop_peer(&mut self, address: &PeerAddress, reason: String) {
+        if let Some(peer_state) = self.peers.get(address) {
+            let _ = peer_state.commands.send(PeerCommand::Disconnect(reason.clone()));
+        }
+        self.remove_peer(address, &reason);
+    }
+
+    // Another peer can download the blocks which were in progress
+    fn remove_peer(&mut self, address: &PeerAddress, reason: &str) {
+        if let Some(peer_state) = self.peers.remove(address) {
+            println!("Disconnected from peer {}: {}", peer_state.id(), reason);
+            self.piece_picker.release_blocks(&peer_state.peer);
+            self.piece_picker.remove_peer(&peer_state.bitfield);
+            self.peers_dropped_since_announce = true;
+            self.choker.remove_peer(address);
+            self.update_choking(Instant::now());
+        }
+    }
+}
+
+// Only the pieces of the file matching their hashes are seeded
+pub(crate) fn verify_pieces(info: &TorrentInfo, file_path: &str) -> Result<Bitfield, anyhow::Error> {
+    let mut verified_pieces = Bitfield::new(info.total_piece_number());
+    for (piece_index, expected_piece_hash) in info.piece_hashes().into_iter().enumerate() {
+        let piece_length = info.piece_length_at_index(piece_index as u32)? as usize;
+        let verified = match file::read_piece_from(file_path, piece_index * info.piece_length, piece_length) {
+            Ok(piece) => hash::compute_hash(&piece) == expected_piece_hash,
+            Err(_) => false
+        };
+        if verified {
+            verified_pieces.set(piece_index)?;
+        }
+    }
+    Ok(verified_pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use tempfile::NamedTempFile;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::dht::DhtConfig;
+    use crate::peer::{MessageFramer, PeerHandshake};
+    use crate::tracker::ScrapeStatistics;
+    use crate::tracker::server::{TrackerServer, TrackerServerConfig};
+
+    fn torrent_with(content: &[u8], piece_length: usize) -> Torrent {
+        Torrent {
+            // Nothing listens there, so announcing finds no new peers
+            announce: "http://127.0.0.1:1/announce".to_string(),
+            announce_list: None,
+            nodes: None,
+            info: TorrentInfo {
+                name: "content.bin".to_string(),
+                pieces: content.chunks(piece_length).flat_map(|piece| hash::compute_hash(&piece.to_vec())).collect(),
+                piece_length,
+                length: Some(content.len()),
+                files: None,
+                private: None
+            }
+        }
+    }
+
+    async fn udp_tracker_socket() -> Arc<UdpTrackerSocket> {
+        Arc::new(UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap())
+    }
+
+    fn content_file_with(content: &[u8]) -> NamedTempFile {
+        let mut content_file = NamedTempFile::new().unwrap();
+        content_file.write_all(content).unwrap();
+        content_file
+    }
+
+    // A torrent of the content along with the address of a torrent actor which seeds it
+    async fn seeded_torrent(content: &[u8], piece_length: usize) -> (Torrent, NamedTempFile, PeerAddress) {
+        let torrent = torrent_with(content, piece_length);
+        let content_file = content_file_with(content);
+        let seeder_address = start_seeder(&torrent, &content_file).await;
+        (torrent, content_file, seeder_address)
+    }
+
+    // A torrent actor which downloads all the pieces into the returned file
+    fn downloading_actor(torrent: Torrent, udp_tracker_socket: Arc<UdpTrackerSocket>) -> (TorrentActor, NamedTempFile) {
+        let output_file = NamedTempFile::new().unwrap();
+        let output_file_path = output_file.path().to_str().unwrap();
+        file::touch_and_fill_with_zeros(output_file_path, torrent.info.length.unwrap()).unwrap();
+        let all_pieces = torrent.info.get_all_pieces();
+        let torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(ClientConfig::default()), &peer::random_peer_id(), all_pieces, output_file_path, DownloadMode::File, udp_tracker_socket);
+        (torrent_actor, output_file)
+    }
+
+    async fn start_seeder(torrent: &Torrent, content_file: &NamedTempFile) -> PeerAddress {
+        start_seeder_with(torrent, content_file, ClientConfig::default()).await
+    }
+
+    async fn start_seeder_with(torrent: &Torrent, content_file: &NamedTempFile, config: ClientConfig) -> PeerAddress {
+        let content_file_path = content_file.path().to_str().unwrap();
+        let verified_pieces = verify_pieces(&torrent.info, content_file_path).unwrap();
+        let torrent_actor = TorrentActor::new(Arc::new(torrent.clone()), Arc::new(config), &peer::random_peer_id(), Vec::new(), content_file_path, DownloadMode::File, udp_tracker_socket().await)
+            .seeding(verified_pieces);
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let acceptor = PeerAcceptor::default();
+        acceptor.add_torrent(&torrent_actor);
+        acceptor.serve(tcp_listener);
+        tokio::spawn(torrent_actor.run(Vec::new()));
+        PeerAddress { address: address.ip(), port: address.port() }
+    }
+
+    // The blocks are never read since nothing handles the events of the torrent actor, so the requests stay queued
+    fn send_requests_beyond_queue_length(torrent_actor: &mut TorrentActor, address: &PeerAddress, fast: bool) -> mpsc::UnboundedReceiver<PeerCommand> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        torrent_actor.handle_event(TorrentEvent::PeerConnected { address: address.clone(), peer: Peer { id: vec![address.port as u8; 20] }, capabilities: Capabilities { fast, ..Capabilities::default() }, incoming: true, commands }).unwrap();
+        let peer_state = torrent_actor.peers.get_mut(address).unwrap();
+        peer_state.connection_state = peer_state.connection_state.update_peer_choked(PeerChokedState::Unchoked);
+        for begin in 0..=ADVERTISED_REQUEST_QUEUE_LENGTH as u32 {
+            torrent_actor.handle_event(TorrentEvent::PeerMessage { address: address.clone(), message: PeerMessage::Request { index: 0, begin: begin % 28, length: 4 } }).unwrap();
+        }
+        receiver
+    }
+
+    // A peer connecting to a seeding torrent actor
+    struct TestPeer {
+        stream: TcpStream,
+        framer: MessageFramer
+    }
+
+    impl TestPeer {
+        async fn connect(address: &PeerAddress, info_hash: &[u8], capabilities: Capabilities) -> TestPeer {
+            let mut stream = TcpStream::connect((address.address, address.port)).await.unwrap();
+            let handshake = PeerHandshake { info_hash: info_hash.to_vec(), peer: Peer { id: peer::random_peer_id().into_bytes() }, capabilities };
+            stream.write_all(&handshake.get_bytes()).await.unwrap();
+            PeerHandshake::read_from_async(&mut stream, info_hash).await.unwrap();
+            TestPeer { stream, framer: MessageFramer::new(peer::DEFAULT_MAX_MESSAGE_LENGTH) }
+        }
+
+        // The messages are written at once, so the torrent actor handles them without waiting for each other
+        async fn send(&mut self, messages: &[PeerMessage]) {
+            let bytes: Vec<u8> = messages.iter().flat_map(|message| message.get_bytes()).collect();
+            self.stream.write_all(&bytes).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> PeerMessage {
+            loop {
+                match self.framer.next_message().unwrap() {
+                    Some(PeerMessage::KeepAlive) => {},
+                    Some(message) => return message,
+                    None => {
+                        let mut buffer = [0; 16 * 1024];
+                        let read_bytes = tokio::time::timeout(Duration::from_secs(10), self.stream.read(&mut buffer)).await.unwrap().unwrap();
+                        assert!(read_bytes > 0, "The torrent actor closed the connection");
+                        self.framer.push(&buffer[..read_bytes]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_download_from_several_peers() {
+        let content: Vec<u8> = (0..200_000u32).map(|value| (value % 251) as u8).collect();
+        let (torrent, content_file, seeder_address) = seeded_torrent(&content, 32 * 1024).await;
+        let peer_addresses = vec![seeder_address, start_seeder(&torrent, &content_file).await];
+
+        let (torrent_actor, output_file) = downloading_actor(torrent, udp_tracker_socket().await);
+        tokio::time::timeout(Duration::from_secs(30), torrent_actor.run(peer_addresses)).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(output_file.path()).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn should_tell_trackers_about_completed_download_when_leaving() {
+        let content: Vec<u8> = (0..50_000u32).map(|value| (value % 239) as u8).collect();
+        let (mut torrent, _content_file, seeder_address) = seeded_torrent(&content, 16 * 1024).await;
+        let tracker_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        torrent.announce = format!("http://{}/announce", tracker_listener.local_addr().unwrap());
+        TrackerServer::new(TrackerServerConfig::default()).serve_http(tracker_listener);
+        let tracker = Tracker { url: torrent.announce.clone() };
+        let info_hash = torrent.info.compute_hash();
+
+        let udp_tracker_socket = udp_tracker_socket().await;
+        let (torrent_actor, _output_file) = downloading_actor(torrent, Arc::clone(&udp_tracker_socket));
+        tokio::time::timeout(Duration::from_secs(30), torrent_actor.run(vec![seeder_address])).await.unwrap().unwrap();
+        // Our download is counted and we left the swarm
+        let statistics = tracker.scrape(&info_hash, &udp_tracker_socket).await.unwrap();
+        assert_eq!(statistics, ScrapeStatistics { seeders: 0, completed: 1, leechers: 0 });
+    }
+
+    #[tokio::test]
+    async fn should_download_from_peers_found_in_dht() {
+        let content: Vec<u8> = (0..100_000u32).map(|value| (value % 253) as u8).collect();
+        let (torrent, _content_file, seeder_address) = seeded_torrent(&content, 32 * 1024).await;
+
+        let info_hash = NodeId::from_bytes(&torrent.info.compute_hash()).unwrap();
+        let loopback_config = || DhtConfig { addresses: vec!["127.0.0.1:0".parse().unwrap()], ..DhtConfig::default() };
+        let bootstrap_node = DhtNode::bind(loopback_config()).await.unwrap();
+        let bootstrap_nodes = vec![bootstrap_node.local_addrs()[0].to_string()];
+        let seeder_node = DhtNode::bind(loopback_config()).await.unwrap();
+        seeder_node.bootstrap(&bootstrap_nodes).await;
+        seeder_node.announce(&info_hash, seeder_address.port).await;
+        let dht = DhtNode::bind(loopback_config()).await.unwrap();
+        dht.bootstrap(&bootstrap_nodes).await;
+
+        let (torrent_actor, output_file) = downloading_actor(torrent, udp_tracker_socket().await);
+        // The trackers know no peers, so they all come from the DHT
+        tokio::time::timeout(Duration::from_secs(30), torrent_actor.with_dht(Some(dht)).run(Vec::new())).await.unwrap().unwrap();
+        assert_eq!(std::fs::read(output_file.path()).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn should_fail_when_no_peers_are_left() {
+        let torrent = torrent_with(&[1, 2, 3], 16);
+        let unreachable_peer = {
+            let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = tcp_listener.local_addr().unwrap();
+            PeerAddress { address: address.ip(), port: address.port() }
+        };
+        let output_file = NamedTempFile::new().unwrap();
+        let all_pieces = torrent.info.get_all_pieces();
+        let config = ClientConfig { peer_connect_attempts: 1, ..ClientConfig::default() };
+        let torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(config), &peer::random_peer_id(), all_pieces, output_file.path().to_str().unwrap(), DownloadMode::Piece, udp_tracker_socket().await);
+        let result = tokio::time::timeout(Duration::from_secs(30), torrent_actor.run(vec![unreachable_peer])).await.unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_cancel_block_at_other_peers_once_received() {
+        let content: Vec<u8> = (0..16 * 1024u32).map(|value| (value % 241) as u8).collect();
+        let (mut torrent_actor, _output_file) = downloading_actor(torrent_with(&content, 16 * 1024), udp_tracker_socket().await);
+
+        // The single block is requested from both peers in the endgame
+        let mut peer_commands = Vec::new();
+        for port in [6881, 6882] {
+            let address = PeerAddress { address: "127.0.0.1".parse().unwrap(), port };
+            let (commands, receiver) = mpsc::unbounded_channel();
+            torrent_actor.handle_event(TorrentEvent::PeerConnected { address: address.clone(), peer: Peer { id: vec![port as u8; 20] }, capabilities: Capabilities::default(), incoming: false, commands }).unwrap();
+            for message in [PeerMessage::Bitfield { bitfield: vec![0b10000000] }, PeerMessage::Unchoke] {
+                torrent_actor.handle_event(TorrentEvent::PeerMessage { address: address.clone(), message }).unwrap();
+            }
+            peer_commands.push((address, receiver));
+        }
+        let block = BlockRequest { index: 0, begin: 0, length: 16 * 1024 };
+        assert!(torrent_actor.peers.values().all(|peer_state| peer_state.pipeline.requests().any(|request| *request == block)));
+
+        let (first_address, _) = &peer_commands[0];
+        torrent_actor.handle_event(TorrentEvent::PeerMessage { address: first_address.clone(), message: PeerMessage::Piece { index: 0, begin: 0, block: content.clone() } }).unwrap();
+        let (second_address, second_commands) = &mut peer_commands[1];
+        let mut sent = Vec::new();
+        while let Ok(PeerCommand::Send(message)) = second_commands.try_recv() {
+            sent.push(message);
+        }
+        assert!(sent.contains(&PeerMessage::Cancel { index: 0, begin: 0, length: 16 * 1024 }));
+        assert!(torrent_actor.peers[second_address].pipeline.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_verify_pieces_on_disk() {
+        let content: Vec<u8> = (0..100u8).collect();
+        let torrent = torrent_with(&content, 32);
+        let content_file = content_file_with(&content);
+        let content_file_path = content_file.path().to_str().unwrap();
+        let verified_pieces = verify_pieces(&torrent.info, content_file_path).unwrap();
+        assert!(verified_pieces.is_complete());
+        assert_eq!(verified_pieces.as_bytes(), &[0b11110000]);
+
+        file::write_piece_to(content_file_path, 40, &[0, 0]).unwrap();
+        let verified_pieces = verify_pieces(&torrent.info, content_file_path).unwrap();
+        assert_eq!(verified_pieces.piece_indices(), vec![0, 2, 3]);
+        let torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(ClientConfig::default()), &peer::random_peer_id(), Vec::new(), content_file_path, DownloadMode::File, udp_tracker_socket().await)
+            .seeding(verified_pieces);
+        assert_eq!(torrent_actor.tracker_request().left, 32);
+    }
+
+    #[tokio::test]
+    async fn should_upload_requested_blocks_to_interested_peer() {
+        let content: Vec<u8> = (0..100u8).collect();
+        let torrent = torrent_with(&content, 32);
+        let content_file = content_file_with(&content);
+        let info_hash = torrent.info.compute_hash();
+        let seeder_address = start_seeder_with(&torrent, &content_file, ClientConfig { upload_slots: 1, ..ClientConfig::default() }).await;
+
+        let mut test_peer = TestPeer::connect(&seeder_address, &info_hash, Capabilities::default()).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::Bitfield { bitfield: vec![0b11110000] });
+        test_peer.send(&[PeerMessage::Interested]).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::Unchoke);
+
+        // The only upload slot is taken
+        let mut other_test_peer = TestPeer::connect(&seeder_address, &info_hash, Capabilities::default()).await;
+        other_test_peer.receive().await;
+        other_test_peer.send(&[PeerMessage::Interested]).await;
+
+        test_peer.send(&[
+            PeerMessage::Request { index: 1, begin: 8, length: 4 },
+            PeerMessage::Request { index: 2, begin: 0, length: 4 },
+            PeerMessage::Cancel { index: 2, begin: 0, length: 4 },
+            PeerMessage::Request { index: 3, begin: 2, length: 2 }
+        ]).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::Piece { index: 1, begin: 8, block: vec![40, 41, 42, 43] });
+        assert_eq!(test_peer.receive().await, PeerMessage::Piece { index: 3, begin: 2, block: vec![98, 99] });
+        test_peer.send(&[PeerMessage::NotInterested]).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::Choke);
+        assert_eq!(other_test_peer.receive().await, PeerMessage::Unchoke);
+    }
+
+    #[tokio::test]
+    async fn should_tell_connecting_peers_that_we_only_upload() {
+        let content: Vec<u8> = (0..100u8).collect();
+        let (torrent, _content_file, seeder_address) = seeded_torrent(&content, 32).await;
+        let info_hash = torrent.info.compute_hash();
+
+        let mut test_peer = TestPeer::connect(&seeder_address, &info_hash, Capabilities { extension_protocol: true, ..Capabilities::default() }).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::Bitfield { bitfield: vec![0b11110000] });
+        let PeerMessage::Extended { extended_id: peer::EXTENSION_HANDSHAKE_ID, payload } = test_peer.receive().await else {
+            panic!("Expected the extension handshake");
+        };
+        let handshake = ExtensionHandshake::parse(&payload).unwrap();
+        assert_eq!(handshake.extensions.get(peer::UPLOAD_ONLY_EXTENSION), Some(&1));
+        assert_eq!(handshake.your_ip, Some(seeder_address.address));
+        assert_eq!(handshake.metadata_size, Some(torrent.info.bencode().len()));
+
+        let mut extensions = ExtensionRegistry::default();
+        extensions.register(peer::PEX_EXTENSION);
+        extensions.register(peer::UPLOAD_ONLY_EXTENSION);
+        test_peer.send(&[PeerMessage::Extended { extended_id: peer::EXTENSION_HANDSHAKE_ID, payload: extensions.handshake().get_bytes() }]).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::Extended { extended_id: 2, payload: vec![1] });
+    }
+
+    #[tokio::test]
+    async fn should_serve_allowed_fast_pieces_to_choked_peer_and_reject_other_requests() {
+        let content: Vec<u8> = (0..=255u8).collect();
+        let (torrent, _content_file, seeder_address) = seeded_torrent(&content, 8).await;
+        let info_hash = torrent.info.compute_hash();
+
+        let mut test_peer = TestPeer::connect(&seeder_address, &info_hash, Capabilities { fast: true, ..Capabilities::default() }).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::HaveAll);
+        let mut allowed_fast = Vec::new();
+        for _ in 0..peer::ALLOWED_FAST_SET_SIZE {
+            match test_peer.receive().await {
+                PeerMessage::AllowedFast { index } => allowed_fast.push(index),
+                message => panic!("Expected 'allowed fast', got {:?}", message)
+            }
+        }
+        assert_eq!(allowed_fast, peer::allowed_fast_set(&seeder_address.address, &info_hash, 32, peer::ALLOWED_FAST_SET_SIZE));
+
+        let not_allowed = (0..32).find(|index| !allowed_fast.contains(index)).unwrap();
+        test_peer.send(&[PeerMessage::Request { index: not_allowed, begin: 0, length: 8 }]).await;
+        assert_eq!(test_peer.receive().await, PeerMessage::RejectRequest { index: not_allowed, begin: 0, length: 8 });
+        test_peer.send(&[PeerMessage::Request { index: allowed_fast[0], begin: 4, length: 4 }]).await;
+        let begin = allowed_fast[0] as u8 * 8 + 4;
+        assert_eq!(test_peer.receive().await, PeerMessage::Piece { index: allowed_fast[0], begin: 4, block: vec![begin, begin + 1, begin + 2, begin + 3] });
+    }
+
+    #[tokio::test]
+    async fn should_reject_requests_beyond_the_advertised_queue_length() {
+        let content: Vec<u8> = (0..100u8).collect();
+        let torrent = torrent_with(&content, 32);
+        let content_file = content_file_with(&content);
+        let content_file_path = content_file.path().to_str().unwrap();
+        let verified_pieces = verify_pieces(&torrent.info, content_file_path).unwrap();
+        let mut torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(ClientConfig::default()), &peer::random_peer_id(), Vec::new(), content_file_path, DownloadMode::File, udp_tracker_socket().await)
+            .seeding(verified_pieces);
+
+        let fast_address = PeerAddress { address: "127.0.0.1".parse().unwrap(), port: 6881 };
+        let mut fast_commands = send_requests_beyond_queue_length(&mut torrent_actor, &fast_address, true);
+        let mut sent = Vec::new();
+        while let Ok(PeerCommand::Send(message)) = fast_commands.try_recv() {
+            sent.push(message);
+        }
+        let last_begin = ADVERTISED_REQUEST_QUEUE_LENGTH as u32 % 28;
+        assert_eq!(sent.last(), Some(&PeerMessage::RejectRequest { index: 0, begin: last_begin, length: 4 }));
+        assert_eq!(torrent_actor.peers[&fast_address].upload_queue.len(), ADVERTISED_REQUEST_QUEUE_LENGTH);
+
+        let slow_address = PeerAddress { address: "127.0.0.1".parse().unwrap(), port: 6882 };
+        send_requests_beyond_queue_length(&mut torrent_actor, &slow_address, false);
+        assert!(!torrent_actor.peers.contains_key(&slow_address));
+    }
+
+    #[tokio::test]
+    async fn should_serve_metadata_to_peers_which_only_know_the_info_hash() {
+        let content: Vec<u8> = (0..100u8).collect();
+        let (torrent, _content_file, seeder_address) = seeded_torrent(&content, 32).await;
+
+        let address = SocketAddr::new(seeder_address.address, seeder_address.port);
+        let metadata = peer::fetch_metadata(address, &torrent.info.compute_hash(), &[b'1'; 20]).await.unwrap();
+        assert_eq!(TorrentInfo::from_bytes(&metadata).unwrap(), torrent.info);
+    }
+}
@@ -0,0 +1,86 @@
+// Accepts the connections of other peers and hands each of them to the torrent actor of the torrent it asks for
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use crate::error::new_error;
+use crate::format;
+use crate::peer::{self, PeerAddress, PeerHandshake};
+use super::{TorrentActor, TorrentEvent};
+use super::peer_task::{self, PeerTaskConfig};
+
+// The torrent actor which gets the peers connecting for one info hash
+struct AcceptingTorrent {
+    config: PeerTaskConfig,
+    events: mpsc::Sender<TorrentEvent>
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct PeerAcceptor {
+    torrents: Arc<Mutex<HashMap<Vec<u8>, AcceptingTorrent>>>
+}
+
+impl PeerAcceptor {
+    pub(crate) fn add_torrent(&self, torrent_actor: &TorrentActor) {
+        self.torrents.lock().unwrap().insert(torrent_actor.peer_task_config.info_hash.clone(), AcceptingTorrent {
+            config: torrent_actor.peer_task_config.clone(),
+            events: torrent_actor.events_sender.clone()
+        });
+    }
+
+    pub(crate) fn serve(&self, listener: TcpListener) -> JoinHandle<()> {
+        let acceptor = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, address)) => {
+                        let acceptor = acceptor.clone();
+                        tokio::spawn(async move {
+                            if let Err(error) = acceptor.handle_connection(stream, address).await {
+                                println!("Closed connection from peer {}: {}", address, error);
+                            }
+                        });
+                    },
+                    Err(error) => println!("Failed to accept peer connection: {}", error)
+                }
+            }
+        })
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream, address: SocketAddr) -> Result<(), anyhow::Error> {
+        let handshake = time::timeout(peer::HANDSHAKE_TIMEOUT, PeerHandshake::read_async(&mut stream)).await??;
+        let (config, events) = {
+            let mut torrents = self.torrents.lock().unwrap();
+            // The torrent actors which finished do not take peers anymore
+            torrents.retain(|_, torrent| !torrent.events.is_closed());
+            let torrent = torrents.get(&handshake.info_hash)
+                .ok_or(new_error(format!("Not serving torrent {}", format::format_as_hex_string(&handshake.info_hash))))?;
+            (torrent.config.clone(), torrent.events.clone())
+        };
+        println!("Accepted connection from peer {}", format::format_as_hex_string(&handshake.peer.id));
+        peer_task::run_accepted_peer(stream, PeerAddress { address: address.ip(), port: address.port() }, handshake, config, events).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use crate::peer::{Capabilities, Peer};
+
+    #[tokio::test]
+    async fn should_refuse_unknown_torrents() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        PeerAcceptor::default().serve(listener);
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let handshake = PeerHandshake { info_hash: vec![9; 20], peer: Peer { id: vec![b'1'; 20] }, capabilities: Capabilities::default() };
+        stream.write_all(&handshake.get_bytes()).await.unwrap();
+        assert!(PeerHandshake::read_from_async(&mut stream, &[9; 20]).await.is_err());
+    }
+}
@@ -0,0 +1,230 @@
+// Decides which peers of a torrent to connect to, within the per-torrent and the global connection limits
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::peer::PeerAddress;
+
+// Backoff before retrying a peer after its first failure, it doubles with every further failure
+const RETRY_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(600);
+
+// Number of peer connections shared by all the torrents of the client
+pub(crate) struct ConnectionLimit {
+    max_connections: usize,
+    used_connections: AtomicUsize
+}
+
+impl ConnectionLimit {
+    pub(crate) fn new(max_connections: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            max_connections,
+            used_connections: AtomicUsize::new(0)
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.used_connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+            if used < self.max_connections { Some(used + 1) } else { None }
+        }).is_ok()
+    }
+
+    fn release(&self) {
+        let _ = self.used_connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| used.checked_sub(1));
+    }
+}
+
+// Peer which we may connect to
+struct Candidate {
+    failures: u32,
+    next_attempt: Instant
+}
+
+pub(crate) struct ConnectionManager {
+    candidates: HashMap<PeerAddress, Candidate>,
+    // Both the connecting and the connected peers take a connection slot, the connecting ones with their earlier failures
+    connecting: HashMap<PeerAddress, u32>,
+    connected: HashSet<PeerAddress>,
+    // Peers which connected to us, they are not dialed once they leave as they may not accept connections
+    accepted: HashSet<PeerAddress>,
+    max_connections: usize,
+    max_attempts: u32,
+    global_limit: Arc<ConnectionLimit>
+}
+
+impl ConnectionManager {
+    pub(crate) fn new(max_connections: usize, max_attempts: u32, global_limit: Arc<ConnectionLimit>) -> ConnectionManager {
+        ConnectionManager {
+            candidates: HashMap::new(),
+            connecting: HashMap::new(),
+            connected: HashSet::new(),
+            accepted: HashSet::new(),
+            max_connections,
+            max_attempts: max_attempts.max(1),
+            global_limit
+        }
+    }
+
+    // Returns how many of the addresses were not known yet
+    pub(crate) fn add_candidates(&mut self, addresses: Vec<PeerAddress>, now: Instant) -> usize {
+        let mut new_candidate_count = 0;
+        for address in addresses {
+            if self.connecting.contains_key(&address) || self.connected.contains(&address) || self.candidates.contains_key(&address) {
+                continue;
+            }
+            self.candidates.insert(address, Candidate { failures: 0, next_attempt: now });
+            new_candidate_count += 1;
+        }
+        new_candidate_count
+    }
+
+    // Takes the free connection slots and returns the peers to connect to
+    pub(crate) fn next_to_dial(&mut self, now: Instant) -> Vec<PeerAddress> {
+        let mut ready: Vec<(PeerAddress, u32)> = self.candidates.iter()
+            .filter(|(_, candidate)| candidate.next_attempt <= now)
+            .map(|(address, candidate)| (address.clone(), candidate.failures))
+            .collect();
+        // The peers which never failed are tried first
+        ready.sort_by_key(|(_, failures)| *failures);
+        let mut to_dial = Vec::new();
+        for (address, failures) in ready {
+            if self.connection_count() >= self.max_connections || !self.global_limit.try_acquire() {
+                break;
+            }
+            self.candidates.remove(&address);
+            self.connecting.insert(address.clone(), failures);
+            to_dial.push(address);
+        }
+        to_dial
+    }
+
+    pub(crate) fn connected(&mut self, address: &PeerAddress) {
+        if self.connecting.remove(address).is_some() {
+            self.connected.insert(address.clone());
+        }
+    }
+
+    // Returns whether there is a free connection slot for the peer which connected to us
+    pub(crate) fn accepted(&mut self, address: &PeerAddress) -> bool {
+        if self.accepted.contains(address) || self.connection_count() >= self.max_connections || !self.global_limit.try_acquire() {
+            return false;
+        }
+        self.accepted.insert(address.clone());
+        true
+    }
+
+    // Frees the slot of the peer, it is retried after a backoff unless it failed to connect too many times
+    pub(crate) fn disconnected(&mut self, address: &PeerAddress, now: Instant) {
+        let failures = match self.connecting.remove(address) {
+            Some(failures) => failures + 1,
+            // A peer which dropped after connecting is retried as if it failed once
+            None if self.connected.remove(address) => 1,
+            None if self.accepted.remove(address) => {
+                self.global_limit.release();
+                return;
+            },
+            None => return
+        };
+        self.global_limit.release();
+        if failures < self.max_attempts {
+            let backoff = RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(failures - 1)).min(MAX_RETRY_BACKOFF);
+            self.candidates.insert(address.clone(), Candidate { failures, next_attempt: now + backoff });
+        }
+    }
+
+    pub(crate) fn is_connecting(&self, address: &PeerAddress) -> bool {
+        self.connecting.contains_key(address)
+    }
+
+    pub(crate) fn connection_count(&self) -> usize {
+        self.connecting.len() + self.connected.len() + self.accepted.len()
+    }
+
+    // No peer is connected and there is no one left to connect to
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.connection_count() == 0 && self.candidates.is_empty()
+    }
+}
+
+impl Drop for ConnectionManager {
+    fn drop(&mut self) {
+        for _ in 0..self.connection_count() {
+            self.global_limit.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> PeerAddress {
+        PeerAddress { address: "127.0.0.1".parse().unwrap(), port }
+    }
+
+    #[test]
+    fn should_dial_deduplicated_candidates_up_to_the_limits() {
+        let now = Instant::now();
+        let global_limit = Arc::new(ConnectionLimit::new(3));
+        let mut connections = ConnectionManager::new(2, 3, Arc::clone(&global_limit));
+        assert_eq!(connections.add_candidates(vec![address(1), address(2), address(1), address(3)], now), 3);
+        let dialed = connections.next_to_dial(now);
+        assert_eq!(dialed.len(), 2);
+        assert!(connections.next_to_dial(now).is_empty());
+        assert_eq!(connections.add_candidates(vec![address(1), address(2), address(3)], now), 0);
+
+        // The other torrent only gets the last global slot
+        let mut other_connections = ConnectionManager::new(2, 3, Arc::clone(&global_limit));
+        other_connections.add_candidates(vec![address(4), address(5)], now);
+        assert_eq!(other_connections.next_to_dial(now).len(), 1);
+        drop(other_connections);
+
+        // A dropped connection frees a slot for the remaining candidate
+        connections.connected(&dialed[0]);
+        connections.disconnected(&dialed[0], now);
+        assert_eq!(connections.connection_count(), 1);
+        let redialed = connections.next_to_dial(now);
+        assert_eq!(redialed.len(), 1);
+        assert!(!dialed.contains(&redialed[0]));
+    }
+
+    #[test]
+    fn should_retry_failed_peers_with_backoff() {
+        let now = Instant::now();
+        let mut connections = ConnectionManager::new(10, 3, Arc::new(ConnectionLimit::new(10)));
+        connections.add_candidates(vec![address(1)], now);
+        assert_eq!(connections.next_to_dial(now), vec![address(1)]);
+        connections.disconnected(&address(1), now);
+        assert!(connections.next_to_dial(now + RETRY_BACKOFF - Duration::from_secs(1)).is_empty());
+
+        let now = now + RETRY_BACKOFF;
+        assert_eq!(connections.next_to_dial(now), vec![address(1)]);
+        connections.disconnected(&address(1), now);
+        assert!(connections.next_to_dial(now + RETRY_BACKOFF).is_empty());
+
+        let now = now + 2 * RETRY_BACKOFF;
+        assert_eq!(connections.next_to_dial(now), vec![address(1)]);
+        connections.disconnected(&address(1), now);
+        assert!(connections.is_exhausted());
+    }
+
+    #[test]
+    fn should_accept_peers_within_the_limits_without_dialing_them_later() {
+        let now = Instant::now();
+        let global_limit = Arc::new(ConnectionLimit::new(10));
+        let mut connections = ConnectionManager::new(2, 3, Arc::clone(&global_limit));
+        connections.add_candidates(vec![address(1)], now);
+        assert_eq!(connections.next_to_dial(now), vec![address(1)]);
+        assert!(connections.accepted(&address(2)));
+        assert!(!connections.accepted(&address(3)));
+        assert_eq!(connections.connection_count(), 2);
+
+        connections.disconnected(&address(2), now);
+        assert_eq!(connections.connection_count(), 1);
+        assert!(connections.next_to_dial(now + MAX_RETRY_BACKOFF).is_empty());
+        assert!(connections.accepted(&address(3)));
+        drop(connections);
+        assert!(global_limit.try_acquire());
+        assert_eq!(global_limit.used_connections.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,177 @@
+// Task which owns the connection to one peer: it forwards the received messages to the torrent actor and sends the ones it is told to
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use crate::error::new_error;
+use crate::peer::{self, Capabilities, MessageFramer, Peer, PeerAddress, PeerHandshake, PeerMessage};
+use super::{PeerCommand, TorrentEvent};
+
+// Unreachable peers are given up on sooner than the ones which accepted the connection, but are slow to handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the peer task needs to know to connect to a peer of the torrent
+#[derive(Clone)]
+pub(crate) struct PeerTaskConfig {
+    pub(crate) info_hash: Vec<u8>,
+    pub(crate) peer_id: Vec<u8>,
+    pub(crate) max_message_length: usize,
+    // Whether we run a DHT node https://www.bittorrent.org/beps/bep_0005.html
+    pub(crate) dht: bool
+}
+
+// Reads whole messages from the peer, cancelling a read loses no data so it can be used in select!
+struct FramedReader {
+    reader: OwnedReadHalf,
+    framer: MessageFramer
+}
+
+impl FramedReader {
+    async fn next_message(&mut self) -> Result<PeerMessage, anyhow::Error> {
+        let mut read_buffer: [u8; 16 * 1024] = [0; 16 * 1024];
+        loop {
+            if let Some(message) = self.framer.next_message()? {
+                return Ok(message);
+            }
+            let read_bytes = self.reader.read(&mut read_buffer).await?;
+            if read_bytes == 0 {
+                return Err(new_error("Peer closed the connection".to_string()));
+            }
+            self.framer.push(&read_buffer[0..read_bytes]);
+        }
+    }
+}
+
+pub(crate) async fn run_peer(address: PeerAddress, config: PeerTaskConfig, events: mpsc::Sender<TorrentEvent>) {
+    let result = dial(&address, &config, &events).await;
+    disconnected(address, result, &events).await;
+}
+
+// Runs the connection which the peer opened to us once we know from its handshake which torrent it wants
+pub(crate) async fn run_accepted_peer(mut stream: TcpStream, address: PeerAddress, handshake: PeerHandshake, config: PeerTaskConfig, events: mpsc::Sender<TorrentEvent>) {
+    let result = match stream.write_all(&our_handshake(&config).get_bytes()).await {
+        Ok(()) => exchange_messages(stream, &address, handshake, true, &config, &events).await,
+        Err(error) => Err(error.into())
+    };
+    disconnected(address, result, &events).await;
+}
+
+fn our_handshake(config: &PeerTaskConfig) -> PeerHandshake {
+    PeerHandshake {
+        info_hash: config.info_hash.clone(),
+        peer: Peer { id: config.peer_id.clone() },
+        capabilities: Capabilities { extension_protocol: true, fast: true, dht: config.dht }
+    }
+}
+
+async fn disconnected(address: PeerAddress, result: Result<(), anyhow::Error>, events: &mpsc::Sender<TorrentEvent>) {
+    let _ = events.send(TorrentEvent::PeerDisconnected {
+        address,
+        reason: result.err().map(|error| error.to_string()).unwrap_or("Connection closed".to_string())
+    }).await;
+}
+
+async fn dial(address: &PeerAddress, config: &PeerTaskConfig, events: &mpsc::Sender<TorrentEvent>) -> Result<(), anyhow::Error> {
+    let mut stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(SocketAddr::new(address.address, address.port))).await??;
+    stream.write_all(&our_handshake(config).get_bytes()).await?;
+    let handshake = time::timeout(peer::HANDSHAKE_TIMEOUT, PeerHandshake::read_from_async(&mut stream, &config.info_hash)).await??;
+    exchange_messages(stream, address, handshake, false, config, events).await
+}
+
+async fn exchange_messages(stream: TcpStream, address: &PeerAddress, handshake: PeerHandshake, incoming: bool, config: &PeerTaskConfig, events: &mpsc::Sender<TorrentEvent>) -> Result<(), anyhow::Error> {
+    let (commands_sender, mut commands) = mpsc::unbounded_channel();
+    events.send(TorrentEvent::PeerConnected { address: address.clone(), peer: handshake.peer, capabilities: handshake.capabilities, incoming, commands: commands_sender }).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = FramedReader { reader, framer: MessageFramer::new(config.max_message_length) };
+    let mut last_sent = Instant::now();
+    let mut last_received = Instant::now();
+    loop {
+        tokio::select! {
+            message = reader.next_message() => {
+                last_received = Instant::now();
+                events.send(TorrentEvent::PeerMessage { address: address.clone(), message: message? }).await?;
+            },
+            command = commands.recv() => match command {
+                Some(PeerCommand::Send(message)) => {
+                    writer.write_all(&message.get_bytes()).await?;
+                    last_sent = Instant::now();
+                    // The torrent actor reads the next requested block only once this one is written
+                    if let PeerMessage::Piece { .. } = message {
+                        events.send(TorrentEvent::BlockSent { address: address.clone() }).await?;
+                    }
+                },
+                Some(PeerCommand::Disconnect(reason)) => return Err(new_error(reason)),
+                // The torrent actor is no longer interested in the peer
+                None => return Ok(())
+            },
+            _ = time::sleep_until(last_sent + peer::KEEP_ALIVE_INTERVAL) => {
+                writer.write_all(&PeerMessage::KeepAlive.get_bytes()).await?;
+                last_sent = Instant::now();
+            },
+            _ = time::sleep_until(last_received + peer::PEER_INACTIVITY_TIMEOUT) => {
+                return Err(new_error(format!("Peer did not send anything for {:?}", peer::PEER_INACTIVITY_TIMEOUT)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Runs a peer task against a test peer listening on a loopback socket, returns the end of the test peer once the handshakes are exchanged
+    async fn start_peer_task() -> (TcpStream, mpsc::Receiver<TorrentEvent>, mpsc::UnboundedSender<PeerCommand>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = PeerTaskConfig { info_hash: vec![7; 20], peer_id: vec![b'1'; 20], max_message_length: peer::DEFAULT_MAX_MESSAGE_LENGTH, dht: false };
+        let (events_sender, mut events) = mpsc::channel(16);
+        tokio::spawn(run_peer(PeerAddress { address: address.ip(), port: address.port() }, config, events_sender));
+        let (mut stream, _) = listener.accept().await.unwrap();
+        PeerHandshake::read_from_async(&mut stream, &[7; 20]).await.unwrap();
+        let handshake = PeerHandshake { info_hash: vec![7; 20], peer: Peer { id: vec![b'2'; 20] }, capabilities: Capabilities::default() };
+        stream.write_all(&handshake.get_bytes()).await.unwrap();
+        let Some(TorrentEvent::PeerConnected { commands, .. }) = events.recv().await else {
+            panic!("Expected the peer to connect");
+        };
+        (stream, events, commands)
+    }
+
+    async fn disconnect_reason(events: &mut mpsc::Receiver<TorrentEvent>) -> String {
+        match events.recv().await {
+            Some(TorrentEvent::PeerDisconnected { reason, .. }) => reason,
+            _ => panic!("Expected the peer to disconnect")
+        }
+    }
+
+    #[tokio::test]
+    async fn should_send_keep_alive_when_nothing_was_sent_for_a_while() {
+        let (mut stream, _events, _commands) = start_peer_task().await;
+        time::pause();
+        let started = Instant::now();
+        let mut message = [0; 4];
+        stream.read_exact(&mut message).await.unwrap();
+        assert_eq!(message, [0, 0, 0, 0]);
+        assert!(started.elapsed() >= peer::KEEP_ALIVE_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_peer_which_stays_silent() {
+        let (_stream, mut events, _commands) = start_peer_task().await;
+        time::pause();
+        let started = Instant::now();
+        assert_eq!(disconnect_reason(&mut events).await, format!("Peer did not send anything for {:?}", peer::PEER_INACTIVITY_TIMEOUT));
+        assert!(started.elapsed() >= peer::PEER_INACTIVITY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn should_disconnect_when_peer_closes_connection() {
+        let (stream, mut events, _commands) = start_peer_task().await;
+        time::pause();
+        drop(stream);
+        assert_eq!(disconnect_reason(&mut events).await, "Peer closed the connection");
+    }
+}
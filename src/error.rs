@@ -1,3 +1,3 @@
 pub(crate) fn new_error(message: String) -> anyhow::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, message).into()
+    std::io::Error::other(message).into()
 }
\ No newline at end of file
@@ -5,6 +5,7 @@ pub(crate) fn touch_and_fill_with_zeros(file_path: &str, desired_length: usize)
     let mut file = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(file_path)?;
     let data: Vec<u8> = vec![0; desired_length];
     file.write_all(&data)?;
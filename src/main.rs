@@ -1,19 +1,16 @@
 use std::env;
-use std::io::Write;
-use std::fs::File;
-use std::net::TcpStream;
-use std::thread;
-use std::time::Duration;
-use std::sync::{Arc, Mutex};
-use std::thread::JoinHandle;
-use std::cmp::min;
-use std::path::Path;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
 use anyhow::Result;
-use peer::{ Peer, PeerChokedState, PeerConnectionState, PeerInterestedState, PeerMessage, PeerMessageId, Piece, PieceBlock };
-use torrent::TorrentInfo;
-use std::collections::HashMap;
+use engine::{DownloadMode, TorrentActor};
+use peer::Piece;
 
 mod bencoded;
+mod bitfield;
+mod piece_picker;
+mod choker;
 mod torrent;
 mod format;
 mod tracker;
@@ -22,28 +19,20 @@ mod peer;
 mod hash;
 mod file;
 mod error;
-
-#[derive(PartialEq)]
-enum DownloadMode {
-    Piece,
-    File
-}
+mod config;
+mod engine;
+mod dht;
 
 // Usage: your_bittorrent.sh decode "<encoded_value>"
-fn main() -> Result<(), anyhow::Error> {
-    //let args: Vec<String> = env::args().collect();
-    //let command = &args[1];
-    let command = "peers";
-    let args: Vec<String> = vec!["", "", "big-buck-bunny.torrent"].iter().map(|x| x.to_string()).collect();
-    //let command = "download_piece";
-    //let args: Vec<String> = vec!["", "", "-o", "/tmp/test-piece-0", "sample.torrent", "0"].iter().map(|x| x.to_string()).collect();
-    //let command = "download";
-    //let args: Vec<String> = vec!["", "", "-o", "/tmp/test.txt", "sample.torrent"].iter().map(|x| x.to_string()).collect();
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args: Vec<String> = env::args().collect();
+    let command = args.get(1).map(|command| command.as_str()).unwrap_or("");
 
     if command == "decode" {
         let encoded_value = &args[2];
-        let decoded_value = bencoded::decode_bencoded_from_str(&encoded_value)?.as_json();
-        println!("{}", decoded_value.to_string());
+        let decoded_value = bencoded::decode_bencoded_from_str(encoded_value)?.as_json();
+        println!("{}", decoded_value);
         Ok(())
     } else if command == "info" {
         let torrent_file_path = &args[2];
@@ -70,23 +59,58 @@ fn main() -> Result<(), anyhow::Error> {
 
         let current_peer_id = peer::random_peer_id();
         let torrent_hash = torrent.info.compute_hash();
-        let port = 6881;
-        let tracker = tracker::Tracker { url: torrent.announce };
+        let config = config::ClientConfig::from_args(&args)?;
+        let timeout = config::find_option(&args, "--timeout").map(|timeout| timeout.parse::<u64>()).transpose()?.map(Duration::from_secs);
+        let udp_socket = Arc::new(tracker::udp::UdpTrackerSocket::bind(config.udp_tracker_address).await?);
 
-        let request = tracker::TrackerRequest {
-            peer_id: current_peer_id,
-            info_hash: url_utils::url_encode_bytes(&torrent_hash),
-            port,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.info.length.unwrap_or(0) as u64,
-            compact: true
-        };
-        let response = tracker.get(&tracker.url, &request)?;
-        for peer in response.get_peer_addresses()? {
-            println!("{}:{}", peer.address, peer.port)
+        let request = tracker::TrackerRequest::new(&current_peer_id, torrent_hash, torrent.info.length.unwrap_or(0) as u64, &config);
+        let mut announcement = tracker::SwarmAnnouncement::start(&torrent.tracker_tiers(), &request, &udp_socket, tracker::TRACKER_TIMEOUT);
+        if let Some(timeout) = timeout {
+            // Trackers which did not answer by the deadline are given up on
+            if tokio::time::timeout(timeout, print_announced_peers(&mut announcement)).await.is_err() {
+                announcement.cancel();
+            }
+        } else {
+            print_announced_peers(&mut announcement).await;
+        }
+        Ok(())
+    } else if command == "trackers" {
+        let torrent_file_path = &args[2];
+        let torrent = torrent::Torrent::parse_torrent(torrent_file_path)?;
+        let config = config::ClientConfig::from_args(&args)?;
+        let mut trackers = tracker::SwarmTrackers::new(&torrent.tracker_tiers(), Instant::now());
+        let udp_socket = Arc::new(tracker::udp::UdpTrackerSocket::bind(config.udp_tracker_address).await?);
+        let request = tracker::TrackerRequest::new(&peer::random_peer_id(), torrent.info.compute_hash(), torrent.info.length.unwrap_or(0) as u64, &config);
+        tracker::Tracker::announce_to_swarm(&mut trackers, &request, &udp_socket).await;
+        let now = Instant::now();
+        for status in trackers.statuses() {
+            println!("{}", status.url);
+            let last_announce = status.last_announce.map(|last_announce| format!("{}s ago", now.saturating_duration_since(last_announce).as_secs()));
+            println!("  Last announce: {}", last_announce.unwrap_or("never".to_string()));
+            println!("  Next announce: in {}s", status.next_announce.saturating_duration_since(now).as_secs());
+            println!("  Seeders: {}", status.seeders.map(|seeders| seeders.to_string()).unwrap_or("-".to_string()));
+            println!("  Leechers: {}", status.leechers.map(|leechers| leechers.to_string()).unwrap_or("-".to_string()));
+            println!("  Peers returned: {}", status.peers_returned);
+            println!("  Consecutive failures: {}", status.consecutive_failures);
+            if let Some(warning) = &status.warning {
+                println!("  Warning: {}", warning);
+            }
+            if let Some(error) = &status.last_error {
+                println!("  Error: {}", error);
+            }
         }
         Ok(())
+    } else if command == "scrape" {
+        let torrent_file_path = &args[2];
+        let torrent = torrent::Torrent::parse_torrent(torrent_file_path)?;
+        let config = config::ClientConfig::from_args(&args)?;
+        let tracker = tracker::Tracker { url: torrent.announce.clone() };
+        let udp_socket = tracker::udp::UdpTrackerSocket::bind(config.udp_tracker_address).await?;
+        let statistics = tracker.scrape(&torrent.info.compute_hash(), &udp_socket).await?;
+        println!("Seeders: {}", statistics.seeders);
+        println!("Leechers: {}", statistics.leechers);
+        println!("Completed: {}", statistics.completed);
+        Ok(())
     } else if command == "handshake" {
         let torrent_file_path = &args[2];
         let other_peer_address = peer::PeerAddress::from_str(&args[3])?;
@@ -94,21 +118,14 @@ fn main() -> Result<(), anyhow::Error> {
         let torrent = torrent::Torrent::from_bytes(&torrent_file_bytes)?;
 
         let current_peer_id = peer::random_peer_id();
-        let torrent_hash = torrent.info.compute_hash();
-
-        let current_peer_handshake = peer::PeerHandshake {
-            info_hash: torrent_hash,
-            peer: peer::Peer {
-                id: current_peer_id.as_bytes().to_vec()
-            }
-        };
-        let (other_peer_handshake, _) = peer::Peer::handshake(&other_peer_address, &current_peer_handshake)?;
+        let (other_peer_handshake, _) = peer::Peer::handshake_for_peer(&other_peer_address, &torrent.info, &current_peer_id)?;
         println!("Peer ID: {}", format::format_as_hex_string(&other_peer_handshake.peer.id));
+        println!("Capabilities: {:?}", other_peer_handshake.capabilities);
         Ok(())
     } else if command == "download_piece" {
         let option = &args[2];
         if option != "-o" {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Unexpected arguments, did not find -o, {:?}", &args)).into())
+            Err(std::io::Error::other(format!("Unexpected arguments, did not find -o, {:?}", &args)).into())
         } else {
             let output_file_path = &args[3];
             let torrent_file_path = &args[4];
@@ -117,251 +134,266 @@ fn main() -> Result<(), anyhow::Error> {
 
             let torrent = torrent::Torrent::parse_torrent(torrent_file_path)?;
             let current_peer_id = peer::random_peer_id();
-            let port = 6881;
-            let peer_addresses = tracker::Tracker::join_swarm(&current_peer_id, port,&torrent)?;
-            let mut peer_threads: HashMap<Peer, JoinHandle<i32>> = HashMap::new();
+            let config = config::ClientConfig::from_args(&args)?;
 
-            let piece_length_to_download = torrent.info.piece_length_at_index(piece_index as u32)?;
+            let piece_length_to_download = torrent.info.piece_length_at_index(piece_index)?;
             let piece = peer::Piece {
-                index: piece_index as u32,
+                index: piece_index,
                 piece_length: piece_length_to_download
             };
 
-            let pieces_to_download: Arc<Mutex<Vec<Piece>>> = Arc::new(Mutex::new(vec![piece]));
-            let shared_output_file_path: Arc<String> = Arc::new(output_file_path.to_string());
-            let shared_torrent_info: Arc<TorrentInfo>  = Arc::new(torrent.info.clone());
-            for other_peer_address in peer_addresses {
-                let (other_peer_handshake, other_peer_stream) = peer::Peer::handshake_for_peer(&other_peer_address, &torrent.info, &current_peer_id)?;
-
-                //other_peer_stream.set_read_timeout(Some(Duration::new(5, 0)))?;
-                println!("Established connection to peer {:?} peer address {:?}", format::format_as_hex_string(&other_peer_handshake.peer.id), &other_peer_address);
-                let peer_thread = exchange_messages_with_peer(
-                    &pieces_to_download,
-                    &shared_output_file_path,
-                    &shared_torrent_info,
-                    &other_peer_handshake.peer,
-                    other_peer_stream,
-                    DownloadMode::Piece
-                )?;
-                peer_threads.insert(other_peer_handshake.peer.clone(), peer_thread);
+            let dht = start_dht(&config, &torrent).await?;
+            let udp_socket = Arc::new(tracker::udp::UdpTrackerSocket::bind(config.udp_tracker_address).await?);
+            let port = config.port;
+            let torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(config), &current_peer_id, vec![piece], output_file_path, DownloadMode::Piece, udp_socket).with_dht(dht);
+            if let Err(error) = accept_peers(&torrent_actor, port).await {
+                println!("Other peers cannot connect to us: {}", error);
             }
-            for (_, thread) in peer_threads {
-                thread.join().unwrap();
-            }
-            Ok(())
+            torrent_actor.run(Vec::new()).await
         }
     } else if command == "download" {
         let option = &args[2];
         if option != "-o" {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Unexpected arguments, did not find -o, {:?}", &args)).into())
+            Err(std::io::Error::other(format!("Unexpected arguments, did not find -o, {:?}", &args)).into())
         } else {
             let output_file_path = &args[3];
             let torrent_file_path = &args[4];
             println!("Downloading from torrent {:?} to file {:?}", torrent_file_path, output_file_path);
 
-            let torrent = torrent::Torrent::parse_torrent(torrent_file_path)?;
             let current_peer_id = peer::random_peer_id();
-            let port = 6881;
-            let peer_addresses = tracker::Tracker::join_swarm(&current_peer_id, port,&torrent)?;
-            let mut peer_threads: HashMap<Peer, JoinHandle<i32>> = HashMap::new();
+            if torrent_file_path.starts_with("magnet:") {
+                return download_mutable_torrent(&args, torrent_file_path, output_file_path, &current_peer_id).await;
+            }
+            let torrent = torrent::Torrent::parse_torrent(torrent_file_path)?;
+            let config = config::ClientConfig::from_args(&args)?;
 
             let all_pieces: Vec<Piece> = torrent.info.get_all_pieces();
             if !Path::new(output_file_path).exists() {
-                file::touch_and_fill_with_zeros(&output_file_path, torrent.info.length.unwrap_or(0))?;
+                file::touch_and_fill_with_zeros(output_file_path, torrent.info.length.unwrap_or(0))?;
             }
             //TODO: Decide which pieces are missing and still need to be downloaded by checking the hashes of the pieces of the file which has been downloaded so far
 
-            let pieces_to_download: Arc<Mutex<Vec<Piece>>> = Arc::new(Mutex::new(all_pieces));
-            let shared_output_file_path: Arc<String> = Arc::new(output_file_path.to_string());
-            let shared_torrent_info: Arc<TorrentInfo>  = Arc::new(torrent.info.clone());
-            for other_peer_address in peer_addresses {
-                let (other_peer_handshake, other_peer_stream) = peer::Peer::handshake_for_peer(&other_peer_address, &torrent.info, &current_peer_id)?;
-
-                //other_peer_stream.set_read_timeout(Some(Duration::new(5, 0)))?;
-                println!("Established connection to peer {:?} peer address {:?}", format::format_as_hex_string(&other_peer_handshake.peer.id), &other_peer_address);
-                let peer_thread = exchange_messages_with_peer(
-                    &pieces_to_download,
-                    &shared_output_file_path,
-                    &shared_torrent_info,
-                    &other_peer_handshake.peer,
-                    other_peer_stream,
-                    DownloadMode::File
-                )?;
-                peer_threads.insert(other_peer_handshake.peer.clone(), peer_thread);
+            let dht = start_dht(&config, &torrent).await?;
+            let udp_socket = Arc::new(tracker::udp::UdpTrackerSocket::bind(config.udp_tracker_address).await?);
+            let port = config.port;
+            let torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(config), &current_peer_id, all_pieces, output_file_path, DownloadMode::File, udp_socket).with_dht(dht);
+            if let Err(error) = accept_peers(&torrent_actor, port).await {
+                println!("Other peers cannot connect to us: {}", error);
             }
-            //TODO: Once downloading a piece is completed send a "have" message to the peers
-            //TODO: Receive an interpret "have" messages from the peers
-            //TODO: Re-factor and extract the function(s) for downloading the piece to the "peer" module
-            //TODO: Send the "bitfield" message to the peers when connecting to them and when a download of each piece is finished
-            for (_, thread) in peer_threads {
-                thread.join().unwrap();
-            }
-            Ok(())
+            torrent_actor.run(Vec::new()).await
+        }
+    } else if command == "seed" {
+        let torrent_file_path = &args[2];
+        let file_path = &args[3];
+        let torrent = torrent::Torrent::parse_torrent(torrent_file_path)?;
+        let config = config::ClientConfig::from_args(&args)?;
+        let current_peer_id = peer::random_peer_id();
+
+        let verified_pieces = engine::verify_pieces(&torrent.info, file_path)?;
+        let piece_count = torrent.info.total_piece_number();
+        println!("Verified {} of {} pieces of {}", verified_pieces.count(), piece_count, file_path);
+        if !verified_pieces.is_complete() {
+            println!("Only the verified pieces are uploaded, {} pieces are missing", piece_count - verified_pieces.count());
+        }
+        // With a key the torrent is also published as the current version of a mutable torrent https://www.bittorrent.org/beps/bep_0046.html
+        let publish_key = config::find_option(&args, "--dht-key").map(|path| dht::read_key(Path::new(path))).transpose()?;
+        let salt = config::find_option(&args, "--salt").unwrap_or("").as_bytes().to_vec();
+        let dht = start_dht(&config, &torrent).await?;
+        if let (Some(dht), Ok(info_hash)) = (dht.clone(), dht::NodeId::from_bytes(&torrent.info.compute_hash())) {
+            let port = config.port as u16;
+            tokio::spawn(async move {
+                loop {
+                    dht.announce(&info_hash, port).await;
+                    // The items expire unless they are put again
+                    if let Some(key) = &publish_key {
+                        match dht.publish_mutable_torrent(key, &salt, &info_hash).await {
+                            Ok(link) => println!("Published as {}", link.to_magnet_link()),
+                            Err(error) => println!("Could not publish the torrent: {}", error)
+                        }
+                    }
+                    tokio::time::sleep(dht::ANNOUNCE_INTERVAL).await;
+                }
+            });
+        }
+
+        println!("Seeding {} on port {}", torrent.info.name, config.port);
+        let port = config.port;
+        let udp_socket = Arc::new(tracker::udp::UdpTrackerSocket::bind(config.udp_tracker_address).await?);
+        let torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(config), &current_peer_id, Vec::new(), file_path, DownloadMode::File, udp_socket)
+            .with_dht(dht)
+            .seeding(verified_pieces);
+        accept_peers(&torrent_actor, port).await?;
+        torrent_actor.run(Vec::new()).await
+    } else if command == "dht" {
+        let torrent_file_path = &args[2];
+        let torrent = torrent::Torrent::parse_torrent(torrent_file_path)?;
+        let config = dht_command_config(&args)?;
+        let dht = start_dht(&config, &torrent).await?.ok_or(std::io::Error::other("The DHT is not used for private torrents"))?;
+        for peer in dht.get_peers(&dht::NodeId::from_bytes(&torrent.info.compute_hash())?).await {
+            println!("{}:{}", peer.address, peer.port)
+        }
+        Ok(())
+    } else if command == "dht_key" {
+        let key_file_path = Path::new(&args[3]);
+        let key = match args[2].as_str() {
+            "generate" => dht::generate_key(key_file_path)?,
+            "show" => dht::read_key(key_file_path)?,
+            action => return Err(std::io::Error::other(format!("Unknown key action {:?}, expected generate or show", action)).into())
+        };
+        println!("Public key: {}", format::format_as_hex_string(key.verifying_key().as_bytes()));
+        Ok(())
+    } else if command == "dht_put" {
+        // Without a key the value is put as an immutable item https://www.bittorrent.org/beps/bep_0044.html
+        let value = bencoded::decode_bencoded_from_str(&args[2])?.bencode();
+        let key = config::find_option(&args, "--key").map(|path| dht::read_key(Path::new(path))).transpose()?;
+        let salt = config::find_option(&args, "--salt").unwrap_or("").as_bytes();
+        let dht = join_dht(&dht_command_config(&args)?, &[]).await?;
+        match key {
+            Some(key) => {
+                let item = dht.put_mutable(&key, salt, value).await?;
+                println!("Target: {}", format::format_as_hex_string(&item.target().0));
+                println!("Sequence number: {}", item.seq().unwrap_or(0));
+            },
+            None => println!("Target: {}", format::format_as_hex_string(&dht.put_immutable(value).await?.0))
         }
+        Ok(())
+    } else if command == "dht_get" {
+        // Immutable items are got by their 20 byte target, mutable ones by the 32 byte public key and the salt
+        let target_or_public_key = hex::decode(&args[2])?;
+        let salt = config::find_option(&args, "--salt").unwrap_or("").as_bytes();
+        let dht = join_dht(&dht_command_config(&args)?, &[]).await?;
+        let item = match <[u8; 32]>::try_from(target_or_public_key.as_slice()) {
+            Ok(public_key) => dht.get_mutable(&public_key, salt).await,
+            Err(_) => dht.get_immutable(&dht::NodeId::from_bytes(&target_or_public_key)?).await.map(dht::Item::immutable)
+        };
+        let item = item.ok_or(std::io::Error::other("No DHT node has the item"))?;
+        if let Some(seq) = item.seq() {
+            println!("Sequence number: {}", seq);
+        }
+        println!("{}", bencoded::decode_bencoded_from_bytes(&item.value)?.as_json());
+        Ok(())
+    } else if command == "dht_publish" {
+        let torrent = torrent::Torrent::parse_torrent(&args[2])?;
+        let key_file_path = config::find_option(&args, "--key").ok_or(std::io::Error::other("A key file is needed to publish a torrent, see dht_key"))?;
+        let key = dht::read_key(Path::new(key_file_path))?;
+        let salt = config::find_option(&args, "--salt").unwrap_or("").as_bytes();
+        let dht = join_dht(&dht_command_config(&args)?, &[]).await?;
+        let link = dht.publish_mutable_torrent(&key, salt, &dht::NodeId::from_bytes(&torrent.info.compute_hash())?).await?;
+        println!("{}", link.to_magnet_link());
+        Ok(())
+    } else if command == "tracker" {
+        let http_address = config::find_option(&args, "--http").unwrap_or("0.0.0.0:6969");
+        let udp_address = config::find_option(&args, "--udp").unwrap_or("0.0.0.0:6969");
+        let interval = config::find_option(&args, "--interval").map(|interval| interval.parse::<u32>()).transpose()?.unwrap_or(1800);
+        let whitelist = config::find_option(&args, "--whitelist").map(tracker::server::read_whitelist).transpose()?;
+
+        let server = tracker::server::TrackerServer::new(tracker::server::TrackerServerConfig {
+            interval,
+            peer_expiry: Duration::from_secs(2 * interval as u64),
+            whitelist
+        });
+        let http_thread = server.serve_http(TcpListener::bind(http_address)?);
+        let udp_thread = server.serve_udp(UdpSocket::bind(udp_address)?);
+        println!("Tracker listening on http://{}/announce and udp://{}", http_address, udp_address);
+        http_thread.join().unwrap();
+        udp_thread.join().unwrap();
+        Ok(())
     } else {
-        println!("unknown command: {}", args[1]);
+        println!("unknown command: {}", command);
         Ok(())
     }
 }
 
-fn exchange_messages_with_peer(
-        pieces_to_download: &Arc<Mutex<Vec<Piece>>>,
-        output_file_path: &Arc<String>,
-        torrent_info: &Arc<TorrentInfo>,
-        peer: &peer::Peer,
-        mut peer_stream: TcpStream,
-        download_mode: DownloadMode) -> Result<JoinHandle<i32>, anyhow::Error> {
-    const MAXIMUM_CONCURRENT_REQUEST_COUNT: usize = 5;
-
-    let pieces_to_download_per_thread = Arc::clone(pieces_to_download);
-    let torrent_info_per_thread = Arc::clone(torrent_info);
-    let output_file_path_per_thread = Arc::clone(output_file_path);
-    let peer_in_this_thread = peer.clone();
-    let thread = thread::spawn(move || {
-        let mut sending = true;
-        let mut concurrent_request_count = 0;
-        let mut downloading_piece: bool = false;
-        let mut current_piece: Option<Piece> = None;
-        let mut piece_blocks_to_download: Vec<PieceBlock> = Vec::new();
-        let mut remaining_piece_bytes_to_download = 0;
-        let mut ready_piece_blocks = Vec::new();
-        let mut peer_bitfield: Option<Vec<u8>> = None;
-        let mut connection_state = PeerConnectionState::initial();
-
-        loop {
-            if !downloading_piece {
-                let piece_indexes_present_on_peer = {
-                    if let Some(bitfield) = &peer_bitfield {
-                        Peer::get_piece_indices(bitfield)
-                    } else {
-                        Vec::new()
-                    }
-                };
-                {
-                    let mut pieces = pieces_to_download_per_thread.lock().unwrap();
-                    let index_of_next_piece_to_download = pieces.iter().position(|piece| {
-                        let piece_index: usize = piece.index as usize;
-                        piece_indexes_present_on_peer.contains(&piece_index)
-                    });
-                    if let Some(piece_index) = index_of_next_piece_to_download {
-                        let piece = pieces[piece_index].clone();
-                        pieces.remove(piece_index);
-                        piece_blocks_to_download = piece.get_blocks(piece.piece_length);
-                        remaining_piece_bytes_to_download = piece.piece_length;
-                        ready_piece_blocks = vec![0; piece.piece_length as usize];
-                        downloading_piece = true;
-                        current_piece = Some(piece.clone());
-                    }
+// Prints the peers as soon as each of the trackers answers
+async fn print_announced_peers(announcement: &mut tracker::SwarmAnnouncement) {
+    while let Some(tracker_announce) = announcement.next().await {
+        match tracker_announce.result.and_then(|response| response.get_peer_addresses()) {
+            Ok(peer_addresses) => {
+                for peer in peer_addresses {
+                    println!("{}:{}", peer.address, peer.port)
                 }
-            }
+            },
+            Err(error) => println!("Could not announce to tracker {}: {}", tracker_announce.url, error)
+        }
+    }
+}
 
-            {
-                let pieces = pieces_to_download_per_thread.lock().unwrap();
-                if !downloading_piece && pieces.is_empty() {
-                    println!("Finished downloading the file from peer {}", format::format_as_hex_string(&peer_in_this_thread.id));
-                    break;
-                }
-            }
-            if sending {
-                /* 
-                 * Current assumption: it makes sense to send messages to a peer only if we are downloading a piece from it
-                 * This assumption might change when we send the "bitfield" and "have" messages to the peer in the future
-                 */
-                if downloading_piece {
-                    let piece = &current_piece.clone().unwrap();
-                    if connection_state.interested == PeerInterestedState::NotInterested {
-                        let interested_message = PeerMessage::with_id(PeerMessageId::Interested);
-                        peer_stream.write_all(&interested_message.get_bytes()).unwrap();
-                        connection_state = connection_state.update_interested(PeerInterestedState::Interested);
-                        println!("Sent: 'interested' to peer {:?}", format::format_as_hex_string(&peer_in_this_thread.id));
-                    } else if connection_state.choked == PeerChokedState::Unchoked {
-                        let next_blocks_to_ask = {
-                            let remaining_piece_blocks_number = piece_blocks_to_download.len();
-                            let remaining_concurrent_requests = if MAXIMUM_CONCURRENT_REQUEST_COUNT >= concurrent_request_count {
-                                MAXIMUM_CONCURRENT_REQUEST_COUNT - concurrent_request_count
-                            } else {
-                                0
-                            };
-                            let next_piece_blocks_number = min(remaining_concurrent_requests, remaining_piece_blocks_number);
-                            if next_piece_blocks_number == 0 {
-                                Vec::new()
-                            } else {
-                                piece_blocks_to_download.drain(0..next_piece_blocks_number).collect()
-                            }
-                        };
-                        concurrent_request_count = concurrent_request_count + next_blocks_to_ask.len();
-                        if next_blocks_to_ask.len() > 0 {
-                            let next_block_requests: Vec<PeerMessage> = next_blocks_to_ask.iter().map(|block| {
-                                PeerMessage::new_request(piece.index, block.begin, block.length)
-                            }).collect();
-                            for request in next_block_requests {
-                                //thread::sleep(std::time::Duration::from_millis(100));
-                                println!("Sent: 'request' {:?} to peer {:?}", &request, format::format_as_hex_string(&peer_in_this_thread.id));
-                                let request_bytes = request.get_bytes();
-                                peer_stream.write_all(&request_bytes).unwrap();
-                                println!("Request: {:?}", request_bytes);
-                            }
-                        }
-                    }
-                }
-            } else {
-                if let Some(message) = Peer::read_message(&mut peer_stream).unwrap() {
-                    if message.message_id == PeerMessageId::Bitfield {
-                        println!("Received: 'bitfield' from peer {:?}", format::format_as_hex_string(&peer_in_this_thread.id));
-                        peer_bitfield = Some(message.payload);
-                    } else if message.message_id == PeerMessageId::Unchoke {
-                        println!("Received: 'unchoke' from peer {:?}", format::format_as_hex_string(&peer_in_this_thread.id));
-                        connection_state = connection_state.update_choked(PeerChokedState::Unchoked);
-                    } else if message.message_id == PeerMessageId::Piece {
-                        println!("Received: 'piece' from peer {:?}", format::format_as_hex_string(&peer_in_this_thread.id));
-                        if downloading_piece {
-                            let piece = &current_piece.clone().unwrap();
-                            concurrent_request_count = if concurrent_request_count > 1 {
-                                concurrent_request_count - 1
-                            } else {
-                                0
-                            };
-                            let piece_message = message.parse_as_piece().unwrap();
-                            println!("Details about received 'piece': index={:?}  begin={:?} length={:?} from peer {:?}", piece_message.index, piece_message.begin, piece_message.block.len(), format::format_as_hex_string(&peer_in_this_thread.id));
-                            remaining_piece_bytes_to_download = remaining_piece_bytes_to_download - (piece_message.block.len() as u32);
-                            ready_piece_blocks[piece_message.begin..(piece_message.begin + piece_message.block.len())].copy_from_slice(&piece_message.block);
-                            if remaining_piece_bytes_to_download == 0 {
-                                //println!("torrent_info_pieces.len() = {}", torrent_info_per_thread.pieces.len());
-                                let expected_piece_hash = torrent_info_per_thread.pieces[(piece.index as usize) * 20..((piece.index as usize) + 1) * 20].to_vec();
-                                let computed_piece_hash = hash::compute_hash(&ready_piece_blocks);
-                                println!("Finished download, expected and computed hashes:");
-                                println!("Expected hash: {}", format::format_as_hex_string(&expected_piece_hash));
-                                println!("Computed hash: {}", format::format_as_hex_string(&computed_piece_hash));
-                                if expected_piece_hash == computed_piece_hash {
-                                    println!("Piece {} downloaded to {}.", piece.index, output_file_path_per_thread);
-                                    //Finished downloading the piece and is ready to pick up the next piece
-                                    if download_mode == DownloadMode::File {
-                                        let begin_in_file = torrent_info_per_thread.piece_length * (piece.index as usize);
-                                        file::write_piece_to(&output_file_path_per_thread.as_str(), begin_in_file, ready_piece_blocks.as_slice()).unwrap();
-                                    } else {
-                                        let mut file = File::create(output_file_path_per_thread.as_str()).unwrap();
-                                        file.write_all(ready_piece_blocks.as_slice()).unwrap();
-                                    }
-                                    downloading_piece = false;
-                                } else {
-                                    //Restarting the download of the piece from scratch: something went wrong
-                                    piece_blocks_to_download = piece.get_blocks(piece.piece_length);
-                                    remaining_piece_bytes_to_download = piece.piece_length;
-                                    ready_piece_blocks = vec![0; piece.piece_length as usize];
-                                }
-                            }
-                        } else {
-                            println!("Ignoring 'piece' since the piece is no longer being downloaded...")
-                        }
-                    }
-                }
-            }
-            //TODO: In case download is taking too much or the peer did not respond with the blocks of the piece, return the piece to the queue
-            sending = !sending;
-            let sleep_duration = Duration::from_millis(100);
-            thread::sleep(sleep_duration);
+// Other peers connect to us on the port which we announce
+async fn accept_peers(torrent_actor: &TorrentActor, port: usize) -> Result<(), anyhow::Error> {
+    let acceptor = engine::PeerAcceptor::default();
+    acceptor.add_torrent(torrent_actor);
+    acceptor.serve(tokio::net::TcpListener::bind(("0.0.0.0", port as u16)).await?);
+    Ok(())
+}
+
+// Resolves the link to the current version of the torrent, gets its info dictionary from the peers and downloads it
+// https://www.bittorrent.org/beps/bep_0046.html
+async fn download_mutable_torrent(args: &[String], link: &str, output_file_path: &str, current_peer_id: &str) -> Result<(), anyhow::Error> {
+    let link = dht::MutableTorrentLink::parse(link)?;
+    let config = dht_command_config(args)?;
+    let dht = join_dht(&config, &[]).await?;
+    let info_hash = dht.resolve_mutable_torrent(&link).await?;
+    println!("Current version of the torrent is {}", format::format_as_hex_string(&info_hash.0));
+    let peer_addresses = dht.get_peers(&info_hash).await;
+    let mut info = None;
+    for peer_address in &peer_addresses {
+        match peer::fetch_metadata(SocketAddr::new(peer_address.address, peer_address.port), &info_hash.0, current_peer_id.as_bytes()).await {
+            Ok(metadata) => {
+                info = Some(torrent::TorrentInfo::from_bytes(&metadata)?);
+                break;
+            },
+            Err(error) => println!("Could not get the metadata from peer {}:{}: {}", peer_address.address, peer_address.port, error)
         }
-        0 // result
-    });
-    Ok(thread)
-}
\ No newline at end of file
+    }
+    let info = info.ok_or(std::io::Error::other(format!("None of the {} peers sent the metadata of the torrent", peer_addresses.len())))?;
+    let torrent = torrent::Torrent { announce: String::new(), announce_list: None, nodes: None, info };
+    let all_pieces: Vec<Piece> = torrent.info.get_all_pieces();
+    if !Path::new(output_file_path).exists() {
+        file::touch_and_fill_with_zeros(output_file_path, torrent.info.length.unwrap_or(0))?;
+    }
+    let udp_socket = Arc::new(tracker::udp::UdpTrackerSocket::bind(config.udp_tracker_address).await?);
+    let port = config.port;
+    let torrent_actor = TorrentActor::new(Arc::new(torrent), Arc::new(config), current_peer_id, all_pieces, output_file_path, DownloadMode::File, udp_socket).with_dht(Some(dht));
+    if let Err(error) = accept_peers(&torrent_actor, port).await {
+        println!("Other peers cannot connect to us: {}", error);
+    }
+    torrent_actor.run(peer_addresses).await
+}
+
+// The commands which only use the DHT join it even when it is not turned on
+fn dht_command_config(args: &[String]) -> Result<config::ClientConfig, anyhow::Error> {
+    let mut config = config::ClientConfig::from_args(args)?;
+    if config.dht_address.is_none() && config.dht_address6.is_none() {
+        config.dht_address = Some(SocketAddr::from(([0, 0, 0, 0], 0)));
+    }
+    Ok(config)
+}
+
+// Joins the DHT when it is turned on, private torrents only get their peers from the trackers https://www.bittorrent.org/beps/bep_0027.html
+async fn start_dht(config: &config::ClientConfig, torrent: &torrent::Torrent) -> Result<Option<Arc<dht::DhtNode>>, anyhow::Error> {
+    if (config.dht_address.is_none() && config.dht_address6.is_none()) || torrent.info.is_private() {
+        return Ok(None);
+    }
+    let torrent_nodes: Vec<String> = torrent.nodes.iter().flatten().map(|(host, port)| format!("{}:{}", host, port)).collect();
+    Ok(Some(join_dht(config, &torrent_nodes).await?))
+}
+
+// Joins through the configured bootstrap nodes and the given ones
+async fn join_dht(config: &config::ClientConfig, extra_bootstrap_nodes: &[String]) -> Result<Arc<dht::DhtNode>, anyhow::Error> {
+    let addresses: Vec<SocketAddr> = config.dht_address.into_iter().chain(config.dht_address6).collect();
+    let dht = dht::DhtNode::bind(dht::DhtConfig {
+        addresses,
+        state_path: Some(PathBuf::from(&config.dht_state_path)),
+        // Our node id is derived from the address we tell the trackers about, the other nodes tell us about it otherwise
+        external_ip: config.announce_ip,
+        verify_local_ids: false
+    }).await?;
+    let mut bootstrap_nodes = config.dht_bootstrap_nodes.clone();
+    bootstrap_nodes.extend_from_slice(extra_bootstrap_nodes);
+    let node_count = dht.bootstrap(&bootstrap_nodes).await;
+    for address in dht.local_addrs() {
+        println!("DHT node {} on {}", format::format_as_hex_string(&dht.id(address.is_ipv6()).0), address);
+    }
+    println!("DHT node knows {} nodes", node_count);
+    Ok(dht)
+}
@@ -0,0 +1,202 @@
+// Extension protocol https://www.bittorrent.org/beps/bep_0010.html
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use crate::bencoded::{self, BencodeEncoding, Value};
+use crate::error::new_error;
+use super::PeerMessage;
+
+// Extended message id of the extension handshake, the other ids are chosen by the receiver of the messages
+pub(crate) const EXTENSION_HANDSHAKE_ID: u8 = 0;
+// Sent by the peers which only upload https://www.libtorrent.org/extension_protocol.html#upload-only
+pub(crate) const UPLOAD_ONLY_EXTENSION: &str = "upload_only";
+pub(crate) const CLIENT_NAME: &str = concat!("bittorrent-client ", env!("CARGO_PKG_VERSION"));
+
+// Payload of the extension handshake, all the fields are optional
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ExtensionHandshake {
+    // Names of the supported extensions mapped to the extended message ids the sender receives them with, id 0 disables an extension
+    pub(crate) extensions: BTreeMap<String, u8>,
+    pub(crate) client: Option<String>,
+    // Port on which the sender accepts peer connections
+    pub(crate) listen_port: Option<u16>,
+    // Number of outstanding requests the sender accepts
+    pub(crate) request_queue_length: Option<usize>,
+    // Our address as the sender sees it
+    pub(crate) your_ip: Option<IpAddr>,
+    // Length of the info dictionary https://www.bittorrent.org/beps/bep_0009.html
+    pub(crate) metadata_size: Option<usize>
+}
+
+impl ExtensionHandshake {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        // The keys of a bencoded dictionary are sorted
+        let mut bencoded: Vec<u8> = Vec::new();
+        bencoded.push(b'd');
+        bencoded.encode_str("m");
+        bencoded.push(b'd');
+        for (name, extended_id) in &self.extensions {
+            bencoded.encode_str(name);
+            bencoded.encode_i64(&(*extended_id as i64));
+        }
+        bencoded.push(b'e');
+        if let Some(metadata_size) = self.metadata_size {
+            bencoded.encode_str("metadata_size");
+            bencoded.encode_usize(&metadata_size);
+        }
+        if let Some(listen_port) = self.listen_port {
+            bencoded.encode_str("p");
+            bencoded.encode_i64(&(listen_port as i64));
+        }
+        if let Some(request_queue_length) = self.request_queue_length {
+            bencoded.encode_str("reqq");
+            bencoded.encode_usize(&request_queue_length);
+        }
+        if let Some(client) = &self.client {
+            bencoded.encode_str("v");
+            bencoded.encode_str(client);
+        }
+        if let Some(your_ip) = self.your_ip {
+            bencoded.encode_str("yourip");
+            match your_ip {
+                IpAddr::V4(address) => bencoded.encode_bytes(&address.octets()),
+                IpAddr::V6(address) => bencoded.encode_bytes(&address.octets())
+            }
+        }
+        bencoded.push(b'e');
+        bencoded
+    }
+
+    // Fields which are malformed are ignored, only a payload which is not a dictionary is rejected
+    pub(crate) fn parse(payload: &[u8]) -> Result<ExtensionHandshake, anyhow::Error> {
+        let handshake = bencoded::decode_bencoded_from_bytes(payload)?;
+        let Value::Object(_) = handshake else {
+            return Err(new_error(format!("Extension handshake {:?} is not a dictionary", handshake)));
+        };
+        let mut extensions = BTreeMap::new();
+        if let Some(Value::Object(pairs)) = handshake.get_optional_by_key("m") {
+            for (name, extended_id) in pairs {
+                if let (Ok(name), Some(extended_id)) = (name.as_string(), extended_id.as_number().ok().and_then(|id| u8::try_from(id).ok())) {
+                    extensions.insert(name, extended_id);
+                }
+            }
+        }
+        let number = |key: &str| handshake.get_optional_by_key(key).and_then(|value| value.as_number().ok());
+        Ok(ExtensionHandshake {
+            extensions,
+            client: handshake.get_optional_by_key("v").and_then(|client| client.as_bytes().ok()).map(|client| String::from_utf8_lossy(&client).to_string()),
+            listen_port: number("p").and_then(|port| u16::try_from(port).ok()),
+            request_queue_length: number("reqq").and_then(|length| usize::try_from(length).ok()),
+            your_ip: handshake.get_optional_by_key("yourip").and_then(|address| address.as_bytes().ok()).and_then(|address| parse_ip(&address)),
+            metadata_size: number("metadata_size").and_then(|size| usize::try_from(size).ok())
+        })
+    }
+}
+
+fn parse_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None
+    }
+}
+
+// Extensions we support, each registered under its name with the extended message id the peers send it to us with
+#[derive(Default)]
+pub(crate) struct ExtensionRegistry {
+    names: Vec<&'static str>
+}
+
+impl ExtensionRegistry {
+    pub(crate) fn register(&mut self, name: &'static str) -> u8 {
+        if let Some(extended_id) = self.local_id(name) {
+            return extended_id;
+        }
+        self.names.push(name);
+        self.names.len() as u8
+    }
+
+    fn local_id(&self, name: &str) -> Option<u8> {
+        self.names.iter().position(|registered| *registered == name).map(|position| position as u8 + 1)
+    }
+
+    // Name of the extension an extended message sent to us belongs to
+    pub(crate) fn name_of(&self, extended_id: u8) -> Option<&'static str> {
+        self.names.get((extended_id as usize).checked_sub(1)?).copied()
+    }
+
+    // Our handshake advertising the registered extensions, the other fields are filled in by the caller
+    pub(crate) fn handshake(&self) -> ExtensionHandshake {
+        ExtensionHandshake {
+            extensions: self.names.iter().enumerate().map(|(position, name)| (name.to_string(), position as u8 + 1)).collect(),
+            client: Some(CLIENT_NAME.to_string()),
+            ..ExtensionHandshake::default()
+        }
+    }
+}
+
+// Extensions which a peer supports, with the extended message ids it wants to receive them with
+#[derive(Default)]
+pub(crate) struct PeerExtensions {
+    extended_ids: HashMap<String, u8>
+}
+
+impl PeerExtensions {
+    pub(crate) fn from_handshake(handshake: &ExtensionHandshake) -> PeerExtensions {
+        PeerExtensions {
+            extended_ids: handshake.extensions.iter()
+                .filter(|(_, extended_id)| **extended_id != 0)
+                .map(|(name, extended_id)| (name.clone(), *extended_id))
+                .collect()
+        }
+    }
+
+    // None when the peer does not support the extension
+    pub(crate) fn message(&self, name: &str, payload: Vec<u8>) -> Option<PeerMessage> {
+        self.extended_ids.get(name).map(|extended_id| PeerMessage::Extended { extended_id: *extended_id, payload })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_parse_extension_handshake() {
+        let handshake = ExtensionHandshake {
+            extensions: BTreeMap::from([("ut_pex".to_string(), 1), ("ut_metadata".to_string(), 2)]),
+            client: Some("client 1.0".to_string()),
+            listen_port: Some(6881),
+            request_queue_length: Some(500),
+            your_ip: Some("10.0.0.1".parse().unwrap()),
+            metadata_size: Some(31235)
+        };
+        let bytes = handshake.get_bytes();
+        assert_eq!(bytes, b"d1:md11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei31235e1:pi6881e4:reqqi500e1:v10:client 1.06:yourip4:\x0a\x00\x00\x01e".to_vec());
+        assert_eq!(ExtensionHandshake::parse(&bytes).unwrap(), handshake);
+
+        let ipv6_handshake = ExtensionHandshake { your_ip: Some("::1".parse().unwrap()), ..ExtensionHandshake::default() };
+        assert_eq!(ExtensionHandshake::parse(&ipv6_handshake.get_bytes()).unwrap(), ipv6_handshake);
+
+        let malformed_fields = ExtensionHandshake::parse(b"d1:md6:ut_pexi300ee1:pi70000e4:reqqi-1e6:yourip3:abce").unwrap();
+        assert_eq!(malformed_fields, ExtensionHandshake::default());
+        assert!(ExtensionHandshake::parse(b"i5e").is_err());
+        assert!(ExtensionHandshake::parse(b"garbage").is_err());
+    }
+
+    #[test]
+    fn should_route_extended_messages_by_negotiated_ids() {
+        let mut registry = ExtensionRegistry::default();
+        assert_eq!(registry.register("ut_pex"), 1);
+        assert_eq!(registry.register("upload_only"), 2);
+        assert_eq!(registry.register("ut_pex"), 1);
+        assert_eq!(registry.name_of(2), Some("upload_only"));
+        assert_eq!(registry.name_of(EXTENSION_HANDSHAKE_ID), None);
+        assert_eq!(registry.name_of(3), None);
+        assert_eq!(registry.handshake().extensions, BTreeMap::from([("ut_pex".to_string(), 1), ("upload_only".to_string(), 2)]));
+
+        let peer_handshake = ExtensionHandshake::parse(b"d1:md11:upload_onlyi0e6:ut_pexi7eee").unwrap();
+        let peer_extensions = PeerExtensions::from_handshake(&peer_handshake);
+        assert_eq!(peer_extensions.message("ut_pex", vec![1]), Some(PeerMessage::Extended { extended_id: 7, payload: vec![1] }));
+        assert_eq!(peer_extensions.message("upload_only", vec![1]), None);
+    }
+}
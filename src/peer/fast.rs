@@ -0,0 +1,50 @@
+// Fast extension https://www.bittorrent.org/beps/bep_0006.html
+use std::net::IpAddr;
+use crate::hash;
+
+// Number of pieces a peer may download from us while it is choked
+pub(crate) const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+// The canonical allowed fast set of a peer, the same peer always gets the same pieces https://www.bittorrent.org/beps/bep_0006.html#allowed-fast
+// it is only defined for IPv4 peers
+pub(crate) fn allowed_fast_set(address: &IpAddr, info_hash: &[u8], piece_count: usize, size: usize) -> Vec<u32> {
+    let IpAddr::V4(address) = address else {
+        return Vec::new();
+    };
+    let size = size.min(piece_count);
+    let mut allowed_fast = Vec::new();
+    // Peers in the same /24 network get the same set
+    let mut x: Vec<u8> = (u32::from(*address) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while allowed_fast.len() < size {
+        x = hash::compute_hash(&x);
+        for chunk in x.chunks(4) {
+            if allowed_fast.len() == size {
+                break;
+            }
+            let index = (u32::from_be_bytes(chunk.try_into().unwrap()) as u64 % piece_count as u64) as u32;
+            if !allowed_fast.contains(&index) {
+                allowed_fast.push(index);
+            }
+        }
+    }
+    allowed_fast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_canonical_allowed_fast_set() {
+        let address: IpAddr = "80.4.4.200".parse().unwrap();
+        assert_eq!(allowed_fast_set(&address, &[0xaa; 20], 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(&address, &[0xaa; 20], 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+        assert_eq!(allowed_fast_set(&"80.4.4.1".parse().unwrap(), &[0xaa; 20], 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+
+        let mut small_torrent_set = allowed_fast_set(&address, &[0xaa; 20], 3, ALLOWED_FAST_SET_SIZE);
+        small_torrent_set.sort();
+        assert_eq!(small_torrent_set, vec![0, 1, 2]);
+        assert!(allowed_fast_set(&"::1".parse().unwrap(), &[0xaa; 20], 1313, 7).is_empty());
+    }
+}
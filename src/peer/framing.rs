@@ -0,0 +1,125 @@
+// Length prefixed framing of the peer messages https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+use std::time::Duration;
+use anyhow::ensure;
+use super::PeerMessage;
+
+// "Keepalives are generally sent once every two minutes"
+pub(crate) const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+// A peer which did not send anything, not even a keep-alive, for this long is considered gone
+pub(crate) const PEER_INACTIVITY_TIMEOUT: Duration = Duration::from_secs(180);
+// Large enough for a 16 KiB block and for the bitfield of a torrent with a million pieces
+pub(crate) const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1 << 17;
+
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+// Accumulates the bytes received from the peer until a whole message is available
+pub(crate) struct MessageFramer {
+    buffer: Vec<u8>,
+    max_message_length: usize
+}
+
+impl MessageFramer {
+    pub(crate) fn new(max_message_length: usize) -> MessageFramer {
+        MessageFramer {
+            buffer: Vec::new(),
+            max_message_length
+        }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // Returns None until all the bytes of the next message are received
+    pub(crate) fn next_message(&mut self) -> Result<Option<PeerMessage>, anyhow::Error> {
+        if self.buffer.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let message_length = u32::from_be_bytes(self.buffer[0..LENGTH_PREFIX_SIZE].try_into()?) as usize;
+        ensure!(message_length <= self.max_message_length, "Peer message of {} bytes exceeds the maximum of {} bytes", message_length, self.max_message_length);
+        if self.buffer.len() < LENGTH_PREFIX_SIZE + message_length {
+            return Ok(None);
+        }
+        let message = PeerMessage::parse(&self.buffer[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + message_length]);
+        self.buffer.drain(0..LENGTH_PREFIX_SIZE + message_length);
+        message.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_bitfield_message_from_peer() {
+        let mut framer = MessageFramer::new(DEFAULT_MAX_MESSAGE_LENGTH);
+        framer.push(&[
+            0, 0, 0, 4,   // message length prefix - 4
+            5,             // message id byte - 5 “bitfield”
+            255, 248, 128
+        ]);
+        assert_eq!(framer.next_message().unwrap(), Some(PeerMessage::Bitfield { bitfield: vec![255, 248, 128] }));
+    }
+
+    #[test]
+    fn should_read_unchoke_message_from_peer() {
+        let mut framer = MessageFramer::new(DEFAULT_MAX_MESSAGE_LENGTH);
+        framer.push(&[
+            0, 0, 0, 1,   // message length prefix - 1
+            1,            // message id byte - 1 “unchoke”
+        ]);
+        assert_eq!(framer.next_message().unwrap(), Some(PeerMessage::Unchoke));
+    }
+
+    #[test]
+    fn should_read_piece_message_from_peer() {
+        let mut framer = MessageFramer::new(DEFAULT_MAX_MESSAGE_LENGTH);
+        framer.push(&[
+            0, 0, 0, 21,   // message length prefix - 21
+            7,             // message id byte - 7 “piece”
+            0, 0, 0, 1,    // piece index - 1
+            0, 2, 128, 0,  // begin - 163840
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12 // piece block content
+        ]);
+        assert_eq!(framer.next_message().unwrap(), Some(PeerMessage::Piece {
+            index: 1,
+            begin: 163840,
+            block: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]
+        }));
+    }
+
+    #[test]
+    fn should_buffer_partial_reads() {
+        let bytes = [
+            0, 0, 0, 0,    // message length prefix - 0 "keep-alive"
+            0, 0, 0, 1,    // message length prefix - 1
+            1,             // message id byte - 1 “unchoke”
+            0, 0, 0, 21,   // message length prefix - 21
+            7,             // message id byte - 7 “piece”
+            0, 0, 0, 1,    // piece index - 1
+            0, 2, 128, 0,  // begin - 163840
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12 // piece block content
+        ];
+        let mut framer = MessageFramer::new(DEFAULT_MAX_MESSAGE_LENGTH);
+        let mut messages = Vec::new();
+        // Simulates a peer sending the bytes in small portions
+        for chunk in bytes.chunks(3) {
+            framer.push(chunk);
+            while let Some(message) = framer.next_message().unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(messages, vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Unchoke,
+            PeerMessage::Piece { index: 1, begin: 163840, block: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12] }
+        ]);
+    }
+
+    #[test]
+    fn should_reject_too_large_messages() {
+        let mut framer = MessageFramer::new(16);
+        framer.push(&[0, 0, 0, 17, 7]);
+        assert!(framer.next_message().is_err());
+    }
+}
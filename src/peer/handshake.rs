@@ -0,0 +1,151 @@
+// Handshake of the peer wire protocol https://www.bittorrent.org/beps/bep_0003.html#peer-protocol
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use anyhow::ensure;
+use crate::format;
+use super::Peer;
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+pub(crate) const HANDSHAKE_LENGTH: usize = 68; //1 + 19 + 8 + 20 + 20
+
+// Extensions announced in the reserved bytes of the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Capabilities {
+    // https://www.bittorrent.org/beps/bep_0010.html
+    pub(crate) extension_protocol: bool,
+    // https://www.bittorrent.org/beps/bep_0006.html
+    pub(crate) fast: bool,
+    // https://www.bittorrent.org/beps/bep_0005.html
+    pub(crate) dht: bool
+}
+
+impl Capabilities {
+    pub(crate) fn from_reserved(reserved: &[u8; 8]) -> Capabilities {
+        Capabilities {
+            extension_protocol: reserved[5] & 0x10 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            dht: reserved[7] & 0x01 != 0
+        }
+    }
+
+    pub(crate) fn to_reserved(self) -> [u8; 8] {
+        let mut reserved: [u8; 8] = [0; 8];
+        if self.extension_protocol {
+            reserved[5] |= 0x10;
+        }
+        if self.fast {
+            reserved[7] |= 0x04;
+        }
+        if self.dht {
+            reserved[7] |= 0x01;
+        }
+        reserved
+    }
+}
+
+pub(crate) struct PeerHandshake {
+    pub(crate) info_hash: Vec<u8>,
+    pub(crate) peer: Peer,
+    pub(crate) capabilities: Capabilities
+}
+
+impl PeerHandshake {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut message: Vec<u8> = Vec::new();
+        message.push(PROTOCOL.len() as u8);
+        message.extend_from_slice(PROTOCOL);
+        message.extend_from_slice(&self.capabilities.to_reserved());
+        message.extend_from_slice(&self.info_hash);
+        message.extend_from_slice(&self.peer.id);
+        message
+    }
+
+    pub(crate) fn parse(message: &[u8; HANDSHAKE_LENGTH]) -> Result<PeerHandshake, anyhow::Error> {
+        ensure!(message[0] as usize == PROTOCOL.len(), "Unexpected protocol string length {} in the handshake", message[0]);
+        ensure!(&message[1..20] == PROTOCOL, "Unexpected protocol {:?} in the handshake", String::from_utf8_lossy(&message[1..20]));
+        let reserved: [u8; 8] = message[20..28].try_into()?;
+        Ok(PeerHandshake {
+            info_hash: message[28..48].to_vec(),
+            peer: Peer { id: message[48..].to_vec() },
+            capabilities: Capabilities::from_reserved(&reserved)
+        })
+    }
+
+    // Reads the handshake of the other peer, the connection should be severed if it is for another torrent
+    pub(crate) fn read_from(stream: &mut impl Read, expected_info_hash: &[u8]) -> Result<PeerHandshake, anyhow::Error> {
+        let mut message: [u8; HANDSHAKE_LENGTH] = [0; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut message)?;
+        let handshake = PeerHandshake::parse(&message)?;
+        ensure!(handshake.info_hash == expected_info_hash, "Peer responded with the info hash {} instead of {}",
+            format::format_as_hex_string(&handshake.info_hash), format::format_as_hex_string(expected_info_hash));
+        Ok(handshake)
+    }
+
+    // The peers which connect to us tell the torrent with their handshake
+    pub(crate) async fn read_async(stream: &mut (impl AsyncRead + Unpin)) -> Result<PeerHandshake, anyhow::Error> {
+        let mut message: [u8; HANDSHAKE_LENGTH] = [0; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut message).await?;
+        PeerHandshake::parse(&message)
+    }
+
+    pub(crate) async fn read_from_async(stream: &mut (impl AsyncRead + Unpin), expected_info_hash: &[u8]) -> Result<PeerHandshake, anyhow::Error> {
+        let mut message: [u8; HANDSHAKE_LENGTH] = [0; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut message).await?;
+        PeerHandshake::read_from(&mut message.as_slice(), expected_info_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake_bytes(capabilities: Capabilities) -> [u8; HANDSHAKE_LENGTH] {
+        let handshake = PeerHandshake {
+            info_hash: vec![1; 20],
+            peer: Peer { id: vec![2; 20] },
+            capabilities
+        };
+        handshake.get_bytes().try_into().unwrap()
+    }
+
+    #[test]
+    fn should_serialize_peer_handshake_message_correctly() {
+        let peer_handshake = PeerHandshake {
+            info_hash: vec![1, 2, 3, 4],
+            peer: Peer {
+                id: vec![5, 6, 7, 8]
+            },
+            capabilities: Capabilities { extension_protocol: true, fast: true, dht: true }
+        };
+        assert_eq!(peer_handshake.get_bytes(), vec![
+            19, // length of the protocol string which follows - 19
+            66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99, 111, 108, // protocol string:"BitTorrent protocol"
+            0, 0, 0, 0, 0, 0x10, 0, 0x05, // reserved 8 bytes: extension protocol, fast and DHT bits
+            1, 2, 3, 4, // info_hash bytes
+            5, 6, 7, 8 // peer id bytes
+        ]);
+    }
+
+    #[test]
+    fn should_read_capabilities_from_reserved_bytes() {
+        let capabilities = Capabilities { extension_protocol: true, fast: false, dht: true };
+        let handshake = PeerHandshake::read_from(&mut handshake_bytes(capabilities).as_slice(), &[1; 20]).unwrap();
+        assert_eq!(handshake.capabilities, capabilities);
+        assert_eq!(handshake.peer.id, vec![2; 20]);
+        assert_eq!(Capabilities::from_reserved(&[0; 8]), Capabilities::default());
+    }
+
+    #[test]
+    fn should_reject_invalid_handshakes() {
+        let valid = handshake_bytes(Capabilities::default());
+        assert!(PeerHandshake::read_from(&mut valid.as_slice(), &[3; 20]).is_err());
+        assert!(PeerHandshake::read_from(&mut &valid[0..40], &[1; 20]).is_err());
+
+        let mut wrong_protocol_length = valid;
+        wrong_protocol_length[0] = 18;
+        assert!(PeerHandshake::parse(&wrong_protocol_length).is_err());
+        let mut wrong_protocol = valid;
+        wrong_protocol[1] = b'b';
+        assert!(PeerHandshake::parse(&wrong_protocol).is_err());
+    }
+}
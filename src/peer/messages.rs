@@ -0,0 +1,269 @@
+// Messages of the peer wire protocol https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+use anyhow::ensure;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub(crate) enum PeerMessageId {
+    Choke = 0,
+    Unchoke = 1,
+    Interested = 2,
+    NotInterested = 3,
+    Have = 4,
+    Bitfield = 5,
+    Request = 6,
+    Piece = 7,
+    Cancel = 8,
+    // https://www.bittorrent.org/beps/bep_0005.html
+    Port = 9,
+    // https://www.bittorrent.org/beps/bep_0006.html
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    // https://www.bittorrent.org/beps/bep_0010.html
+    Extended = 20
+}
+
+impl PeerMessageId {
+    fn lookup(value: u8) -> Option<PeerMessageId> {
+        match value {
+            0 => Some(PeerMessageId::Choke),
+            1 => Some(PeerMessageId::Unchoke),
+            2 => Some(PeerMessageId::Interested),
+            3 => Some(PeerMessageId::NotInterested),
+            4 => Some(PeerMessageId::Have),
+            5 => Some(PeerMessageId::Bitfield),
+            6 => Some(PeerMessageId::Request),
+            7 => Some(PeerMessageId::Piece),
+            8 => Some(PeerMessageId::Cancel),
+            9 => Some(PeerMessageId::Port),
+            13 => Some(PeerMessageId::SuggestPiece),
+            14 => Some(PeerMessageId::HaveAll),
+            15 => Some(PeerMessageId::HaveNone),
+            16 => Some(PeerMessageId::RejectRequest),
+            17 => Some(PeerMessageId::AllowedFast),
+            20 => Some(PeerMessageId::Extended),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have { index: u32 },
+    Bitfield { bitfield: Vec<u8> },
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port { port: u16 },
+    SuggestPiece { index: u32 },
+    HaveAll,
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast { index: u32 },
+    Extended { extended_id: u8, payload: Vec<u8> },
+    // Messages of the extensions we do not support are skipped instead of dropping the peer
+    Unknown { message_id: u8, payload: Vec<u8> }
+}
+
+impl PeerMessage {
+    // Length prefixed message as it is sent over the wire
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::new();
+        match self {
+            PeerMessage::KeepAlive => {},
+            PeerMessage::Choke => body.push(PeerMessageId::Choke as u8),
+            PeerMessage::Unchoke => body.push(PeerMessageId::Unchoke as u8),
+            PeerMessage::Interested => body.push(PeerMessageId::Interested as u8),
+            PeerMessage::NotInterested => body.push(PeerMessageId::NotInterested as u8),
+            PeerMessage::Have { index } => {
+                body.push(PeerMessageId::Have as u8);
+                body.extend(index.to_be_bytes());
+            },
+            PeerMessage::Bitfield { bitfield } => {
+                body.push(PeerMessageId::Bitfield as u8);
+                body.extend(bitfield);
+            },
+            PeerMessage::Request { index, begin, length } => {
+                body.push(PeerMessageId::Request as u8);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            },
+            PeerMessage::Piece { index, begin, block } => {
+                body.push(PeerMessageId::Piece as u8);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(block);
+            },
+            PeerMessage::Cancel { index, begin, length } => {
+                body.push(PeerMessageId::Cancel as u8);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            },
+            PeerMessage::Port { port } => {
+                body.push(PeerMessageId::Port as u8);
+                body.extend(port.to_be_bytes());
+            },
+            PeerMessage::SuggestPiece { index } => {
+                body.push(PeerMessageId::SuggestPiece as u8);
+                body.extend(index.to_be_bytes());
+            },
+            PeerMessage::HaveAll => body.push(PeerMessageId::HaveAll as u8),
+            PeerMessage::HaveNone => body.push(PeerMessageId::HaveNone as u8),
+            PeerMessage::RejectRequest { index, begin, length } => {
+                body.push(PeerMessageId::RejectRequest as u8);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            },
+            PeerMessage::AllowedFast { index } => {
+                body.push(PeerMessageId::AllowedFast as u8);
+                body.extend(index.to_be_bytes());
+            },
+            PeerMessage::Extended { extended_id, payload } => {
+                body.push(PeerMessageId::Extended as u8);
+                body.push(*extended_id);
+                body.extend(payload);
+            },
+            PeerMessage::Unknown { message_id, payload } => {
+                body.push(*message_id);
+                body.extend(payload);
+            }
+        }
+        let mut result: Vec<u8> = Vec::new();
+        result.extend((body.len() as u32).to_be_bytes());
+        result.extend(body);
+        result
+    }
+
+    // Parses the message without its length prefix, an empty message is a keep-alive
+    pub(crate) fn parse(message: &[u8]) -> Result<PeerMessage, anyhow::Error> {
+        let Some((&message_id, payload)) = message.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let Some(known_message_id) = PeerMessageId::lookup(message_id) else {
+            return Ok(PeerMessage::Unknown { message_id, payload: payload.to_vec() });
+        };
+        let expected_payload_length: Option<usize> = match known_message_id {
+            PeerMessageId::Choke | PeerMessageId::Unchoke | PeerMessageId::Interested | PeerMessageId::NotInterested
+                | PeerMessageId::HaveAll | PeerMessageId::HaveNone => Some(0),
+            PeerMessageId::Have | PeerMessageId::SuggestPiece | PeerMessageId::AllowedFast => Some(4),
+            PeerMessageId::Request | PeerMessageId::Cancel | PeerMessageId::RejectRequest => Some(12),
+            PeerMessageId::Port => Some(2),
+            PeerMessageId::Bitfield | PeerMessageId::Piece | PeerMessageId::Extended => None
+        };
+        if let Some(expected_payload_length) = expected_payload_length {
+            ensure!(payload.len() == expected_payload_length, "Peer message {:?} should have a payload of {} bytes, got {} bytes", known_message_id, expected_payload_length, payload.len());
+        }
+        Ok(match known_message_id {
+            PeerMessageId::Choke => PeerMessage::Choke,
+            PeerMessageId::Unchoke => PeerMessage::Unchoke,
+            PeerMessageId::Interested => PeerMessage::Interested,
+            PeerMessageId::NotInterested => PeerMessage::NotInterested,
+            PeerMessageId::Have => PeerMessage::Have { index: read_u32(payload, 0) },
+            PeerMessageId::Bitfield => PeerMessage::Bitfield { bitfield: payload.to_vec() },
+            PeerMessageId::Request => PeerMessage::Request {
+                index: read_u32(payload, 0),
+                begin: read_u32(payload, 4),
+                length: read_u32(payload, 8)
+            },
+            PeerMessageId::Piece => {
+                ensure!(payload.len() >= 8, "Not enough bytes in the 'piece' message {:?}", payload);
+                PeerMessage::Piece {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    block: payload[8..].to_vec()
+                }
+            },
+            PeerMessageId::Cancel => PeerMessage::Cancel {
+                index: read_u32(payload, 0),
+                begin: read_u32(payload, 4),
+                length: read_u32(payload, 8)
+            },
+            PeerMessageId::Port => PeerMessage::Port { port: u16::from_be_bytes([payload[0], payload[1]]) },
+            PeerMessageId::SuggestPiece => PeerMessage::SuggestPiece { index: read_u32(payload, 0) },
+            PeerMessageId::HaveAll => PeerMessage::HaveAll,
+            PeerMessageId::HaveNone => PeerMessage::HaveNone,
+            PeerMessageId::RejectRequest => PeerMessage::RejectRequest {
+                index: read_u32(payload, 0),
+                begin: read_u32(payload, 4),
+                length: read_u32(payload, 8)
+            },
+            PeerMessageId::AllowedFast => PeerMessage::AllowedFast { index: read_u32(payload, 0) },
+            PeerMessageId::Extended => {
+                ensure!(!payload.is_empty(), "Extended message without the extended message id");
+                PeerMessage::Extended { extended_id: payload[0], payload: payload[1..].to_vec() }
+            }
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_parse_every_message() {
+        let messages = vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { index: 7 },
+            PeerMessage::Bitfield { bitfield: vec![255, 128] },
+            PeerMessage::Request { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Piece { index: 1, begin: 16384, block: vec![1, 2, 3] },
+            PeerMessage::Cancel { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Port { port: 6881 },
+            PeerMessage::SuggestPiece { index: 3 },
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::AllowedFast { index: 5 },
+            PeerMessage::Extended { extended_id: 0, payload: b"d1:md6:ut_pexi1eee".to_vec() },
+            PeerMessage::Unknown { message_id: 42, payload: vec![1, 2] }
+        ];
+        for message in messages {
+            let bytes = message.get_bytes();
+            let length = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+            assert_eq!(length, bytes.len() - 4);
+            assert_eq!(PeerMessage::parse(&bytes[4..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn should_serialize_request_message_correctly() {
+        let request = PeerMessage::Request { index: 11, begin: 163840, length: 16384 };
+        assert_eq!(request.get_bytes(), vec![
+            0, 0, 0, 13,   // message length prefix - 13
+            6,             // message id byte - 6 “request”
+            0, 0, 0, 11,   // piece index - 11
+            0, 2, 128, 0,  // begin - 163840
+            0, 0, 64, 0    // length - 16384
+        ]);
+    }
+
+    #[test]
+    fn should_reject_messages_with_invalid_payload_length() {
+        assert!(PeerMessage::parse(&[4, 0, 0, 1]).is_err());
+        assert!(PeerMessage::parse(&[1, 0]).is_err());
+        assert!(PeerMessage::parse(&[6, 0, 0, 0, 1, 0, 0, 0, 0]).is_err());
+        assert!(PeerMessage::parse(&[7, 0, 0, 0, 1]).is_err());
+        assert!(PeerMessage::parse(&[9, 26]).is_err());
+        assert!(PeerMessage::parse(&[20]).is_err());
+        assert!(PeerMessage::parse(&[14, 0]).is_err());
+        assert!(PeerMessage::parse(&[17, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// Exchange of the info dictionary, which lets a torrent be downloaded knowing only its info hash https://www.bittorrent.org/beps/bep_0009.html
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::ensure;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use crate::bencoded::{self, BencodeEncoding};
+use crate::error::new_error;
+use crate::hash;
+use super::{Capabilities, ExtensionHandshake, ExtensionRegistry, MessageFramer, Peer, PeerExtensions, PeerHandshake, PeerMessage, DEFAULT_MAX_MESSAGE_LENGTH, EXTENSION_HANDSHAKE_ID};
+
+pub(crate) const METADATA_EXTENSION: &str = "ut_metadata";
+// The info dictionary is sent in pieces of 16 KiB, only the last one is shorter
+pub(crate) const METADATA_PIECE_LENGTH: usize = 16 * 1024;
+// Bounds the memory which a peer can make us use
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+const REQUEST_TYPE: i64 = 0;
+const DATA_TYPE: i64 = 1;
+const REJECT_TYPE: i64 = 2;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum MetadataMessage {
+    Request { piece: usize },
+    // The piece follows the bencoded dictionary
+    Data { piece: usize, total_size: usize, data: Vec<u8> },
+    Reject { piece: usize }
+}
+
+impl MetadataMessage {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (REQUEST_TYPE, piece),
+            MetadataMessage::Data { piece, .. } => (DATA_TYPE, piece),
+            MetadataMessage::Reject { piece } => (REJECT_TYPE, piece)
+        };
+        // The keys of a bencoded dictionary are sorted
+        let mut bencoded: Vec<u8> = Vec::new();
+        bencoded.push(b'd');
+        bencoded.encode_str("msg_type");
+        bencoded.encode_i64(&msg_type);
+        bencoded.encode_str("piece");
+        bencoded.encode_usize(piece);
+        if let MetadataMessage::Data { total_size, data, .. } = self {
+            bencoded.encode_str("total_size");
+            bencoded.encode_usize(total_size);
+            bencoded.push(b'e');
+            bencoded.extend_from_slice(data);
+        } else {
+            bencoded.push(b'e');
+        }
+        bencoded
+    }
+
+    pub(crate) fn parse(payload: &[u8]) -> Result<MetadataMessage, anyhow::Error> {
+        let (dictionary, length) = bencoded::decode_bencoded_prefix(payload)?;
+        let piece = usize::try_from(dictionary.get_by_key("piece")?.as_number()?)?;
+        match dictionary.get_by_key("msg_type")?.as_number()? {
+            REQUEST_TYPE => Ok(MetadataMessage::Request { piece }),
+            DATA_TYPE => Ok(MetadataMessage::Data {
+                piece,
+                total_size: usize::try_from(dictionary.get_by_key("total_size")?.as_number()?)?,
+                data: payload[length..].to_vec()
+            }),
+            REJECT_TYPE => Ok(MetadataMessage::Reject { piece }),
+            msg_type => Err(new_error(format!("Unknown metadata message type {}", msg_type)))
+        }
+    }
+}
+
+// Answer to a request for a piece of the info dictionary we have
+pub(crate) fn metadata_response(metadata: &[u8], piece: usize) -> MetadataMessage {
+    match metadata.chunks(METADATA_PIECE_LENGTH).nth(piece) {
+        Some(data) => MetadataMessage::Data { piece, total_size: metadata.len(), data: data.to_vec() },
+        None => MetadataMessage::Reject { piece }
+    }
+}
+
+// Downloads the info dictionary of the torrent from a peer, it is only returned when it matches the info hash
+pub(crate) async fn fetch_metadata(address: SocketAddr, info_hash: &[u8], peer_id: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    time::timeout(METADATA_TIMEOUT, exchange_metadata(address, info_hash, peer_id)).await?
+}
+
+async fn exchange_metadata(address: SocketAddr, info_hash: &[u8], peer_id: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut stream = TcpStream::connect(address).await?;
+    let request = PeerHandshake {
+        info_hash: info_hash.to_vec(),
+        peer: Peer { id: peer_id.to_vec() },
+        capabilities: Capabilities { extension_protocol: true, ..Capabilities::default() }
+    };
+    stream.write_all(&request.get_bytes()).await?;
+    let handshake = PeerHandshake::read_from_async(&mut stream, info_hash).await?;
+    ensure!(handshake.capabilities.extension_protocol, "Peer does not support the extension protocol");
+    let mut extensions = ExtensionRegistry::default();
+    let metadata_id = extensions.register(METADATA_EXTENSION);
+    stream.write_all(&PeerMessage::Extended { extended_id: EXTENSION_HANDSHAKE_ID, payload: extensions.handshake().get_bytes() }.get_bytes()).await?;
+
+    let mut framer = MessageFramer::new(DEFAULT_MAX_MESSAGE_LENGTH);
+    let mut read_buffer: [u8; 16 * 1024] = [0; 16 * 1024];
+    let mut pieces: Vec<Option<Vec<u8>>> = Vec::new();
+    let mut metadata_size = 0;
+    let mut peer_extensions = PeerExtensions::default();
+    loop {
+        let Some(message) = framer.next_message()? else {
+            let read_bytes = stream.read(&mut read_buffer).await?;
+            ensure!(read_bytes > 0, "Peer closed the connection");
+            framer.push(&read_buffer[0..read_bytes]);
+            continue;
+        };
+        match message {
+            PeerMessage::Extended { extended_id: EXTENSION_HANDSHAKE_ID, payload } if pieces.is_empty() => {
+                let peer_handshake = ExtensionHandshake::parse(&payload)?;
+                metadata_size = peer_handshake.metadata_size.ok_or(new_error("Peer did not tell the size of the metadata".to_string()))?;
+                ensure!(metadata_size > 0 && metadata_size <= MAX_METADATA_SIZE, "Peer has metadata of {} bytes", metadata_size);
+                peer_extensions = PeerExtensions::from_handshake(&peer_handshake);
+                pieces = vec![None; metadata_size.div_ceil(METADATA_PIECE_LENGTH)];
+                for piece in 0..pieces.len() {
+                    let request = peer_extensions.message(METADATA_EXTENSION, MetadataMessage::Request { piece }.get_bytes())
+                        .ok_or(new_error("Peer does not support ut_metadata".to_string()))?;
+                    stream.write_all(&request.get_bytes()).await?;
+                }
+            },
+            PeerMessage::Extended { extended_id, payload } if extended_id == metadata_id => match MetadataMessage::parse(&payload)? {
+                MetadataMessage::Data { piece, total_size, data } => {
+                    ensure!(total_size == metadata_size && piece < pieces.len(), "Peer sent unexpected metadata piece {} of {} bytes", piece, total_size);
+                    let expected_length = (metadata_size - piece * METADATA_PIECE_LENGTH).min(METADATA_PIECE_LENGTH);
+                    ensure!(data.len() == expected_length, "Metadata piece {} has {} bytes instead of {}", piece, data.len(), expected_length);
+                    pieces[piece] = Some(data);
+                    if pieces.iter().all(|piece| piece.is_some()) {
+                        let metadata: Vec<u8> = pieces.into_iter().flatten().flatten().collect();
+                        ensure!(hash::compute_hash(&metadata) == info_hash, "Metadata does not match the info hash");
+                        return Ok(metadata);
+                    }
+                },
+                MetadataMessage::Reject { piece } => return Err(new_error(format!("Peer rejected the request for metadata piece {}", piece))),
+                // We have no metadata to offer
+                MetadataMessage::Request { piece } => {
+                    if let Some(reject) = peer_extensions.message(METADATA_EXTENSION, MetadataMessage::Reject { piece }.get_bytes()) {
+                        stream.write_all(&reject.get_bytes()).await?;
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_parse_metadata_messages() {
+        let request = MetadataMessage::Request { piece: 0 };
+        assert_eq!(request.get_bytes(), b"d8:msg_typei0e5:piecei0ee".to_vec());
+        assert_eq!(MetadataMessage::parse(&request.get_bytes()).unwrap(), request);
+        let data = MetadataMessage::Data { piece: 1, total_size: 16390, data: b"d4:name1:ae".to_vec() };
+        assert_eq!(data.get_bytes(), b"d8:msg_typei1e5:piecei1e10:total_sizei16390eed4:name1:ae".to_vec());
+        assert_eq!(MetadataMessage::parse(&data.get_bytes()).unwrap(), data);
+        let reject = MetadataMessage::Reject { piece: 2 };
+        assert_eq!(MetadataMessage::parse(&reject.get_bytes()).unwrap(), reject);
+        assert!(MetadataMessage::parse(b"d8:msg_typei3e5:piecei0ee").is_err());
+        assert!(MetadataMessage::parse(b"d8:msg_typei0ee").is_err());
+    }
+
+    #[test]
+    fn should_answer_requests_with_pieces_of_metadata() {
+        let metadata = vec![7; METADATA_PIECE_LENGTH + 10];
+        assert_eq!(metadata_response(&metadata, 0), MetadataMessage::Data { piece: 0, total_size: metadata.len(), data: vec![7; METADATA_PIECE_LENGTH] });
+        assert_eq!(metadata_response(&metadata, 1), MetadataMessage::Data { piece: 1, total_size: metadata.len(), data: vec![7; 10] });
+        assert_eq!(metadata_response(&metadata, 2), MetadataMessage::Reject { piece: 2 });
+    }
+}
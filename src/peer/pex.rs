@@ -0,0 +1,100 @@
+// Peer exchange https://www.bittorrent.org/beps/bep_0011.html
+use std::net::IpAddr;
+use crate::bencoded::{self, BencodeEncoding};
+use crate::tracker;
+use super::PeerAddress;
+
+pub(crate) const PEX_EXTENSION: &str = "ut_pex";
+// Peers are exchanged at most once a minute, a message lists at most this many added and dropped peers
+pub(crate) const MAX_PEX_PEERS: usize = 50;
+
+// Flags of an added peer
+pub(crate) const PEX_FLAG_SEED: u8 = 0x02;
+// We connected to the peer, so it accepts incoming connections
+pub(crate) const PEX_FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PexMessage {
+    pub(crate) added: Vec<(PeerAddress, u8)>,
+    pub(crate) dropped: Vec<PeerAddress>
+}
+
+impl PexMessage {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let (added, added6): (Vec<_>, Vec<_>) = self.added.iter().partition(|(address, _)| address.address.is_ipv4());
+        let (dropped, dropped6): (Vec<_>, Vec<_>) = self.dropped.iter().partition(|address| address.address.is_ipv4());
+        // The keys of a bencoded dictionary are sorted
+        let mut bencoded: Vec<u8> = Vec::new();
+        bencoded.push(b'd');
+        bencoded.encode_str("added");
+        bencoded.encode_bytes(&compact_peers(added.iter().map(|(address, _)| address)));
+        bencoded.encode_str("added.f");
+        bencoded.encode_bytes(&added.iter().map(|(_, flags)| *flags).collect::<Vec<u8>>());
+        bencoded.encode_str("added6");
+        bencoded.encode_bytes(&compact_peers(added6.iter().map(|(address, _)| address)));
+        bencoded.encode_str("added6.f");
+        bencoded.encode_bytes(&added6.iter().map(|(_, flags)| *flags).collect::<Vec<u8>>());
+        bencoded.encode_str("dropped");
+        bencoded.encode_bytes(&compact_peers(dropped.into_iter()));
+        bencoded.encode_str("dropped6");
+        bencoded.encode_bytes(&compact_peers(dropped6.into_iter()));
+        bencoded.push(b'e');
+        bencoded
+    }
+
+    // Missing keys are empty lists, the flags of the added peers are optional
+    pub(crate) fn parse(payload: &[u8]) -> Result<PexMessage, anyhow::Error> {
+        let message = bencoded::decode_bencoded_from_bytes(payload)?;
+        let bytes = |key: &str| message.get_optional_by_key(key).and_then(|value| value.as_bytes().ok()).unwrap_or_default();
+        let mut added = Vec::new();
+        for (peers, flags) in [(tracker::parse_compact_peers_v4(&bytes("added"))?, bytes("added.f")), (tracker::parse_compact_peers_v6(&bytes("added6"))?, bytes("added6.f"))] {
+            added.extend(peers.into_iter().enumerate().map(|(position, address)| (address, flags.get(position).copied().unwrap_or(0))));
+        }
+        let mut dropped = tracker::parse_compact_peers_v4(&bytes("dropped"))?;
+        dropped.extend(tracker::parse_compact_peers_v6(&bytes("dropped6"))?);
+        Ok(PexMessage { added, dropped })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+}
+
+fn compact_peers<'a>(addresses: impl Iterator<Item = &'a PeerAddress>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for address in addresses {
+        match address.address {
+            IpAddr::V4(ip) => bytes.extend(ip.octets()),
+            IpAddr::V6(ip) => bytes.extend(ip.octets())
+        }
+        bytes.extend(address.port.to_be_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(input: &str) -> PeerAddress {
+        let address: std::net::SocketAddr = input.parse().unwrap();
+        PeerAddress { address: address.ip(), port: address.port() }
+    }
+
+    #[test]
+    fn should_encode_and_parse_pex_message() {
+        let message = PexMessage {
+            added: vec![(address("10.0.0.1:6881"), PEX_FLAG_REACHABLE), (address("[::1]:6882"), PEX_FLAG_SEED | PEX_FLAG_REACHABLE)],
+            dropped: vec![address("10.0.0.2:6883")]
+        };
+        let bytes = message.get_bytes();
+        assert_eq!(bytes, b"d5:added6:\x0a\x00\x00\x01\x1a\xe17:added.f1:\x106:added618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe28:added6.f1:\x127:dropped6:\x0a\x00\x00\x02\x1a\xe38:dropped60:e".to_vec());
+        assert_eq!(PexMessage::parse(&bytes).unwrap(), message);
+
+        let without_flags = PexMessage::parse(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(without_flags.added, vec![(address("10.0.0.1:6881"), 0)]);
+        assert!(without_flags.dropped.is_empty());
+        assert!(PexMessage::parse(b"de").unwrap().is_empty());
+        assert!(PexMessage::parse(b"d5:added5:12345e").is_err());
+    }
+}
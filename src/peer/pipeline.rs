@@ -0,0 +1,203 @@
+// Keeps enough block requests in flight to a peer to use the whole bandwidth of the connection
+use std::time::{Duration, Instant};
+use crate::piece_picker::BlockRequest;
+
+// Number of requests before anything is known about the peer
+const MIN_OUTSTANDING_REQUESTS: usize = 5;
+// Used by the peers which do not advertise 'reqq' in the extension handshake https://www.bittorrent.org/beps/bep_0010.html
+const MAX_OUTSTANDING_REQUESTS: usize = 250;
+// The throughput is re-estimated once per window
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+const BLOCK_LENGTH: usize = 16 * 1024;
+// A block which did not arrive in this time is requested from another peer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// A peer which has our requests, but sends no blocks for this long is snubbing us
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct OutstandingRequest {
+    request: BlockRequest,
+    sent_at: Instant
+}
+
+pub(crate) struct RequestPipeline {
+    outstanding: Vec<OutstandingRequest>,
+    // Maximum number of outstanding requests the peer accepts, the 'reqq' of the extension handshake
+    peer_limit: Option<usize>,
+    // The lowest time between sending a request and receiving its block, approximates the round trip time
+    min_latency: Option<Duration>,
+    // Bytes per second
+    throughput: f64,
+    window_start: Instant,
+    window_bytes: usize,
+    // When the peer last sent a block or got requests after having none
+    last_progress: Instant,
+    snubbed: bool
+}
+
+impl RequestPipeline {
+    pub(crate) fn new(now: Instant) -> RequestPipeline {
+        RequestPipeline {
+            outstanding: Vec::new(),
+            peer_limit: None,
+            min_latency: None,
+            throughput: 0.0,
+            window_start: now,
+            window_bytes: 0,
+            last_progress: now,
+            snubbed: false
+        }
+    }
+
+    // Twice the bandwidth-delay product, so that the queue keeps growing while the latency rather than the link limits the throughput
+    pub(crate) fn target_depth(&self) -> usize {
+        if self.snubbed {
+            return 1;
+        }
+        let bandwidth_delay_blocks = match self.min_latency {
+            Some(latency) => (self.throughput * latency.as_secs_f64() / BLOCK_LENGTH as f64).ceil() as usize,
+            None => 0
+        };
+        let limit = self.peer_limit.unwrap_or(MAX_OUTSTANDING_REQUESTS).min(MAX_OUTSTANDING_REQUESTS);
+        (2 * bandwidth_delay_blocks).max(MIN_OUTSTANDING_REQUESTS).min(limit)
+    }
+
+    pub(crate) fn free_slots(&self) -> usize {
+        self.target_depth().saturating_sub(self.outstanding.len())
+    }
+
+    pub(crate) fn set_peer_limit(&mut self, request_queue_length: usize) {
+        self.peer_limit = Some(request_queue_length.max(1));
+    }
+
+    pub(crate) fn sent(&mut self, request: BlockRequest, now: Instant) {
+        if self.outstanding.is_empty() {
+            self.last_progress = now;
+        }
+        self.outstanding.push(OutstandingRequest { request, sent_at: now });
+    }
+
+    // Returns whether the block was requested from this peer
+    pub(crate) fn received(&mut self, index: u32, begin: u32, length: usize, now: Instant) -> bool {
+        let Some(position) = self.outstanding.iter().position(|outstanding| outstanding.request.index == index && outstanding.request.begin == begin) else {
+            return false;
+        };
+        let outstanding = self.outstanding.remove(position);
+        self.last_progress = now;
+        self.snubbed = false;
+        let latency = now.saturating_duration_since(outstanding.sent_at);
+        self.min_latency = Some(self.min_latency.map_or(latency, |min_latency| min_latency.min(latency)));
+        self.window_bytes += length;
+        let window = now.saturating_duration_since(self.window_start);
+        if window >= THROUGHPUT_WINDOW {
+            let window_throughput = self.window_bytes as f64 / window.as_secs_f64();
+            self.throughput = if self.throughput == 0.0 { window_throughput } else { (self.throughput + window_throughput) / 2.0 };
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        true
+    }
+
+    // Removes the requests which the peer did not answer in time
+    pub(crate) fn take_timed_out(&mut self, now: Instant) -> Vec<BlockRequest> {
+        let (timed_out, outstanding): (Vec<OutstandingRequest>, Vec<OutstandingRequest>) = self.outstanding.drain(..)
+            .partition(|outstanding| now.saturating_duration_since(outstanding.sent_at) >= REQUEST_TIMEOUT);
+        self.outstanding = outstanding;
+        timed_out.into_iter().map(|outstanding| outstanding.request).collect()
+    }
+
+    // Returns true for every snub timeout the peer keeps our requests without sending a block, until it sends one
+    // only a single request is kept in flight
+    pub(crate) fn check_snubbed(&mut self, now: Instant) -> bool {
+        if self.outstanding.is_empty() || now.saturating_duration_since(self.last_progress) < SNUB_TIMEOUT {
+            return false;
+        }
+        self.snubbed = true;
+        self.last_progress = now;
+        true
+    }
+
+    pub(crate) fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    pub(crate) fn remove(&mut self, request: &BlockRequest) {
+        self.outstanding.retain(|outstanding| outstanding.request != *request);
+    }
+
+    // A choking peer discards all of our requests
+    pub(crate) fn clear(&mut self) {
+        self.outstanding.clear();
+    }
+
+    pub(crate) fn requests(&self) -> impl Iterator<Item = &BlockRequest> {
+        self.outstanding.iter().map(|outstanding| &outstanding.request)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(begin: u32) -> BlockRequest {
+        BlockRequest { index: 0, begin, length: BLOCK_LENGTH as u32 }
+    }
+
+    #[test]
+    fn should_grow_queue_with_throughput_and_latency() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(start);
+        assert_eq!(pipeline.target_depth(), MIN_OUTSTANDING_REQUESTS);
+
+        // 100 blocks per second with a round trip of 100 ms: 10 blocks are in flight
+        for block in 0..100 {
+            let sent_at = start + Duration::from_millis(10 * block);
+            pipeline.sent(request(block as u32), sent_at);
+            assert!(pipeline.received(0, block as u32, BLOCK_LENGTH, sent_at + Duration::from_millis(100)));
+        }
+        assert!(pipeline.target_depth() >= 18 && pipeline.target_depth() <= 22, "{}", pipeline.target_depth());
+        assert!(!pipeline.received(0, 5, BLOCK_LENGTH, start));
+    }
+
+    #[test]
+    fn should_honour_request_queue_length_of_peer() {
+        let now = Instant::now();
+        let mut pipeline = RequestPipeline::new(now);
+        pipeline.set_peer_limit(2);
+        assert_eq!(pipeline.free_slots(), 2);
+        pipeline.sent(request(0), now);
+        assert_eq!(pipeline.free_slots(), 1);
+        pipeline.sent(request(1), now);
+        assert_eq!(pipeline.free_slots(), 0);
+        pipeline.remove(&request(0));
+        assert_eq!(pipeline.requests().collect::<Vec<_>>(), vec![&request(1)]);
+        pipeline.clear();
+        assert!(pipeline.is_empty());
+    }
+
+    #[test]
+    fn should_time_out_requests_and_detect_snubbing() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(start);
+        pipeline.sent(request(0), start);
+        pipeline.sent(request(1), start + Duration::from_secs(10));
+        assert!(pipeline.take_timed_out(start + Duration::from_secs(29)).is_empty());
+        assert_eq!(pipeline.take_timed_out(start + REQUEST_TIMEOUT), vec![request(0)]);
+        assert!(!pipeline.check_snubbed(start + Duration::from_secs(59)));
+
+        assert!(pipeline.check_snubbed(start + SNUB_TIMEOUT));
+        assert!(!pipeline.check_snubbed(start + SNUB_TIMEOUT));
+        assert_eq!(pipeline.target_depth(), 1);
+        assert!(pipeline.received(0, 1, BLOCK_LENGTH, start + SNUB_TIMEOUT));
+        assert_eq!(pipeline.target_depth(), MIN_OUTSTANDING_REQUESTS);
+
+        // Without requests the peer has no reason to send anything
+        assert!(!pipeline.check_snubbed(start + 10 * SNUB_TIMEOUT));
+        pipeline.sent(request(2), start + 10 * SNUB_TIMEOUT);
+        assert!(pipeline.check_snubbed(start + 11 * SNUB_TIMEOUT));
+        assert!(pipeline.check_snubbed(start + 12 * SNUB_TIMEOUT));
+    }
+}
@@ -0,0 +1,454 @@
+// Decides which blocks to request next from a peer https://www.bittorrent.org/beps/bep_0003.html#peer-messages
+use std::collections::BTreeMap;
+use anyhow::ensure;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use crate::bitfield::Bitfield;
+use crate::error::new_error;
+use crate::peer::{Peer, Piece, PieceBlock};
+
+// Until this many pieces are verified random pieces are picked, so that there is soon something to upload to the other peers
+const RANDOM_FIRST_PIECE_COUNT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PieceState {
+    NotWanted,
+    Missing,
+    // Some of the blocks are requested or received
+    Partial,
+    // All the blocks are received and the piece waits for its hash to be checked
+    Complete,
+    Verified
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BlockRequest {
+    pub(crate) index: u32,
+    pub(crate) begin: u32,
+    pub(crate) length: u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BlockState {
+    Missing,
+    // Several peers are asked for the same block in the endgame
+    Requested(Vec<Peer>),
+    Received
+}
+
+// Piece which is being filled with the blocks received from possibly several peers
+struct PartialPiece {
+    blocks: Vec<PieceBlock>,
+    block_states: Vec<BlockState>,
+    data: Vec<u8>
+}
+
+impl PartialPiece {
+    fn new(piece: &Piece) -> PartialPiece {
+        let blocks = piece.get_blocks(piece.piece_length);
+        PartialPiece {
+            block_states: vec![BlockState::Missing; blocks.len()],
+            blocks,
+            data: vec![0; piece.piece_length as usize]
+        }
+    }
+
+    fn has_missing_blocks(&self) -> bool {
+        self.block_states.contains(&BlockState::Missing)
+    }
+
+    fn request_missing_blocks(&mut self, index: u32, peer: &Peer, count: usize) -> Vec<BlockRequest> {
+        let mut requests = Vec::new();
+        for (block, state) in self.blocks.iter().zip(self.block_states.iter_mut()) {
+            if requests.len() == count {
+                break;
+            }
+            if *state == BlockState::Missing {
+                *state = BlockState::Requested(vec![peer.clone()]);
+                requests.push(BlockRequest { index, begin: block.begin, length: block.length });
+            }
+        }
+        requests
+    }
+
+    fn request_duplicate_blocks(&mut self, index: u32, peer: &Peer, count: usize) -> Vec<BlockRequest> {
+        let mut requests = Vec::new();
+        for (block, state) in self.blocks.iter().zip(self.block_states.iter_mut()) {
+            if requests.len() == count {
+                break;
+            }
+            if let BlockState::Requested(peers) = state {
+                if !peers.contains(peer) {
+                    peers.push(peer.clone());
+                    requests.push(BlockRequest { index, begin: block.begin, length: block.length });
+                }
+            }
+        }
+        requests
+    }
+}
+
+pub(crate) struct PiecePicker {
+    pieces: Vec<Option<Piece>>,
+    states: Vec<PieceState>,
+    partial_pieces: BTreeMap<usize, PartialPiece>,
+    // How many of the connected peers have each of the pieces
+    availability: Vec<u32>,
+    verified_count: usize
+}
+
+impl PiecePicker {
+    pub(crate) fn new(piece_count: usize, wanted_pieces: Vec<Piece>) -> PiecePicker {
+        let mut pieces: Vec<Option<Piece>> = vec![None; piece_count];
+        let mut states = vec![PieceState::NotWanted; piece_count];
+        for piece in wanted_pieces {
+            let index = piece.index as usize;
+            states[index] = PieceState::Missing;
+            pieces[index] = Some(piece);
+        }
+        PiecePicker {
+            pieces,
+            states,
+            partial_pieces: BTreeMap::new(),
+            availability: vec![0; piece_count],
+            verified_count: 0
+        }
+    }
+
+    pub(crate) fn add_peer(&mut self, peer_pieces: &Bitfield) {
+        for index in peer_pieces.piece_indices() {
+            self.availability[index] += 1;
+        }
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer_pieces: &Bitfield) {
+        for index in peer_pieces.piece_indices() {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+
+    pub(crate) fn add_have(&mut self, index: usize) -> Result<(), anyhow::Error> {
+        ensure!(index < self.availability.len(), "Piece index {} is out of range, there are {} pieces", index, self.availability.len());
+        self.availability[index] += 1;
+        Ok(())
+    }
+
+    // Blocks of the partial pieces are requested first, then a new piece is started: the rarest one, ties are broken randomly
+    pub(crate) fn pick_blocks(&mut self, peer: &Peer, peer_pieces: &Bitfield, count: usize, rng: &mut impl Rng) -> Vec<BlockRequest> {
+        let mut requests = Vec::new();
+        while requests.len() < count {
+            let partial: Vec<usize> = self.partial_pieces.iter()
+                .filter(|(index, partial_piece)| peer_pieces.has(**index) && partial_piece.has_missing_blocks())
+                .map(|(index, _)| *index)
+                .collect();
+            let index = if !partial.is_empty() {
+                self.choose_rarest(&partial, rng)
+            } else {
+                self.start_piece(peer_pieces, rng)
+            };
+            let Some(index) = index else {
+                break;
+            };
+            let partial_piece = self.partial_pieces.get_mut(&index).unwrap();
+            requests.extend(partial_piece.request_missing_blocks(index as u32, peer, count - requests.len()));
+        }
+        if self.is_endgame() {
+            for (index, partial_piece) in self.partial_pieces.iter_mut() {
+                if requests.len() == count {
+                    break;
+                }
+                if peer_pieces.has(*index) {
+                    requests.extend(partial_piece.request_duplicate_blocks(*index as u32, peer, count - requests.len()));
+                }
+            }
+        }
+        requests
+    }
+
+    // Every remaining block is already requested, so the blocks still in flight are requested from all the peers which have them
+    pub(crate) fn is_endgame(&self) -> bool {
+        !self.states.contains(&PieceState::Missing)
+            && !self.partial_pieces.is_empty()
+            && self.partial_pieces.values().all(|partial_piece| !partial_piece.has_missing_blocks())
+    }
+
+    // A requested block which is no longer needed was received from another peer, the request should then be cancelled
+    pub(crate) fn is_block_needed(&self, request: &BlockRequest) -> bool {
+        match self.partial_pieces.get(&(request.index as usize)) {
+            Some(partial_piece) => partial_piece.blocks.iter().zip(partial_piece.block_states.iter())
+                .any(|(block, state)| block.begin == request.begin && *state != BlockState::Received),
+            None => false
+        }
+    }
+
+    // Returns the data of the piece once all of its blocks are received, the piece hash should then be checked
+    pub(crate) fn block_received(&mut self, index: usize, begin: u32, block: &[u8]) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let Some(partial_piece) = self.partial_pieces.get_mut(&index) else {
+            return Ok(None);
+        };
+        let position = partial_piece.blocks.iter().position(|piece_block| piece_block.begin == begin)
+            .ok_or(new_error(format!("Piece {} does not have a block starting at {}", index, begin)))?;
+        let length = partial_piece.blocks[position].length as usize;
+        ensure!(block.len() == length, "Block {} of piece {} should have {} bytes, got {} bytes", begin, index, length, block.len());
+        if partial_piece.block_states[position] == BlockState::Received {
+            return Ok(None);
+        }
+        partial_piece.data[begin as usize..begin as usize + length].copy_from_slice(block);
+        partial_piece.block_states[position] = BlockState::Received;
+        if partial_piece.block_states.iter().all(|state| *state == BlockState::Received) {
+            let partial_piece = self.partial_pieces.remove(&index).unwrap();
+            self.states[index] = PieceState::Complete;
+            return Ok(Some(partial_piece.data));
+        }
+        Ok(None)
+    }
+
+    // The blocks requested from a peer which choked us or disconnected can be requested from the other peers
+    pub(crate) fn release_blocks(&mut self, peer: &Peer) {
+        for partial_piece in self.partial_pieces.values_mut() {
+            for state in partial_piece.block_states.iter_mut() {
+                if let BlockState::Requested(peers) = state {
+                    peers.retain(|requested_from| requested_from != peer);
+                    if peers.is_empty() {
+                        *state = BlockState::Missing;
+                    }
+                }
+            }
+        }
+    }
+
+    // A block which the peer did not send in time can be requested from the other peers
+    pub(crate) fn release_block(&mut self, peer: &Peer, request: &BlockRequest) {
+        let Some(partial_piece) = self.partial_pieces.get_mut(&(request.index as usize)) else {
+            return;
+        };
+        for (block, state) in partial_piece.blocks.iter().zip(partial_piece.block_states.iter_mut()) {
+            if let (true, BlockState::Requested(peers)) = (block.begin == request.begin, &mut *state) {
+                peers.retain(|requested_from| requested_from != peer);
+                if peers.is_empty() {
+                    *state = BlockState::Missing;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn verified(&mut self, index: usize) {
+        if self.states[index] != PieceState::Verified {
+            self.states[index] = PieceState::Verified;
+            self.verified_count += 1;
+        }
+    }
+
+    // The piece did not match its hash and is downloaded again from scratch
+    pub(crate) fn failed(&mut self, index: usize) {
+        if self.states[index] == PieceState::Complete {
+            self.states[index] = PieceState::Missing;
+        }
+    }
+
+    // Whether the peer has some of the pieces which we still need
+    pub(crate) fn is_interesting(&self, peer_pieces: &Bitfield) -> bool {
+        (0..self.states.len()).any(|index| matches!(self.states[index], PieceState::Missing | PieceState::Partial) && peer_pieces.has(index))
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.states.iter().all(|state| matches!(state, PieceState::NotWanted | PieceState::Verified))
+    }
+
+    fn start_piece(&mut self, peer_pieces: &Bitfield, rng: &mut impl Rng) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.states.len())
+            .filter(|index| self.states[*index] == PieceState::Missing && peer_pieces.has(*index))
+            .collect();
+        let index = if self.verified_count < RANDOM_FIRST_PIECE_COUNT {
+            candidates.choose(rng).copied()
+        } else {
+            self.choose_rarest(&candidates, rng)
+        }?;
+        let piece = self.pieces[index].as_ref()?;
+        self.partial_pieces.insert(index, PartialPiece::new(piece));
+        self.states[index] = PieceState::Partial;
+        Some(index)
+    }
+
+    fn choose_rarest(&self, candidates: &[usize], rng: &mut impl Rng) -> Option<usize> {
+        let lowest_availability = candidates.iter().map(|index| self.availability[*index]).min()?;
+        let rarest: Vec<usize> = candidates.iter().copied()
+            .filter(|index| self.availability[*index] == lowest_availability)
+            .collect();
+        rarest.choose(rng).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    const BLOCK_SIZE: u32 = 16 * 1024;
+
+    fn pieces(count: u32, piece_length: u32) -> Vec<Piece> {
+        (0..count).map(|index| Piece { index, piece_length }).collect()
+    }
+
+    fn bitfield(piece_count: usize, indices: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(piece_count);
+        for index in indices {
+            bitfield.set(*index).unwrap();
+        }
+        bitfield
+    }
+
+    fn peer(id: u8) -> Peer {
+        Peer { id: vec![id; 20] }
+    }
+
+    fn picker_past_random_first_pieces(piece_count: u32) -> PiecePicker {
+        let mut picker = PiecePicker::new(piece_count as usize + RANDOM_FIRST_PIECE_COUNT, pieces(piece_count + RANDOM_FIRST_PIECE_COUNT as u32, 16));
+        for index in piece_count as usize..piece_count as usize + RANDOM_FIRST_PIECE_COUNT {
+            picker.verified(index);
+        }
+        picker
+    }
+
+    fn pick_piece(picker: &mut PiecePicker, peer_pieces: &Bitfield, rng: &mut StdRng) -> Option<u32> {
+        picker.pick_blocks(&peer(1), peer_pieces, 1, rng).first().map(|request| request.index)
+    }
+
+    #[test]
+    fn should_pick_rarest_piece_the_peer_has() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = picker_past_random_first_pieces(4);
+        picker.add_peer(&bitfield(8, &[0, 1, 2, 3]));
+        picker.add_peer(&bitfield(8, &[0, 1, 3]));
+        picker.add_peer(&bitfield(8, &[1, 3]));
+        picker.add_have(2).unwrap();
+        picker.add_have(2).unwrap();
+
+        let peer_pieces = bitfield(8, &[0, 1, 2, 3]);
+        assert_eq!(pick_piece(&mut picker, &peer_pieces, &mut rng), Some(0));
+        assert_eq!(pick_piece(&mut picker, &peer_pieces, &mut rng), Some(2));
+
+        picker.remove_peer(&bitfield(8, &[1, 3]));
+        picker.remove_peer(&bitfield(8, &[0, 1, 3]));
+        let last_two = [pick_piece(&mut picker, &peer_pieces, &mut rng).unwrap(), pick_piece(&mut picker, &peer_pieces, &mut rng).unwrap()];
+        assert!(last_two.contains(&1) && last_two.contains(&3));
+        assert_eq!(pick_piece(&mut picker, &peer_pieces, &mut rng), None);
+    }
+
+    #[test]
+    fn should_break_ties_randomly() {
+        let mut picked = HashSet::new();
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut picker = picker_past_random_first_pieces(4);
+            picked.insert(pick_piece(&mut picker, &bitfield(8, &[0, 1, 2, 3]), &mut rng).unwrap());
+        }
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn should_pick_random_pieces_until_first_pieces_are_verified() {
+        let mut picked = HashSet::new();
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut picker = PiecePicker::new(4, pieces(4, 16));
+            // Piece 0 is the rarest, but the first pieces are random
+            picker.add_peer(&bitfield(4, &[0, 1, 2, 3]));
+            picker.add_peer(&bitfield(4, &[1, 2, 3]));
+            picked.insert(pick_piece(&mut picker, &bitfield(4, &[0, 1, 2, 3]), &mut rng).unwrap());
+        }
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn should_share_blocks_of_a_piece_between_peers() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(2, vec![Piece { index: 1, piece_length: 2 * BLOCK_SIZE + 10 }]);
+        let peer_pieces = bitfield(2, &[0, 1]);
+        assert!(picker.is_interesting(&peer_pieces));
+        assert!(!picker.is_interesting(&bitfield(2, &[0])));
+
+        let first_peer_blocks = picker.pick_blocks(&peer(1), &peer_pieces, 2, &mut rng);
+        assert_eq!(first_peer_blocks, vec![
+            BlockRequest { index: 1, begin: 0, length: BLOCK_SIZE },
+            BlockRequest { index: 1, begin: BLOCK_SIZE, length: BLOCK_SIZE }
+        ]);
+        let second_peer_blocks = picker.pick_blocks(&peer(2), &peer_pieces, 1, &mut rng);
+        assert_eq!(second_peer_blocks, vec![BlockRequest { index: 1, begin: 2 * BLOCK_SIZE, length: 10 }]);
+
+        assert_eq!(picker.block_received(1, 2 * BLOCK_SIZE, &[3; 10]).unwrap(), None);
+        assert!(picker.block_received(1, 2 * BLOCK_SIZE, &[3; 9]).is_err());
+        assert!(picker.block_received(1, 5, &[3; 10]).is_err());
+        assert_eq!(picker.block_received(1, 0, &[1; BLOCK_SIZE as usize]).unwrap(), None);
+        let data = picker.block_received(1, BLOCK_SIZE, &[2; BLOCK_SIZE as usize]).unwrap().unwrap();
+        assert_eq!(data.len(), 2 * BLOCK_SIZE as usize + 10);
+        assert_eq!((data[0], data[BLOCK_SIZE as usize], data[2 * BLOCK_SIZE as usize]), (1, 2, 3));
+        assert!(!picker.is_complete());
+
+        picker.failed(1);
+        assert_eq!(picker.pick_blocks(&peer(2), &peer_pieces, 5, &mut rng).len(), 3);
+        picker.verified(1);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn should_release_blocks_of_a_peer_and_prefer_partial_pieces() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(3, pieces(3, 2 * BLOCK_SIZE));
+        let peer_pieces = bitfield(3, &[0, 1, 2]);
+        let first_peer_blocks = picker.pick_blocks(&peer(1), &peer_pieces, 1, &mut rng);
+        let partial_index = first_peer_blocks[0].index;
+        assert_eq!(picker.pick_blocks(&peer(2), &peer_pieces, 1, &mut rng)[0].index, partial_index);
+        let other_blocks = picker.pick_blocks(&peer(2), &peer_pieces, 1, &mut rng);
+        assert_ne!(other_blocks[0].index, partial_index);
+
+        picker.release_blocks(&peer(1));
+        assert_eq!(picker.pick_blocks(&peer(3), &bitfield(3, &[partial_index as usize]), 2, &mut rng), first_peer_blocks);
+    }
+
+    #[test]
+    fn should_request_remaining_blocks_from_several_peers_in_endgame() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(2, pieces(2, 2 * BLOCK_SIZE));
+        let peer_pieces = bitfield(2, &[0, 1]);
+        let first_peer_blocks = picker.pick_blocks(&peer(1), &peer_pieces, 3, &mut rng);
+        assert_eq!(first_peer_blocks.len(), 3);
+        assert!(!picker.is_endgame());
+        let second_peer_blocks = picker.pick_blocks(&peer(2), &peer_pieces, 1, &mut rng);
+        assert_eq!(second_peer_blocks.len(), 1);
+        assert!(picker.is_endgame());
+
+        // Only the blocks which were not requested from the peer yet
+        let duplicates = picker.pick_blocks(&peer(2), &peer_pieces, 10, &mut rng);
+        assert_eq!(duplicates.len(), 3);
+        assert!(duplicates.iter().all(|block| first_peer_blocks.contains(block)));
+        assert!(picker.pick_blocks(&peer(2), &peer_pieces, 10, &mut rng).is_empty());
+        assert_eq!(picker.pick_blocks(&peer(3), &bitfield(2, &[0]), 10, &mut rng).len(), 2);
+
+        let received = &duplicates[0];
+        assert!(picker.is_block_needed(received));
+        picker.block_received(received.index as usize, received.begin, &vec![0; received.length as usize]).unwrap();
+        assert!(!picker.is_block_needed(received));
+        // The duplicate 'piece' message from the other peer is ignored
+        assert_eq!(picker.block_received(received.index as usize, received.begin, &vec![0; received.length as usize]).unwrap(), None);
+
+        // Only the block which no other peer was asked for goes back to the pool, the others stay requested from the other peers
+        picker.release_blocks(&peer(2));
+        assert_eq!(picker.pick_blocks(&peer(1), &peer_pieces, 10, &mut rng), second_peer_blocks);
+    }
+
+    #[test]
+    fn should_release_a_single_block() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(1, pieces(1, 2 * BLOCK_SIZE));
+        let peer_pieces = bitfield(1, &[0]);
+        let blocks = picker.pick_blocks(&peer(1), &peer_pieces, 2, &mut rng);
+        picker.release_block(&peer(2), &blocks[1]);
+        assert_eq!(picker.pick_blocks(&peer(2), &peer_pieces, 2, &mut rng).len(), 2);
+        picker.release_block(&peer(1), &blocks[1]);
+        picker.release_block(&peer(2), &blocks[1]);
+        assert_eq!(picker.pick_blocks(&peer(3), &peer_pieces, 1, &mut rng), vec![blocks[1].clone()]);
+    }
+}
@@ -1,5 +1,5 @@
-use anyhow::Result;
-use crate::bencoded::BencodeEncoding;
+use anyhow::{ensure, Result};
+use crate::bencoded::{BencodeEncoding, Value};
 use crate::peer;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -15,35 +15,81 @@ pub struct TorrentInfo {
     pub piece_length: usize,
     pub length: Option<usize>,
     pub files: Option<Vec<TorrentFileInfo>>,
+    // Peers of a private torrent only come from its trackers https://www.bittorrent.org/beps/bep_0027.html
+    pub private: Option<i64>
 }
 
 impl TorrentInfo {
     const PIECE_HASH_SIZE: usize = 20;
 
+    fn from_value(info: &Value) -> Result<TorrentInfo, anyhow::Error> {
+        let name = info.get_by_key("name")?.as_string()?;
+        let pieces = info.get_by_key("pieces")?.as_bytes()?;
+        let piece_length = info.get_by_key("piece length")?.as_number()? as usize;
+        let length: Option<usize> = info.get_optional_by_key("length").and_then(|x| x.as_number().ok()).map(|x| x as usize);
+        let private: Option<i64> = info.get_optional_by_key("private").and_then(|x| x.as_number().ok());
+        let mut torrent_file_infos: Vec<TorrentFileInfo> = Vec::new();
+        if let Some(files) = info.get_optional_by_key("files").and_then(|x| x.as_values().ok()) {
+            for file in files {
+                let file_length = file.get_by_key("length")?.as_number()? as usize;
+                let mut path_parts: Vec<String> = Vec::new();
+                let path_values = file.get_by_key("path")?.as_values()?;
+                for path_value in path_values {
+                    path_parts.push(path_value.as_string()?);
+                }
+                torrent_file_infos.push(TorrentFileInfo {
+                    length: file_length,
+                    path: path_parts
+                })
+            }
+        }
+
+        Ok(TorrentInfo {
+            name,
+            pieces,
+            piece_length,
+            length,
+            files: if torrent_file_infos.is_empty() {
+                None
+            } else {
+                Some(torrent_file_infos)
+            },
+            private
+        })
+    }
+
+    // The info dictionary alone, as the peers send it https://www.bittorrent.org/beps/bep_0009.html
+    pub(crate) fn from_bytes(info_bytes: &[u8]) -> Result<TorrentInfo, anyhow::Error> {
+        TorrentInfo::from_value(&crate::bencoded::decode_bencoded_from_bytes(info_bytes)?)
+    }
+
     pub(crate) fn piece_hashes(&self) -> Vec<&[u8]> {
         self.pieces.chunks(TorrentInfo::PIECE_HASH_SIZE).collect()
     }
 
     pub(crate) fn total_piece_number(&self) -> usize {
         let piece_length = self.piece_length;
-        (self.length.unwrap_or(0) + piece_length - 1) / piece_length
+        self.length.unwrap_or(0).div_ceil(piece_length)
+    }
+
+    pub(crate) fn is_private(&self) -> bool {
+        self.private == Some(1)
     }
 
     pub(crate) fn get_all_pieces(&self) -> Vec<peer::Piece> {
         (0..self.total_piece_number()).map(|piece_index| {
             let piece_length_to_download = self.piece_length_at_index(piece_index as u32).unwrap();
-            let piece = peer::Piece {
+            peer::Piece {
                 index: piece_index as u32,
                 piece_length: piece_length_to_download
-            };
-            piece
+            }
         }).collect()
     }
 
     pub(crate) fn piece_length_at_index(&self, piece_index: u32) -> Result<u32, anyhow::Error> {
         let total_piece_number = self.total_piece_number();
         if piece_index >= total_piece_number as u32 {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Invalid piece index {:?}, total number of pieces {:?}", piece_index, total_piece_number)).into())
+            Err(std::io::Error::other(format!("Invalid piece index {:?}, total number of pieces {:?}", piece_index, total_piece_number)).into())
         } else {
             if piece_index + 1 == total_piece_number as u32 {
                 let last_piece_length = self.length.unwrap_or(0) - self.piece_length * (total_piece_number - 1);
@@ -67,6 +113,10 @@ impl TorrentInfo {
         bencoded.encode_usize(&self.piece_length);
         bencoded.encode_str("pieces");
         bencoded.encode_bytes(&self.pieces);
+        if let Some(private) = self.private {
+            bencoded.encode_str("private");
+            bencoded.encode_i64(&private);
+        }
         bencoded.push(b'e');
         bencoded
     }
@@ -76,56 +126,66 @@ impl TorrentInfo {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Torrent {
     pub announce: String,
-    //TODO: Also parse the announce-list, as all the trackers from that list can be contacted
+    // Tiers of the trackers https://www.bittorrent.org/beps/bep_0012.html
+    pub announce_list: Option<Vec<Vec<String>>>,
+    // DHT nodes to join through, trackerless torrents only have these https://www.bittorrent.org/beps/bep_0005.html#torrent-file-extensions
+    pub nodes: Option<Vec<(String, u16)>>,
     pub info: TorrentInfo
 }
 
 impl Torrent {
 
-    pub fn from_bytes(torrent_bytes: &Vec<u8>) -> Result<Torrent, anyhow::Error> {
+    pub fn from_bytes(torrent_bytes: &[u8]) -> Result<Torrent, anyhow::Error> {
         let chars: Vec<char> = torrent_bytes.iter().map(|b| *b as char).collect();
         let decoded = crate::bencoded::decode_bencoded(&chars)?;
-        let announce = decoded.get_by_key("announce")?.as_string()?;
-        let info = decoded.get_by_key("info")?;
-        let name = info.get_by_key("name")?.as_string()?;
-        let pieces = info.get_by_key("pieces")?.as_bytes()?;
-        let piece_length = info.get_by_key("piece length")?.as_number()? as usize;
-        let length: Option<usize> = info.get_optional_by_key("length").and_then(|x| x.as_number().ok()).map(|x| x as usize);
-        let mut torrent_file_infos: Vec<TorrentFileInfo> = Vec::new();
-        if let Some(files) = info.get_optional_by_key("files").and_then(|x| x.as_values().ok()) {
-            for file in files {
-                let file_length = file.get_by_key("length")?.as_number()? as usize;
-                let mut path_parts: Vec<String> = Vec::new();
-                let path_values = file.get_by_key("path")?.as_values()?;
-                for path_value in path_values {
-                    path_parts.push(path_value.as_string()?);
+        let announce = decoded.get_optional_by_key("announce").map(|announce| announce.as_string()).transpose()?.unwrap_or_default();
+        let announce_list = match decoded.get_optional_by_key("announce-list") {
+            Some(tiers) => {
+                let mut announce_list: Vec<Vec<String>> = Vec::new();
+                for tier in tiers.as_values()? {
+                    let mut tier_urls: Vec<String> = Vec::new();
+                    for url in tier.as_values()? {
+                        tier_urls.push(url.as_string()?);
+                    }
+                    announce_list.push(tier_urls);
                 }
-                torrent_file_infos.push(TorrentFileInfo {
-                    length: file_length,
-                    path: path_parts
-                })
-            }
-        }
-
-        Ok(Torrent {
-            announce: announce,
-            info: TorrentInfo {
-                name: name,
-                pieces: pieces,
-                piece_length: piece_length,
-                length: length,
-                files: if torrent_file_infos.is_empty() {
-                    None
-                } else {
-                    Some(torrent_file_infos)
+                Some(announce_list)
+            },
+            None => None
+        };
+        let nodes = match decoded.get_optional_by_key("nodes") {
+            Some(nodes) => {
+                let mut host_ports: Vec<(String, u16)> = Vec::new();
+                for node in nodes.as_values()? {
+                    let host_port = node.as_values()?;
+                    ensure!(host_port.len() == 2, "DHT node {:?} is not a host and a port", host_port);
+                    host_ports.push((host_port[0].as_string()?, u16::try_from(host_port[1].as_number()?)?));
                 }
-            }
+                Some(host_ports)
+            },
+            None => None
+        };
+        let info = TorrentInfo::from_value(decoded.get_by_key("info")?)?;
+        Ok(Torrent {
+            announce,
+            announce_list,
+            nodes,
+            info
         })
     }
 
+    // The announce-list takes precedence over the announce URL when it is present
+    pub(crate) fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(announce_list) if !announce_list.is_empty() => announce_list.clone(),
+            _ if self.announce.is_empty() => Vec::new(),
+            _ => vec![vec![self.announce.clone()]]
+        }
+    }
+
     pub fn parse_torrent(torrent_file_path: &str) -> Result<Torrent, anyhow::Error> {
         let torrent_file_bytes = std::fs::read(torrent_file_path)?;
         Torrent::from_bytes(&torrent_file_bytes)
@@ -140,7 +200,7 @@ mod tests {
     #[test]
     fn read_torrent_from_bytes() {
         let input = "d8:announce55:http://bittorrent-test-tracker.codecrafters.io/announce10:created by13:mktorrent 1.14:infod6:lengthi92063e4:name10:sample.txt12:piece lengthi32768e6:pieces20:00000000000000000000ee";
-        let torrent = Torrent::from_bytes(&input.as_bytes().to_vec()).unwrap();
+        let torrent = Torrent::from_bytes(input.as_bytes()).unwrap();
         assert_eq!(torrent.announce, "http://bittorrent-test-tracker.codecrafters.io/announce");
         assert_eq!(torrent.info.name, "sample.txt");
         assert_eq!(torrent.info.length, Some(92063));
@@ -149,18 +209,47 @@ mod tests {
         assert_eq!(torrent.info.pieces, "00000000000000000000".as_bytes());
     }
 
+    #[test]
+    fn read_announce_list_from_bytes() {
+        let input = "d8:announce17:http://a/announce13:announce-listll17:http://a/announce21:udp://b:6969/announceel17:http://c/announceee4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:00000000000000000000ee";
+        let torrent = Torrent::from_bytes(input.as_bytes()).unwrap();
+        assert_eq!(torrent.tracker_tiers(), vec![
+            vec!["http://a/announce".to_string(), "udp://b:6969/announce".to_string()],
+            vec!["http://c/announce".to_string()]
+        ]);
+
+        let input_without_announce_list = "d8:announce17:http://a/announce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:00000000000000000000ee";
+        let torrent_without_announce_list = Torrent::from_bytes(input_without_announce_list.as_bytes()).unwrap();
+        assert_eq!(torrent_without_announce_list.announce_list, None);
+        assert_eq!(torrent_without_announce_list.tracker_tiers(), vec![vec!["http://a/announce".to_string()]]);
+    }
+
+    #[test]
+    fn read_trackerless_torrent_from_bytes() {
+        let input = "d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:00000000000000000000e5:nodesll9:127.0.0.1i6881eel15:dht.example.comi6882eeee";
+        let torrent = Torrent::from_bytes(input.as_bytes()).unwrap();
+        assert_eq!(torrent.nodes, Some(vec![("127.0.0.1".to_string(), 6881), ("dht.example.com".to_string(), 6882)]));
+        assert!(torrent.tracker_tiers().is_empty());
+    }
+
     #[test]
     fn bencode_torrent_info() {
         let input = "d8:announce55:http://bittorrent-test-tracker.codecrafters.io/announce10:created by13:mktorrent 1.14:infod6:lengthi92063e4:name10:sample.txt12:piece lengthi32768e6:pieces20:00000000000000000000ee";
-        let torrent = Torrent::from_bytes(&input.as_bytes().to_vec()).unwrap();
+        let torrent = Torrent::from_bytes(input.as_bytes()).unwrap();
         let expected_torrent_info = "d6:lengthi92063e4:name10:sample.txt12:piece lengthi32768e6:pieces20:00000000000000000000e";
-        assert_eq!(String::from_utf8(torrent.info.bencode()).unwrap(), expected_torrent_info)
+        assert_eq!(String::from_utf8(torrent.info.bencode()).unwrap(), expected_torrent_info);
+        assert!(!torrent.info.is_private());
+
+        let private_input = "d8:announce17:http://a/announce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:000000000000000000007:privatei1eee";
+        let private_torrent = Torrent::from_bytes(private_input.as_bytes()).unwrap();
+        assert!(private_torrent.info.is_private());
+        assert_eq!(String::from_utf8(private_torrent.info.bencode()).unwrap(), "d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:000000000000000000007:privatei1ee");
     }
 
     #[test]
     fn compute_hash() {
         let input = "d8:announce55:http://bittorrent-test-tracker.codecrafters.io/announce10:created by13:mktorrent 1.14:infod6:lengthi92063e4:name10:sample.txt12:piece lengthi32768e6:pieces20:00000000000000000000ee";
-        let torrent = Torrent::from_bytes(&input.as_bytes().to_vec()).unwrap();
+        let torrent = Torrent::from_bytes(input.as_bytes()).unwrap();
         assert_eq!(format::format_as_hex_string(&torrent.info.compute_hash()), "e68d67c4b84274f741d7293fc0657102a36e7e3b");
     }
 }
\ No newline at end of file
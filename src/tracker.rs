@@ -1,195 +1,588 @@
-use std::net::UdpSocket;
-use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, SocketAddr};
-use std::time::Duration;
-use anyhow::ensure;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
 use crate::bencoded;
-use crate::torrent;
-use crate::tracker;
+use crate::config::ClientConfig;
 use crate::peer;
 use crate::url_utils;
 use crate::error::new_error;
-use messages::ConnectRequest;
-use messages::ConnectResponse;
+use messages::{Action, AnnounceRequest, AnnounceResponse, ConnectRequest, ConnectResponse, ScrapeRequest, ScrapeResponse};
+pub(crate) use messages::{AnnounceEvent, ScrapeStatistics};
+pub(crate) use status::SwarmTrackers;
+use udp::UdpTrackerSocket;
 use url::Url;
 
 mod messages;
+pub(crate) mod server;
+pub(crate) mod status;
+pub(crate) mod udp;
+
+// Upper bound for a single tracker to answer, an unresponsive tracker should not hold up the others
+pub(crate) const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
 
 pub(crate) struct TrackerResponse {
-    interval: u32,
-    peers: Vec<u8>
+    pub(crate) interval: u32,
+    peers: Vec<u8>,
+    peers6: Vec<u8>,
+    pub(crate) seeders: Option<u32>,
+    pub(crate) leechers: Option<u32>,
+    pub(crate) warning: Option<String>
 }
 
+#[derive(Clone)]
 pub(crate) struct Tracker {
     pub(crate) url: String
 }
 
+#[derive(Clone)]
 pub(crate) struct TrackerRequest {
     pub(crate) peer_id: String,
-    pub(crate) info_hash: String,
+    pub(crate) info_hash: Vec<u8>,
     pub(crate) port: usize,
     pub(crate) uploaded: u64,
     pub(crate) downloaded: u64,
     pub(crate) left: u64,
-    pub(crate) compact: bool
+    pub(crate) compact: bool,
+    pub(crate) key: u32,
+    pub(crate) num_want: Option<u32>,
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) no_peer_id: bool,
+    pub(crate) event: AnnounceEvent
+}
+
+impl TrackerRequest {
+    pub(crate) fn new(peer_id: &str, info_hash: Vec<u8>, left: u64, config: &ClientConfig) -> TrackerRequest {
+        TrackerRequest {
+            peer_id: peer_id.to_string(),
+            info_hash,
+            port: config.port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: true,
+            key: config.tracker_key,
+            num_want: config.num_want,
+            ip: config.announce_ip,
+            no_peer_id: config.no_peer_id,
+            event: AnnounceEvent::None
+        }
+    }
 }
 
 impl TrackerResponse {
     pub(crate) fn get_peer_addresses(&self) -> Result<Vec<peer::PeerAddress>, anyhow::Error> {
-        if self.peers.len() % 6 == 0 {
-            Ok(self.peers.chunks(6).into_iter().map(|peer| {
-                let address = IpAddr::V4(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]));
-                let port = (peer[5] as u16) | (peer[4] as u16) << 8;
-                peer::PeerAddress { address, port }
-            }).collect())
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Peers field size is not a multiple of 6, {:?}", self.peers)).into())
-        }
+        let mut peer_addresses = parse_compact_peers_v4(&self.peers)?;
+        peer_addresses.extend(parse_compact_peers_v6(&self.peers6)?);
+        Ok(peer_addresses)
+    }
+}
+
+pub(crate) fn parse_compact_peers_v4(peers: &[u8]) -> Result<Vec<peer::PeerAddress>, anyhow::Error> {
+    if peers.len().is_multiple_of(6) {
+        Ok(peers.chunks(6).map(|peer| {
+            let address = IpAddr::V4(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]));
+            let port = (peer[5] as u16) | (peer[4] as u16) << 8;
+            peer::PeerAddress { address, port }
+        }).collect())
+    } else {
+        Err(std::io::Error::other(format!("Peers field size is not a multiple of 6, {:?}", peers)).into())
+    }
+}
+
+pub(crate) fn parse_compact_peers_v6(peers: &[u8]) -> Result<Vec<peer::PeerAddress>, anyhow::Error> {
+    if peers.len().is_multiple_of(18) {
+        Ok(peers.chunks(18).map(|peer| {
+            let address_bytes: [u8; 16] = peer[0..16].try_into().unwrap();
+            let address = IpAddr::V6(Ipv6Addr::from(address_bytes));
+            let port = (peer[17] as u16) | (peer[16] as u16) << 8;
+            peer::PeerAddress { address, port }
+        }).collect())
+    } else {
+        Err(std::io::Error::other(format!("Peers6 field size is not a multiple of 18, {:?}", peers)).into())
     }
 }
 
 impl Tracker {
 
-    pub(crate) fn join_swarm(current_peer_id: &str, port: usize, torrent: &torrent::Torrent) -> Result<Vec<peer::PeerAddress>, anyhow::Error> {
-        let torrent_hash = torrent.info.compute_hash();
-        //TODO: Some trackers might refuse "Connect" requests or be offline, try several trackers associated with the torrent and find the one which works
-        let tracker = tracker::Tracker { url: torrent.announce.clone() };
-    
-        let request = tracker::TrackerRequest {
-            peer_id: current_peer_id.to_string(),
-            info_hash: url_utils::url_encode_bytes(&torrent_hash),
-            port,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.info.length.unwrap_or(0) as u64,
-            compact: true
+    // Announces to the trackers which are due and records their statuses, returns the peers from all the trackers which answered.
+    // The UDP socket is bound once for the whole session, so that the trackers see the same port at every announce
+    pub(crate) async fn announce_to_swarm(trackers: &mut SwarmTrackers, request: &TrackerRequest, udp_socket: &Arc<UdpTrackerSocket>) -> Vec<peer::PeerAddress> {
+        let mut announcement = trackers.announce_due(request, udp_socket, TRACKER_TIMEOUT, Instant::now());
+        let mut peer_addresses: Vec<peer::PeerAddress> = Vec::new();
+        while let Some(tracker_announce) = announcement.next().await {
+            if let Err(error) = &tracker_announce.result {
+                println!("Could not announce to tracker {}: {}", tracker_announce.url, error);
+            }
+            for peer_address in trackers.record(&tracker_announce, Instant::now()) {
+                if !peer_addresses.contains(&peer_address) {
+                    peer_addresses.push(peer_address);
+                }
+            }
+        }
+        peer_addresses
+    }
+
+    pub(crate) async fn get(&self, request: &TrackerRequest, udp_socket: &UdpTrackerSocket) -> Result<TrackerResponse, anyhow::Error> {
+        if self.url.starts_with("http") {
+            self.get_http(request).await
+        } else if self.url.starts_with("udp") {
+            self.get_udp(request, udp_socket).await
+        } else {
+            Err(std::io::Error::other(format!("Unknown URL scheme, only HTTP and UDP are supported, {:?}", self.url)).into())
+        }
+    }
+
+    async fn get_udp(&self, request: &TrackerRequest, udp_socket: &UdpTrackerSocket) -> Result<TrackerResponse, anyhow::Error> {
+        let tracker_address = self.udp_tracker_address().await?;
+        println!("tracker_address = {}, local address = {}", tracker_address, udp_socket.local_addr()?);
+        let connection_id = Tracker::udp_connect(tracker_address, udp_socket).await?;
+
+        let announce_request = AnnounceRequest {
+            connection_id,
+            action: Action::Announce,
+            transaction_id: rand::random::<u32>(),
+            info_hash: request.info_hash.clone(),
+            peer_id: request.peer_id.as_bytes().to_vec(),
+            downloaded: request.downloaded,
+            left: request.left,
+            uploaded: request.uploaded,
+            event: request.event,
+            // The IP address field only fits an IPv4 address https://www.bittorrent.org/beps/bep_0015.html
+            ip_address: match request.ip {
+                Some(IpAddr::V4(ip)) => u32::from(ip),
+                _ => 0
+            },
+            key: request.key,
+            num_want: request.num_want.map(|num_want| num_want.min(i32::MAX as u32) as i32).unwrap_or(-1),
+            port: request.port as u16,
+            url_data: self.udp_url_data()?
         };
-        //TODO: Go over the list of announce-list and try getting peer addresses from every of the listed servers if announce-list is present
-        let response = tracker.get(&tracker.url, &request)?;
-        response.get_peer_addresses()
+        let announce_response_bytes = udp_socket.request(&announce_request.get_bytes(), tracker_address, announce_request.transaction_id).await?;
+        let announce_response = AnnounceResponse::parse(&announce_response_bytes)?;
+        println!("Received 'announce' response with {} seeders and {} leechers", announce_response.seeders, announce_response.leechers);
+
+        let (peers, peers6) = if tracker_address.is_ipv4() {
+            (announce_response.peers, Vec::new())
+        } else {
+            (Vec::new(), announce_response.peers)
+        };
+        Ok(TrackerResponse {
+            interval: announce_response.interval,
+            peers,
+            peers6,
+            seeders: Some(announce_response.seeders),
+            leechers: Some(announce_response.leechers),
+            warning: None
+        })
     }
 
-    pub(crate) fn get(&self, url: &str, request: &TrackerRequest) -> Result<TrackerResponse, anyhow::Error> {
+    pub(crate) async fn scrape(&self, info_hash: &[u8], udp_socket: &UdpTrackerSocket) -> Result<ScrapeStatistics, anyhow::Error> {
         if self.url.starts_with("http") {
-            self.get_http(url, request)
+            self.scrape_http(info_hash).await
         } else if self.url.starts_with("udp") {
-            self.get_udp(url, request)
+            self.scrape_udp(info_hash, udp_socket).await
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Unknown URL scheme, only HTTP and UDP are supported, {:?}", self.url)).into())
+            Err(std::io::Error::other(format!("Unknown URL scheme, only HTTP and UDP are supported, {:?}", self.url)).into())
         }
     }
 
-    fn get_udp(&self, url: &str, request: &TrackerRequest) -> Result<TrackerResponse, anyhow::Error> {
-        //TODO: Some trackers just do not respond to the UDP "Connect" request, return an Error in this case
-        let socket: UdpSocket = UdpSocket::bind("0.0.0.0:6969")?;
-        socket.set_read_timeout(Some(Duration::new(2, 0)))?;
+    async fn scrape_udp(&self, info_hash: &[u8], udp_socket: &UdpTrackerSocket) -> Result<ScrapeStatistics, anyhow::Error> {
+        let tracker_address = self.udp_tracker_address().await?;
+        let connection_id = Tracker::udp_connect(tracker_address, udp_socket).await?;
+        let scrape_request = ScrapeRequest {
+            connection_id,
+            action: Action::Scrape,
+            transaction_id: rand::random::<u32>(),
+            info_hashes: vec![info_hash.to_vec()],
+            url_data: self.udp_url_data()?
+        };
+        let scrape_response_bytes = udp_socket.request(&scrape_request.get_bytes(), tracker_address, scrape_request.transaction_id).await?;
+        let scrape_response = ScrapeResponse::parse(&scrape_response_bytes)?;
+        scrape_response.torrents.first().copied()
+            .ok_or(new_error(format!("UDP tracker {} returned no statistics for the scraped torrent", self.url)))
+    }
+
+    async fn scrape_http(&self, info_hash: &[u8]) -> Result<ScrapeStatistics, anyhow::Error> {
+        // Following the convention https://wiki.theory.org/BitTorrentSpecification#Tracker_.27scrape.27_Convention
+        let mut scrape_url = Url::parse(&self.url)?;
+        let (path_prefix, last_path_segment) = scrape_url.path().rsplit_once('/').unwrap_or(("", scrape_url.path()));
+        if !last_path_segment.starts_with("announce") {
+            return Err(new_error(format!("Tracker {} does not support scraping", self.url)));
+        }
+        let scrape_path = format!("{}/{}", path_prefix, last_path_segment.replacen("announce", "scrape", 1));
+        scrape_url.set_path(&scrape_path);
+        let url_with_params = build_announce_url(scrape_url.as_str(), &format!("info_hash={}", url_utils::url_encode_bytes(info_hash)))?;
+
+        let response = reqwest::get(url_with_params).await?;
+        if response.status().is_success() {
+            let decoded = bencoded::decode_bencoded_from_bytes(&response.bytes().await?)?;
+            let info_hash_key: String = info_hash.iter().map(|byte| *byte as char).collect();
+            let statistics = decoded.get_by_key("files")?.get_by_key(&info_hash_key)?;
+            Ok(ScrapeStatistics {
+                seeders: statistics.get_by_key("complete")?.as_number()? as u32,
+                completed: statistics.get_by_key("downloaded")?.as_number()? as u32,
+                leechers: statistics.get_by_key("incomplete")?.as_number()? as u32
+            })
+        } else {
+            Err(std::io::Error::other(format!("Got response {}", &response.status())).into())
+        }
+    }
 
-        let tracker_url = Url::parse(url)?;
+    async fn udp_tracker_address(&self) -> Result<SocketAddr, anyhow::Error> {
+        let tracker_url = Url::parse(&self.url)?;
 
         let tracker_host = tracker_url.host_str().ok_or(new_error(format!("Could not parse host from url {}", tracker_url)))?;
         let tracker_port: u16 = tracker_url.port().ok_or(new_error(format!("Could not parse port from url {}", tracker_url)))?;
 
-        let tracker_address: SocketAddr = (tracker_host, tracker_port).to_socket_addrs()?.next()
+        let tracker_address: SocketAddr = tokio::net::lookup_host((tracker_host, tracker_port)).await?.next()
             .ok_or(new_error(format!("Could not resolve as SocketAddr {}", tracker_url)))?;
+        Ok(tracker_address)
+    }
 
-        println!("tracker_address = {}", tracker_address);
-
-        let connect_request = ConnectRequest::new(rand::random::<u32>());
-        let bytes_sent = socket.send_to(&connect_request.get_bytes(), tracker_address)?;
-        println!("Sent 'connect' request {:?}, bytes sent = {}", connect_request, bytes_sent);
-
-        let mut response_buf: [u8; 16] = [0; 16];
-        let max_connect_response_connect_attempts = 5;
-        let mut current_connect_attempt = 1;
-        let mut received_connect_response = false;
-        while !received_connect_response && current_connect_attempt <= max_connect_response_connect_attempts {
-            match socket.recv_from(&mut response_buf) {
-                Ok((bytes_read, _)) => {
-                    println!("Received {} bytes in Connect responst", bytes_read);
-                    received_connect_response = true;
-                }
-                Err(_) => {
-                    println!("Will try to connect again, failed so far... UDP tracker {}", &self.url);
-                    socket.send_to(&connect_request.get_bytes(), tracker_address)?;
-                    current_connect_attempt = current_connect_attempt + 1;
-                }
-            };
-        }
-        if received_connect_response {
-            let connect_response = ConnectResponse::parse(&response_buf)?;
-            println!("Received 'connect' response {:?}", connect_response);
-            ensure!(connect_response.transaction_id == connect_request.transaction_id,
-                "transaction_id in 'connect' request was {}, transaction_id in 'connect' response was {}, they should match but did not",
-                connect_request.transaction_id,
-                connect_response.transaction_id
-            );
-            //TODO: Check that the transaction id matches in the request and response
-            let connection_id = connect_response.connection_id;
-        } else {
-            println!("Failed to connect to UDP tracker {}", &self.url);
+    // Path and query of the UDP tracker URL, used by private trackers for authentication https://www.bittorrent.org/beps/bep_0041.html
+    fn udp_url_data(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let tracker_url = Url::parse(&self.url)?;
+        let mut url_data = tracker_url.path().to_string();
+        if let Some(query) = tracker_url.query() {
+            url_data.push('?');
+            url_data.push_str(query);
         }
+        Ok(url_data.into_bytes())
+    }
 
-        //Following the spec https://www.bittorrent.org/beps/bep_0015.html
-        //TODO: Send the "connect" request
-        //TODO: Receive the response from the "connect" request, read and store the connection_id
-        //TODO: Send the "announce" request
-        //TODO: Receive the "announce" response and parse the list of peers from its bytes
-        Err(std::io::Error::new(std::io::ErrorKind::Other, format!("UDP support is not yet implemented, url = {}", self.url)).into())
+    //Following the spec https://www.bittorrent.org/beps/bep_0015.html
+    async fn udp_connect(tracker_address: SocketAddr, udp_socket: &UdpTrackerSocket) -> Result<u64, anyhow::Error> {
+        let connect_request = ConnectRequest::new(rand::random::<u32>());
+        let connect_response_bytes = udp_socket.request(&connect_request.get_bytes(), tracker_address, connect_request.transaction_id).await?;
+        let connect_response = ConnectResponse::parse(&connect_response_bytes)?;
+        println!("Received 'connect' response {:?}", connect_response);
+        Ok(connect_response.connection_id)
     }
 
-    fn get_http(&self, url: &str, request: &TrackerRequest) -> Result<TrackerResponse, anyhow::Error> {
-        let client = reqwest::blocking::Client::new();
-        let rest_of_params = [
+    async fn get_http(&self, request: &TrackerRequest) -> Result<TrackerResponse, anyhow::Error> {
+        let client = reqwest::Client::new();
+        let url = &self.url;
+        let mut rest_of_params = vec![
             ("peer_id", request.peer_id.to_string()),
             ("port", request.port.to_string()),
             ("uploaded", request.uploaded.to_string()),
             ("downloaded", request.downloaded.to_string()),
             ("left", request.left.to_string()),
-            ("compact", (if request.compact { "1" } else { "0" }).to_string())
+            ("compact", (if request.compact { "1" } else { "0" }).to_string()),
+            ("key", format!("{:08x}", request.key))
         ];
+        if let Some(num_want) = request.num_want {
+            rest_of_params.push(("numwant", num_want.to_string()));
+        }
+        if let Some(ip) = request.ip {
+            rest_of_params.push(("ip", ip.to_string()));
+        }
+        if request.no_peer_id {
+            rest_of_params.push(("no_peer_id", "1".to_string()));
+        }
+        if let Some(event) = request.event.http_param() {
+            rest_of_params.push(("event", event.to_string()));
+        }
         let url_encoded_rest_of_params = serde_urlencoded::to_string(rest_of_params)?;
-        let url_with_params = format!("{}?{}&info_hash={}", url, url_encoded_rest_of_params, request.info_hash);
+        let url_encoded_params = format!("{}&info_hash={}", url_encoded_rest_of_params, url_utils::url_encode_bytes(&request.info_hash));
+        let url_with_params = build_announce_url(url, &url_encoded_params)?;
         let response = client.get(url_with_params)
-            .send()?;
+            .send().await?;
 
         if response.status().is_success() {
-            let response_chars = response.bytes()?.to_vec();
+            let response_chars = response.bytes().await?.to_vec();
             let decoded = bencoded::decode_bencoded_from_bytes(&response_chars)?;
+            if let Some(failure_reason) = decoded.get_optional_by_key("failure reason") {
+                return Err(new_error(format!("Tracker {} responded with failure: {}", url, failure_reason.as_string()?)));
+            }
             let interval = decoded.get_by_key("interval")?.as_number()? as u32;
-            let peers = decoded.get_by_key("peers")?.as_bytes()?;
+            let peers = decoded.get_optional_by_key("peers").map(|peers| peers.as_bytes()).transpose()?.unwrap_or_default();
+            let peers6 = decoded.get_optional_by_key("peers6").map(|peers| peers.as_bytes()).transpose()?.unwrap_or_default();
+            let seeders = decoded.get_optional_by_key("complete").map(|seeders| seeders.as_number()).transpose()?.map(|seeders| seeders as u32);
+            let leechers = decoded.get_optional_by_key("incomplete").map(|leechers| leechers.as_number()).transpose()?.map(|leechers| leechers as u32);
+            let warning = decoded.get_optional_by_key("warning message").map(|warning| warning.as_string()).transpose()?;
             Ok(TrackerResponse {
                 interval,
-                peers
+                peers,
+                peers6,
+                seeders,
+                leechers,
+                warning
             })
         } else {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Got response {}", &response.status())).into())
+            Err(std::io::Error::other(format!("Got response {}", &response.status())).into())
         }
     }
 }
 
-//TODO: Add tests
+// Outcome of the announce to one of the trackers of the torrent
+pub(crate) struct TrackerAnnounce {
+    pub(crate) url: String,
+    pub(crate) result: Result<TrackerResponse, anyhow::Error>
+}
+
+// Announces to the trackers of all the tiers at once instead of trying the tiers one after another
+// https://www.bittorrent.org/beps/bep_0012.html, the responses are yielded in the order the trackers answer.
+// Dropping the announcement cancels the announces to the trackers which did not answer yet
+pub(crate) struct SwarmAnnouncement {
+    announces: JoinSet<TrackerAnnounce>
+}
+
+impl SwarmAnnouncement {
+    pub(crate) fn start(tiers: &[Vec<String>], request: &TrackerRequest, udp_socket: &Arc<UdpTrackerSocket>, tracker_timeout: Duration) -> SwarmAnnouncement {
+        let mut announces: JoinSet<TrackerAnnounce> = JoinSet::new();
+        let mut announced_urls: HashSet<&String> = HashSet::new();
+        for url in tiers.iter().flatten() {
+            if !announced_urls.insert(url) {
+                continue;
+            }
+            let tracker = Tracker { url: url.clone() };
+            let request = request.clone();
+            let udp_socket = Arc::clone(udp_socket);
+            announces.spawn(async move {
+                let result = match tokio::time::timeout(tracker_timeout, tracker.get(&request, &udp_socket)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(new_error(format!("Tracker {} did not respond within {:?}", tracker.url, tracker_timeout)))
+                };
+                TrackerAnnounce {
+                    url: tracker.url,
+                    result
+                }
+            });
+        }
+        SwarmAnnouncement { announces }
+    }
+
+    // Waits for the next tracker to answer, None once all the trackers answered or the announcement was cancelled
+    pub(crate) async fn next(&mut self) -> Option<TrackerAnnounce> {
+        while let Some(joined) = self.announces.join_next().await {
+            if let Ok(tracker_announce) = joined {
+                return Some(tracker_announce);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn cancel(&mut self) {
+        self.announces.abort_all();
+    }
+}
+
+// Keeps the parameters already present in the announce URL, such as a passkey of a private tracker
+fn build_announce_url(url: &str, url_encoded_params: &str) -> Result<String, anyhow::Error> {
+    let mut announce_url = Url::parse(url)?;
+    let query = match announce_url.query() {
+        Some(existing_query) if !existing_query.is_empty() => format!("{}&{}", existing_query, url_encoded_params),
+        _ => url_encoded_params.to_string()
+    };
+    announce_url.set_query(Some(&query));
+    Ok(announce_url.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{TcpListener, UdpSocket};
+    use server::{TrackerServer, TrackerServerConfig};
 
-    #[test]
-    fn should_send_connect_request_and_receive_connect_response() {
-        //This tracker responds to the connection request as expected (as debugged with Wireshark and local Transmission)
-        let tracker_url = "udp://93.158.213.92:1337";
-        let tracker = Tracker {
-            //url: "udp://tracker.openbittorrent.com:80/announce".to_string(),
-            //url: "udp://tracker.coppersurfer.tk:6969".to_string(),
-            url: tracker_url.to_string(),
-            //url: "udp://107.150.14.110:6969".to_string()
-        };
-        let tracker_request = tracker::TrackerRequest {
-            peer_id: "todo".to_string(),
-            info_hash: "todo".to_string(),
-            port: 6969,
+    fn tracker_request(peer_id: &str, port: usize) -> TrackerRequest {
+        TrackerRequest {
+            peer_id: peer_id.to_string(),
+            info_hash: vec![7; 20],
+            port,
             uploaded: 0,
             downloaded: 0,
             left: 9999,
-            compact: true
-        };
-        let response = tracker.get_udp(&tracker.url,&tracker_request).unwrap();
+            compact: true,
+            key: 1,
+            num_want: None,
+            ip: None,
+            no_peer_id: false,
+            event: AnnounceEvent::None
+        }
+    }
+
+    #[tokio::test]
+    async fn should_announce_to_local_http_tracker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker_url = format!("http://{}/announce", listener.local_addr().unwrap());
+        TrackerServer::new(TrackerServerConfig::default()).serve_http(listener);
+        let tracker = Tracker { url: tracker_url };
+        let udp_socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let first_response = tracker.get(&tracker_request("00000000000000000001", 6881), &udp_socket).await.unwrap();
+        assert_eq!(first_response.get_peer_addresses().unwrap().len(), 0);
+
+        let second_response = tracker.get(&tracker_request("00000000000000000002", 6882), &udp_socket).await.unwrap();
+        let peer_addresses = second_response.get_peer_addresses().unwrap();
+        assert_eq!(peer_addresses.len(), 1);
+        assert_eq!(peer_addresses[0].address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(peer_addresses[0].port, 6881);
+    }
+
+    #[tokio::test]
+    async fn should_send_connect_request_and_receive_announce_response_from_local_udp_tracker() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tracker_url = format!("udp://{}", socket.local_addr().unwrap());
+        TrackerServer::new(TrackerServerConfig::default()).serve_udp(socket);
+        let tracker = Tracker { url: tracker_url };
+        let udp_socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        tracker.get(&tracker_request("00000000000000000001", 6881), &udp_socket).await.unwrap();
+        let response = tracker.get(&tracker_request("00000000000000000002", 6882), &udp_socket).await.unwrap();
+        let peer_addresses = response.get_peer_addresses().unwrap();
+        assert_eq!(peer_addresses.len(), 1);
+        assert_eq!(peer_addresses[0].port, 6881);
+    }
+
+    #[tokio::test]
+    async fn should_scrape_local_trackers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let http_tracker = Tracker { url: format!("http://{}/announce", listener.local_addr().unwrap()) };
+        let udp_tracker = Tracker { url: format!("udp://{}/announce?passkey=secret", socket.local_addr().unwrap()) };
+        let server = TrackerServer::new(TrackerServerConfig::default());
+        server.serve_http(listener);
+        server.serve_udp(socket);
+        let udp_socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        http_tracker.get(&tracker_request("00000000000000000001", 6881), &udp_socket).await.unwrap();
+        udp_tracker.get(&tracker_request("00000000000000000002", 6882), &udp_socket).await.unwrap();
+
+        let expected_statistics = ScrapeStatistics { seeders: 0, completed: 0, leechers: 2 };
+        assert_eq!(http_tracker.scrape(&[7; 20], &udp_socket).await.unwrap(), expected_statistics);
+        assert_eq!(udp_tracker.scrape(&[7; 20], &udp_socket).await.unwrap(), expected_statistics);
+    }
+
+    #[tokio::test]
+    async fn should_send_announce_events_to_local_trackers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let http_tracker = Tracker { url: format!("http://{}/announce", listener.local_addr().unwrap()) };
+        let udp_tracker = Tracker { url: format!("udp://{}", socket.local_addr().unwrap()) };
+        let server = TrackerServer::new(TrackerServerConfig::default());
+        server.serve_http(listener);
+        server.serve_udp(socket);
+        let udp_socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        for (tracker, peer_id, port) in [(&http_tracker, "00000000000000000001", 6881), (&udp_tracker, "00000000000000000002", 6882)] {
+            tracker.get(&TrackerRequest { event: AnnounceEvent::Started, ..tracker_request(peer_id, port) }, &udp_socket).await.unwrap();
+            tracker.get(&TrackerRequest { event: AnnounceEvent::Completed, left: 0, ..tracker_request(peer_id, port) }, &udp_socket).await.unwrap();
+        }
+        assert_eq!(http_tracker.scrape(&[7; 20], &udp_socket).await.unwrap(), ScrapeStatistics { seeders: 2, completed: 2, leechers: 0 });
+
+        http_tracker.get(&TrackerRequest { event: AnnounceEvent::Stopped, left: 0, ..tracker_request("00000000000000000001", 6881) }, &udp_socket).await.unwrap();
+        udp_tracker.get(&TrackerRequest { event: AnnounceEvent::Stopped, left: 0, ..tracker_request("00000000000000000002", 6882) }, &udp_socket).await.unwrap();
+        assert_eq!(udp_tracker.scrape(&[7; 20], &udp_socket).await.unwrap(), ScrapeStatistics { seeders: 0, completed: 2, leechers: 0 });
+    }
+
+    #[tokio::test]
+    async fn should_advertise_configured_ip_to_local_trackers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let http_tracker = Tracker { url: format!("http://{}/announce", listener.local_addr().unwrap()) };
+        let udp_tracker = Tracker { url: format!("udp://{}", socket.local_addr().unwrap()) };
+        let server = TrackerServer::new(TrackerServerConfig::default());
+        server.serve_http(listener);
+        server.serve_udp(socket);
+        let udp_socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let mut http_request = tracker_request("00000000000000000001", 6881);
+        http_request.ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        http_request.no_peer_id = true;
+        http_tracker.get(&http_request, &udp_socket).await.unwrap();
+        let mut udp_request = tracker_request("00000000000000000002", 6882);
+        udp_request.ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        udp_request.num_want = Some(10);
+        udp_tracker.get(&udp_request, &udp_socket).await.unwrap();
+
+        let mut request = tracker_request("00000000000000000003", 6883);
+        request.num_want = Some(1);
+        let peer_addresses = http_tracker.get(&request, &udp_socket).await.unwrap().get_peer_addresses().unwrap();
+        assert_eq!(peer_addresses.len(), 1);
+        let mut request = tracker_request("00000000000000000003", 6883);
+        request.num_want = Some(50);
+        let mut peer_addresses: Vec<IpAddr> = udp_tracker.get(&request, &udp_socket).await.unwrap()
+            .get_peer_addresses().unwrap().into_iter().map(|peer_address| peer_address.address).collect();
+        peer_addresses.sort();
+        assert_eq!(peer_addresses, vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]);
+    }
+
+    #[tokio::test]
+    async fn should_stream_responses_of_all_tiers_and_time_out_unresponsive_trackers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let http_url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let udp_url = format!("udp://{}", socket.local_addr().unwrap());
+        let server = TrackerServer::new(TrackerServerConfig::default());
+        server.serve_http(listener);
+        server.serve_udp(socket);
+        // Nothing answers on this socket
+        let silent_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_url = format!("udp://{}", silent_socket.local_addr().unwrap());
+        let udp_socket = Arc::new(UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap());
+        Tracker { url: http_url.clone() }.get(&tracker_request("00000000000000000001", 6881), &udp_socket).await.unwrap();
+
+        let tiers = vec![vec![silent_url.clone(), http_url.clone()], vec![udp_url.clone(), http_url.clone()]];
+        let started_at = Instant::now();
+        let mut announcement = SwarmAnnouncement::start(&tiers, &tracker_request("00000000000000000002", 6882), &udp_socket, Duration::from_millis(500));
+        let mut answered_urls: Vec<String> = Vec::new();
+        while let Some(tracker_announce) = announcement.next().await {
+            if tracker_announce.url == silent_url {
+                assert!(tracker_announce.result.is_err());
+            } else {
+                let peer_addresses = tracker_announce.result.unwrap().get_peer_addresses().unwrap();
+                assert_eq!(peer_addresses[0].port, 6881);
+            }
+            answered_urls.push(tracker_announce.url);
+        }
+        assert!(started_at.elapsed() < Duration::from_secs(2));
+        assert_eq!(answered_urls.len(), 3);
+        assert_eq!(answered_urls.last(), Some(&silent_url));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn should_cancel_announcement() {
+        let silent_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tiers = vec![vec![format!("udp://{}", silent_socket.local_addr().unwrap())]];
+        let udp_socket = Arc::new(UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap());
+        let mut announcement = SwarmAnnouncement::start(&tiers, &tracker_request("00000000000000000001", 6881), &udp_socket, TRACKER_TIMEOUT);
+        announcement.cancel();
+        assert!(announcement.next().await.is_none());
+    }
+
+    #[test]
+    fn should_send_path_and_query_of_udp_tracker_url_as_url_data() {
+        let tracker = Tracker { url: "udp://tracker.example:6969/announce?passkey=secret".to_string() };
+        assert_eq!(tracker.udp_url_data().unwrap(), b"/announce?passkey=secret".to_vec());
+        let tracker_without_path = Tracker { url: "udp://tracker.example:6969".to_string() };
+        assert_eq!(tracker_without_path.udp_url_data().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn should_merge_params_into_announce_url() {
+        assert_eq!(build_announce_url("http://tracker.example/announce", "port=6881&info_hash=%01").unwrap(),
+            "http://tracker.example/announce?port=6881&info_hash=%01");
+        assert_eq!(build_announce_url("http://tracker.example/announce?passkey=abc", "port=6881&info_hash=%01").unwrap(),
+            "http://tracker.example/announce?passkey=abc&port=6881&info_hash=%01");
+        assert_eq!(build_announce_url("http://tracker.example/announce?", "port=6881").unwrap(),
+            "http://tracker.example/announce?port=6881");
+    }
+
+    #[test]
+    fn should_parse_compact_peers() {
+        let peers = parse_compact_peers_v4(&[127, 0, 0, 1, 26, 225]).unwrap();
+        assert_eq!(peers[0].address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(peers[0].port, 6881);
+
+        let mut peers6_bytes = Ipv6Addr::LOCALHOST.octets().to_vec();
+        peers6_bytes.extend([26, 225]);
+        let peers6 = parse_compact_peers_v6(&peers6_bytes).unwrap();
+        assert_eq!(peers6[0].address, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(peers6[0].port, 6881);
+
+        assert!(parse_compact_peers_v4(&[1, 2, 3]).is_err());
+    }
+}
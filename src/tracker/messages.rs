@@ -2,6 +2,15 @@
 use crate::error::new_error;
 use anyhow::ensure;
 
+const PROTOCOL_ID: u64 = 0x41727101980u64;
+const ANNOUNCE_REQUEST_LENGTH: usize = 98;
+
+// Option types of the extensions https://www.bittorrent.org/beps/bep_0041.html
+const OPTION_END_OF_OPTIONS: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_URL_DATA: u8 = 2;
+const MAX_OPTION_LENGTH: usize = 255;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Action {
     Connect = 0,
@@ -20,6 +29,56 @@ impl Action {
             _ => Err(new_error(format!("Action with the id {} does not exist", action_id)))
         }
     }
+
+    pub(crate) fn of_request(bytes: &[u8]) -> Result<Action, anyhow::Error> {
+        ensure!(bytes.len() >= 16, "UDP tracker request is too short, {} bytes", bytes.len());
+        Action::from(u32::from_be_bytes(bytes[8..12].try_into()?))
+    }
+
+    pub(crate) fn of_response(bytes: &[u8]) -> Result<Action, anyhow::Error> {
+        ensure!(bytes.len() >= 8, "UDP tracker response is too short, {} bytes", bytes.len());
+        Action::from(u32::from_be_bytes(bytes[0..4].try_into()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AnnounceEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3
+}
+
+impl AnnounceEvent {
+    fn from(event_id: u32) -> Result<AnnounceEvent, anyhow::Error> {
+        match event_id {
+            0 => Ok(AnnounceEvent::None),
+            1 => Ok(AnnounceEvent::Completed),
+            2 => Ok(AnnounceEvent::Started),
+            3 => Ok(AnnounceEvent::Stopped),
+            _ => Err(new_error(format!("Announce event with the id {} does not exist", event_id)))
+        }
+    }
+
+    pub(crate) fn from_http_param(value: &str) -> Result<AnnounceEvent, anyhow::Error> {
+        match value {
+            "" | "empty" => Ok(AnnounceEvent::None),
+            "completed" => Ok(AnnounceEvent::Completed),
+            "started" => Ok(AnnounceEvent::Started),
+            "stopped" => Ok(AnnounceEvent::Stopped),
+            _ => Err(new_error(format!("Unknown announce event {:?}", value)))
+        }
+    }
+
+    // Regular announces leave out the event parameter
+    pub(crate) fn http_param(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped")
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -33,7 +92,7 @@ impl ConnectRequest {
 
     pub(crate) fn new(transaction_id: u32) -> ConnectRequest {
         ConnectRequest {
-            protocol_id: 0x41727101980u64,
+            protocol_id: PROTOCOL_ID,
             action: Action::Connect,
             transaction_id
         }
@@ -46,6 +105,20 @@ impl ConnectRequest {
         bytes.extend(self.transaction_id.to_be_bytes().to_vec());
         bytes
     }
+
+    pub(crate) fn parse(bytes: &[u8]) -> Result<ConnectRequest, anyhow::Error> {
+        ensure!(bytes.len() >= 16, "'connect' request should be at least 16 bytes, got {}", bytes.len());
+        let protocol_id: u64 = u64::from_be_bytes(bytes[0..8].try_into()?);
+        ensure!(protocol_id == PROTOCOL_ID, "Unexpected protocol_id {:#x} in 'connect' request", protocol_id);
+        let action: Action = Action::from(u32::from_be_bytes(bytes[8..12].try_into()?))?;
+        ensure!(action == Action::Connect, "Expected 'connect' action 0 but got {:?}", action);
+        let transaction_id: u32 = u32::from_be_bytes(bytes[12..16].try_into()?);
+        Ok(ConnectRequest {
+            protocol_id,
+            action,
+            transaction_id
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -56,7 +129,24 @@ pub(crate) struct ConnectResponse {
 }
 
 impl ConnectResponse {
+    pub(crate) fn new(transaction_id: u32, connection_id: u64) -> ConnectResponse {
+        ConnectResponse {
+            action: Action::Connect,
+            transaction_id,
+            connection_id
+        }
+    }
+
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend((self.action as u32).to_be_bytes());
+        bytes.extend(self.transaction_id.to_be_bytes());
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes
+    }
+
     pub(crate) fn parse(bytes: &[u8]) -> Result<ConnectResponse, anyhow::Error> {
+        ensure!(bytes.len() >= 16, "'connect' response should be at least 16 bytes, got {}", bytes.len());
         let message_bytes: [u8; 16] = bytes[0..16].try_into()?;
         let action: Action = Action::from(u32::from_be_bytes(message_bytes[0..4].try_into()?))?;
         ensure!(action == Action::Connect, "Expected 'connect' action 0 but got {:?}", action);
@@ -69,3 +159,446 @@ impl ConnectResponse {
         })
     }
 }
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct AnnounceRequest {
+    pub(crate) connection_id: u64,
+    pub(crate) action: Action,
+    pub(crate) transaction_id: u32,
+    pub(crate) info_hash: Vec<u8>,
+    pub(crate) peer_id: Vec<u8>,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    pub(crate) uploaded: u64,
+    pub(crate) event: AnnounceEvent,
+    pub(crate) ip_address: u32,
+    pub(crate) key: u32,
+    pub(crate) num_want: i32,
+    pub(crate) port: u16,
+    // Path and query of the tracker URL, sent as the URLData option
+    pub(crate) url_data: Vec<u8>
+}
+
+impl AnnounceRequest {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend((self.action as u32).to_be_bytes());
+        bytes.extend(self.transaction_id.to_be_bytes());
+        bytes.extend(&self.info_hash);
+        bytes.extend(&self.peer_id);
+        bytes.extend(self.downloaded.to_be_bytes());
+        bytes.extend(self.left.to_be_bytes());
+        bytes.extend(self.uploaded.to_be_bytes());
+        bytes.extend((self.event as u32).to_be_bytes());
+        bytes.extend(self.ip_address.to_be_bytes());
+        bytes.extend(self.key.to_be_bytes());
+        bytes.extend(self.num_want.to_be_bytes());
+        bytes.extend(self.port.to_be_bytes());
+        bytes.extend(encode_url_data_options(&self.url_data));
+        bytes
+    }
+
+    pub(crate) fn parse(bytes: &[u8]) -> Result<AnnounceRequest, anyhow::Error> {
+        ensure!(bytes.len() >= ANNOUNCE_REQUEST_LENGTH, "'announce' request should be at least {} bytes, got {}", ANNOUNCE_REQUEST_LENGTH, bytes.len());
+        let action: Action = Action::from(u32::from_be_bytes(bytes[8..12].try_into()?))?;
+        ensure!(action == Action::Announce, "Expected 'announce' action 1 but got {:?}", action);
+        Ok(AnnounceRequest {
+            connection_id: u64::from_be_bytes(bytes[0..8].try_into()?),
+            action,
+            transaction_id: u32::from_be_bytes(bytes[12..16].try_into()?),
+            info_hash: bytes[16..36].to_vec(),
+            peer_id: bytes[36..56].to_vec(),
+            downloaded: u64::from_be_bytes(bytes[56..64].try_into()?),
+            left: u64::from_be_bytes(bytes[64..72].try_into()?),
+            uploaded: u64::from_be_bytes(bytes[72..80].try_into()?),
+            event: AnnounceEvent::from(u32::from_be_bytes(bytes[80..84].try_into()?))?,
+            ip_address: u32::from_be_bytes(bytes[84..88].try_into()?),
+            key: u32::from_be_bytes(bytes[88..92].try_into()?),
+            num_want: i32::from_be_bytes(bytes[92..96].try_into()?),
+            port: u16::from_be_bytes(bytes[96..98].try_into()?),
+            url_data: parse_url_data_options(&bytes[ANNOUNCE_REQUEST_LENGTH..])?
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct AnnounceResponse {
+    pub(crate) action: Action,
+    pub(crate) transaction_id: u32,
+    pub(crate) interval: u32,
+    pub(crate) leechers: u32,
+    pub(crate) seeders: u32,
+    // 6 bytes per peer for IPv4 trackers, 18 bytes per peer for IPv6 trackers
+    pub(crate) peers: Vec<u8>
+}
+
+impl AnnounceResponse {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend((self.action as u32).to_be_bytes());
+        bytes.extend(self.transaction_id.to_be_bytes());
+        bytes.extend(self.interval.to_be_bytes());
+        bytes.extend(self.leechers.to_be_bytes());
+        bytes.extend(self.seeders.to_be_bytes());
+        bytes.extend(&self.peers);
+        bytes
+    }
+
+    pub(crate) fn parse(bytes: &[u8]) -> Result<AnnounceResponse, anyhow::Error> {
+        ensure!(bytes.len() >= 20, "'announce' response should be at least 20 bytes, got {}", bytes.len());
+        let action: Action = Action::from(u32::from_be_bytes(bytes[0..4].try_into()?))?;
+        ensure!(action == Action::Announce, "Expected 'announce' action 1 but got {:?}", action);
+        Ok(AnnounceResponse {
+            action,
+            transaction_id: u32::from_be_bytes(bytes[4..8].try_into()?),
+            interval: u32::from_be_bytes(bytes[8..12].try_into()?),
+            leechers: u32::from_be_bytes(bytes[12..16].try_into()?),
+            seeders: u32::from_be_bytes(bytes[16..20].try_into()?),
+            peers: bytes[20..].to_vec()
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ScrapeRequest {
+    pub(crate) connection_id: u64,
+    pub(crate) action: Action,
+    pub(crate) transaction_id: u32,
+    pub(crate) info_hashes: Vec<Vec<u8>>,
+    pub(crate) url_data: Vec<u8>
+}
+
+impl ScrapeRequest {
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend((self.action as u32).to_be_bytes());
+        bytes.extend(self.transaction_id.to_be_bytes());
+        for info_hash in self.info_hashes.iter() {
+            bytes.extend(info_hash);
+        }
+        bytes.extend(encode_url_data_options(&self.url_data));
+        bytes
+    }
+
+    pub(crate) fn parse(bytes: &[u8]) -> Result<ScrapeRequest, anyhow::Error> {
+        ensure!(bytes.len() >= 16, "'scrape' request should be at least 16 bytes, got {}", bytes.len());
+        let action: Action = Action::from(u32::from_be_bytes(bytes[8..12].try_into()?))?;
+        ensure!(action == Action::Scrape, "Expected 'scrape' action 2 but got {:?}", action);
+        let (info_hashes, url_data) = split_scrape_options(&bytes[16..]);
+        Ok(ScrapeRequest {
+            connection_id: u64::from_be_bytes(bytes[0..8].try_into()?),
+            action,
+            transaction_id: u32::from_be_bytes(bytes[12..16].try_into()?),
+            info_hashes: info_hashes.chunks_exact(20).map(|info_hash| info_hash.to_vec()).collect(),
+            url_data
+        })
+    }
+}
+
+pub(crate) fn encode_url_data_options(url_data: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    if url_data.is_empty() {
+        return bytes;
+    }
+    for chunk in url_data.chunks(MAX_OPTION_LENGTH) {
+        bytes.push(OPTION_URL_DATA);
+        bytes.push(chunk.len() as u8);
+        bytes.extend(chunk);
+    }
+    bytes.push(OPTION_END_OF_OPTIONS);
+    bytes
+}
+
+// The options which follow the info hashes end with End-of-Options, so the longest run of whole info hashes
+// after which the remaining bytes are options carrying URL data and ending exactly with End-of-Options is taken,
+// a lone End-of-Options could otherwise be the last byte of the URL data
+fn split_scrape_options(bytes: &[u8]) -> (&[u8], Vec<u8>) {
+    if bytes.len().is_multiple_of(20) {
+        return (bytes, Vec::new());
+    }
+    for info_hash_count in (0..=bytes.len() / 20).rev() {
+        let (info_hashes, options) = bytes.split_at(info_hash_count * 20);
+        if let Ok((url_data, true)) = read_url_data_options(options) {
+            if !url_data.is_empty() {
+                return (info_hashes, url_data);
+            }
+        }
+    }
+    // No valid options, the trailing bytes which do not make a whole info hash are ignored
+    (&bytes[..bytes.len() / 20 * 20], Vec::new())
+}
+
+// Concatenates all the URLData options, the options of unknown types are skipped
+pub(crate) fn parse_url_data_options(bytes: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    read_url_data_options(bytes).map(|(url_data, _)| url_data)
+}
+
+// Also tells whether the options end with End-of-Options as the very last byte
+fn read_url_data_options(bytes: &[u8]) -> Result<(Vec<u8>, bool), anyhow::Error> {
+    let mut url_data: Vec<u8> = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let option_type = bytes[position];
+        match option_type {
+            OPTION_END_OF_OPTIONS => return Ok((url_data, position + 1 == bytes.len())),
+            OPTION_NOP => position += 1,
+            _ => {
+                ensure!(position + 1 < bytes.len(), "Option {} is missing its length", option_type);
+                let length = bytes[position + 1] as usize;
+                let data_start = position + 2;
+                ensure!(data_start + length <= bytes.len(), "Option {} of length {} is truncated", option_type, length);
+                if option_type == OPTION_URL_DATA {
+                    url_data.extend(&bytes[data_start..data_start + length]);
+                }
+                position = data_start + length;
+            }
+        }
+    }
+    Ok((url_data, false))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ScrapeStatistics {
+    pub(crate) seeders: u32,
+    pub(crate) completed: u32,
+    pub(crate) leechers: u32
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ScrapeResponse {
+    pub(crate) action: Action,
+    pub(crate) transaction_id: u32,
+    pub(crate) torrents: Vec<ScrapeStatistics>
+}
+
+impl ScrapeResponse {
+    pub(crate) fn parse(bytes: &[u8]) -> Result<ScrapeResponse, anyhow::Error> {
+        ensure!(bytes.len() >= 8, "'scrape' response should be at least 8 bytes, got {}", bytes.len());
+        let action: Action = Action::from(u32::from_be_bytes(bytes[0..4].try_into()?))?;
+        ensure!(action == Action::Scrape, "Expected 'scrape' action 2 but got {:?}", action);
+        let mut torrents: Vec<ScrapeStatistics> = Vec::new();
+        for statistics in bytes[8..].chunks_exact(12) {
+            torrents.push(ScrapeStatistics {
+                seeders: u32::from_be_bytes(statistics[0..4].try_into()?),
+                completed: u32::from_be_bytes(statistics[4..8].try_into()?),
+                leechers: u32::from_be_bytes(statistics[8..12].try_into()?)
+            });
+        }
+        Ok(ScrapeResponse {
+            action,
+            transaction_id: u32::from_be_bytes(bytes[4..8].try_into()?),
+            torrents
+        })
+    }
+
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend((self.action as u32).to_be_bytes());
+        bytes.extend(self.transaction_id.to_be_bytes());
+        for statistics in self.torrents.iter() {
+            bytes.extend(statistics.seeders.to_be_bytes());
+            bytes.extend(statistics.completed.to_be_bytes());
+            bytes.extend(statistics.leechers.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ErrorResponse {
+    pub(crate) action: Action,
+    pub(crate) transaction_id: u32,
+    pub(crate) message: String
+}
+
+impl ErrorResponse {
+    pub(crate) fn new(transaction_id: u32, message: &str) -> ErrorResponse {
+        ErrorResponse {
+            action: Action::Error,
+            transaction_id,
+            message: message.to_string()
+        }
+    }
+
+    pub(crate) fn get_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend((self.action as u32).to_be_bytes());
+        bytes.extend(self.transaction_id.to_be_bytes());
+        bytes.extend(self.message.as_bytes());
+        bytes
+    }
+
+    pub(crate) fn parse(bytes: &[u8]) -> Result<ErrorResponse, anyhow::Error> {
+        ensure!(bytes.len() >= 8, "'error' response should be at least 8 bytes, got {}", bytes.len());
+        let action: Action = Action::from(u32::from_be_bytes(bytes[0..4].try_into()?))?;
+        ensure!(action == Action::Error, "Expected 'error' action 3 but got {:?}", action);
+        Ok(ErrorResponse {
+            action,
+            transaction_id: u32::from_be_bytes(bytes[4..8].try_into()?),
+            message: String::from_utf8_lossy(&bytes[8..]).to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_serialize_and_parse_connect_messages() {
+        let request = ConnectRequest::new(42);
+        let parsed_request = ConnectRequest::parse(&request.get_bytes()).unwrap();
+        assert_eq!(parsed_request.transaction_id, 42);
+        assert_eq!(parsed_request.protocol_id, PROTOCOL_ID);
+
+        let response = ConnectResponse::new(42, 0x0102030405060708);
+        assert_eq!(response.get_bytes(), vec![
+            0, 0, 0, 0,             // action - 0 "connect"
+            0, 0, 0, 42,            // transaction_id
+            1, 2, 3, 4, 5, 6, 7, 8  // connection_id
+        ]);
+        let parsed_response = ConnectResponse::parse(&response.get_bytes()).unwrap();
+        assert_eq!(parsed_response.connection_id, 0x0102030405060708);
+    }
+
+    #[test]
+    fn should_serialize_and_parse_announce_request() {
+        let request = AnnounceRequest {
+            connection_id: 7,
+            action: Action::Announce,
+            transaction_id: 8,
+            info_hash: vec![1; 20],
+            peer_id: vec![2; 20],
+            downloaded: 100,
+            left: 200,
+            uploaded: 300,
+            event: AnnounceEvent::Started,
+            ip_address: 0,
+            key: 9,
+            num_want: -1,
+            port: 6881,
+            url_data: Vec::new()
+        };
+        let bytes = request.get_bytes();
+        assert_eq!(bytes.len(), ANNOUNCE_REQUEST_LENGTH);
+        assert_eq!(Action::of_request(&bytes).unwrap(), Action::Announce);
+        assert_eq!(AnnounceRequest::parse(&bytes).unwrap(), request);
+    }
+
+    #[test]
+    fn should_parse_announce_response() {
+        let bytes = vec![
+            0, 0, 0, 1,           // action - 1 "announce"
+            0, 0, 0, 5,           // transaction_id
+            0, 0, 7, 8,           // interval - 1800
+            0, 0, 0, 2,           // leechers
+            0, 0, 0, 3,           // seeders
+            127, 0, 0, 1, 26, 225 // peer 127.0.0.1:6881
+        ];
+        assert_eq!(AnnounceResponse::parse(&bytes).unwrap(), AnnounceResponse {
+            action: Action::Announce,
+            transaction_id: 5,
+            interval: 1800,
+            leechers: 2,
+            seeders: 3,
+            peers: vec![127, 0, 0, 1, 26, 225]
+        });
+        assert_eq!(AnnounceResponse::parse(&bytes).unwrap().get_bytes(), bytes);
+    }
+
+    #[test]
+    fn should_serialize_and_parse_scrape_messages() {
+        let mut request_bytes: Vec<u8> = vec![
+            0, 0, 0, 0, 0, 0, 0, 1, // connection_id
+            0, 0, 0, 2,             // action - 2 "scrape"
+            0, 0, 0, 3              // transaction_id
+        ];
+        request_bytes.extend([4; 20]);
+        request_bytes.extend([5; 20]);
+        assert_eq!(ScrapeRequest::parse(&request_bytes).unwrap(), ScrapeRequest {
+            connection_id: 1,
+            action: Action::Scrape,
+            transaction_id: 3,
+            info_hashes: vec![vec![4; 20], vec![5; 20]],
+            url_data: Vec::new()
+        });
+        let request = ScrapeRequest::parse(&request_bytes).unwrap();
+        assert_eq!(request.get_bytes(), request_bytes);
+        let request_with_url_data = ScrapeRequest { url_data: b"/scrape".to_vec(), ..request };
+        assert_eq!(request_with_url_data.get_bytes()[request_bytes.len()..], [OPTION_URL_DATA, 7, b'/', b's', b'c', b'r', b'a', b'p', b'e', OPTION_END_OF_OPTIONS]);
+        assert_eq!(ScrapeRequest::parse(&request_with_url_data.get_bytes()).unwrap(), request_with_url_data);
+        let options_like_info_hash = ScrapeRequest { info_hashes: vec![[[OPTION_URL_DATA, 17].as_slice(), &[6; 18]].concat()], url_data: b"/s".to_vec(), ..request_with_url_data };
+        assert_eq!(ScrapeRequest::parse(&options_like_info_hash.get_bytes()).unwrap(), options_like_info_hash);
+
+        let response = ScrapeResponse {
+            action: Action::Scrape,
+            transaction_id: 3,
+            torrents: vec![ScrapeStatistics { seeders: 1, completed: 2, leechers: 3 }]
+        };
+        assert_eq!(ScrapeResponse::parse(&response.get_bytes()).unwrap(), response);
+        assert_eq!(response.get_bytes(), vec![
+            0, 0, 0, 2, // action - 2 "scrape"
+            0, 0, 0, 3, // transaction_id
+            0, 0, 0, 1, // seeders
+            0, 0, 0, 2, // completed
+            0, 0, 0, 3  // leechers
+        ]);
+    }
+
+    #[test]
+    fn should_split_url_data_into_options() {
+        assert_eq!(encode_url_data_options(b""), Vec::<u8>::new());
+        assert_eq!(encode_url_data_options(b"/announce?passkey=1"), [
+            vec![OPTION_URL_DATA, 19],
+            b"/announce?passkey=1".to_vec(),
+            vec![OPTION_END_OF_OPTIONS]
+        ].concat());
+
+        let long_url_data: Vec<u8> = (0..600).map(|index| b'a' + (index % 26) as u8).collect();
+        let options = encode_url_data_options(&long_url_data);
+        assert_eq!(options.len(), 600 + 3 * 2 + 1);
+        assert_eq!(&options[0..2], &[OPTION_URL_DATA, 255]);
+        assert_eq!(&options[257..259], &[OPTION_URL_DATA, 255]);
+        assert_eq!(&options[514..516], &[OPTION_URL_DATA, 90]);
+        assert_eq!(parse_url_data_options(&options).unwrap(), long_url_data);
+    }
+
+    #[test]
+    fn should_parse_url_data_options_skipping_other_options() {
+        let options = vec![OPTION_NOP, OPTION_URL_DATA, 2, b'/', b'a', 7, 1, 0, OPTION_URL_DATA, 1, b'b', OPTION_END_OF_OPTIONS, OPTION_URL_DATA, 1, b'c'];
+        assert_eq!(parse_url_data_options(&options).unwrap(), b"/ab".to_vec());
+        assert!(parse_url_data_options(&[OPTION_URL_DATA, 5, b'a']).is_err());
+    }
+
+    #[test]
+    fn should_append_url_data_to_announce_request() {
+        let request = AnnounceRequest {
+            connection_id: 7,
+            action: Action::Announce,
+            transaction_id: 8,
+            info_hash: vec![1; 20],
+            peer_id: vec![2; 20],
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: AnnounceEvent::None,
+            ip_address: 0,
+            key: 0,
+            num_want: -1,
+            port: 6881,
+            url_data: b"/announce?passkey=secret".to_vec()
+        };
+        let bytes = request.get_bytes();
+        assert_eq!(&bytes[ANNOUNCE_REQUEST_LENGTH..ANNOUNCE_REQUEST_LENGTH + 2], &[OPTION_URL_DATA, 24]);
+        assert_eq!(AnnounceRequest::parse(&bytes).unwrap(), request);
+    }
+
+    #[test]
+    fn should_parse_error_response() {
+        let response = ErrorResponse::new(11, "unknown torrent");
+        assert_eq!(Action::of_response(&response.get_bytes()).unwrap(), Action::Error);
+        assert_eq!(ErrorResponse::parse(&response.get_bytes()).unwrap(), response);
+    }
+}
@@ -0,0 +1,682 @@
+// A minimal tracker for private swarms, serving both the HTTP protocol
+// https://www.bittorrent.org/beps/bep_0003.html#trackers and the UDP protocol https://www.bittorrent.org/beps/bep_0015.html
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use anyhow::ensure;
+use rand::seq::SliceRandom;
+use crate::bencoded::BencodeEncoding;
+use crate::error::new_error;
+use crate::format;
+use crate::url_utils;
+use super::messages::{Action, AnnounceEvent, AnnounceRequest, AnnounceResponse, ConnectRequest, ConnectResponse, ErrorResponse, ScrapeRequest, ScrapeResponse, ScrapeStatistics};
+
+const DEFAULT_NUM_WANT: usize = 50;
+const MAX_NUM_WANT: usize = 200;
+const MAX_HTTP_REQUEST_SIZE: usize = 8192;
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(120);
+
+pub(crate) struct TrackerServerConfig {
+    pub(crate) interval: u32,
+    // Peers which did not re-announce within this time are dropped from the swarm
+    pub(crate) peer_expiry: Duration,
+    // If present, only these info hashes are tracked
+    pub(crate) whitelist: Option<HashSet<Vec<u8>>>
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> TrackerServerConfig {
+        TrackerServerConfig {
+            interval: 1800,
+            peer_expiry: Duration::from_secs(2 * 1800),
+            whitelist: None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SwarmPeer {
+    pub(crate) peer_id: Vec<u8>,
+    pub(crate) address: SocketAddr,
+    pub(crate) left: u64,
+    last_announce: Instant
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<Vec<u8>, SwarmPeer>,
+    // A peer which announces its completion more than once is counted once
+    completed_peers: HashSet<Vec<u8>>
+}
+
+impl Swarm {
+    fn remove_expired_peers(&mut self, now: Instant, peer_expiry: Duration) {
+        self.peers.retain(|_, peer| now.duration_since(peer.last_announce) < peer_expiry);
+    }
+
+    fn statistics(&self) -> ScrapeStatistics {
+        let seeders = self.peers.values().filter(|peer| peer.left == 0).count() as u32;
+        ScrapeStatistics {
+            seeders,
+            completed: self.completed_peers.len() as u32,
+            leechers: self.peers.len() as u32 - seeders
+        }
+    }
+}
+
+pub(crate) struct Announce {
+    pub(crate) info_hash: Vec<u8>,
+    pub(crate) peer_id: Vec<u8>,
+    pub(crate) address: SocketAddr,
+    pub(crate) left: u64,
+    pub(crate) event: AnnounceEvent,
+    pub(crate) num_want: Option<usize>
+}
+
+pub(crate) struct AnnounceResult {
+    pub(crate) peers: Vec<SwarmPeer>,
+    pub(crate) statistics: ScrapeStatistics
+}
+
+// Peer lists of all the tracked torrents keyed by info hash
+pub(crate) struct Swarms {
+    torrents: HashMap<Vec<u8>, Swarm>,
+    peer_expiry: Duration,
+    whitelist: Option<HashSet<Vec<u8>>>
+}
+
+impl Swarms {
+    pub(crate) fn new(peer_expiry: Duration, whitelist: Option<HashSet<Vec<u8>>>) -> Swarms {
+        Swarms {
+            torrents: HashMap::new(),
+            peer_expiry,
+            whitelist
+        }
+    }
+
+    fn ensure_whitelisted(&self, info_hash: &[u8]) -> Result<(), anyhow::Error> {
+        if let Some(whitelist) = &self.whitelist {
+            ensure!(whitelist.contains(info_hash), "Torrent {} is not tracked by this tracker", format::format_as_hex_string(info_hash));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn announce(&mut self, announce: &Announce, now: Instant) -> Result<AnnounceResult, anyhow::Error> {
+        ensure!(announce.info_hash.len() == 20, "info_hash should be 20 bytes, got {}", announce.info_hash.len());
+        ensure!(announce.peer_id.len() == 20, "peer_id should be 20 bytes, got {}", announce.peer_id.len());
+        self.ensure_whitelisted(&announce.info_hash)?;
+
+        // A peer leaving a torrent nobody announced does not start tracking it
+        if announce.event == AnnounceEvent::Stopped {
+            let statistics = match self.torrents.get_mut(&announce.info_hash) {
+                Some(swarm) => {
+                    swarm.remove_expired_peers(now, self.peer_expiry);
+                    swarm.peers.remove(&announce.peer_id);
+                    swarm.statistics()
+                },
+                None => ScrapeStatistics::default()
+            };
+            return Ok(AnnounceResult { peers: Vec::new(), statistics });
+        }
+
+        let swarm = self.torrents.entry(announce.info_hash.clone()).or_default();
+        swarm.remove_expired_peers(now, self.peer_expiry);
+        if announce.event == AnnounceEvent::Completed {
+            swarm.completed_peers.insert(announce.peer_id.clone());
+        }
+        swarm.peers.insert(announce.peer_id.clone(), SwarmPeer {
+            peer_id: announce.peer_id.clone(),
+            address: announce.address,
+            left: announce.left,
+            last_announce: now
+        });
+
+        let num_want = announce.num_want.unwrap_or(DEFAULT_NUM_WANT).min(MAX_NUM_WANT);
+        let other_peers: Vec<&SwarmPeer> = swarm.peers.values()
+            .filter(|peer| peer.peer_id != announce.peer_id)
+            .collect();
+        let peers: Vec<SwarmPeer> = other_peers
+            .choose_multiple(&mut rand::thread_rng(), num_want)
+            .map(|peer| (*peer).clone())
+            .collect();
+        Ok(AnnounceResult {
+            peers,
+            statistics: swarm.statistics()
+        })
+    }
+
+    // None for a torrent which is not whitelisted, so that the other torrents of a scrape are still answered
+    pub(crate) fn scrape(&mut self, info_hash: &[u8], now: Instant) -> Option<ScrapeStatistics> {
+        if self.ensure_whitelisted(info_hash).is_err() {
+            return None;
+        }
+        match self.torrents.get_mut(info_hash) {
+            Some(swarm) => {
+                swarm.remove_expired_peers(now, self.peer_expiry);
+                Some(swarm.statistics())
+            },
+            None => Some(ScrapeStatistics::default())
+        }
+    }
+
+    fn tracked_info_hashes(&self) -> Vec<Vec<u8>> {
+        self.torrents.keys().cloned().collect()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct TrackerServer {
+    interval: u32,
+    swarms: Arc<Mutex<Swarms>>,
+    udp_connection_ids: Arc<Mutex<HashMap<u64, (SocketAddr, Instant)>>>
+}
+
+impl TrackerServer {
+    pub(crate) fn new(config: TrackerServerConfig) -> TrackerServer {
+        TrackerServer {
+            interval: config.interval,
+            swarms: Arc::new(Mutex::new(Swarms::new(config.peer_expiry, config.whitelist))),
+            udp_connection_ids: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    pub(crate) fn serve_http(&self, listener: TcpListener) -> JoinHandle<()> {
+        let server = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let server_per_connection = server.clone();
+                        thread::spawn(move || {
+                            if let Err(error) = server_per_connection.handle_http_connection(stream) {
+                                println!("Failed to handle HTTP tracker request: {}", error);
+                            }
+                        });
+                    },
+                    Err(error) => println!("Failed to accept HTTP tracker connection: {}", error)
+                }
+            }
+        })
+    }
+
+    pub(crate) fn serve_udp(&self, socket: UdpSocket) -> JoinHandle<()> {
+        let server = self.clone();
+        thread::spawn(move || {
+            let mut request_buf: [u8; 2048] = [0; 2048];
+            loop {
+                match socket.recv_from(&mut request_buf) {
+                    Ok((bytes_read, client_address)) => {
+                        let response = server.handle_udp_request(&request_buf[0..bytes_read], client_address);
+                        if let Some(response_bytes) = response {
+                            if let Err(error) = socket.send_to(&response_bytes, client_address) {
+                                println!("Failed to send UDP tracker response to {}: {}", client_address, error);
+                            }
+                        }
+                    },
+                    Err(error) => println!("Failed to receive UDP tracker request: {}", error)
+                }
+            }
+        })
+    }
+
+    fn handle_http_connection(&self, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+        stream.set_read_timeout(Some(HTTP_READ_TIMEOUT))?;
+        let client_address = stream.peer_addr()?;
+        let request_head = read_http_request_head(&mut stream)?;
+        let request_line = request_head.lines().next().unwrap_or("");
+        let mut request_line_parts = request_line.split(' ');
+        let method = request_line_parts.next().unwrap_or("");
+        let target = request_line_parts.next().unwrap_or("");
+
+        let (status, body) = if method != "GET" {
+            ("405 Method Not Allowed", failure_body("Only GET requests are supported"))
+        } else {
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            let result = url_utils::parse_query(query).and_then(|params| {
+                if path.ends_with("/announce") {
+                    self.http_announce(&params, client_address.ip())
+                } else if path.ends_with("/scrape") {
+                    self.http_scrape(&params)
+                } else {
+                    Err(new_error(format!("Unknown path {}", path)))
+                }
+            });
+            match result {
+                Ok(body) => ("200 OK", body),
+                Err(error) => ("200 OK", failure_body(&error.to_string()))
+            }
+        };
+
+        let mut response: Vec<u8> = Vec::new();
+        response.extend(format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len()).as_bytes());
+        response.extend(body);
+        stream.write_all(&response)?;
+        Ok(())
+    }
+
+    fn http_announce(&self, params: &[(String, Vec<u8>)], client_ip: IpAddr) -> Result<Vec<u8>, anyhow::Error> {
+        let info_hash = required_param(params, "info_hash")?.to_vec();
+        let peer_id = required_param(params, "peer_id")?.to_vec();
+        let port = param_as_string(params, "port")?.ok_or(new_error("Missing parameter 'port'".to_string()))?.parse::<u16>()?;
+        let left = param_as_string(params, "left")?.map(|left| left.parse::<u64>()).transpose()?.unwrap_or(0);
+        let event = AnnounceEvent::from_http_param(&param_as_string(params, "event")?.unwrap_or_default())?;
+        let num_want = param_as_string(params, "numwant")?.map(|num_want| num_want.parse::<usize>()).transpose()?;
+        let compact = param_as_string(params, "compact")?.map(|compact| compact == "1").unwrap_or(false);
+        let no_peer_id = param_as_string(params, "no_peer_id")?.map(|no_peer_id| no_peer_id == "1").unwrap_or(false);
+        let ip = match param_as_string(params, "ip")? {
+            Some(ip) => ip.parse::<IpAddr>()?,
+            None => client_ip
+        };
+
+        let announce = Announce {
+            info_hash,
+            peer_id,
+            address: SocketAddr::new(ip, port),
+            left,
+            event,
+            num_want
+        };
+        let result = self.swarms.lock().unwrap().announce(&announce, Instant::now())?;
+        println!("HTTP announce from {} for {}, returning {} peers", announce.address, format::format_as_hex_string(&announce.info_hash), result.peers.len());
+
+        let mut bencoded: Vec<u8> = Vec::new();
+        bencoded.push(b'd');
+        bencoded.encode_str("complete");
+        bencoded.encode_i64(&(result.statistics.seeders as i64));
+        bencoded.encode_str("incomplete");
+        bencoded.encode_i64(&(result.statistics.leechers as i64));
+        bencoded.encode_str("interval");
+        bencoded.encode_i64(&(self.interval as i64));
+        bencoded.encode_str("min interval");
+        bencoded.encode_i64(&(self.interval as i64 / 2));
+        if compact {
+            bencoded.encode_str("peers");
+            bencoded.encode_bytes(&compact_peers(&result.peers, false));
+            bencoded.encode_str("peers6");
+            bencoded.encode_bytes(&compact_peers(&result.peers, true));
+        } else {
+            bencoded.encode_str("peers");
+            bencoded.push(b'l');
+            for peer in result.peers.iter() {
+                bencoded.push(b'd');
+                bencoded.encode_str("ip");
+                bencoded.encode_str(&peer.address.ip().to_string());
+                if !no_peer_id {
+                    bencoded.encode_str("peer id");
+                    bencoded.encode_bytes(&peer.peer_id);
+                }
+                bencoded.encode_str("port");
+                bencoded.encode_i64(&(peer.address.port() as i64));
+                bencoded.push(b'e');
+            }
+            bencoded.push(b'e');
+        }
+        bencoded.push(b'e');
+        Ok(bencoded)
+    }
+
+    fn http_scrape(&self, params: &[(String, Vec<u8>)]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut swarms = self.swarms.lock().unwrap();
+        let mut info_hashes: Vec<Vec<u8>> = params.iter()
+            .filter(|(name, _)| name == "info_hash")
+            .map(|(_, value)| value.clone())
+            .collect();
+        if info_hashes.is_empty() {
+            info_hashes = swarms.tracked_info_hashes();
+        }
+        // Keys of a bencoded dictionary should be sorted
+        info_hashes.sort();
+        info_hashes.dedup();
+
+        let now = Instant::now();
+        let mut bencoded: Vec<u8> = Vec::new();
+        bencoded.push(b'd');
+        bencoded.encode_str("files");
+        bencoded.push(b'd');
+        for info_hash in info_hashes.iter() {
+            // Torrents which are not tracked are left out of the files
+            let Some(statistics) = swarms.scrape(info_hash, now) else {
+                continue;
+            };
+            bencoded.encode_bytes(info_hash);
+            bencoded.push(b'd');
+            bencoded.encode_str("complete");
+            bencoded.encode_i64(&(statistics.seeders as i64));
+            bencoded.encode_str("downloaded");
+            bencoded.encode_i64(&(statistics.completed as i64));
+            bencoded.encode_str("incomplete");
+            bencoded.encode_i64(&(statistics.leechers as i64));
+            bencoded.push(b'e');
+        }
+        bencoded.push(b'e');
+        bencoded.push(b'e');
+        Ok(bencoded)
+    }
+
+    // Datagrams which are not tracker requests get no reply, otherwise the tracker could be used to send error responses to spoofed addresses
+    fn handle_udp_request(&self, request: &[u8], client_address: SocketAddr) -> Option<Vec<u8>> {
+        let (transaction_id, response) = match Action::of_request(request).ok()? {
+            Action::Connect => {
+                let connect_request = ConnectRequest::parse(request).ok()?;
+                (connect_request.transaction_id, Ok(self.udp_connect(&connect_request, client_address)))
+            },
+            Action::Announce => {
+                let announce_request = AnnounceRequest::parse(request).ok()?;
+                (announce_request.transaction_id, self.udp_announce(announce_request, client_address))
+            },
+            Action::Scrape => {
+                let scrape_request = ScrapeRequest::parse(request).ok()?;
+                (scrape_request.transaction_id, self.udp_scrape(&scrape_request, client_address))
+            },
+            Action::Error => return None
+        };
+        match response {
+            Ok(response_bytes) => Some(response_bytes),
+            Err(error) => Some(ErrorResponse::new(transaction_id, &error.to_string()).get_bytes())
+        }
+    }
+
+    fn udp_connect(&self, connect_request: &ConnectRequest, client_address: SocketAddr) -> Vec<u8> {
+        let connection_id = rand::random::<u64>();
+        let now = Instant::now();
+        let mut connection_ids = self.udp_connection_ids.lock().unwrap();
+        connection_ids.retain(|_, (_, issued_at)| now.duration_since(*issued_at) < UDP_CONNECTION_ID_LIFETIME);
+        connection_ids.insert(connection_id, (client_address, now));
+        ConnectResponse::new(connect_request.transaction_id, connection_id).get_bytes()
+    }
+
+    fn ensure_udp_connected(&self, connection_id: u64, client_address: SocketAddr) -> Result<(), anyhow::Error> {
+        let connection_ids = self.udp_connection_ids.lock().unwrap();
+        match connection_ids.get(&connection_id) {
+            Some((address, issued_at)) if *address == client_address && issued_at.elapsed() < UDP_CONNECTION_ID_LIFETIME => Ok(()),
+            _ => Err(new_error(format!("Invalid or expired connection id {}", connection_id)))
+        }
+    }
+
+    fn udp_announce(&self, announce_request: AnnounceRequest, client_address: SocketAddr) -> Result<Vec<u8>, anyhow::Error> {
+        self.ensure_udp_connected(announce_request.connection_id, client_address)?;
+        let ip = if announce_request.ip_address != 0 && client_address.is_ipv4() {
+            IpAddr::from(announce_request.ip_address.to_be_bytes())
+        } else {
+            client_address.ip()
+        };
+        let announce = Announce {
+            info_hash: announce_request.info_hash,
+            peer_id: announce_request.peer_id,
+            address: SocketAddr::new(ip, announce_request.port),
+            left: announce_request.left,
+            event: announce_request.event,
+            num_want: if announce_request.num_want < 0 { None } else { Some(announce_request.num_want as usize) }
+        };
+        let result = self.swarms.lock().unwrap().announce(&announce, Instant::now())?;
+        println!("UDP announce from {} for {}, returning {} peers", announce.address, format::format_as_hex_string(&announce.info_hash), result.peers.len());
+        Ok(AnnounceResponse {
+            action: Action::Announce,
+            transaction_id: announce_request.transaction_id,
+            interval: self.interval,
+            leechers: result.statistics.leechers,
+            seeders: result.statistics.seeders,
+            // The address family of the peers matches the one of the request
+            peers: compact_peers(&result.peers, client_address.is_ipv6())
+        }.get_bytes())
+    }
+
+    fn udp_scrape(&self, scrape_request: &ScrapeRequest, client_address: SocketAddr) -> Result<Vec<u8>, anyhow::Error> {
+        self.ensure_udp_connected(scrape_request.connection_id, client_address)?;
+        let now = Instant::now();
+        let mut swarms = self.swarms.lock().unwrap();
+        // The statistics are matched to the info hashes by position, torrents which are not tracked get zeros
+        let torrents: Vec<ScrapeStatistics> = scrape_request.info_hashes.iter()
+            .map(|info_hash| swarms.scrape(info_hash, now).unwrap_or_default())
+            .collect();
+        Ok(ScrapeResponse {
+            action: Action::Scrape,
+            transaction_id: scrape_request.transaction_id,
+            torrents
+        }.get_bytes())
+    }
+}
+
+fn read_http_request_head(stream: &mut TcpStream) -> Result<String, anyhow::Error> {
+    let mut request: Vec<u8> = Vec::new();
+    let mut buffer: [u8; 1024] = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        ensure!(request.len() < MAX_HTTP_REQUEST_SIZE, "HTTP request is larger than {} bytes", MAX_HTTP_REQUEST_SIZE);
+        let bytes_read = stream.read(&mut buffer)?;
+        ensure!(bytes_read > 0, "Connection closed before the HTTP request was received");
+        request.extend(&buffer[0..bytes_read]);
+    }
+    Ok(String::from_utf8_lossy(&request).to_string())
+}
+
+fn required_param<'a>(params: &'a [(String, Vec<u8>)], name: &str) -> Result<&'a [u8], anyhow::Error> {
+    params.iter()
+        .find(|(param_name, _)| param_name == name)
+        .map(|(_, value)| value.as_slice())
+        .ok_or(new_error(format!("Missing parameter '{}'", name)))
+}
+
+fn param_as_string(params: &[(String, Vec<u8>)], name: &str) -> Result<Option<String>, anyhow::Error> {
+    match params.iter().find(|(param_name, _)| param_name == name) {
+        Some((_, value)) => Ok(Some(String::from_utf8(value.clone())?)),
+        None => Ok(None)
+    }
+}
+
+fn failure_body(reason: &str) -> Vec<u8> {
+    let mut bencoded: Vec<u8> = Vec::new();
+    bencoded.push(b'd');
+    bencoded.encode_str("failure reason");
+    bencoded.encode_str(reason);
+    bencoded.push(b'e');
+    bencoded
+}
+
+// 6 bytes per IPv4 peer, 18 bytes per IPv6 peer https://www.bittorrent.org/beps/bep_0007.html
+fn compact_peers(peers: &[SwarmPeer], ipv6: bool) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for peer in peers.iter() {
+        match peer.address.ip() {
+            IpAddr::V4(address) if !ipv6 => bytes.extend(address.octets()),
+            IpAddr::V6(address) if ipv6 => bytes.extend(address.octets()),
+            _ => continue
+        }
+        bytes.extend(peer.address.port().to_be_bytes());
+    }
+    bytes
+}
+
+pub(crate) fn read_whitelist(file_path: &str) -> Result<HashSet<Vec<u8>>, anyhow::Error> {
+    let mut whitelist: HashSet<Vec<u8>> = HashSet::new();
+    for line in std::fs::read_to_string(file_path)?.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+        let info_hash = hex::decode(line)?;
+        ensure!(info_hash.len() == 20, "Info hash {:?} in the whitelist should be 20 bytes long", line);
+        whitelist.insert(info_hash);
+    }
+    Ok(whitelist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencoded;
+
+    fn announce(info_hash: u8, peer_id: u8, port: u16, left: u64, event: AnnounceEvent) -> Announce {
+        Announce {
+            info_hash: vec![info_hash; 20],
+            peer_id: vec![peer_id; 20],
+            address: SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port),
+            left,
+            event,
+            num_want: None
+        }
+    }
+
+    #[test]
+    fn should_keep_peer_lists_per_info_hash() {
+        let mut swarms = Swarms::new(Duration::from_secs(60), None);
+        let now = Instant::now();
+        swarms.announce(&announce(1, 1, 6881, 0, AnnounceEvent::Started), now).unwrap();
+        swarms.announce(&announce(2, 2, 6882, 10, AnnounceEvent::Started), now).unwrap();
+
+        let result = swarms.announce(&announce(1, 3, 6883, 10, AnnounceEvent::Started), now).unwrap();
+        assert_eq!(result.peers.len(), 1);
+        assert_eq!(result.peers[0].address.port(), 6881);
+        assert_eq!(result.statistics, ScrapeStatistics { seeders: 1, completed: 0, leechers: 1 });
+
+        swarms.announce(&announce(1, 3, 6883, 0, AnnounceEvent::Completed), now).unwrap();
+        swarms.announce(&announce(1, 3, 6883, 0, AnnounceEvent::Completed), now).unwrap();
+        assert_eq!(swarms.scrape(&[1; 20], now).unwrap(), ScrapeStatistics { seeders: 2, completed: 1, leechers: 0 });
+
+        swarms.announce(&announce(1, 3, 6883, 0, AnnounceEvent::Stopped), now).unwrap();
+        assert_eq!(swarms.scrape(&[1; 20], now).unwrap(), ScrapeStatistics { seeders: 1, completed: 1, leechers: 0 });
+    }
+
+    #[test]
+    fn should_not_track_torrent_on_stopped_announce() {
+        let mut swarms = Swarms::new(Duration::from_secs(60), None);
+        let result = swarms.announce(&announce(1, 1, 6881, 0, AnnounceEvent::Stopped), Instant::now()).unwrap();
+        assert!(result.peers.is_empty());
+        assert_eq!(result.statistics, ScrapeStatistics::default());
+        assert!(swarms.tracked_info_hashes().is_empty());
+    }
+
+    #[test]
+    fn should_expire_peers_which_did_not_announce() {
+        let mut swarms = Swarms::new(Duration::from_secs(60), None);
+        let now = Instant::now();
+        swarms.announce(&announce(1, 1, 6881, 0, AnnounceEvent::Started), now).unwrap();
+
+        let result = swarms.announce(&announce(1, 2, 6882, 10, AnnounceEvent::Started), now + Duration::from_secs(61)).unwrap();
+        assert_eq!(result.peers.len(), 0);
+        assert_eq!(result.statistics, ScrapeStatistics { seeders: 0, completed: 0, leechers: 1 });
+    }
+
+    #[test]
+    fn should_reject_torrents_which_are_not_whitelisted() {
+        let whitelist: HashSet<Vec<u8>> = HashSet::from([vec![1; 20]]);
+        let mut swarms = Swarms::new(Duration::from_secs(60), Some(whitelist));
+        let now = Instant::now();
+        assert!(swarms.announce(&announce(1, 1, 6881, 0, AnnounceEvent::Started), now).is_ok());
+        assert!(swarms.announce(&announce(2, 1, 6881, 0, AnnounceEvent::Started), now).is_err());
+        assert!(swarms.scrape(&[2; 20], now).is_none());
+    }
+
+    #[test]
+    fn should_encode_compact_peers_by_address_family() {
+        let now = Instant::now();
+        let peers = vec![
+            SwarmPeer { peer_id: vec![1; 20], address: "10.0.0.1:6881".parse().unwrap(), left: 0, last_announce: now },
+            SwarmPeer { peer_id: vec![2; 20], address: "[::1]:6882".parse().unwrap(), left: 0, last_announce: now }
+        ];
+        assert_eq!(compact_peers(&peers, false), vec![10, 0, 0, 1, 26, 225]);
+        assert_eq!(compact_peers(&peers, true), vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 26, 226]);
+    }
+
+    #[test]
+    fn should_serve_http_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = TrackerServer::new(TrackerServerConfig {
+            whitelist: Some(HashSet::from([vec![1; 20]])),
+            ..TrackerServerConfig::default()
+        });
+        server.swarms.lock().unwrap().announce(&announce(1, 1, 6881, 0, AnnounceEvent::Started), Instant::now()).unwrap();
+        server.serve_http(listener);
+
+        let url = format!("http://{}/scrape?info_hash={}&info_hash={}", address, url_utils::url_encode_bytes(&[1; 20]), url_utils::url_encode_bytes(&[2; 20]));
+        let response = reqwest::blocking::get(url).unwrap().bytes().unwrap().to_vec();
+        let decoded = bencoded::decode_bencoded_from_bytes(&response).unwrap();
+        let files = decoded.get_by_key("files").unwrap();
+        let statistics = files.get_by_key(&"\u{1}".repeat(20)).unwrap();
+        assert_eq!(statistics.get_by_key("complete").unwrap().as_number().unwrap(), 1);
+        assert_eq!(statistics.get_by_key("incomplete").unwrap().as_number().unwrap(), 0);
+        assert!(files.get_by_key(&"\u{2}".repeat(20)).is_err());
+    }
+
+    #[test]
+    fn should_reject_udp_announce_without_connection_id() {
+        let server = TrackerServer::new(TrackerServerConfig::default());
+        let client_address: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let announce_request = AnnounceRequest {
+            connection_id: 1,
+            action: Action::Announce,
+            transaction_id: 2,
+            info_hash: vec![1; 20],
+            peer_id: vec![1; 20],
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: AnnounceEvent::Started,
+            ip_address: 0,
+            key: 0,
+            num_want: -1,
+            port: 6881,
+            url_data: b"/announce".to_vec()
+        };
+        let response = server.handle_udp_request(&announce_request.get_bytes(), client_address).unwrap();
+        assert_eq!(Action::of_response(&response).unwrap(), Action::Error);
+
+        let connect_response = server.handle_udp_request(&ConnectRequest::new(3).get_bytes(), client_address).unwrap();
+        let connection_id = ConnectResponse::parse(&connect_response).unwrap().connection_id;
+        let response = server.handle_udp_request(&AnnounceRequest { connection_id, ..announce_request }.get_bytes(), client_address).unwrap();
+        assert_eq!(Action::of_response(&response).unwrap(), Action::Announce);
+    }
+
+    #[test]
+    fn should_only_answer_udp_tracker_requests() {
+        let server = TrackerServer::new(TrackerServerConfig {
+            whitelist: Some(HashSet::from([vec![1; 20]])),
+            ..TrackerServerConfig::default()
+        });
+        let client_address: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        assert_eq!(server.handle_udp_request(b"GET / HTTP/1.1\r\n\r\n", client_address), None);
+        let mut unknown_protocol = ConnectRequest::new(3).get_bytes();
+        unknown_protocol[0] = 1;
+        assert_eq!(server.handle_udp_request(&unknown_protocol, client_address), None);
+        assert_eq!(server.handle_udp_request(&ErrorResponse::new(3, "error").get_bytes(), client_address), None);
+
+        let connect_response = server.handle_udp_request(&ConnectRequest::new(3).get_bytes(), client_address).unwrap();
+        let connection_id = ConnectResponse::parse(&connect_response).unwrap().connection_id;
+        server.swarms.lock().unwrap().announce(&announce(1, 1, 6881, 0, AnnounceEvent::Started), Instant::now()).unwrap();
+        let mut scrape_request: Vec<u8> = Vec::new();
+        scrape_request.extend(connection_id.to_be_bytes());
+        scrape_request.extend((Action::Scrape as u32).to_be_bytes());
+        scrape_request.extend(4u32.to_be_bytes());
+        scrape_request.extend([1; 20]);
+        scrape_request.extend([2; 20]);
+        let response = ScrapeResponse::parse(&server.handle_udp_request(&scrape_request, client_address).unwrap()).unwrap();
+        assert_eq!(response.torrents, vec![
+            ScrapeStatistics { seeders: 1, completed: 0, leechers: 0 },
+            ScrapeStatistics::default()
+        ]);
+    }
+
+    #[test]
+    fn should_answer_udp_scrape_with_url_data() {
+        let server = TrackerServer::new(TrackerServerConfig::default());
+        let client_address: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let connect_response = server.handle_udp_request(&ConnectRequest::new(3).get_bytes(), client_address).unwrap();
+        let connection_id = ConnectResponse::parse(&connect_response).unwrap().connection_id;
+        server.swarms.lock().unwrap().announce(&announce(1, 1, 6881, 0, AnnounceEvent::Started), Instant::now()).unwrap();
+        server.swarms.lock().unwrap().announce(&announce(2, 2, 6882, 10, AnnounceEvent::Started), Instant::now()).unwrap();
+        let scrape_request = ScrapeRequest {
+            connection_id,
+            action: Action::Scrape,
+            transaction_id: 4,
+            info_hashes: vec![vec![1; 20], vec![2; 20]],
+            url_data: b"/scrape?passkey=12".to_vec()
+        };
+        let response = ScrapeResponse::parse(&server.handle_udp_request(&scrape_request.get_bytes(), client_address).unwrap()).unwrap();
+        assert_eq!(response.transaction_id, 4);
+        assert_eq!(response.torrents, vec![
+            ScrapeStatistics { seeders: 1, completed: 0, leechers: 0 },
+            ScrapeStatistics { seeders: 0, completed: 0, leechers: 1 }
+        ]);
+    }
+}
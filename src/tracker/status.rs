@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::peer::PeerAddress;
+use super::udp::UdpTrackerSocket;
+use super::{AnnounceEvent, SwarmAnnouncement, TrackerAnnounce, TrackerRequest};
+
+// Delay before re-announcing to a tracker after the first failure, doubled with every consecutive failure
+const FAILURE_RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_FAILURE_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+// What we know about one of the trackers of the torrent after announcing to it
+#[derive(Debug, Clone)]
+pub(crate) struct TrackerStatus {
+    pub(crate) url: String,
+    pub(crate) last_announce: Option<Instant>,
+    pub(crate) next_announce: Instant,
+    pub(crate) last_error: Option<String>,
+    pub(crate) warning: Option<String>,
+    pub(crate) seeders: Option<u32>,
+    pub(crate) leechers: Option<u32>,
+    pub(crate) peers_returned: usize,
+    pub(crate) consecutive_failures: u32
+}
+
+impl TrackerStatus {
+    pub(crate) fn new(url: &str, now: Instant) -> TrackerStatus {
+        TrackerStatus {
+            url: url.to_string(),
+            last_announce: None,
+            next_announce: now,
+            last_error: None,
+            warning: None,
+            seeders: None,
+            leechers: None,
+            peers_returned: 0,
+            consecutive_failures: 0
+        }
+    }
+
+    pub(crate) fn is_due(&self, now: Instant) -> bool {
+        self.next_announce <= now
+    }
+
+    // Returns the peers of a successful announce
+    pub(crate) fn record(&mut self, tracker_announce: &TrackerAnnounce, now: Instant) -> Vec<PeerAddress> {
+        self.last_announce = Some(now);
+        let result = match &tracker_announce.result {
+            Ok(response) => response.get_peer_addresses().map(|peers| (response, peers)).map_err(|error| error.to_string()),
+            Err(error) => Err(error.to_string())
+        };
+        match result {
+            Ok((response, peers)) => {
+                self.next_announce = now + Duration::from_secs(response.interval as u64);
+                self.last_error = None;
+                self.warning = response.warning.clone();
+                self.seeders = response.seeders;
+                self.leechers = response.leechers;
+                self.peers_returned = peers.len();
+                self.consecutive_failures = 0;
+                peers
+            },
+            Err(error) => {
+                self.consecutive_failures += 1;
+                self.next_announce = now + failure_retry_delay(self.consecutive_failures);
+                self.last_error = Some(error);
+                self.peers_returned = 0;
+                Vec::new()
+            }
+        }
+    }
+}
+
+fn failure_retry_delay(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    FAILURE_RETRY_DELAY.saturating_mul(1 << exponent).min(MAX_FAILURE_RETRY_DELAY)
+}
+
+// Statuses of all the trackers of the torrent, every tracker is announced to only when it is due
+pub(crate) struct SwarmTrackers {
+    statuses: Vec<TrackerStatus>
+}
+
+impl SwarmTrackers {
+    pub(crate) fn new(tiers: &[Vec<String>], now: Instant) -> SwarmTrackers {
+        let mut statuses: Vec<TrackerStatus> = Vec::new();
+        for url in tiers.iter().flatten() {
+            if !statuses.iter().any(|status| &status.url == url) {
+                statuses.push(TrackerStatus::new(url, now));
+            }
+        }
+        SwarmTrackers { statuses }
+    }
+
+    pub(crate) fn statuses(&self) -> &[TrackerStatus] {
+        &self.statuses
+    }
+
+    // When the earliest of the trackers should be announced to again
+    pub(crate) fn next_announce(&self) -> Option<Instant> {
+        self.statuses.iter().map(|status| status.next_announce).min()
+    }
+
+    pub(crate) fn due_urls(&self, now: Instant) -> Vec<String> {
+        self.statuses.iter().filter(|status| status.is_due(now)).map(|status| status.url.clone()).collect()
+    }
+
+    // Every tracker has to learn about the events, the regular announces only go to the trackers which are due
+    pub(crate) fn announce_due(&self, request: &TrackerRequest, udp_socket: &Arc<UdpTrackerSocket>, tracker_timeout: Duration, now: Instant) -> SwarmAnnouncement {
+        let urls = if request.event == AnnounceEvent::None {
+            self.due_urls(now)
+        } else {
+            self.statuses.iter().map(|status| status.url.clone()).collect()
+        };
+        SwarmAnnouncement::start(&[urls], request, udp_socket, tracker_timeout)
+    }
+
+    // When we run out of peers the trackers are asked again without waiting for their intervals
+    pub(crate) fn make_due(&mut self, now: Instant) {
+        for status in self.statuses.iter_mut() {
+            status.next_announce = status.next_announce.min(now);
+        }
+    }
+
+    pub(crate) fn record(&mut self, tracker_announce: &TrackerAnnounce, now: Instant) -> Vec<PeerAddress> {
+        match self.statuses.iter_mut().find(|status| status.url == tracker_announce.url) {
+            Some(status) => status.record(tracker_announce, now),
+            None => Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::new_error;
+    use crate::tracker::TrackerResponse;
+
+    fn failed_announce(url: &str) -> TrackerAnnounce {
+        TrackerAnnounce { url: url.to_string(), result: Err(new_error("Connection refused".to_string())) }
+    }
+
+    #[test]
+    fn should_back_off_exponentially_on_failures() {
+        let now = Instant::now();
+        let mut status = TrackerStatus::new("udp://tracker.example:6969", now);
+        assert!(status.is_due(now));
+
+        status.record(&failed_announce("udp://tracker.example:6969"), now);
+        assert_eq!(status.consecutive_failures, 1);
+        assert_eq!(status.next_announce, now + Duration::from_secs(15));
+        assert_eq!(status.last_error, Some("Connection refused".to_string()));
+        assert!(!status.is_due(now + Duration::from_secs(14)));
+
+        status.record(&failed_announce("udp://tracker.example:6969"), now);
+        assert_eq!(status.next_announce, now + Duration::from_secs(30));
+        status.record(&failed_announce("udp://tracker.example:6969"), now);
+        assert_eq!(status.next_announce, now + Duration::from_secs(60));
+        for _ in 0..20 {
+            status.record(&failed_announce("udp://tracker.example:6969"), now);
+        }
+        assert_eq!(status.next_announce, now + MAX_FAILURE_RETRY_DELAY);
+    }
+
+    #[test]
+    fn should_record_successful_announce() {
+        let now = Instant::now();
+        let mut trackers = SwarmTrackers::new(&[vec!["http://a/announce".to_string()], vec!["http://b/announce".to_string(), "http://a/announce".to_string()]], now);
+        assert_eq!(trackers.statuses().len(), 2);
+        trackers.record(&failed_announce("http://a/announce"), now);
+
+        let response = TrackerResponse {
+            interval: 1800,
+            peers: vec![127, 0, 0, 1, 26, 225],
+            peers6: Vec::new(),
+            seeders: Some(3),
+            leechers: Some(5),
+            warning: Some("Slow down".to_string())
+        };
+        let peers = trackers.record(&TrackerAnnounce { url: "http://a/announce".to_string(), result: Ok(response) }, now);
+        assert_eq!(peers.len(), 1);
+        let status = &trackers.statuses()[0];
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.last_error, None);
+        assert_eq!(status.warning, Some("Slow down".to_string()));
+        assert_eq!((status.seeders, status.leechers, status.peers_returned), (Some(3), Some(5), 1));
+        assert_eq!(status.next_announce, now + Duration::from_secs(1800));
+        assert_eq!(trackers.due_urls(now), vec!["http://b/announce".to_string()]);
+        assert_eq!(trackers.next_announce(), Some(now));
+        trackers.make_due(now);
+        assert_eq!(trackers.due_urls(now).len(), 2);
+    }
+}
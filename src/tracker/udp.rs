@@ -0,0 +1,172 @@
+// Client side of the UDP tracker protocol https://www.bittorrent.org/beps/bep_0015.html
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use crate::error::new_error;
+use super::messages::{Action, ErrorResponse};
+
+const MAX_UDP_ATTEMPTS: u32 = 5;
+const UDP_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The tracker which each request was sent to, only it may answer the request
+type PendingRequests = Arc<Mutex<HashMap<u32, (SocketAddr, oneshot::Sender<Vec<u8>>)>>>;
+
+// A single socket shared by all the UDP trackers: responses are routed back to the
+// waiting requests by their transaction_id
+pub(crate) struct UdpTrackerSocket {
+    socket: Arc<UdpSocket>,
+    pending_requests: PendingRequests,
+    dispatcher: JoinHandle<()>
+}
+
+impl UdpTrackerSocket {
+    pub(crate) async fn bind(address: SocketAddr) -> Result<UdpTrackerSocket, anyhow::Error> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        let pending_requests: PendingRequests = Arc::new(Mutex::new(HashMap::new()));
+
+        let dispatcher_socket = Arc::clone(&socket);
+        let dispatcher_pending_requests = Arc::clone(&pending_requests);
+        let dispatcher = tokio::spawn(async move {
+            let mut response_buf: [u8; 4096] = [0; 4096];
+            loop {
+                if let Ok((bytes_read, from)) = dispatcher_socket.recv_from(&mut response_buf).await {
+                    if bytes_read < 8 {
+                        continue;
+                    }
+                    let transaction_id = u32::from_be_bytes(response_buf[4..8].try_into().unwrap());
+                    let waiting = {
+                        let mut pending_requests = dispatcher_pending_requests.lock().unwrap();
+                        match pending_requests.get(&transaction_id) {
+                            Some((tracker_address, _)) if *tracker_address == from => pending_requests.remove(&transaction_id).map(|(_, sender)| sender),
+                            _ => None
+                        }
+                    };
+                    if let Some(waiting) = waiting {
+                        let _ = waiting.send(response_buf[0..bytes_read].to_vec());
+                    }
+                }
+            }
+        });
+        Ok(UdpTrackerSocket {
+            socket,
+            pending_requests,
+            dispatcher
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // Sends the request and waits for the response with the same transaction_id, retrying when the tracker does not respond
+    pub(crate) async fn request(&self, request: &[u8], tracker_address: SocketAddr, transaction_id: u32) -> Result<Vec<u8>, anyhow::Error> {
+        let tracker_address = self.to_socket_family(tracker_address);
+        let result = self.send_until_response(request, tracker_address, transaction_id).await;
+        self.pending_requests.lock().unwrap().remove(&transaction_id);
+        let response = result?;
+        if Action::of_response(&response)? == Action::Error {
+            let error_response = ErrorResponse::parse(&response)?;
+            return Err(new_error(format!("UDP tracker {} returned an error: {}", tracker_address, error_response.message)));
+        }
+        Ok(response)
+    }
+
+    async fn send_until_response(&self, request: &[u8], tracker_address: SocketAddr, transaction_id: u32) -> Result<Vec<u8>, anyhow::Error> {
+        for attempt in 1..=MAX_UDP_ATTEMPTS {
+            let (sender, receiver) = oneshot::channel::<Vec<u8>>();
+            self.pending_requests.lock().unwrap().insert(transaction_id, (tracker_address, sender));
+            self.socket.send_to(request, tracker_address).await?;
+            match tokio::time::timeout(UDP_RESPONSE_TIMEOUT, receiver).await {
+                Ok(Ok(response)) => return Ok(response),
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    println!("No response from UDP tracker {}, attempt {} of {}", tracker_address, attempt, MAX_UDP_ATTEMPTS);
+                }
+            }
+        }
+        Err(new_error(format!("UDP tracker {} did not respond", tracker_address)))
+    }
+
+    // IPv4 trackers are reached from an IPv6 socket through IPv4-mapped addresses
+    fn to_socket_family(&self, tracker_address: SocketAddr) -> SocketAddr {
+        match (self.socket.local_addr(), tracker_address) {
+            (Ok(SocketAddr::V6(_)), SocketAddr::V4(address)) => SocketAddr::new(address.ip().to_ipv6_mapped().into(), address.port()),
+            _ => tracker_address
+        }
+    }
+}
+
+impl Drop for UdpTrackerSocket {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::messages::{ConnectRequest, ConnectResponse};
+
+    #[tokio::test]
+    async fn should_route_responses_by_transaction_id() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_address = tracker.local_addr().unwrap();
+        // The tracker answers two requests in the reverse order
+        tokio::spawn(async move {
+            let mut buf: [u8; 16] = [0; 16];
+            let mut requests: Vec<(ConnectRequest, SocketAddr)> = Vec::new();
+            while requests.len() < 2 {
+                let (_, client_address) = tracker.recv_from(&mut buf).await.unwrap();
+                requests.push((ConnectRequest::parse(&buf).unwrap(), client_address));
+            }
+            for (request, client_address) in requests.iter().rev() {
+                let response = ConnectResponse::new(request.transaction_id, request.transaction_id as u64 * 10);
+                tracker.send_to(&response.get_bytes(), client_address).await.unwrap();
+            }
+        });
+
+        let socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let request = |transaction_id: u32| {
+            let socket = &socket;
+            async move {
+                let request = ConnectRequest::new(transaction_id);
+                let response = socket.request(&request.get_bytes(), tracker_address, transaction_id).await.unwrap();
+                ConnectResponse::parse(&response).unwrap().connection_id
+            }
+        };
+        let connection_ids = tokio::join!(request(1), request(2));
+        assert_eq!(connection_ids, (10, 20));
+    }
+
+    #[tokio::test]
+    async fn should_bind_to_ephemeral_port() {
+        let first_socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let second_socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        assert_ne!(first_socket.local_addr().unwrap().port(), 0);
+        assert_ne!(first_socket.local_addr().unwrap(), second_socket.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_ignore_responses_from_other_addresses() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_address = tracker.local_addr().unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        tokio::spawn(async move {
+            let mut buf: [u8; 16] = [0; 16];
+            let (_, client_address) = tracker.recv_from(&mut buf).await.unwrap();
+            let request = ConnectRequest::parse(&buf).unwrap();
+            // Another host guessing the transaction_id answers first
+            spoofer.send_to(&ConnectResponse::new(request.transaction_id, 666).get_bytes(), client_address).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tracker.send_to(&ConnectResponse::new(request.transaction_id, 42).get_bytes(), client_address).await.unwrap();
+        });
+
+        let socket = UdpTrackerSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let response = socket.request(&ConnectRequest::new(7).get_bytes(), tracker_address, 7).await.unwrap();
+        assert_eq!(ConnectResponse::parse(&response).unwrap().connection_id, 42);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::new_error;
+
 pub(crate) fn url_encode_bytes(bytes: &[u8]) -> String {
     let encoded_bytes: Vec<String> = bytes.iter()
         .map(|b: &u8| format!("%{:02X}", b))
@@ -5,4 +7,62 @@ pub(crate) fn url_encode_bytes(bytes: &[u8]) -> String {
     encoded_bytes.join("")
 }
 
-//TODO: Add tests
\ No newline at end of file
+pub(crate) fn url_decode_bytes(input: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let input_bytes = input.as_bytes();
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut position = 0;
+    while position < input_bytes.len() {
+        match input_bytes[position] {
+            b'%' => {
+                let hex_digits = input.get(position + 1..position + 3)
+                    .ok_or(new_error(format!("Incomplete percent-encoding at position {} in {:?}", position, input)))?;
+                decoded.push(u8::from_str_radix(hex_digits, 16)?);
+                position += 3;
+            },
+            b'+' => {
+                decoded.push(b' ');
+                position += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                position += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+pub(crate) fn parse_query(query: &str) -> Result<Vec<(String, Vec<u8>)>, anyhow::Error> {
+    let mut params: Vec<(String, Vec<u8>)> = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decoded_name = String::from_utf8(url_decode_bytes(name)?)?;
+        params.push((decoded_name, url_decode_bytes(value)?));
+    }
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_decode_bytes() {
+        let bytes: Vec<u8> = vec![0, 18, 52, 86, 120, 154, 188, 222, 255];
+        assert_eq!(url_encode_bytes(&bytes), "%00%12%34%56%78%9A%BC%DE%FF");
+        assert_eq!(url_decode_bytes(&url_encode_bytes(&bytes)).unwrap(), bytes);
+        assert_eq!(url_decode_bytes("a+b%2fc").unwrap(), b"a b/c".to_vec());
+        assert!(url_decode_bytes("%1").is_err());
+    }
+
+    #[test]
+    fn should_parse_query_with_repeated_params() {
+        let params = parse_query("info_hash=%01%02&info_hash=%03&compact=1&no_value").unwrap();
+        assert_eq!(params, vec![
+            ("info_hash".to_string(), vec![1, 2]),
+            ("info_hash".to_string(), vec![3]),
+            ("compact".to_string(), b"1".to_vec()),
+            ("no_value".to_string(), Vec::new())
+        ]);
+    }
+}